@@ -0,0 +1,273 @@
+use crate::chunk::{Chunk, Function};
+use crate::compiler::{self, CompileOptions};
+use crate::error::LoxResult;
+use crate::program::Program;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const LOX_CACHE_DIR_VAR: &str = "LOX_CACHE_DIR";
+const LOX_NO_CACHE_VAR: &str = "LOX_NO_CACHE";
+
+/// Returns the compiled script for `source`, the file `name`, reusing a previously cached copy
+/// of its chunk when the source hash matches. Only scripts that compiled without errors are
+/// returned or cached.
+pub fn load_or_compile(
+    name: String,
+    source: String,
+    options: &CompileOptions,
+) -> LoxResult<Program> {
+    match cache_dir() {
+        Some(dir) => load_or_compile_in(&dir, name, source, options),
+        None => compiler::compile_file(name, source, options),
+    }
+}
+
+/// Like `load_or_compile`, with the cache kept in `dir`.
+fn load_or_compile_in(
+    dir: &Path,
+    name: String,
+    source: String,
+    options: &CompileOptions,
+) -> LoxResult<Program> {
+    // Includes are found relative to the file, and diagnostics name it
+    let key = format!("{:?}\0{}\0{}", options, name, source);
+    let path = dir.join(format!("{:016x}.loxc", source_hash(&key)));
+
+    if let Some(chunk) = fs::read(&path)
+        .ok()
+        .and_then(|bytes| Chunk::from_bytes(&bytes).ok())
+    {
+        return Ok(Function::script(chunk).into());
+    }
+
+    let script = compiler::compile_file(name.clone(), source, options)?;
+    // Cached programs don't keep their warnings or probe sites, so those are compiled each time.
+    // The key only covers this file, so neither are programs whose includes could change.
+    let includes = script.files().iter().any(|file| *file != name);
+    if script.warnings().is_empty() && script.probes().is_empty() && !includes {
+        // A cache we can't write to only costs us the speedup
+        let _ = fs::create_dir_all(dir)
+            .and_then(|_| fs::write(&path, script.script().chunk.to_bytes()));
+    }
+
+    Ok(script)
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    if env::var(LOX_NO_CACHE_VAR).is_ok() {
+        return None;
+    }
+    if let Ok(dir) = env::var(LOX_CACHE_DIR_VAR) {
+        return Some(PathBuf::from(dir));
+    }
+    if let Ok(dir) = env::var("XDG_CACHE_HOME") {
+        return Some(PathBuf::from(dir).join("lox"));
+    }
+    env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".cache").join("lox"))
+}
+
+/// FNV-1a over the crate version and the source text (along with the options it was compiled
+/// with). Unlike `DefaultHasher` this is stable across Rust releases, and salting with the
+/// version invalidates entries whenever the bytecode format may have changed.
+fn source_hash(source: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in env!("CARGO_PKG_VERSION")
+        .bytes()
+        .chain(std::iter::once(0))
+        .chain(source.bytes())
+    {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chunk::{Constant, OpCode};
+    use crate::intern::intern;
+    use crate::vm::test::Buffer;
+    use crate::vm::{VmOptions, VM};
+    use std::sync::Arc;
+
+    #[test]
+    fn round_trip() {
+        let source = String::from(
+            r#"var a = "one"; print a + " two"; print -1.5 == nil; fun f(x) { return x; } print x"00ff"; fun g() { yield 1; }"#,
+        );
+        let program = compiler::compile(source, &CompileOptions::default()).unwrap();
+        let chunk = &program.script().chunk;
+        let loaded = Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+
+        assert_eq!(chunk.code, loaded.code);
+        assert_eq!(chunk.to_bytes(), loaded.to_bytes());
+    }
+
+    #[test]
+    fn line_numbers() {
+        let source = String::from("var a = 1;\nprint a +\n  2;\n\nprint a;");
+        let program = compiler::compile(source, &CompileOptions::default()).unwrap();
+        let chunk = &program.script().chunk;
+        let loaded = Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+
+        let lines: Vec<_> = (0..chunk.code.len())
+            .map(|offset| loaded.line_for_offset(offset))
+            .collect();
+        // var a = 1; print a + 2; print a; return
+        assert_eq!(vec![1, 1, 1, 1, 2, 2, 3, 3, 3, 3, 5, 5, 5, 5, 5], lines);
+    }
+
+    #[test]
+    fn many_constants() {
+        let source: String = (0..1000).map(|i| format!("print {}.5;", i)).collect();
+        let program = compiler::compile(source, &CompileOptions::default()).unwrap();
+        let chunk = &program.script().chunk;
+        let loaded = Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+
+        assert_eq!(1000, loaded.constants().len());
+        assert_eq!(chunk.constants(), loaded.constants());
+    }
+
+    #[test]
+    fn truncated_input() {
+        let program =
+            compiler::compile(String::from("print 1;"), &CompileOptions::default()).unwrap();
+        let bytes = program.script().chunk.to_bytes();
+
+        assert!(Chunk::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Chunk::from_bytes(&bytes[4..]).is_err());
+    }
+
+    /// Serializes a chunk of the given bytes and constants, and reads it back.
+    fn load(code: &[u8], constants: Vec<Constant>) -> anyhow::Result<Chunk> {
+        let mut chunk = Chunk::new();
+        for &byte in code {
+            chunk.write(byte, 1usize);
+        }
+        for constant in constants {
+            chunk.add_constant(constant).unwrap();
+        }
+        Chunk::from_bytes(&chunk.to_bytes())
+    }
+
+    #[test]
+    fn corrupted_code() {
+        let op = |op: OpCode| u8::from(op);
+        let name = || Constant::String(intern("x"));
+
+        assert!(load(&[op(OpCode::Nil), op(OpCode::Return)], vec![]).is_ok());
+        // An unknown opcode, a missing operand and a constant that isn't there
+        assert!(load(&[200, op(OpCode::Return)], vec![]).is_err());
+        assert!(load(&[op(OpCode::Constant)], vec![]).is_err());
+        assert!(load(
+            &[op(OpCode::GetGlobal), 1, op(OpCode::Return)],
+            vec![name()]
+        )
+        .is_err());
+        // A global named by a number
+        let number = vec![Constant::Number(1.0)];
+        assert!(load(&[op(OpCode::GetGlobal), 0, op(OpCode::Return)], number).is_err());
+        // Running off the end, falling or jumping
+        assert!(load(&[op(OpCode::Nil), op(OpCode::Pop)], vec![]).is_err());
+        let jump = [op(OpCode::Jump), 0, 1, op(OpCode::Return)];
+        assert!(load(&jump, vec![]).is_err());
+        // Into the middle of an instruction
+        let jump = [
+            op(OpCode::Jump),
+            0,
+            1,
+            op(OpCode::Constant),
+            0,
+            op(OpCode::Return),
+        ];
+        assert!(load(&jump, vec![name()]).is_err());
+        // Yielding from the script
+        assert!(load(
+            &[op(OpCode::Nil), op(OpCode::Yield), op(OpCode::Return)],
+            vec![]
+        )
+        .is_err());
+
+        // Nested functions are checked too
+        let mut body = Chunk::new();
+        body.write(OpCode::Nil, 1usize);
+        let function = Constant::Function(Arc::new(Function {
+            arity: 0,
+            chunk: body,
+            name: Some("f".to_string()),
+            params: Vec::new(),
+            generator: false,
+        }));
+        assert!(load(
+            &[op(OpCode::Constant), 0, op(OpCode::Return)],
+            vec![function]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn corrupted_bytes() {
+        let source = String::from(
+            "fun f(n) { var s = 0; for (var i = 0; i < n; i = i + 1) s = s + i; return s; }\n\
+             class A { m() { return this.x; } }\n\
+             try { print f(3); } catch (e) { print e; }",
+        );
+        let program = compiler::compile(source, &CompileOptions::default()).unwrap();
+        let bytes = program.script().chunk.to_bytes();
+
+        // Whatever byte is damaged, the chunk is rejected or runs to a result or an error, without
+        // panicking the VM
+        for offset in 0..bytes.len() {
+            for damage in [0x01, 0x80, 0xff] {
+                let mut bytes = bytes.clone();
+                bytes[offset] ^= damage;
+                let Ok(chunk) = Chunk::from_bytes(&bytes) else {
+                    continue;
+                };
+                let mut vm = VM::with_output(Box::new(std::io::sink()));
+                vm.set_options(VmOptions {
+                    max_instructions: Some(10_000),
+                    ..Default::default()
+                });
+                let _ = vm.run(&Function::script(chunk).into());
+            }
+        }
+    }
+
+    #[test]
+    fn includes_are_not_cached() {
+        let dir = std::env::temp_dir().join(format!("lox-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("cache");
+        let main = dir.join("main.lox").display().to_string();
+        let run = || {
+            let source = String::from("#include \"lib.lox\"\nhi();");
+            let program =
+                load_or_compile_in(&cache, main.clone(), source, &CompileOptions::default())
+                    .unwrap();
+            let out = Buffer::default();
+            VM::with_output(Box::new(out.clone()))
+                .run(&program)
+                .unwrap();
+            out.contents()
+        };
+
+        std::fs::write(dir.join("lib.lox"), "fun hi() { print \"one\"; }").unwrap();
+        assert_eq!("one\n", run());
+        std::fs::write(dir.join("lib.lox"), "fun hi() { print \"two\"; }").unwrap();
+        let second = run();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!("two\n", second);
+    }
+
+    #[test]
+    fn hash_depends_on_source() {
+        assert_eq!(source_hash("print 1;"), source_hash("print 1;"));
+        assert_ne!(source_hash("print 1;"), source_hash("print 2;"));
+    }
+}
@@ -1,13 +1,34 @@
 use anyhow::{anyhow, Result};
 
 use crate::error::{ChunkError, EvaluationError};
+use crate::intern::{intern, Symbol};
+use crate::natives::Capability;
+use crate::patch::{DecodedChunk, Operand};
+use crate::vm::NativeContext;
+use crate::worker::{Channel, Worker};
 
-use std::ops::{Add, Div, Mul, Neg, Not, Sub};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::{Add, Deref, DerefMut, Div, Mul, Neg, Not, Sub};
+use std::rc::Rc;
+use std::sync::Arc;
 
-const MAX_CONSTANTS: usize = 256;
+/// Constants past the first 256 are loaded with `OpCode::ConstantLong`, whose operand is 24 bits.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+/// The longest string, byte array or list `*` or `+` will build, so a runaway count or a value
+/// doubled in a loop fails cleanly rather than exhausting memory.
+pub const MAX_REPEAT_LEN: usize = 1 << 24;
+
+/// Leads every serialized chunk, followed by a format version byte, so bytecode from another
+/// version of the VM is rejected rather than misread.
+const BYTECODE_MAGIC: &[u8; 4] = b"LOXC";
+const BYTECODE_VERSION: u8 = 3;
 
 // TODO: Move to module
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum OpCode {
     Return,
@@ -29,6 +50,56 @@ pub enum OpCode {
     DefineGlobal,
     GetGlobal,
     SetGlobal,
+    Echo,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    IndexGet,
+    Slice,
+    Yield,
+    /// Replaces the generator on top of the stack with whether it has finished.
+    Done,
+    /// Calls the function beneath its arguments with the value beneath it as an extra first
+    /// argument.
+    Pipe,
+    /// Exchanges the top two values on the stack.
+    Swap,
+    /// Pushes a copy of the value beneath the top of the stack.
+    Over,
+    /// Stores the value on top of the stack at an index of the list beneath it, leaving the value.
+    IndexSet,
+    /// Replaces the given number of values on top of the stack with a list of them.
+    BuildList,
+    /// Replaces the given number of key and value pairs on top of the stack with a map of them.
+    BuildMap,
+    /// Replaces a list or map with a cursor over its elements or keys, for a `for`-`in` loop.
+    /// Generators are left as they are.
+    Iter,
+    /// Registers a handler whose `catch` block starts the given offset ahead, for the `try`
+    /// block that follows.
+    PushHandler,
+    /// Removes the handler registered for the `try` block just finished.
+    PopHandler,
+    /// Unwinds to the innermost handler with the value on top of the stack.
+    Throw,
+    /// Fails unless the local in the given slot has the `ValueType` in the second operand, for
+    /// parameters with a type annotation.
+    CheckType,
+    /// Loads a constant like `Constant`, with a big-endian 24 bit index for chunks with more
+    /// than 256 constants.
+    ConstantLong,
+    /// Calls the VM's probe handler, if it has one, with the big-endian 16 bit probe id.
+    Probe,
+    /// Calls like `Call` with the argument count in the first operand, the last of which, as
+    /// many as the second operand, are matched to parameters by the names on top of the stack.
+    CallKeywords,
 }
 
 impl From<OpCode> for u8 {
@@ -60,15 +131,88 @@ impl TryFrom<u8> for OpCode {
             16 => Ok(OpCode::DefineGlobal),
             17 => Ok(OpCode::GetGlobal),
             18 => Ok(OpCode::SetGlobal),
+            19 => Ok(OpCode::Echo),
+            20 => Ok(OpCode::GetLocal),
+            21 => Ok(OpCode::SetLocal),
+            22 => Ok(OpCode::Jump),
+            23 => Ok(OpCode::JumpIfFalse),
+            24 => Ok(OpCode::Loop),
+            25 => Ok(OpCode::Call),
+            26 => Ok(OpCode::Class),
+            27 => Ok(OpCode::GetProperty),
+            28 => Ok(OpCode::SetProperty),
+            29 => Ok(OpCode::Method),
+            30 => Ok(OpCode::IndexGet),
+            31 => Ok(OpCode::Slice),
+            32 => Ok(OpCode::Yield),
+            33 => Ok(OpCode::Done),
+            34 => Ok(OpCode::Pipe),
+            35 => Ok(OpCode::Swap),
+            36 => Ok(OpCode::Over),
+            37 => Ok(OpCode::IndexSet),
+            38 => Ok(OpCode::BuildList),
+            39 => Ok(OpCode::BuildMap),
+            40 => Ok(OpCode::Iter),
+            41 => Ok(OpCode::PushHandler),
+            42 => Ok(OpCode::PopHandler),
+            43 => Ok(OpCode::Throw),
+            44 => Ok(OpCode::CheckType),
+            45 => Ok(OpCode::ConstantLong),
+            46 => Ok(OpCode::Probe),
+            47 => Ok(OpCode::CallKeywords),
             n => Err(ChunkError::UnknownOpCode(n).into()),
         }
     }
 }
 
+#[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    constants: Array<Value>,
-    lines: Vec<usize>,
+    constants: Array<Constant>,
+    /// The source line of each byte of code, run-length encoded, since consecutive bytes mostly
+    /// come from the same line.
+    lines: Vec<LineRun>,
+}
+
+/// A run of consecutive bytes of code from the same source line.
+#[derive(Debug)]
+struct LineRun {
+    /// Offset of the run's first byte.
+    start: usize,
+    line: usize,
+}
+
+/// A value compiled into a chunk. Unlike a `Value`, nothing in a constant belongs to a
+/// particular VM, so compiled code can be shared between threads; each VM makes its own values
+/// from the constants as it loads them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Constant {
+    #[default]
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Symbol),
+    Bytes(Vec<u8>),
+    Function(Arc<Function>),
+}
+
+impl From<&Constant> for Value {
+    fn from(constant: &Constant) -> Value {
+        match constant {
+            Constant::Nil => Value::Nil,
+            Constant::Bool(b) => Value::Bool(*b),
+            Constant::Number(n) => Value::Number(*n),
+            Constant::String(s) => Value::from_symbol(s.clone()),
+            Constant::Bytes(bytes) => Value::from_bytes(bytes.clone()),
+            Constant::Function(function) => Value::from_function(Arc::clone(function)),
+        }
+    }
+}
+
+impl std::fmt::Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", Value::from(self))
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq, PartialOrd)]
@@ -77,7 +221,7 @@ pub enum Value {
     Nil,
     Bool(bool),
     Number(f64),
-    Obj(Box<Obj>),
+    Obj(Rc<Obj>),
 }
 
 impl Value {
@@ -90,29 +234,848 @@ impl Value {
     }
 
     pub fn from_string(s: String) -> Value {
-        let obj = Obj {
-            obj_type: ObjType::String(s),
-            objects: None,
-        };
-        Value::Obj(Box::new(obj))
+        Value::from_symbol(intern(&s))
+    }
+
+    pub fn from_symbol(s: Symbol) -> Value {
+        let obj = Obj::new(ObjType::String(s));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        self.as_symbol().map(Symbol::as_str)
+    }
+
+    pub fn as_symbol(&self) -> Option<&Symbol> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::String(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Value {
+        let obj = Obj::new(ObjType::Bytes(bytes));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Bytes(bytes) => Some(bytes),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_function(function: Arc<Function>) -> Value {
+        let obj = Obj::new(ObjType::Function(function));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_function(&self) -> Option<Arc<Function>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Function(function) => Some(Arc::clone(function)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_native(native: Native) -> Value {
+        let obj = Obj::new(ObjType::Native(native));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_native(&self) -> Option<Native> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Native(native) => Some(*native),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_host_function(function: HostFunction) -> Value {
+        let obj = Obj::new(ObjType::HostFunction(Rc::new(function)));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_host_function(&self) -> Option<Rc<HostFunction>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::HostFunction(function) => Some(Rc::clone(function)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_class(class: Class) -> Value {
+        let obj = Obj::new(ObjType::Class(Rc::new(RefCell::new(class))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn from_list(items: Vec<Value>) -> Value {
+        let obj = Obj::new(ObjType::List(Rc::new(RefCell::new(List::new(items)))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_list(&self) -> Option<Rc<RefCell<List>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::List(list) => Some(Rc::clone(list)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_map(map: Map) -> Value {
+        let obj = Obj::new(ObjType::Map(Rc::new(RefCell::new(map))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_map(&self) -> Option<Rc<RefCell<Map>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Map(map) => Some(Rc::clone(map)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_cursor(items: Vec<Value>) -> Value {
+        let cursor = Cursor { items, next: 0 };
+        let obj = Obj::new(ObjType::Cursor(Rc::new(RefCell::new(cursor))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_cursor(&self) -> Option<Rc<RefCell<Cursor>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Cursor(cursor) => Some(Rc::clone(cursor)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_channel(channel: Arc<Channel>) -> Value {
+        let obj = Obj::new(ObjType::Channel(channel));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_channel(&self) -> Option<Arc<Channel>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Channel(channel) => Some(Arc::clone(channel)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_worker(worker: Worker) -> Value {
+        let obj = Obj::new(ObjType::Worker(Rc::new(RefCell::new(worker))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_worker(&self) -> Option<Rc<RefCell<Worker>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Worker(worker) => Some(Rc::clone(worker)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<Rc<RefCell<Class>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Class(class) => Some(Rc::clone(class)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_instance(instance: Instance) -> Value {
+        let obj = Obj::new(ObjType::Instance(Rc::new(RefCell::new(instance))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_instance(&self) -> Option<Rc<RefCell<Instance>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Instance(instance) => Some(Rc::clone(instance)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn from_bound_method(bound: BoundMethod) -> Value {
+        let obj = Obj::new(ObjType::BoundMethod(Rc::new(bound)));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn from_generator(generator: Generator) -> Value {
+        let obj = Obj::new(ObjType::Generator(Rc::new(RefCell::new(generator))));
+        Value::Obj(Rc::new(obj))
+    }
+
+    pub fn as_generator(&self) -> Option<Rc<RefCell<Generator>>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::Generator(generator) => Some(Rc::clone(generator)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_bound_method(&self) -> Option<Rc<BoundMethod>> {
+        match self {
+            Value::Obj(obj) => match &obj.obj_type {
+                ObjType::BoundMethod(bound) => Some(Rc::clone(bound)),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, PartialEq, PartialOrd)]
 pub struct Obj {
     obj_type: ObjType,
-    objects: Option<Box<Obj>>,
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// What each element of a list, entry of a map and field of an instance adds to the estimated
+/// size of the heap.
+pub(crate) const LIST_ITEM_SIZE: usize = std::mem::size_of::<Value>();
+pub(crate) const MAP_ENTRY_SIZE: usize = std::mem::size_of::<(MapKey, Value, usize)>();
+pub(crate) const FIELD_SIZE: usize = std::mem::size_of::<(String, Value)>();
+
+thread_local! {
+    /// An estimate of the bytes held by the objects alive on this thread, for
+    /// `VmOptions::max_heap_bytes`. Objects count themselves as they're created and dropped,
+    /// and code changing the size of one in place counts the change.
+    static HEAP_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+pub(crate) fn heap_bytes() -> isize {
+    HEAP_BYTES.with(Cell::get)
+}
+
+/// Adds `bytes`, which is negative for memory given back, to the estimated size of the heap.
+pub(crate) fn track_heap(bytes: isize) {
+    HEAP_BYTES.with(|heap| heap.set(heap.get() + bytes));
+}
+
+impl Obj {
+    fn new(obj_type: ObjType) -> Obj {
+        let obj = Obj { obj_type };
+        track_heap(obj.size() as isize);
+        obj
+    }
+
+    /// Roughly how many bytes the object holds, counting what it contains directly but not the
+    /// objects its contents refer to.
+    fn size(&self) -> usize {
+        let contents = match &self.obj_type {
+            ObjType::String(s) => s.len(),
+            ObjType::Bytes(bytes) => bytes.len(),
+            ObjType::List(list) => list.try_borrow().map_or(0, |l| l.len() * LIST_ITEM_SIZE),
+            ObjType::Map(map) => map.try_borrow().map_or(0, |m| m.len() * MAP_ENTRY_SIZE),
+            ObjType::Cursor(cursor) => cursor
+                .try_borrow()
+                .map_or(0, |c| c.items.len() * LIST_ITEM_SIZE),
+            ObjType::Instance(instance) => instance
+                .try_borrow()
+                .map_or(0, |i| i.fields.len() * FIELD_SIZE),
+            _ => 0,
+        };
+        std::mem::size_of::<Obj>() + contents
+    }
+}
+
+impl Clone for Obj {
+    fn clone(&self) -> Obj {
+        Obj::new(self.obj_type.clone())
+    }
+}
+
+impl Drop for Obj {
+    fn drop(&mut self) {
+        track_heap(-(self.size() as isize));
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ObjType {
+    /// Interned, so equal strings are compared by address.
+    String(Symbol),
+    Bytes(Vec<u8>),
+    Function(Arc<Function>),
+    Native(Native),
+    HostFunction(Rc<HostFunction>),
+    /// Classes gain methods after they're created, and instances, lists and maps are mutated
+    /// through any of the values referring to them, so all are shared rather than copied.
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    List(Rc<RefCell<List>>),
+    Map(Rc<RefCell<Map>>),
+    Cursor(Rc<RefCell<Cursor>>),
+    /// Channels are shared with VMs on other threads.
+    Channel(Arc<Channel>),
+    Worker(Rc<RefCell<Worker>>),
+    BoundMethod(Rc<BoundMethod>),
+    Generator(Rc<RefCell<Generator>>),
+}
+
+/// Strings and byte arrays are equal when their contents are. Lists and maps are only equal to
+/// themselves, like instances, so comparing one that contains itself can't recurse forever.
+impl PartialEq for ObjType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ObjType::String(a), ObjType::String(b)) => a == b,
+            (ObjType::Bytes(a), ObjType::Bytes(b)) => a == b,
+            (ObjType::Function(a), ObjType::Function(b)) => a == b,
+            (ObjType::Native(a), ObjType::Native(b)) => a == b,
+            (ObjType::HostFunction(a), ObjType::HostFunction(b)) => a == b,
+            (ObjType::Class(a), ObjType::Class(b)) => Rc::ptr_eq(a, b),
+            (ObjType::Instance(a), ObjType::Instance(b)) => Rc::ptr_eq(a, b),
+            (ObjType::List(a), ObjType::List(b)) => Rc::ptr_eq(a, b),
+            (ObjType::Map(a), ObjType::Map(b)) => Rc::ptr_eq(a, b),
+            (ObjType::Cursor(a), ObjType::Cursor(b)) => Rc::ptr_eq(a, b),
+            (ObjType::Channel(a), ObjType::Channel(b)) => a == b,
+            (ObjType::Worker(a), ObjType::Worker(b)) => Rc::ptr_eq(a, b),
+            (ObjType::BoundMethod(a), ObjType::BoundMethod(b)) => a == b,
+            (ObjType::Generator(a), ObjType::Generator(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Only strings and byte arrays are ordered, by their contents.
+impl PartialOrd for ObjType {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (ObjType::String(a), ObjType::String(b)) => a.partial_cmp(b),
+            (ObjType::Bytes(a), ObjType::Bytes(b)) => a.partial_cmp(b),
+            _ => (self == other).then_some(Ordering::Equal),
+        }
+    }
+}
+
+/// The types a parameter can be annotated with, as in `fun f(a: number)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ValueType {
+    Bool,
+    Number,
+    String,
+    Bytes,
+    List,
+    Map,
+    /// Anything that can be called but a class: functions, natives and bound methods.
+    Function,
+    Class,
+    Instance,
+    Generator,
+    /// Only for what the compiler knows of literals, since `nil` can't be written as a type.
+    Nil,
+}
+
+impl ValueType {
+    const ALL: [ValueType; 11] = [
+        ValueType::Bool,
+        ValueType::Number,
+        ValueType::String,
+        ValueType::Bytes,
+        ValueType::List,
+        ValueType::Map,
+        ValueType::Function,
+        ValueType::Class,
+        ValueType::Instance,
+        ValueType::Generator,
+        ValueType::Nil,
+    ];
+
+    /// The type of the value a constant makes.
+    pub fn of(constant: &Constant) -> ValueType {
+        match constant {
+            Constant::Nil => ValueType::Nil,
+            Constant::Bool(_) => ValueType::Bool,
+            Constant::Number(_) => ValueType::Number,
+            Constant::String(_) => ValueType::String,
+            Constant::Bytes(_) => ValueType::Bytes,
+            Constant::Function(_) => ValueType::Function,
+        }
+    }
+
+    /// The type named in an annotation.
+    pub fn from_name(name: &str) -> Option<ValueType> {
+        Self::ALL.into_iter().find(|t| t.to_string() == name)
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        let obj_type = match value {
+            Value::Bool(_) => return *self == ValueType::Bool,
+            Value::Number(_) => return *self == ValueType::Number,
+            Value::Nil => return *self == ValueType::Nil,
+            Value::Obj(obj) => &obj.obj_type,
+        };
+        matches!(
+            (self, obj_type),
+            (ValueType::String, ObjType::String(_))
+                | (ValueType::Bytes, ObjType::Bytes(_))
+                | (ValueType::List, ObjType::List(_))
+                | (ValueType::Map, ObjType::Map(_))
+                | (ValueType::Function, ObjType::Function(_))
+                | (ValueType::Function, ObjType::Native(_))
+                | (ValueType::Function, ObjType::HostFunction(_))
+                | (ValueType::Function, ObjType::BoundMethod(_))
+                | (ValueType::Class, ObjType::Class(_))
+                | (ValueType::Instance, ObjType::Instance(_))
+                | (ValueType::Generator, ObjType::Generator(_))
+        )
+    }
+
+    /// The type as a runtime error describes what was expected, e.g. "a number".
+    pub fn description(&self) -> &'static str {
+        match self {
+            ValueType::Bool => "a bool",
+            ValueType::Number => "a number",
+            ValueType::String => "a string",
+            ValueType::Bytes => "bytes",
+            ValueType::List => "a list",
+            ValueType::Map => "a map",
+            ValueType::Function => "a function",
+            ValueType::Class => "a class",
+            ValueType::Instance => "an instance",
+            ValueType::Generator => "a generator",
+            ValueType::Nil => "nil",
+        }
+    }
+}
+
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ValueType::Bool => "bool",
+            ValueType::Number => "number",
+            ValueType::String => "string",
+            ValueType::Bytes => "bytes",
+            ValueType::List => "list",
+            ValueType::Map => "map",
+            ValueType::Function => "function",
+            ValueType::Class => "class",
+            ValueType::Instance => "instance",
+            ValueType::Generator => "generator",
+            ValueType::Nil => "nil",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl From<ValueType> for u8 {
+    fn from(t: ValueType) -> u8 {
+        t as u8
+    }
+}
+
+impl TryFrom<u8> for ValueType {
+    type Error = ChunkError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or(ChunkError::Malformed("unknown value type"))
+    }
+}
+
+/// A compiled function. The top level of a script is compiled into one too, with no name.
+#[derive(Debug, Default)]
+pub struct Function {
+    pub arity: u8,
+    pub chunk: Chunk,
+    pub name: Option<String>,
+    /// The parameters' names, which keyword arguments are matched to.
+    pub params: Vec<String>,
+    /// Calling a generator function creates a `Generator` instead of running the body.
+    pub generator: bool,
+}
+
+impl Function {
+    /// Wraps the chunk for the top level of a script.
+    pub fn script(chunk: Chunk) -> Function {
+        Function {
+            chunk,
+            ..Default::default()
+        }
+    }
+}
+
+/// Functions are only equal to themselves.
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Function {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+/// A function implemented in Rust, called with exactly `arity` arguments.
+#[derive(Clone, Copy, Debug)]
+pub enum NativeFn {
+    /// Depends on nothing but its arguments.
+    Args(fn(&[Value]) -> Result<Value>),
+    /// Also uses the state of the VM calling it.
+    Context(fn(&mut NativeContext, &[Value]) -> Result<Value>),
+}
+
+/// A function the host implemented in Rust, which can capture state unlike a `NativeFn`.
+pub type HostFn = dyn Fn(&[Value]) -> Result<Value>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: u8,
+    pub function: NativeFn,
+    /// Natives that reach outside the VM can only be called when the host grants this.
+    pub capability: Option<Capability>,
+}
+
+/// Natives are identified by name, since comparing function pointers is unreliable.
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl PartialOrd for Native {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+/// A function the host registered with `VM::register_fn`, called with exactly `arity`
+/// arguments.
+pub struct HostFunction {
+    pub name: String,
+    pub arity: u8,
+    pub function: Box<HostFn>,
+}
+
+impl std::fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "HostFunction({})", self.name)
+    }
+}
+
+/// Host functions are only equal to themselves.
+impl PartialEq for HostFunction {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for HostFunction {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<String, Arc<Function>>,
+}
+
+impl Class {
+    pub fn new(name: String) -> Class {
+        Class {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<RefCell<Class>>,
+    pub fields: HashMap<String, Value>,
+    /// Set by `freeze()`, after which the fields can't be assigned.
+    pub frozen: bool,
+}
+
+impl Instance {
+    pub fn new(class: Rc<RefCell<Class>>) -> Instance {
+        Instance {
+            class,
+            fields: HashMap::new(),
+            frozen: false,
+        }
+    }
+}
+
+/// A suspended call to a generator function, resumed by calling it.
+#[derive(Debug)]
+pub struct Generator {
+    pub function: Arc<Function>,
+    /// While suspended, the generator's window of the stack: slot zero, then its arguments and
+    /// locals.
+    pub stack: Vec<Value>,
+    pub ip: usize,
+    pub state: GeneratorState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GeneratorState {
+    Suspended,
+    Running,
+    Done,
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Generator {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+/// A map's hashable form of a key. Only numbers, strings and booleans can be keys.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Bool(bool),
+    /// The number's bits, with `-0` stored as `0` since the two are equal.
+    Number(u64),
     String(String),
 }
 
+impl MapKey {
+    /// The key for `value`, or `None` if it can't be one. NaN isn't equal to anything, itself
+    /// included, so couldn't be looked up again.
+    pub fn new(value: &Value) -> Option<MapKey> {
+        match value {
+            Value::Bool(b) => Some(MapKey::Bool(*b)),
+            Value::Number(n) if n.is_nan() => None,
+            Value::Number(n) => Some(MapKey::Number((n + 0.0).to_bits())),
+            value => value.as_string().map(|s| MapKey::String(s.to_string())),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Bool(b) => Value::Bool(*b),
+            MapKey::Number(bits) => Value::Number(f64::from_bits(*bits)),
+            MapKey::String(s) => Value::from_string(s.clone()),
+        }
+    }
+}
+
+/// A list's elements, which it dereferences to. Code that changes them checks `is_frozen()`
+/// first.
+#[derive(Debug, Default)]
+pub struct List {
+    items: Vec<Value>,
+    frozen: bool,
+}
+
+impl List {
+    pub fn new(items: Vec<Value>) -> List {
+        List {
+            items,
+            frozen: false,
+        }
+    }
+
+    /// Whether `freeze()` has made the list read-only.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+}
+
+impl Deref for List {
+    type Target = Vec<Value>;
+
+    fn deref(&self) -> &Vec<Value> {
+        &self.items
+    }
+}
+
+impl DerefMut for List {
+    fn deref_mut(&mut self) -> &mut Vec<Value> {
+        &mut self.items
+    }
+}
+
+/// Keys and their values, kept in the order the keys were first inserted so iterating over a
+/// map is deterministic.
+#[derive(Debug, Default)]
+pub struct Map {
+    entries: Vec<(MapKey, Value)>,
+    indices: HashMap<MapKey, usize>,
+    frozen: bool,
+}
+
+impl Map {
+    /// Whether `freeze()` has made the map read-only.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.indices.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        match self.indices.get(&key) {
+            Some(i) => self.entries[*i].1 = value,
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let i = self.indices.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for index in self.indices.values_mut() {
+            if *index > i {
+                *index -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&MapKey, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Value> + '_ {
+        self.entries.iter().map(|(key, _)| key.to_value())
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+/// Maps are equal when they have the same keys with equal values, whatever their order.
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .entries
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl PartialOrd for Map {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+/// Steps through a snapshot of a list's elements or a map's keys. Calling it returns the next
+/// one, and once they run out it's done, like a generator that has returned.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub struct Cursor {
+    items: Vec<Value>,
+    next: usize,
+}
+
+impl Cursor {
+    /// The next item, or `None` once there are no more, after which the cursor is done.
+    pub fn advance(&mut self) -> Option<Value> {
+        let item = self.items.get(self.next).cloned();
+        self.next = (self.next + 1).min(self.items.len() + 1);
+        item
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.next > self.items.len()
+    }
+}
+
+/// A method read from an instance, which remembers that instance as its receiver.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Arc<Function>,
+}
+
+/// Like functions, classes and instances are only equal to themselves.
+impl PartialEq for Class {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Class {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Instance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::Number(ref n) => write!(f, "{}", n),
+            Self::Number(ref n) => write!(f, "{}", crate::number::format(*n)),
             Self::Bool(ref b) => write!(f, "{}", b),
             Self::Nil => write!(f, "nil"),
             Self::Obj(obj) => {
@@ -122,10 +1085,78 @@ impl std::fmt::Display for Value {
     }
 }
 
+thread_local! {
+    /// The lists and maps being displayed, outermost first.
+    static DISPLAYING: RefCell<Vec<*const ()>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Displays a list or map with `display`, unless it's already being displayed further out because it
+/// contains itself, when `placeholder` stands in for it.
+fn display_once<F>(
+    f: &mut std::fmt::Formatter,
+    container: *const (),
+    placeholder: &str,
+    display: F,
+) -> std::fmt::Result
+where
+    F: FnOnce(&mut std::fmt::Formatter) -> std::fmt::Result,
+{
+    if DISPLAYING.with(|displaying| displaying.borrow().contains(&container)) {
+        return write!(f, "{}", placeholder);
+    }
+    DISPLAYING.with(|displaying| displaying.borrow_mut().push(container));
+    let result = display(f);
+    DISPLAYING.with(|displaying| displaying.borrow_mut().pop());
+    result
+}
+
 impl std::fmt::Display for Obj {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match &self.obj_type {
             ObjType::String(s) => write!(f, "{}", s),
+            ObjType::Bytes(bytes) => {
+                let escaped: String = bytes
+                    .iter()
+                    .flat_map(|b| std::ascii::escape_default(*b))
+                    .map(char::from)
+                    .collect();
+                write!(f, "b\"{}\"", escaped)
+            }
+            ObjType::Function(function) => write!(f, "{}", function),
+            ObjType::Native(_) | ObjType::HostFunction(_) => write!(f, "<native fn>"),
+            ObjType::Class(class) => write!(f, "{}", class.borrow().name),
+            ObjType::BoundMethod(bound) => write!(f, "{}", bound.method),
+            ObjType::Generator(generator) => {
+                let generator = generator.borrow();
+                let name = generator.function.name.as_deref().unwrap_or_default();
+                write!(f, "<generator {}>", name)
+            }
+            ObjType::Instance(instance) => {
+                write!(f, "{} instance", instance.borrow().class.borrow().name)
+            }
+            ObjType::List(list) => display_once(f, Rc::as_ptr(list) as *const (), "[...]", |f| {
+                write!(f, "[")?;
+                for (i, item) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }),
+            ObjType::Map(map) => display_once(f, Rc::as_ptr(map) as *const (), "{...}", |f| {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key.to_value(), value)?;
+                }
+                write!(f, "}}")
+            }),
+            ObjType::Cursor(_) => write!(f, "<cursor>"),
+            ObjType::Channel(_) => write!(f, "<channel>"),
+            ObjType::Worker(_) => write!(f, "<thread>"),
         }
     }
 }
@@ -136,17 +1167,36 @@ impl Add for Value {
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (Self::Number(a), Self::Number(b)) => Ok(Self::Number(a + b)),
-            (Self::Obj(a), Self::Obj(b)) => match (a.obj_type, b.obj_type) {
-                (ObjType::String(a), ObjType::String(b)) => Ok(Self::Obj(Box::new(Obj {
-                    obj_type: ObjType::String(a + &b),
-                    objects: None,
-                }))),
+            (Self::Obj(a), Self::Obj(b)) => match (&a.obj_type, &b.obj_type) {
+                (ObjType::String(a), ObjType::String(b)) => {
+                    checked_concat(a.len(), b.len())?;
+                    Ok(Self::from_string(a.to_string() + b))
+                }
+                (ObjType::Bytes(a), ObjType::Bytes(b)) => {
+                    checked_concat(a.len(), b.len())?;
+                    Ok(Self::from_bytes([&a[..], b].concat()))
+                }
+                (ObjType::List(a), ObjType::List(b)) => {
+                    checked_concat(a.borrow().len(), b.borrow().len())?;
+                    let mut items = a.borrow().clone();
+                    items.extend(b.borrow().iter().cloned());
+                    Ok(Self::from_list(items))
+                }
+                (_, _) => Err(EvaluationError::Arithmatic("add".to_string()).into()),
             },
             (_, _) => Err(EvaluationError::Arithmatic("add".to_string()).into()),
         }
     }
 }
 
+/// Fails if joining sequences of these lengths would build one longer than `MAX_REPEAT_LEN`.
+fn checked_concat(a: usize, b: usize) -> Result<(), EvaluationError> {
+    match a.checked_add(b) {
+        Some(len) if len <= MAX_REPEAT_LEN => Ok(()),
+        _ => Err(EvaluationError::ConcatTooLong(MAX_REPEAT_LEN)),
+    }
+}
+
 impl Sub for Value {
     type Output = Result<Value>;
     fn sub(self, rhs: Self) -> Self::Output {
@@ -162,11 +1212,43 @@ impl Mul for Value {
     fn mul(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
             (Self::Number(a), Self::Number(b)) => Ok(Self::Number(a * b)),
+            (Self::Obj(a), Self::Number(n)) | (Self::Number(n), Self::Obj(a)) => repeat(&a, n),
             (_, _) => Err(EvaluationError::Arithmatic("multiply".to_string()).into()),
         }
     }
 }
 
+/// Repeats a string, byte array or list `count` times. A repeated list holds the same items
+/// each time, not copies of them.
+fn repeat(sequence: &Obj, count: f64) -> Result<Value> {
+    if count.fract() != 0.0 || count < 0.0 || count > usize::MAX as f64 {
+        return Err(EvaluationError::RepeatCount(count).into());
+    }
+    let count = count as usize;
+    let checked_len = |len: usize| match len.checked_mul(count) {
+        Some(len) if len <= MAX_REPEAT_LEN => Ok(()),
+        _ => Err(EvaluationError::RepeatTooLong(MAX_REPEAT_LEN)),
+    };
+
+    match &sequence.obj_type {
+        ObjType::String(s) => {
+            checked_len(s.len())?;
+            Ok(Value::from_string(s.repeat(count)))
+        }
+        ObjType::Bytes(bytes) => {
+            checked_len(bytes.len())?;
+            Ok(Value::from_bytes(bytes.repeat(count)))
+        }
+        ObjType::List(list) => {
+            let list = list.borrow();
+            checked_len(list.len())?;
+            let items = (0..count).flat_map(|_| list.iter().cloned()).collect();
+            Ok(Value::from_list(items))
+        }
+        _ => Err(EvaluationError::Arithmatic("multiply".to_string()).into()),
+    }
+}
+
 impl Div for Value {
     type Output = Result<Value>;
     fn div(self, rhs: Value) -> Self::Output {
@@ -211,36 +1293,66 @@ impl Not for &Value {
     }
 }
 
+/// A pool of values that grows as they're written. Whatever limits how many a chunk can have is
+/// up to the chunk.
 #[derive(Debug)]
-pub struct Array<T>
-where
-    T: Default,
-{
-    head: usize,
-    values: [T; MAX_CONSTANTS],
+pub struct Array<T> {
+    values: Vec<T>,
 }
 
-impl<T> Array<T>
-where
-    T: Default,
-{
+impl<T> Array<T> {
     pub fn new() -> Array<T> {
-        Array {
-            values: std::array::from_fn(|_| T::default()),
-            head: 0,
-        }
+        Array { values: Vec::new() }
     }
 
     pub fn write(&mut self, value: T) {
-        if self.head >= MAX_CONSTANTS {
-            todo!()
-        };
-        self.values[self.head] = value;
-        self.head += 1;
+        self.values.push(value);
     }
 
     pub fn len(&self) -> usize {
-        self.head
+        self.values.len()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.values
+    }
+}
+
+impl<T> Default for Array<T> {
+    fn default() -> Self {
+        Array::new()
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(ChunkError::Malformed("unexpected end of input"))?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read_slice(4)?.try_into()?))
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -258,68 +1370,307 @@ impl Chunk {
         T: Into<u8>,
         U: Into<usize>,
     {
+        let line = line.into();
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineRun {
+                start: self.code.len(),
+                line,
+            });
+        }
         self.code.push(byte.into());
-        self.lines.push(line.into());
     }
 
-    // TODO: value: dyn Into<Value>
-    pub fn add_constant(&mut self, value: Value) -> Result<u8> {
-        if self.constants.len() >= MAX_CONSTANTS as usize {
+    /// Removes the code from `at` onwards, along with the line number of each byte.
+    pub fn split_off(&mut self, at: usize) -> (Vec<u8>, Vec<usize>) {
+        let lines = (at..self.code.len())
+            .map(|offset| self.line_for_offset(offset))
+            .collect();
+        let runs = self.lines.partition_point(|run| run.start < at);
+        self.lines.truncate(runs);
+        (self.code.split_off(at), lines)
+    }
+
+    /// Appends code previously removed with `split_off`.
+    pub fn append(&mut self, code: &[u8], lines: &[usize]) {
+        for (&byte, &line) in code.iter().zip(lines) {
+            self.write(byte, line);
+        }
+    }
+
+    pub fn add_constant(&mut self, value: Constant) -> Result<usize> {
+        if self.constants.len() >= MAX_CONSTANTS {
             return Err(anyhow!("too many constants in this chunk"));
         }
         self.constants.write(value);
-        Ok(self.constants.len() as u8 - 1)
+        Ok(self.constants.len() - 1)
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        self.constants.as_slice()
+    }
+
+    /// The source line the byte at `offset` was compiled from.
+    pub fn line_for_offset(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|run| run.start <= offset);
+        self.lines[run - 1].line
+    }
+
+    /// Reads the big-endian 16 bit operand of a jump instruction.
+    pub fn read_short(&self, offset: usize) -> usize {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]]) as usize
+    }
+
+    /// Reads the big-endian 24 bit operand of `OP_CONSTANT_LONG`.
+    pub fn read_long(&self, offset: usize) -> usize {
+        u32::from_be_bytes([
+            0,
+            self.code[offset],
+            self.code[offset + 1],
+            self.code[offset + 2],
+        ]) as usize
+    }
+
+    /// Serializes the chunk into a flat byte buffer: a version header, then the code, the line
+    /// table and the constant pool, each prefixed with its length. Functions in the constant
+    /// pool are serialized along with their own chunks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(BYTECODE_MAGIC);
+        bytes.push(BYTECODE_VERSION);
+
+        bytes.extend((self.code.len() as u32).to_le_bytes());
+        bytes.extend(&self.code);
+
+        bytes.extend((self.lines.len() as u32).to_le_bytes());
+        for run in &self.lines {
+            bytes.extend((run.start as u32).to_le_bytes());
+            bytes.extend((run.line as u32).to_le_bytes());
+        }
+
+        bytes.extend((self.constants.len() as u32).to_le_bytes());
+        for constant in self.constants() {
+            match constant {
+                Constant::Nil => bytes.push(0),
+                Constant::Bool(b) => bytes.extend([1, *b as u8]),
+                Constant::Number(n) => {
+                    bytes.push(2);
+                    bytes.extend(n.to_le_bytes());
+                }
+                Constant::String(s) => {
+                    bytes.push(3);
+                    bytes.extend((s.len() as u32).to_le_bytes());
+                    bytes.extend(s.as_bytes());
+                }
+                Constant::Bytes(b) => {
+                    bytes.push(5);
+                    bytes.extend((b.len() as u32).to_le_bytes());
+                    bytes.extend(b);
+                }
+                Constant::Function(function) => {
+                    bytes.push(if function.generator { 6 } else { 4 });
+                    let name = function.name.as_deref().unwrap_or_default();
+                    bytes.extend((name.len() as u32).to_le_bytes());
+                    bytes.extend(name.as_bytes());
+                    bytes.push(function.arity);
+                    bytes.push(function.params.len() as u8);
+                    for param in &function.params {
+                        bytes.extend((param.len() as u32).to_le_bytes());
+                        bytes.extend(param.as_bytes());
+                    }
+                    let chunk = function.chunk.to_bytes();
+                    bytes.extend((chunk.len() as u32).to_le_bytes());
+                    bytes.extend(chunk);
+                }
+            }
+        }
+
+        bytes
+    }
+
+    /// Rebuilds a chunk from the output of [`Chunk::to_bytes`]. The bytes may have come from
+    /// anywhere, so the code is verified before it's returned to be run.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk> {
+        let chunk = Chunk::read(bytes)?;
+        chunk.verify(false)?;
+        Ok(chunk)
+    }
+
+    fn read(bytes: &[u8]) -> Result<Chunk> {
+        let mut reader = ByteReader { bytes, offset: 0 };
+        let mut chunk = Chunk::new();
+
+        if bytes.get(..BYTECODE_MAGIC.len()) != Some(BYTECODE_MAGIC) {
+            return Err(ChunkError::NotBytecode.into());
+        }
+        reader.read_slice(BYTECODE_MAGIC.len())?;
+        let version = reader.read_u8()?;
+        if version != BYTECODE_VERSION {
+            return Err(ChunkError::Version(version, BYTECODE_VERSION).into());
+        }
+
+        let code_len = reader.read_u32()? as usize;
+        chunk.code = reader.read_slice(code_len)?.to_vec();
+
+        let lines_len = reader.read_u32()? as usize;
+        for _ in 0..lines_len {
+            let start = reader.read_u32()? as usize;
+            let line = reader.read_u32()? as usize;
+            // Runs start at the first byte of code and then at increasing offsets within it
+            let expected_start = match chunk.lines.last() {
+                None => start == 0,
+                Some(run) => start > run.start,
+            };
+            if !expected_start || start >= chunk.code.len() {
+                return Err(ChunkError::Malformed("line table does not match code").into());
+            }
+            chunk.lines.push(LineRun { start, line });
+        }
+        if chunk.lines.is_empty() != chunk.code.is_empty() {
+            return Err(ChunkError::Malformed("line table does not match code").into());
+        }
+
+        let constants_len = reader.read_u32()? as usize;
+        for _ in 0..constants_len {
+            let value = match reader.read_u8()? {
+                0 => Constant::Nil,
+                1 => Constant::Bool(reader.read_u8()? != 0),
+                2 => {
+                    let n = reader.read_slice(8)?;
+                    Constant::Number(f64::from_le_bytes(n.try_into()?))
+                }
+                3 => {
+                    let len = reader.read_u32()? as usize;
+                    let s = std::str::from_utf8(reader.read_slice(len)?)?;
+                    Constant::String(intern(s))
+                }
+                tag @ (4 | 6) => {
+                    let len = reader.read_u32()? as usize;
+                    let name = std::str::from_utf8(reader.read_slice(len)?)?;
+                    let arity = reader.read_u8()?;
+                    let mut params = Vec::new();
+                    for _ in 0..reader.read_u8()? {
+                        let len = reader.read_u32()? as usize;
+                        params.push(std::str::from_utf8(reader.read_slice(len)?)?.to_string());
+                    }
+                    let len = reader.read_u32()? as usize;
+                    let chunk = Chunk::read(reader.read_slice(len)?)?;
+                    chunk.verify(tag == 6)?;
+                    Constant::Function(Arc::new(Function {
+                        arity,
+                        chunk,
+                        // Only the script goes unnamed, and it's never a constant
+                        name: Some(name.to_string()),
+                        params,
+                        generator: tag == 6,
+                    }))
+                }
+                5 => {
+                    let len = reader.read_u32()? as usize;
+                    Constant::Bytes(reader.read_slice(len)?.to_vec())
+                }
+                _ => return Err(ChunkError::Malformed("unknown constant tag").into()),
+            };
+            chunk.add_constant(value)?;
+        }
+
+        if reader.offset != bytes.len() {
+            return Err(ChunkError::Malformed("trailing bytes").into());
+        }
+
+        Ok(chunk)
     }
 
-    pub fn read_constant(&self, loc: usize) -> Value {
-        self.constants.values[loc].clone()
+    /// Checks the VM can run the code without reading past it or misusing a constant: on top of
+    /// what [`DecodedChunk::decode`] checks, names must be strings, only a generator's code may
+    /// yield, and the code must end with a return that no jump skips.
+    pub(crate) fn verify(&self, generator: bool) -> Result<(), ChunkError> {
+        let decoded = DecodedChunk::decode(self)?;
+        let instructions = decoded.instructions();
+        if instructions.last().map(|instruction| instruction.op) != Some(OpCode::Return) {
+            return Err(ChunkError::Malformed("code does not end with a return"));
+        }
+
+        for instruction in instructions {
+            match (instruction.op, instruction.operand) {
+                (_, Operand::Jump(target)) if target == instructions.len() => {
+                    return Err(ChunkError::Malformed(
+                        "jump target is past the end of the code",
+                    ));
+                }
+                (
+                    OpCode::DefineGlobal
+                    | OpCode::GetGlobal
+                    | OpCode::SetGlobal
+                    | OpCode::Class
+                    | OpCode::GetProperty
+                    | OpCode::SetProperty
+                    | OpCode::Method,
+                    Operand::Constant(index),
+                ) if !matches!(self.constants()[index], Constant::String(_)) => {
+                    return Err(ChunkError::Malformed("name is not a string"));
+                }
+                (OpCode::Yield, _) if !generator => {
+                    return Err(ChunkError::Malformed("yield outside a generator"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
-    pub fn disassemble(&self, header: &str) {
-        println!("== {} ==", header);
+    /// Writes a listing of the chunk's instructions to `out`, under `header`.
+    pub fn disassemble(&self, header: &str, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "== {} ==", header)?;
         let mut offset = 0;
 
         // TODO: Iterator for this
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, out)?;
         }
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+    /// Writes the instruction at `offset` to `out`, returning the offset of the next one.
+    pub fn disassemble_instruction(
+        &self,
+        offset: usize,
+        out: &mut dyn Write,
+    ) -> std::io::Result<usize> {
         let mut offset = offset;
-        print!("{:0>4} ", offset);
+        write!(out, "{:0>4} ", offset)?;
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+        let line = self.line_for_offset(offset);
+        if offset > 0 && line == self.line_for_offset(offset - 1) {
+            write!(out, "   | ")?;
         } else {
-            print!("{:>4} ", self.lines[offset]);
+            write!(out, "{:>4} ", line)?;
         }
 
         let instruction = self.code[offset];
         let output = match instruction.try_into() {
             Ok(OpCode::Return) => {
                 offset += 1;
-                format!("{}", "OP_RETURN")
+                "OP_RETURN".to_string()
             }
             Ok(OpCode::Negate) => {
                 offset += 1;
-                format!("{}", "OP_NEGATE")
+                "OP_NEGATE".to_string()
             }
             Ok(OpCode::Add) => {
                 offset += 1;
-                format!("{}", "OP_ADD")
+                "OP_ADD".to_string()
             }
             Ok(OpCode::Subtract) => {
                 offset += 1;
-                format!("{}", "OP_SUBTRACT")
+                "OP_SUBTRACT".to_string()
             }
             Ok(OpCode::Multiply) => {
                 offset += 1;
-                format!("{}", "OP_MULTIPLY")
+                "OP_MULTIPLY".to_string()
             }
             Ok(OpCode::Divide) => {
                 offset += 1;
-                format!("{}", "OP_DIVIDE")
+                "OP_DIVIDE".to_string()
             }
             Ok(OpCode::Constant) => {
                 let constant = &self.code[offset + 1];
@@ -329,38 +1680,53 @@ impl Chunk {
                     "OP_CONSTANT", constant, self.constants.values[*constant as usize]
                 )
             }
+            Ok(OpCode::ConstantLong) => {
+                let constant = self.read_long(offset + 1);
+                offset += 4;
+                format!(
+                    "{:<16} {:>4} '{}'",
+                    "OP_CONSTANT_LONG", constant, self.constants.values[constant]
+                )
+            }
             Ok(OpCode::Nil) => {
                 offset += 1;
-                format!("{}", "OP_NIL")
+                "OP_NIL".to_string()
             }
             Ok(OpCode::True) => {
                 offset += 1;
-                format!("{}", "OP_TRUE")
+                "OP_TRUE".to_string()
             }
             Ok(OpCode::False) => {
                 offset += 1;
-                format!("{}", "OP_FALSE")
+                "OP_FALSE".to_string()
             }
             Ok(OpCode::Not) => {
                 offset += 1;
-                format!("{}", "OP_NOT")
+                "OP_NOT".to_string()
             }
             Ok(OpCode::Equal) => {
-                todo!()
+                offset += 1;
+                "OP_EQUAL".to_string()
             }
             Ok(OpCode::Greater) => {
-                todo!()
+                offset += 1;
+                "OP_GREATER".to_string()
             }
             Ok(OpCode::Less) => {
-                todo!()
+                offset += 1;
+                "OP_LESS".to_string()
             }
             Ok(OpCode::Print) => {
                 offset += 1;
-                format!("{}", "OP_PRINT")
+                "OP_PRINT".to_string()
             }
             Ok(OpCode::Pop) => {
                 offset += 1;
-                format!("{}", "OP_POP")
+                "OP_POP".to_string()
+            }
+            Ok(OpCode::Echo) => {
+                offset += 1;
+                "OP_ECHO".to_string()
             }
             Ok(OpCode::DefineGlobal) => {
                 let constant = &self.code[offset + 1];
@@ -386,12 +1752,162 @@ impl Chunk {
                     "OP_SET_GLOBAL", constant, self.constants.values[*constant as usize]
                 )
             }
+            Ok(OpCode::GetLocal) => {
+                let slot = &self.code[offset + 1];
+                offset += 2;
+                format!("{:<16} {:>4}", "OP_GET_LOCAL", slot)
+            }
+            Ok(OpCode::SetLocal) => {
+                let slot = &self.code[offset + 1];
+                offset += 2;
+                format!("{:<16} {:>4}", "OP_SET_LOCAL", slot)
+            }
+            Ok(OpCode::Jump) => {
+                let jump = self.read_short(offset + 1);
+                offset += 3;
+                format!("{:<16} {:>4} -> {}", "OP_JUMP", offset - 3, offset + jump)
+            }
+            Ok(OpCode::JumpIfFalse) => {
+                let jump = self.read_short(offset + 1);
+                offset += 3;
+                format!(
+                    "{:<16} {:>4} -> {}",
+                    "OP_JUMP_IF_FALSE",
+                    offset - 3,
+                    offset + jump
+                )
+            }
+            Ok(OpCode::Call) => {
+                let arg_count = &self.code[offset + 1];
+                offset += 2;
+                format!("{:<16} {:>4}", "OP_CALL", arg_count)
+            }
+            Ok(OpCode::CallKeywords) => {
+                let (arg_count, keywords) = (self.code[offset + 1], self.code[offset + 2]);
+                offset += 3;
+                format!("{:<16} {:>4} {}", "OP_CALL_KEYWORDS", arg_count, keywords)
+            }
+            Ok(OpCode::Swap) => {
+                offset += 1;
+                "OP_SWAP".to_string()
+            }
+            Ok(OpCode::Over) => {
+                offset += 1;
+                "OP_OVER".to_string()
+            }
+            Ok(OpCode::IndexSet) => {
+                offset += 1;
+                "OP_INDEX_SET".to_string()
+            }
+            Ok(OpCode::BuildList) => {
+                let count = &self.code[offset + 1];
+                offset += 2;
+                format!("{:<16} {:>4}", "OP_BUILD_LIST", count)
+            }
+            Ok(OpCode::BuildMap) => {
+                let count = &self.code[offset + 1];
+                offset += 2;
+                format!("{:<16} {:>4}", "OP_BUILD_MAP", count)
+            }
+            Ok(OpCode::Iter) => {
+                offset += 1;
+                "OP_ITER".to_string()
+            }
+            Ok(OpCode::PushHandler) => {
+                let jump = self.read_short(offset + 1);
+                offset += 3;
+                format!(
+                    "{:<16} {:>4} -> {}",
+                    "OP_PUSH_HANDLER",
+                    offset - 3,
+                    offset + jump
+                )
+            }
+            Ok(OpCode::PopHandler) => {
+                offset += 1;
+                "OP_POP_HANDLER".to_string()
+            }
+            Ok(OpCode::Throw) => {
+                offset += 1;
+                "OP_THROW".to_string()
+            }
+            Ok(OpCode::CheckType) => {
+                let slot = &self.code[offset + 1];
+                let value_type = ValueType::try_from(self.code[offset + 2]);
+                offset += 3;
+                match value_type {
+                    Ok(value_type) => format!("{:<16} {:>4} {}", "OP_CHECK_TYPE", slot, value_type),
+                    Err(_) => format!("{:<16} {:>4} ?", "OP_CHECK_TYPE", slot),
+                }
+            }
+            Ok(OpCode::Pipe) => {
+                let arg_count = &self.code[offset + 1];
+                offset += 2;
+                format!("{:<16} {:>4}", "OP_PIPE", arg_count)
+            }
+            Ok(OpCode::Class) => {
+                let constant = &self.code[offset + 1];
+                offset += 2;
+                format!(
+                    "{:<16} {:>4} '{}'",
+                    "OP_CLASS", constant, self.constants.values[*constant as usize]
+                )
+            }
+            Ok(OpCode::GetProperty) => {
+                let constant = &self.code[offset + 1];
+                offset += 2;
+                format!(
+                    "{:<16} {:>4} '{}'",
+                    "OP_GET_PROPERTY", constant, self.constants.values[*constant as usize]
+                )
+            }
+            Ok(OpCode::SetProperty) => {
+                let constant = &self.code[offset + 1];
+                offset += 2;
+                format!(
+                    "{:<16} {:>4} '{}'",
+                    "OP_SET_PROPERTY", constant, self.constants.values[*constant as usize]
+                )
+            }
+            Ok(OpCode::Method) => {
+                let constant = &self.code[offset + 1];
+                offset += 2;
+                format!(
+                    "{:<16} {:>4} '{}'",
+                    "OP_METHOD", constant, self.constants.values[*constant as usize]
+                )
+            }
+            Ok(OpCode::IndexGet) => {
+                offset += 1;
+                "OP_INDEX_GET".to_string()
+            }
+            Ok(OpCode::Slice) => {
+                offset += 1;
+                "OP_SLICE".to_string()
+            }
+            Ok(OpCode::Yield) => {
+                offset += 1;
+                "OP_YIELD".to_string()
+            }
+            Ok(OpCode::Done) => {
+                offset += 1;
+                "OP_DONE".to_string()
+            }
+            Ok(OpCode::Probe) => {
+                let id = self.read_short(offset + 1);
+                offset += 3;
+                format!("{:<16} {:>4}", "OP_PROBE", id)
+            }
+            Ok(OpCode::Loop) => {
+                let jump = self.read_short(offset + 1);
+                offset += 3;
+                format!("{:<16} {:>4} -> {}", "OP_LOOP", offset - 3, offset - jump)
+            }
 
             Err(_) => format!("unknown opcode {}", instruction),
         };
 
-        println!("{}", output);
-
-        offset
+        writeln!(out, "{}", output)?;
+        Ok(offset)
     }
 }
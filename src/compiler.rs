@@ -1,24 +1,578 @@
-use crate::chunk::Value;
+use crate::chunk::{Chunk, Constant, Function, OpCode, Value, ValueType, MAX_CONSTANTS};
+use crate::diagnostic::{self, Diagnostic, MessageFormat, Severity, Span};
+use crate::error::{CompileError, LoxResult, ParseError};
+use crate::intern::{intern, Symbol};
+use crate::lang::{Extension, Lang};
+use crate::lint;
 use crate::parse::{self, ParseFn, ParseRule, Parser, Precedence};
+use crate::profile::Profile;
+use crate::program::Program;
 use crate::token::{Token, TokenType};
-use crate::{Chunk, OpCode};
 
 use anyhow::{anyhow, Result};
 
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Locals are addressed by a single byte stack slot.
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+/// The most instructions a function's body can have, besides loading its parameters, for
+/// calls to it to be inlined.
+const INLINE_BUDGET: usize = 8;
+/// The budget for functions a profile shows are hot.
+const HOT_INLINE_BUDGET: usize = 32;
+
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    pub lang: Lang,
+    pub message_format: MessageFormat,
+    /// Treat sources as templates: literal text with `{{ expr }}` and `{% stmt %}` regions.
+    pub template: bool,
+    pub limits: Limits,
+    /// Parse every nested expression by recursion, rather than keeping groupings, operands,
+    /// arguments and list and map elements on a work stack on the heap. Each of them then counts
+    /// towards `Limits::expression_depth`.
+    pub recursive_expressions: bool,
+    /// Check the types parameters are annotated with each time a function is called. Without
+    /// this, annotations are only documentation.
+    pub check_types: bool,
+    /// Warn about operations on literals that are bound to fail when they run, such as
+    /// `"a" - 1` or calling a number.
+    pub lint: bool,
+    /// Emit a probe before each statement, which calls the VM's probe handler when it runs,
+    /// for coverage and statement-level tracing. `Program::probes` says where each one is.
+    pub probes: bool,
+    pub opt_level: OptLevel,
+    /// Call counts from an earlier run, which `OptLevel::O2` uses to inline bigger functions
+    /// where the calls are hot and none where they never happen.
+    pub profile: Option<Profile>,
+}
+
+/// How hard the compiler works at shrinking the code it emits, selected with `-O`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum OptLevel {
+    /// Compile the source as written.
+    #[default]
+    O0,
+    /// Fold operators whose operands are constants into their result.
+    O1,
+    /// Also replace locals that are never assigned to after their declaration with the
+    /// constant they're initialized to, so uses of them fold too, and inline calls to small
+    /// functions declared at the top level and never redefined. Takes a second pass over the
+    /// source, to find those locals and functions first.
+    ///
+    /// An error raised in an inlined body is reported as if it happened at the call, without a
+    /// line for the function in the stack trace.
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => Err(ParseError::UnknownOptLevel(s.to_string())),
+        }
+    }
+}
+
+/// How much a source can ask of the compiler, so untrusted input can't exhaust memory or
+/// overflow the stack of the recursive descent parser. Exceeding a limit is a compile error.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// The longest source accepted, in bytes. Each file of a project is limited separately.
+    pub source_len: usize,
+    /// How deeply expressions can nest. Groupings, operands of unary, arithmetic and comparison
+    /// operators, arguments and list and map elements only count with
+    /// `CompileOptions::recursive_expressions`.
+    pub expression_depth: usize,
+    /// How deeply statements can nest, counting each block and each body of an `if` or loop,
+    /// and the functions and classes they're declared in.
+    pub block_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            source_len: 16 * 1024 * 1024,
+            expression_depth: 256,
+            block_depth: 256,
+        }
+    }
+}
+
+/// What's left to do once an operand parsed by `parse_iteratively` is complete.
+enum Pending {
+    /// Look for infix operators binding at least as tightly as `precedence`. Straight after a
+    /// prefix expression, an `=` there is an invalid assignment target. Stops at any error
+    /// reported after `was_panicking` was taken, as `parse_operators` does.
+    Operators {
+        precedence: Precedence,
+        can_assign: bool,
+        prefixed: bool,
+        was_panicking: bool,
+    },
+    /// Consume the `)` closing a grouping, recovering from any error inside it that began after
+    /// the grouping did.
+    Grouping {
+        was_panicking: bool,
+    },
+    Unary(Token),
+    /// Finish a binary operator once its right operand is compiled, with the type of its left
+    /// operand if that's known.
+    Binary(Token, Option<Known>),
+    /// Count the argument just compiled, then compile the next one or finish the call.
+    Argument {
+        call: Call,
+        was_panicking: bool,
+    },
+    /// Count the list element just compiled, then compile the next one or build the list.
+    Element {
+        count: u8,
+    },
+    /// Compile the value of the map entry whose key was just compiled.
+    MapKey {
+        count: u8,
+    },
+    /// Count the map entry just compiled, then compile the next one or build the map.
+    MapValue {
+        count: u8,
+    },
+}
+
+/// A call whose arguments are being compiled.
+#[derive(Default)]
+struct Call {
+    /// The function to replace the call with, and where the code loading it starts.
+    inline: Option<(usize, Inline)>,
+    /// The global called, when the callee is only its name.
+    callee: Option<String>,
+    count: u8,
+    /// How many arguments came before the first keyword argument.
+    positional: u8,
+    /// The names of the keyword arguments, which come after the positional ones.
+    keywords: Vec<Token>,
+}
+
+/// A call with keyword arguments to the global `callee`.
+struct KeywordCall {
+    callee: String,
+    positional: u8,
+    keywords: Vec<Token>,
+}
+
+/// What comes after an argument.
+enum AfterArgument {
+    Another,
+    /// The `)` closing the arguments is next.
+    Close,
+    /// The `)` was consumed recovering from an error, or there's no finding it.
+    Closed,
+}
+
+/// What the compiler knows of the value left by the code ending at `end`, for linting and
+/// folding.
+#[derive(Clone)]
+struct Known {
+    end: usize,
+    value_type: ValueType,
+    /// The value and where its code starts, when the code is a single instruction loading it.
+    constant: Option<(usize, Constant)>,
+}
+
+/// A function small enough for `OptLevel::O2` to replace calls to it with its body.
+#[derive(Clone)]
+struct Inline {
+    arity: u8,
+    /// The body's instructions after the ones loading its parameters, which are where the
+    /// arguments of a call already are.
+    body: Vec<Inlined>,
+}
+
+#[derive(Clone)]
+enum Inlined {
+    Op(u8),
+    Constant(Constant),
+    /// An instruction addressing a name, which has to be added to the calling chunk.
+    Named(u8, String),
+}
+
+impl Inline {
+    /// Whether `function` is a single `return` of an expression that loads each parameter once,
+    /// in order, before anything else, and then only uses constants, globals, properties and
+    /// operators. Anything that could call, loop or throw past the body is left alone.
+    fn of(function: &Function, budget: usize) -> Option<Inline> {
+        if function.generator || budget == 0 {
+            return None;
+        }
+        let chunk = &function.chunk;
+        let code = &chunk.code;
+        let mut offset = 0;
+        for slot in 1..=function.arity {
+            if code.get(offset..offset + 2)? != [OpCode::GetLocal as u8, slot] {
+                return None;
+            }
+            offset += 2;
+        }
+
+        let mut body = Vec::new();
+        loop {
+            let op = OpCode::try_from(*code.get(offset)?).ok()?;
+            offset += 1;
+            let inlined = match op {
+                OpCode::Return => break,
+                OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::Negate
+                | OpCode::Not
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less => Inlined::Op(op as u8),
+                OpCode::Constant => {
+                    offset += 1;
+                    Inlined::Constant(
+                        chunk
+                            .constants()
+                            .get(*code.get(offset - 1)? as usize)?
+                            .clone(),
+                    )
+                }
+                OpCode::ConstantLong => {
+                    code.get(offset + 2)?;
+                    offset += 3;
+                    Inlined::Constant(chunk.constants().get(chunk.read_long(offset - 3))?.clone())
+                }
+                OpCode::GetGlobal | OpCode::GetProperty => {
+                    offset += 1;
+                    match chunk.constants().get(*code.get(offset - 1)? as usize)? {
+                        Constant::String(name) => Inlined::Named(op as u8, name.to_string()),
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            };
+            body.push(inlined);
+            if body.len() > budget {
+                return None;
+            }
+        }
+
+        // Anything after the `return` is the `return nil;` ending every body
+        let rest = &code[offset..];
+        (rest.is_empty() || rest == [OpCode::Nil as u8, OpCode::Return as u8]).then_some(Inline {
+            arity: function.arity,
+            body,
+        })
+    }
+}
+
+struct Local {
+    name: Token,
+    /// Scope depth the local was declared at, `None` until its initializer has been compiled.
+    depth: Option<usize>,
+    /// Counts the locals declared before this one in the whole compilation, which is the same
+    /// in each pass over the source.
+    id: usize,
+    /// With `OptLevel::O2`, the value of a local never assigned to after being initialized with
+    /// a constant, which its uses load instead.
+    constant: Option<Constant>,
+}
+
+/// Bytecode for a `defer`red statement, held back until its scope exits. A handler registered
+/// where the statement appears runs it when an exception unwinds past it too.
+struct Deferred {
+    depth: usize,
+    /// The operand of the `OpCode::PushHandler` to patch with where that handler's code starts.
+    handler: usize,
+    /// How many handlers the function had registered before this one.
+    try_depth: usize,
+    code: Vec<u8>,
+    lines: Vec<usize>,
+}
+
+/// An enclosing loop that `break` and `continue` can target.
+struct Loop {
+    label: Option<String>,
+    /// Where `continue` jumps back to.
+    start: usize,
+    /// Scope depth outside the loop body.
+    depth: usize,
+    /// Jumps to patch once the end of the loop is known.
+    breaks: Vec<usize>,
+    /// How many `try` blocks enclose the loop, within its function.
+    try_depth: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FunctionType {
+    Function,
+    /// A method, whose slot zero holds the receiver, available as `this`.
+    Method,
+    /// A class's `init` method, which always returns the receiver.
+    Initializer,
+    Script,
+}
+
+/// Identifies a constant already added to the chunk being compiled, so a name or literal used
+/// many times takes one slot. Numbers are keyed by bit pattern, so `0` and `-0` stay distinct.
+/// Functions are never shared, each declaration being a different function.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(Symbol),
+    Bytes(Vec<u8>),
+}
+
+impl ConstantKey {
+    fn of(constant: &Constant) -> Option<ConstantKey> {
+        match constant {
+            Constant::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Constant::String(s) => Some(ConstantKey::String(s.clone())),
+            Constant::Bytes(bytes) => Some(ConstantKey::Bytes(bytes.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a function whose compilation is suspended while a function nested inside it
+/// is compiled.
+struct Enclosing {
+    chunk: Chunk,
+    constants: HashMap<ConstantKey, usize>,
+    function_type: FunctionType,
+    function_name: Option<String>,
+    arity: u8,
+    params: Vec<String>,
+    generator: bool,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    deferred: Vec<Deferred>,
+    loops: Vec<Loop>,
+    try_depth: usize,
+}
+
 struct Compiler {
     parser: Parser,
     scanner: crate::scanner::Scanner,
     compiling_chunk: Chunk,
+    /// Where each constant already in `compiling_chunk` is.
+    constants: HashMap<ConstantKey, usize>,
+    lang: Lang,
+    template: bool,
+    diagnostics: Vec<Diagnostic>,
+    function_type: FunctionType,
+    function_name: Option<String>,
+    arity: u8,
+    params: Vec<String>,
+    /// Whether the function being compiled contains a `yield`.
+    generator: bool,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    deferred: Vec<Deferred>,
+    loops: Vec<Loop>,
+    /// How many `try` blocks enclose the code being compiled, within the current function.
+    try_depth: usize,
+    enclosing: Vec<Enclosing>,
+    /// How many class declarations enclose the code being compiled.
+    class_depth: usize,
+    limits: Limits,
+    iterative: bool,
+    check_types: bool,
+    lint: bool,
+    /// Where each probe emitted so far is, indexed by id, with `CompileOptions::probes`.
+    probes: Option<Vec<Span>>,
+    /// What's known of the value left by the code ending at an offset of the current chunk,
+    /// when the code there is a literal or an operation on them.
+    known: Option<Known>,
+    opt_level: OptLevel,
+    locals_declared: usize,
+    /// The ids of locals assigned to after their declaration. With `OptLevel::O2`, this comes
+    /// from a first pass over the source, and any other local initialized to a constant is
+    /// replaced by it.
+    assigned: HashSet<usize>,
+    /// How many times each global is defined or assigned to in the source, as counted by the
+    /// first pass of `OptLevel::O2`.
+    global_writes: HashMap<String, usize>,
+    /// Functions declared at the top level and defined nowhere else, which calls compiled after
+    /// their declarations are replaced with the bodies of.
+    inlinable: HashMap<String, Inline>,
+    /// Where the code loading the global just read starts, and its name, in case it's called.
+    callee: Option<(usize, String)>,
+    /// The parameters of each function declared at the top level.
+    signatures: HashMap<String, Vec<String>>,
+    /// Calls to globals with keyword arguments, checked against `signatures` once the whole
+    /// source has been compiled and it's known which globals are only defined once.
+    keyword_calls: Vec<KeywordCall>,
+    /// Set for the second pass of `OptLevel::O2`, which uses what the first found.
+    second_pass: bool,
+    profile: Option<Profile>,
+    /// How deeply the expression and statement being compiled are nested.
+    expression_depth: usize,
+    block_depth: usize,
+    /// Set when a nesting limit is exceeded, after which the rest of the source is skipped and
+    /// nothing more is reported for it.
+    abandoned: bool,
+    /// The optional tokens tried and not found at the current token, which could have come
+    /// instead of it.
+    expected: Vec<TokenType>,
 }
 
 impl Compiler {
-    fn new(source: String) -> Compiler {
-        let scanner = crate::scanner::Scanner::new(source);
+    fn new(source: String, options: &CompileOptions) -> Compiler {
+        let mut scanner = crate::scanner::Scanner::new(source);
+        scanner.lang = options.lang;
+        scanner.template = options.template;
         Compiler {
             parser: Parser::new(),
             scanner,
             compiling_chunk: Chunk::new(),
+            constants: HashMap::new(),
+            lang: options.lang,
+            template: options.template,
+            diagnostics: Vec::new(),
+            function_type: FunctionType::Script,
+            function_name: None,
+            arity: 0,
+            params: Vec::new(),
+            generator: false,
+            locals: vec![Compiler::callee_slot(FunctionType::Script)],
+            scope_depth: 0,
+            deferred: Vec::new(),
+            loops: Vec::new(),
+            try_depth: 0,
+            enclosing: Vec::new(),
+            class_depth: 0,
+            limits: options.limits.clone(),
+            iterative: !options.recursive_expressions,
+            check_types: options.check_types,
+            lint: options.lint,
+            probes: options.probes.then(Vec::new),
+            known: None,
+            opt_level: options.opt_level,
+            locals_declared: 0,
+            assigned: HashSet::new(),
+            global_writes: HashMap::new(),
+            inlinable: HashMap::new(),
+            signatures: HashMap::new(),
+            keyword_calls: Vec::new(),
+            callee: None,
+            second_pass: false,
+            profile: options.profile.clone(),
+            expression_depth: 0,
+            block_depth: 0,
+            abandoned: false,
+            expected: Vec::new(),
+        }
+    }
+
+    /// Slot zero of every call frame holds the function being called, so it's claimed up front
+    /// with a name no variable can have.
+    /// Methods are called with the receiver in that slot instead, so there it's named `this`.
+    fn callee_slot(function_type: FunctionType) -> Local {
+        let name = match function_type {
+            FunctionType::Method | FunctionType::Initializer => String::from("this"),
+            FunctionType::Function | FunctionType::Script => String::new(),
+        };
+        Local {
+            name: Token::new(TokenType::Identifier, name, 0, None),
+            depth: Some(0),
+            // Never assigned to, nor initialized with a constant
+            id: usize::MAX,
+            constant: None,
+        }
+    }
+
+    /// Starts compiling a new function into a fresh chunk, setting aside the current one.
+    fn begin_function(&mut self, name: String, function_type: FunctionType) {
+        let enclosing = Enclosing {
+            chunk: std::mem::take(&mut self.compiling_chunk),
+            constants: std::mem::take(&mut self.constants),
+            function_type: self.function_type,
+            function_name: self.function_name.replace(name),
+            arity: std::mem::take(&mut self.arity),
+            params: std::mem::take(&mut self.params),
+            generator: std::mem::take(&mut self.generator),
+            locals: std::mem::replace(&mut self.locals, vec![Compiler::callee_slot(function_type)]),
+            scope_depth: std::mem::take(&mut self.scope_depth),
+            deferred: std::mem::take(&mut self.deferred),
+            loops: std::mem::take(&mut self.loops),
+            try_depth: std::mem::take(&mut self.try_depth),
+        };
+        self.enclosing.push(enclosing);
+        self.function_type = function_type;
+        self.known = None;
+        self.callee = None;
+    }
+
+    /// Finishes the function started by `begin_function` and resumes the one around it.
+    fn end_function(&mut self) -> Function {
+        self.emit_deferred(0);
+        self.emit_return();
+
+        let enclosing = self.enclosing.pop().expect("no function to end");
+        self.known = None;
+        self.callee = None;
+        self.constants = enclosing.constants;
+        self.function_type = enclosing.function_type;
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.deferred = enclosing.deferred;
+        self.loops = enclosing.loops;
+        self.try_depth = enclosing.try_depth;
+        Function {
+            arity: std::mem::replace(&mut self.arity, enclosing.arity),
+            params: std::mem::replace(&mut self.params, enclosing.params),
+            generator: std::mem::replace(&mut self.generator, enclosing.generator),
+            chunk: std::mem::replace(&mut self.compiling_chunk, enclosing.chunk),
+            name: std::mem::replace(&mut self.function_name, enclosing.function_name),
+        }
+    }
+
+    /// Compiles every declaration in `source` into the current chunk. Globals are resolved by
+    /// name at runtime, so units compiled into the same chunk can reference each other freely.
+    fn compile_unit(&mut self, file: Option<String>, source: String) -> Result<()> {
+        let source_len = source.len();
+        self.scanner = crate::scanner::Scanner::with_file(source, file.clone());
+        self.scanner.lang = self.lang;
+        self.scanner.template = self.template;
+        self.parser.current = None;
+        self.abandoned = false;
+        if source_len > self.limits.source_len {
+            let message = format!(
+                "source is {} bytes, longer than the limit of {}.",
+                source_len, self.limits.source_len
+            );
+            self.report(
+                diagnostic::LIMIT_ERROR,
+                Span {
+                    file,
+                    line: 1,
+                    ..Default::default()
+                },
+                String::new(),
+                &message,
+            );
+            return Ok(());
         }
+        self.advance()?;
+
+        loop {
+            if self.current_token_type_is(TokenType::Eof) {
+                break;
+            }
+            self.declaration();
+        }
+
+        Ok(())
     }
 
     fn error(&mut self, message: &str) {
@@ -30,15 +584,114 @@ impl Compiler {
     }
 
     fn error_at(&mut self, token: &Token, message: &str) {
-        if self.parser.panic_mode {
+        self.report_at(diagnostic::SYNTAX_ERROR, token, message);
+    }
+
+    /// Reports nesting deeper than the limits allow, then skips the rest of the source, since
+    /// recovering would only run into the limit again further in.
+    fn nesting_error(&mut self, message: &str) {
+        self.limit_error(message);
+        self.abandoned = true;
+        while !self.check(TokenType::Eof) {
+            let _ = self.advance();
+        }
+    }
+
+    /// Reports a program that is valid Lox, but that doesn't fit the bytecode format.
+    fn limit_error(&mut self, message: &str) {
+        self.report_at(
+            diagnostic::LIMIT_ERROR,
+            &self.parser.previous.clone().unwrap(),
+            message,
+        );
+    }
+
+    fn report_at(&mut self, code: &'static str, token: &Token, message: &str) {
+        self.report(code, span_of(token), location_of(token), message);
+    }
+
+    fn report(&mut self, code: &'static str, span: Span, location: String, message: &str) {
+        self.push_diagnostic(Diagnostic {
+            code,
+            severity: Severity::Error,
+            message: message.to_string(),
+            span,
+            location,
+            found: None,
+            expected: Vec::new(),
+            excerpt: None,
+        });
+    }
+
+    /// Reports the current token as unexpected when `tt` had to come next. The message is the
+    /// caller's when `tt` is the only token the parser tried here, since that says what it was
+    /// to follow, and otherwise lists every token that would have been accepted.
+    fn unexpected(&mut self, tt: TokenType, message: &str) -> ParseError {
+        let token = self.parser.current.clone().unwrap();
+        if !self.expected.contains(&tt) {
+            self.expected.push(tt);
+        }
+        let error = ParseError::UnexpectedToken {
+            span: span_of(&token),
+            found: token.token_type.clone(),
+            lexeme: token.lexeme.clone(),
+            expected: self.expected.clone(),
+        };
+
+        let message = match self.expected.len() {
+            1 => message.to_string(),
+            _ => error.to_string(),
+        };
+        self.push_diagnostic(Diagnostic {
+            code: diagnostic::SYNTAX_ERROR,
+            severity: Severity::Error,
+            message,
+            span: span_of(&token),
+            location: location_of(&token),
+            found: Some(token.token_type.to_string()),
+            expected: self.expected.iter().map(|tt| tt.to_string()).collect(),
+            excerpt: None,
+        });
+        error
+    }
+
+    /// Reports something that compiles but may not do what was meant. Warnings don't stop the
+    /// program compiling, and don't affect error recovery.
+    fn warn_at(&mut self, code: &'static str, token: &Token, message: &str) {
+        if self.abandoned {
+            return;
+        }
+        let diagnostic = self.quoted(Diagnostic {
+            code,
+            severity: Severity::Warning,
+            message: message.to_string(),
+            span: span_of(token),
+            location: location_of(token),
+            found: None,
+            expected: Vec::new(),
+            excerpt: None,
+        });
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Adds the line of source a diagnostic is on, for it to be shown with.
+    fn quoted(&self, diagnostic: Diagnostic) -> Diagnostic {
+        let excerpt = self
+            .scanner
+            .source_line(diagnostic.span.file.as_deref(), diagnostic.span.line);
+        Diagnostic {
+            excerpt,
+            ..diagnostic
+        }
+    }
+
+    fn push_diagnostic(&mut self, diagnostic: Diagnostic) {
+        if self.parser.panic_mode || self.abandoned {
             return;
         }
         self.parser.panic_mode = true;
-        let suffix = match token.token_type {
-            TokenType::Eof => String::from("end"),
-            _ => format!("'{}'", token.lexeme),
-        };
-        eprintln!("[line {}] Error at {}: {}", token.line, suffix, message);
+        let diagnostic = self.quoted(diagnostic);
+        self.diagnostics.push(diagnostic);
         self.parser.had_error = true;
     }
 
@@ -55,7 +708,12 @@ impl Compiler {
                 | TokenType::If
                 | TokenType::While
                 | TokenType::Print
-                | TokenType::Return => return,
+                | TokenType::Return
+                | TokenType::Yield
+                | TokenType::Switch
+                | TokenType::Try
+                | TokenType::Throw
+                | TokenType::StaticAssert => return,
                 _ => {}
             }
 
@@ -69,6 +727,7 @@ impl Compiler {
 
     fn advance(&mut self) -> Result<()> {
         self.parser.advance();
+        self.expected.clear();
 
         loop {
             match self.scan_token() {
@@ -76,7 +735,10 @@ impl Compiler {
                     self.parser.current = Some(token);
                     break;
                 }
-                Err(_) => self.error_at_current(&self.parser.current.clone().unwrap().lexeme),
+                Err(e) => {
+                    let span = self.scan_error_span(&e);
+                    self.report(diagnostic::SCAN_ERROR, span, String::new(), &e.to_string())
+                }
             }
         }
         Ok(())
@@ -88,8 +750,7 @@ impl Compiler {
                 if token.token_type == tt {
                     self.advance()
                 } else {
-                    self.error_at_current(message);
-                    Err(anyhow!(message.to_owned()))
+                    Err(self.unexpected(tt, message).into())
                 }
             }
             None => Err(anyhow!("no current token")),
@@ -104,32 +765,82 @@ impl Compiler {
         self.named_variable(self.parser.previous.clone().unwrap(), can_assign);
     }
 
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_depth == 0 {
+            self.error("can't use 'this' outside of a class.");
+            return;
+        }
+        // `this` can't be assigned to
+        self.variable(false);
+    }
+
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let arg = Value::from_string(name.lexeme);
-        let constant = self.compiling_chunk.add_constant(arg).unwrap();
+        let local = self.resolve_local(&name);
+        let (get_op, set_op, arg) = match local {
+            Some(slot) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            None => {
+                let constant = self.identifier_constant(&name);
+                (OpCode::GetGlobal, OpCode::SetGlobal, constant)
+            }
+        };
+
         if can_assign && self.current_token_type_is(TokenType::Equal) {
+            match local {
+                Some(slot) => {
+                    self.assigned.insert(self.locals[slot as usize].id);
+                }
+                None => self.count_global_write(name.lexeme),
+            }
             self.expression();
-            self.emit_bytes(OpCode::SetGlobal, constant);
+            self.emit_bytes(set_op, arg);
+        } else if let Some(constant) =
+            local.and_then(|slot| self.locals[slot as usize].constant.clone())
+        {
+            self.emit_value(constant);
         } else {
-            self.emit_bytes(OpCode::GetGlobal, constant);
+            let start = self.compiling_chunk.code.len();
+            self.emit_bytes(get_op, arg);
+            if local.is_none() {
+                self.callee = Some((start, name.lexeme));
+            }
         }
     }
 
-    fn number(&mut self, can_assign: bool) {
-        let value = self
+    /// Finds the stack slot of the innermost local called `name`, if there is one.
+    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
+        let (slot, local) = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)?;
+
+        if local.depth.is_none() {
+            self.error("can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let token = self
             .parser
             .previous
             .clone()
-            .expect("expected previous chunk")
-            .lexeme;
-        let value: f64 = value
-            .parse()
-            .expect(&format!("unable to convert token to float {}", value));
-
-        let _ = self.emit_constant(Value::Number(value));
+            .expect("expected previous chunk");
+        let value = crate::number::parse(&token.lexeme)
+            .unwrap_or_else(|| panic!("unable to convert token to float {}", token.lexeme));
+
+        if crate::number::loses_precision(&token.lexeme) {
+            let message = format!(
+                "number can't be represented exactly, it will be {}.",
+                crate::number::exact(value)
+            );
+            self.warn_at(diagnostic::PRECISION_WARNING, &token, &message);
+        }
+        self.emit_value(Constant::Number(value));
     }
 
-    fn string(&mut self, can_assign: bool) {
+    fn string(&mut self, _can_assign: bool) {
         let value = self
             .parser
             .previous
@@ -139,11 +850,27 @@ impl Compiler {
         // Strip "" from the Token representation
         let value = &value[1..value.len() - 1];
 
-        let value = Value::from_string(value.to_string());
-        let _ = self.emit_constant(value);
+        self.emit_value(Constant::String(intern(value)));
+    }
+
+    fn bytes(&mut self, _can_assign: bool) {
+        let lexeme = self.parser.previous.clone().unwrap().lexeme;
+        // Strip the prefix and quotes
+        let body = &lexeme[2..lexeme.len() - 1];
+
+        let bytes = if lexeme.starts_with('x') {
+            let digits: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+            crate::natives::decode_hex(&digits).ok_or("invalid hex in byte array literal.")
+        } else {
+            unescape_bytes(body)
+        };
+        match bytes {
+            Ok(bytes) => self.emit_value(Constant::Bytes(bytes)),
+            Err(message) => self.error(message),
+        }
     }
 
-    fn literal(&mut self, can_assign: bool) {
+    fn literal(&mut self, _can_assign: bool) {
         let tt = self
             .parser
             .previous
@@ -152,13 +879,20 @@ impl Compiler {
             .token_type;
 
         match tt {
-            TokenType::False => self.emit_byte(OpCode::False),
-            TokenType::Nil => self.emit_byte(OpCode::Nil),
-            TokenType::True => self.emit_byte(OpCode::True),
+            TokenType::False => self.emit_value(Constant::Bool(false)),
+            TokenType::Nil => self.emit_value(Constant::Nil),
+            TokenType::True => self.emit_value(Constant::Bool(true)),
             _ => unreachable!(),
         }
     }
 
+    fn check(&self, tt: TokenType) -> bool {
+        self.parser
+            .current
+            .as_ref()
+            .is_some_and(|token| token.token_type == tt)
+    }
+
     fn current_token_type_is(&mut self, tt: TokenType) -> bool {
         let current_tt = self
             .parser
@@ -170,13 +904,33 @@ impl Compiler {
             let _ = self.advance();
             true
         } else {
+            if !self.expected.contains(&tt) {
+                self.expected.push(tt);
+            }
             false
         }
     }
 
     fn declaration(&mut self) {
-        if self.current_token_type_is(TokenType::Var) {
+        // Statements are probed by `statement`, wherever they're nested
+        let declares = [
+            TokenType::Class,
+            TokenType::Fun,
+            TokenType::Var,
+            TokenType::Defer,
+        ];
+        if declares.into_iter().any(|tt| self.check(tt)) {
+            self.emit_probe();
+        }
+        if self.current_token_type_is(TokenType::Class) {
+            self.class_declaration();
+        } else if self.current_token_type_is(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.current_token_type_is(TokenType::Var) {
             self.var_declaration();
+        } else if self.current_token_type_is(TokenType::Defer) {
+            // A declaration rather than a statement, so it can't be registered conditionally
+            self.defer_declaration();
         } else {
             self.statement();
         }
@@ -186,88 +940,1619 @@ impl Compiler {
         }
     }
 
+    /// Marks the start of the statement at the current token, with `CompileOptions::probes`.
+    fn emit_probe(&mut self) {
+        let Some(probes) = &mut self.probes else {
+            return;
+        };
+        let token = self.parser.current.clone().expect("expected current token");
+        let id = probes.len();
+        probes.push(span_of(&token));
+        match u16::try_from(id) {
+            Ok(id) => {
+                self.compiling_chunk.write(OpCode::Probe, token.line);
+                for byte in id.to_be_bytes() {
+                    self.compiling_chunk.write(byte, token.line);
+                }
+            }
+            Err(_) if id == u16::MAX as usize + 1 => self.report_at(
+                diagnostic::LIMIT_ERROR,
+                &token,
+                "too many statements to probe, the limit is 65536.",
+            ),
+            Err(_) => {}
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        if self
+            .consume(TokenType::Identifier, "expect class name.")
+            .is_err()
+        {
+            return;
+        }
+        let class_name = self.parser.previous.clone().unwrap();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_bytes(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        // Methods are attached to the class while it's on the stack
+        self.named_variable(class_name, false);
+        self.class_depth += 1;
+        if self
+            .consume(TokenType::LeftBrace, "expect '{' before class body.")
+            .is_ok()
+        {
+            while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+                // A token that can't start a method is never consumed, so stop at the first one
+                if self.method().is_err() {
+                    break;
+                }
+            }
+            let _ = self.consume(TokenType::RightBrace, "expect '}' after class body.");
+        }
+        self.emit_byte(OpCode::Pop);
+        self.class_depth -= 1;
+    }
+
+    fn method(&mut self) -> Result<()> {
+        self.consume(TokenType::Identifier, "expect method name.")?;
+        let name = self.parser.previous.clone().unwrap();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        let _ = self.function(function_type);
+        self.emit_bytes(OpCode::Method, constant);
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = match self.parse_variable() {
+            Ok(global) => global,
+            Err(_) => return,
+        };
+        // The function may refer to itself, so its name is usable before the body is compiled
+        self.mark_initialized();
+        let function = self.function(FunctionType::Function);
+        if self.scope_depth == 0 && self.enclosing.is_empty() {
+            let name = function.name.clone().unwrap_or_default();
+            self.signatures
+                .insert(name.clone(), function.params.clone());
+            if self.second_pass && self.global_writes.get(&name) == Some(&1) {
+                if let Some(inline) = Inline::of(&function, self.inline_budget(&name)) {
+                    self.inlinable.insert(name, inline);
+                }
+            }
+        }
+        self.define_variable(global);
+    }
+
+    /// How many instructions the body of the function `name` can have for calls to it to be
+    /// inlined. Without a profile every function gets the same budget.
+    fn inline_budget(&self, name: &str) -> usize {
+        match &self.profile {
+            None => INLINE_BUDGET,
+            Some(profile) if profile.is_hot(name) => HOT_INLINE_BUDGET,
+            // Inlining calls that never happen only makes the code bigger
+            Some(profile) if profile.calls(name) == 0 => 0,
+            Some(_) => INLINE_BUDGET,
+        }
+    }
+
+    /// Compiles a function's parameters and body, leaving the function on the stack.
+    fn function(&mut self, function_type: FunctionType) -> Arc<Function> {
+        let name = self.parser.previous.clone().unwrap().lexeme;
+        self.begin_function(name, function_type);
+        self.begin_scope();
+
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after function name.");
+        let mut checks = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.arity == u8::MAX {
+                    let current = self.parser.current.clone().unwrap();
+                    self.report_at(
+                        diagnostic::LIMIT_ERROR,
+                        &current,
+                        "can't have more than 255 parameters.",
+                    );
+                } else {
+                    self.arity += 1;
+                }
+                if let Ok(constant) = self.parse_variable() {
+                    self.params
+                        .push(self.parser.previous.clone().unwrap().lexeme);
+                    self.define_variable(constant);
+                    if let Some(value_type) = self.type_annotation() {
+                        let slot = (self.locals.len() - 1).min(u8::MAX as usize) as u8;
+                        checks.push((slot, value_type));
+                    }
+                }
+                if !self.current_token_type_is(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        let _ = self.consume(TokenType::RightParen, "expect ')' after parameters.");
+        if self.check_types {
+            for (slot, value_type) in checks {
+                self.emit_byte(OpCode::CheckType);
+                self.emit_bytes(slot, value_type);
+            }
+        }
+        let _ = self.consume(TokenType::LeftBrace, "expect '{' before function body.");
+        if self.nest_block() {
+            self.block();
+            self.block_depth -= 1;
+        }
+
+        let function = Arc::new(self.end_function());
+        self.emit_constant(Constant::Function(Arc::clone(&function)));
+        function
+    }
+
+    /// Parses the `: type` that may follow a parameter name.
+    fn type_annotation(&mut self) -> Option<ValueType> {
+        if !self.current_token_type_is(TokenType::Colon) {
+            return None;
+        }
+        if !self.lang.allows(Extension::TypeAnnotations) {
+            self.error(&ParseError::ExtensionDisabled(Extension::TypeAnnotations).to_string());
+        }
+        self.consume(TokenType::Identifier, "expected a type after ':'")
+            .ok()?;
+        let name = self.parser.previous.clone().unwrap().lexeme;
+        let value_type = ValueType::from_name(&name);
+        if value_type.is_none() {
+            self.error(&format!("unknown type '{}'.", name));
+        }
+        value_type
+    }
+
     fn var_declaration(&mut self) {
-        let global = self.parse_variable().unwrap(); // TODO: Handle this
+        let global = match self.parse_variable() {
+            Ok(global) => global,
+            // Already reported, declaration() will synchronize
+            Err(_) => return,
+        };
 
+        let start = self.compiling_chunk.code.len();
         if self.current_token_type_is(TokenType::Equal) {
             self.expression();
         } else {
             self.emit_byte(OpCode::Nil);
         }
-        self.consume(
+        let _ = self.consume(
             TokenType::Semicolon,
             "expected ';' after variable declaration",
         );
+        if self.scope_depth > 0 && self.second_pass {
+            let constant = self
+                .produced_constant()
+                .filter(|(constant_start, _)| *constant_start == start);
+            if let Some(local) = self.locals.last_mut() {
+                if !self.assigned.contains(&local.id) {
+                    local.constant = constant.map(|(_, constant)| constant);
+                }
+            }
+        }
         self.define_variable(global);
     }
 
+    fn defer_declaration(&mut self) {
+        let handler = self.emit_jump(OpCode::PushHandler);
+        let start = self.compiling_chunk.code.len();
+        // The code is moved once compiled, so it can't jump out to an enclosing loop
+        let loops = std::mem::take(&mut self.loops);
+        self.statement();
+        self.loops = loops;
+
+        let (code, lines) = self.compiling_chunk.split_off(start);
+        self.deferred.push(Deferred {
+            depth: self.scope_depth,
+            handler,
+            try_depth: self.try_depth,
+            code,
+            lines,
+        });
+        self.try_depth += 1;
+    }
+
+    /// Emits, most recent first, the deferred code registered at `depth` or deeper, each once its
+    /// handler is removed. The handlers' code follows: it runs the deferred code too, then throws
+    /// the exception on to the next handler out.
+    fn emit_deferred(&mut self, depth: usize) {
+        let mut unwinding = Vec::new();
+        while self
+            .deferred
+            .last()
+            .is_some_and(|deferred| deferred.depth >= depth)
+        {
+            let deferred = self.deferred.pop().unwrap();
+            self.emit_byte(OpCode::PopHandler);
+            self.try_depth = deferred.try_depth;
+            self.compiling_chunk.append(&deferred.code, &deferred.lines);
+            unwinding.push(deferred);
+        }
+        if unwinding.is_empty() {
+            return;
+        }
+
+        let exit_jump = self.emit_jump(OpCode::Jump);
+        for deferred in unwinding {
+            // The VM pushes the exception above the locals the deferred code uses
+            self.patch_jump(deferred.handler);
+            self.compiling_chunk.append(&deferred.code, &deferred.lines);
+            self.emit_byte(OpCode::Throw);
+        }
+        self.patch_jump(exit_jump);
+    }
+
+    /// Consumes a variable name, returning its name constant for globals. Locals live on the
+    /// stack and need no constant, so `0` is returned for them.
     fn parse_variable(&mut self) -> Result<u8> {
         self.consume(TokenType::Identifier, "expected variable name")?;
-        let value = self.parser.previous.clone().unwrap().lexeme;
-        self.compiling_chunk.add_constant(Value::from_string(value))
+
+        self.declare_variable();
+        if self.scope_depth > 0 {
+            return Ok(0);
+        }
+
+        Ok(self.identifier_constant(&self.parser.previous.clone().unwrap()))
+    }
+
+    /// Instructions naming a global, property or class address its name with a single byte,
+    /// so names have to be among a chunk's first 256 constants.
+    fn identifier_constant(&mut self, name: &Token) -> u8 {
+        let constant = self.make_constant(Constant::String(intern(&name.lexeme)));
+        u8::try_from(constant).unwrap_or_else(|_| {
+            self.limit_error(&format!(
+                "too many constants in one chunk to name '{}', names must be among the first {}.",
+                name.lexeme, UINT8_COUNT
+            ));
+            0
+        })
+    }
+
+    fn declare_variable(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous.clone().unwrap();
+        let duplicate = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= self.scope_depth))
+            .any(|local| local.name.lexeme == name.lexeme);
+        if duplicate {
+            self.error("already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.locals.len() == UINT8_COUNT {
+            self.limit_error(
+                "too many local variables in function, the limit is 255 including parameters.",
+            );
+            return;
+        }
+
+        self.locals.push(Local {
+            name,
+            depth: None,
+            id: self.locals_declared,
+            constant: None,
+        });
+        self.locals_declared += 1;
     }
 
     fn define_variable(&mut self, global: u8) {
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        if let Some(Constant::String(name)) = self.compiling_chunk.constants().get(global as usize)
+        {
+            self.count_global_write(name.to_string());
+        }
         self.emit_bytes(OpCode::DefineGlobal, global);
     }
 
+    /// Counts a definition of or assignment to a global, unless the first pass already did.
+    fn count_global_write(&mut self, name: String) {
+        if !self.second_pass {
+            *self.global_writes.entry(name).or_default() += 1;
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+        }
+    }
+
+    /// Counts one more level of statement nesting, or reports that there are too many and
+    /// returns false.
+    fn nest_block(&mut self) -> bool {
+        if self.block_depth >= self.limits.block_depth {
+            self.nesting_error("statements nested too deeply.");
+            return false;
+        }
+        self.block_depth += 1;
+        true
+    }
+
     fn statement(&mut self) {
+        if !self.nest_block() {
+            return;
+        }
+        self.emit_probe();
+
         if self.current_token_type_is(TokenType::Print) {
             self.print_statement();
+        } else if self.current_token_type_is(TokenType::Return) {
+            self.return_statement();
+        } else if self.current_token_type_is(TokenType::Yield) {
+            self.yield_statement();
+        } else if self.lang.allows(Extension::LoopControl) && self.check_label() {
+            self.labelled_statement();
+        } else if self.current_token_type_is(TokenType::While) {
+            self.while_statement(None);
+        } else if self.current_token_type_is(TokenType::For) {
+            self.for_statement(None);
+        } else if self.current_token_type_is(TokenType::Break) {
+            self.break_statement();
+        } else if self.current_token_type_is(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.current_token_type_is(TokenType::If) {
+            self.if_statement();
+        } else if self.current_token_type_is(TokenType::Switch) {
+            self.switch_statement();
+        } else if self.current_token_type_is(TokenType::Try) {
+            self.try_statement();
+        } else if self.current_token_type_is(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.current_token_type_is(TokenType::Echo) {
+            self.echo_statement();
+        } else if self.current_token_type_is(TokenType::StaticAssert) {
+            self.static_assert_statement();
+        } else if self.current_token_type_is(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
         } else {
             self.expression_statement();
         }
+        self.block_depth -= 1;
     }
 
-    fn print_statement(&mut self) {
-        self.expression();
-        self.consume(TokenType::Semicolon, "expect ';' after value.");
-        self.emit_byte(OpCode::Print);
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        let _ = self.consume(TokenType::RightBrace, "expect '}' after block.");
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.consume(TokenType::Semicolon, "expect ';' after value.");
-        self.emit_byte(OpCode::Pop);
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    /// Leaves the current block, popping the locals it declared off the stack.
+    fn end_scope(&mut self) {
+        // Locals are still on the stack, so deferred code can use them
+        self.emit_deferred(self.scope_depth);
+        self.scope_depth -= 1;
+
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|depth| depth > self.scope_depth))
+        {
+            self.emit_byte(OpCode::Pop);
+            self.locals.pop();
+        }
     }
 
-    fn grouping(&mut self, can_assign: bool) {
+    fn if_statement(&mut self) {
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after 'if'.");
         self.expression();
-        let _ = self.consume(TokenType::RightParen, "expected ')' after expression)");
-    }
+        let _ = self.consume(TokenType::RightParen, "expect ')' after condition.");
 
-    fn unary(&mut self, can_assign: bool) {
-        let operator_type = self
-            .parser
-            .previous
-            .clone()
-            .expect("expected previous token")
-            .token_type;
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
 
-        self.parse_precedence(Precedence::Unary);
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop);
 
-        match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            TokenType::Bang => self.emit_byte(OpCode::Not),
+        if self.current_token_type_is(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    /// Compiles `switch (subject) { case a: ... default: ... }` to a chain of `==` tests against
+    /// the subject, held in a hidden local. Only the first matching case runs; there is no
+    /// fallthrough, and `break` still refers to the enclosing loop.
+    fn switch_statement(&mut self) {
+        let line = self.parser.previous.as_ref().unwrap().line;
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after 'switch'.");
+        self.begin_scope();
+        self.expression();
+        self.add_local(Token::new(TokenType::Identifier, String::new(), line, None));
+        self.mark_initialized();
+        let subject = (self.locals.len() - 1) as u8;
+        let _ = self.consume(TokenType::RightParen, "expect ')' after switch value.");
+        let _ = self.consume(TokenType::LeftBrace, "expect '{' before switch cases.");
+
+        let mut exits = Vec::new();
+        let mut has_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if self.current_token_type_is(TokenType::Case) {
+                if has_default {
+                    self.error("can't have a case after the default case.");
+                }
+                self.emit_bytes(OpCode::GetLocal, subject);
+                self.expression();
+                self.emit_byte(OpCode::Equal);
+                let _ = self.consume(TokenType::Colon, "expect ':' after case value.");
+
+                let next_case = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop);
+                self.case_body();
+                exits.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_case);
+                self.emit_byte(OpCode::Pop);
+            } else if self.current_token_type_is(TokenType::Default) {
+                if has_default {
+                    self.error("a switch can only have one default case.");
+                }
+                has_default = true;
+                let _ = self.consume(TokenType::Colon, "expect ':' after 'default'.");
+                self.case_body();
+            } else {
+                self.error_at_current("expect 'case' or 'default'.");
+                let _ = self.advance();
+            }
+        }
+
+        for exit in exits {
+            self.patch_jump(exit);
+        }
+        let _ = self.consume(TokenType::RightBrace, "expect '}' after switch cases.");
+        self.end_scope();
+    }
+
+    /// Compiles `try { ... } catch (e) { ... }`. Values thrown and runtime errors raised while
+    /// the `try` block runs, including in functions it calls, jump to the `catch` block with
+    /// the thrown value, or the error message, in `e`.
+    fn try_statement(&mut self) {
+        let _ = self.consume(TokenType::LeftBrace, "expect '{' after 'try'.");
+        let handler = self.emit_jump(OpCode::PushHandler);
+        self.try_depth += 1;
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.try_depth -= 1;
+        self.emit_byte(OpCode::PopHandler);
+        let exit_jump = self.emit_jump(OpCode::Jump);
+
+        // The VM pushes the exception where the handler found the top of the stack, which is
+        // where the catch variable's slot is
+        self.patch_jump(handler);
+        let _ = self.consume(TokenType::Catch, "expect 'catch' after try block.");
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after 'catch'.");
+        let _ = self.consume(TokenType::Identifier, "expect exception variable name.");
+        let name = self.parser.previous.clone().unwrap();
+        let _ = self.consume(
+            TokenType::RightParen,
+            "expect ')' after exception variable.",
+        );
+        let _ = self.consume(TokenType::LeftBrace, "expect '{' before catch body.");
+        self.begin_scope();
+        self.add_local(name);
+        self.mark_initialized();
+        self.block();
+        self.end_scope();
+
+        self.patch_jump(exit_jump);
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        let _ = self.consume(TokenType::Semicolon, "expect ';' after thrown value.");
+        self.emit_byte(OpCode::Throw);
+    }
+
+    /// The statements following a `case` or `default` label, up to the next label, in a scope of
+    /// their own.
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    /// Whether the upcoming tokens are a loop label, `name:`.
+    fn check_label(&mut self) -> bool {
+        self.check(TokenType::Identifier)
+            && self
+                .peek_token()
+                .is_some_and(|token| token.token_type == TokenType::Colon)
+    }
+
+    /// Whether the current token is the loop variable of a `for (var x in ...)` loop.
+    fn check_for_in(&mut self) -> bool {
+        self.check(TokenType::Identifier)
+            && self.peek_token().is_some_and(|token| {
+                token.token_type == TokenType::Identifier && token.lexeme == "in"
+            })
+    }
+
+    /// Returns the token after the current one, reporting it if it can't be scanned.
+    fn peek_token(&mut self) -> Option<Token> {
+        match self.scanner.peek_token() {
+            Ok(token) => Some(token.clone()),
+            Err(e) => {
+                let span = self.scan_error_span(&e);
+                self.report(diagnostic::SCAN_ERROR, span, String::new(), &e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Where a scanning error is in the source: the scanner's line, narrowed down to the
+    /// character for one that can't start a token, or back to where an unterminated comment
+    /// started.
+    fn scan_error_span(&self, error: &anyhow::Error) -> Span {
+        let span = Span {
+            file: self.scanner.file.as_deref().map(String::from),
+            line: self.scanner.line,
+            ..Default::default()
+        };
+        match error.downcast_ref() {
+            Some(&ParseError::UnexpectedCharacter { ch, line, column }) => Span {
+                line,
+                column,
+                len: ch.len_utf8(),
+                ..span
+            },
+            Some(&ParseError::UnterminatedComment { line }) => Span { line, ..span },
+            _ => span,
+        }
+    }
+
+    fn labelled_statement(&mut self) {
+        let _ = self.advance();
+        let label = self.parser.previous.clone().unwrap().lexeme;
+        let _ = self.advance();
+
+        if self
+            .loops
+            .iter()
+            .any(|enclosing| enclosing.label.as_ref() == Some(&label))
+        {
+            self.error("a loop with this label is already in scope.");
+        }
+
+        if self.current_token_type_is(TokenType::While) {
+            self.while_statement(Some(label));
+        } else if self.current_token_type_is(TokenType::For) {
+            self.for_statement(Some(label));
+        } else {
+            self.error_at_current("expect loop after label.");
+        }
+    }
+
+    fn while_statement(&mut self, label: Option<String>) {
+        let loop_start = self.compiling_chunk.code.len();
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after 'while'.");
+        self.expression();
+        let _ = self.consume(TokenType::RightParen, "expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        let breaks = self.loop_body(label, loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop);
+        for jump in breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    /// Compiles `for (init; cond; incr) body` as a scope holding `init`, around a loop whose
+    /// increment runs after the body and before the condition is checked again.
+    fn for_statement(&mut self, label: Option<String>) {
+        self.begin_scope();
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after 'for'.");
+        if self.current_token_type_is(TokenType::Semicolon) {
+            // No initializer
+        } else if self.current_token_type_is(TokenType::Var) {
+            if self.lang.allows(Extension::ForIn) && self.check_for_in() {
+                self.for_in_statement(label);
+                return;
+            }
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.compiling_chunk.code.len();
+        let mut exit_jump = None;
+        if !self.current_token_type_is(TokenType::Semicolon) {
+            self.expression();
+            let _ = self.consume(TokenType::Semicolon, "expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop);
+        }
+
+        if !self.current_token_type_is(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.compiling_chunk.code.len();
+            self.expression();
+            self.emit_byte(OpCode::Pop);
+            let _ = self.consume(TokenType::RightParen, "expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        let breaks = self.loop_body(label, loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop);
+        }
+        for jump in breaks {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    /// Compiles the rest of `for (var x in generator) body`, after the `var`. The generator is
+    /// resumed for each value it yields, until it finishes.
+    fn for_in_statement(&mut self, label: Option<String>) {
+        let _ = self.consume(TokenType::Identifier, "expect loop variable name.");
+        let name = self.parser.previous.clone().unwrap();
+        let _ = self.advance(); // in
+
+        // The generator, or a cursor over a list or map, is held in a hidden local below the
+        // loop variable
+        self.expression();
+        self.emit_byte(OpCode::Iter);
+        self.add_local(Token::new(
+            TokenType::Identifier,
+            String::new(),
+            name.line,
+            None,
+        ));
+        self.mark_initialized();
+        let generator = (self.locals.len() - 1) as u8;
+        self.emit_byte(OpCode::Nil);
+        self.add_local(name);
+        self.mark_initialized();
+        let _ = self.consume(TokenType::RightParen, "expect ')' after for clauses.");
+
+        let loop_start = self.compiling_chunk.code.len();
+        self.emit_bytes(OpCode::GetLocal, generator);
+        self.emit_bytes(OpCode::Call, 0);
+        self.emit_bytes(OpCode::GetLocal, generator);
+        self.emit_byte(OpCode::Done);
+        self.emit_byte(OpCode::Not);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.emit_bytes(OpCode::SetLocal, generator + 1);
+        self.emit_byte(OpCode::Pop);
+
+        let breaks = self.loop_body(label, loop_start);
+
+        self.patch_jump(exit_jump);
+        // The condition, then the value the finished generator returned
+        self.emit_byte(OpCode::Pop);
+        self.emit_byte(OpCode::Pop);
+        for jump in breaks {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    /// Compiles a loop body that `continue` and the end of the body jump back to `start` from,
+    /// returning the `break` jumps to patch once the end of the loop is known.
+    fn loop_body(&mut self, label: Option<String>, start: usize) -> Vec<usize> {
+        self.loops.push(Loop {
+            label,
+            start,
+            depth: self.scope_depth,
+            breaks: Vec::new(),
+            try_depth: self.try_depth,
+        });
+        self.statement();
+        self.emit_loop(start);
+
+        self.loops.pop().unwrap().breaks
+    }
+
+    fn break_statement(&mut self) {
+        if let Some(target) = self.loop_target("break") {
+            self.exit_scopes(self.loops[target].depth, self.loops[target].try_depth);
+            let jump = self.emit_jump(OpCode::Jump);
+            self.loops[target].breaks.push(jump);
+        }
+        let _ = self.consume(TokenType::Semicolon, "expect ';' after 'break'.");
+    }
+
+    fn continue_statement(&mut self) {
+        if let Some(target) = self.loop_target("continue") {
+            self.exit_scopes(self.loops[target].depth, self.loops[target].try_depth);
+            self.emit_loop(self.loops[target].start);
+        }
+        let _ = self.consume(TokenType::Semicolon, "expect ';' after 'continue'.");
+    }
+
+    /// Resolves the loop a `break` or `continue` refers to: the one named by an optional label,
+    /// or else the innermost.
+    fn loop_target(&mut self, keyword: &str) -> Option<usize> {
+        if self.current_token_type_is(TokenType::Identifier) {
+            let label = self.parser.previous.clone().unwrap().lexeme;
+            let target = self
+                .loops
+                .iter()
+                .rposition(|enclosing| enclosing.label.as_ref() == Some(&label));
+            if target.is_none() {
+                self.error(&format!("no enclosing loop labeled '{}'.", label));
+            }
+            return target;
+        }
+
+        if self.loops.is_empty() {
+            self.error(&format!("can't use '{}' outside of a loop.", keyword));
+        }
+        self.loops.len().checked_sub(1)
+    }
+
+    /// Emits the cleanup for jumping out to `depth`, where `try_depth` handlers are registered:
+    /// deferred code from the scopes being left, then the removal of the handlers of the `try`
+    /// blocks left and pops for the scopes' locals. The compiler's own bookkeeping is left alone,
+    /// since the code following the jump is still inside those scopes.
+    fn exit_scopes(&mut self, depth: usize, try_depth: usize) {
+        let registered = self.copy_deferred(depth + 1);
+        for _ in try_depth..registered {
+            self.emit_byte(OpCode::PopHandler);
+        }
+
+        let locals = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d > depth))
+            .count();
+        for _ in 0..locals {
+            self.emit_byte(OpCode::Pop);
+        }
+    }
+
+    /// Like `emit_deferred`, but leaves the deferred code registered for the normal scope exit.
+    /// Each statement's handler, and those registered after it, are removed before it runs;
+    /// returns how many handlers are left.
+    fn copy_deferred(&mut self, depth: usize) -> usize {
+        let deferred: Vec<(usize, Vec<u8>, Vec<usize>)> = self
+            .deferred
+            .iter()
+            .rev()
+            .take_while(|deferred| deferred.depth >= depth)
+            .map(|deferred| {
+                (
+                    deferred.try_depth,
+                    deferred.code.clone(),
+                    deferred.lines.clone(),
+                )
+            })
+            .collect();
+        let mut registered = self.try_depth;
+        for (try_depth, code, lines) in deferred {
+            for _ in try_depth..registered {
+                self.emit_byte(OpCode::PopHandler);
+            }
+            registered = try_depth;
+            self.compiling_chunk.append(&code, &lines);
+        }
+        registered
+    }
+
+    fn return_statement(&mut self) {
+        if self.function_type == FunctionType::Script && !self.lang.allows(Extension::ScriptReturn)
+        {
+            self.error("can't return from top-level code.");
+        }
+
+        if self.current_token_type_is(TokenType::Semicolon) {
+            self.copy_deferred(0);
+            self.emit_return();
+        } else {
+            if self.function_type == FunctionType::Initializer {
+                self.error("can't return a value from an initializer.");
+            }
+            self.expression();
+            let _ = self.consume(TokenType::Semicolon, "expect ';' after return value.");
+            // The return value stays on the stack while deferred code runs
+            self.copy_deferred(0);
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
+    fn yield_statement(&mut self) {
+        match self.function_type {
+            FunctionType::Script => self.error("can't yield from top-level code."),
+            FunctionType::Initializer => self.error("can't yield from an initializer."),
+            FunctionType::Function | FunctionType::Method => self.generator = true,
+        }
+        // The handler would be left behind while the generator is suspended
+        if self.try_depth > 0 {
+            self.error("can't yield inside a try block or after a defer.");
+        }
+
+        if self.current_token_type_is(TokenType::Semicolon) {
+            self.emit_byte(OpCode::Nil);
+        } else {
+            self.expression();
+            let _ = self.consume(TokenType::Semicolon, "expect ';' after yield value.");
+        }
+        self.emit_byte(OpCode::Yield);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        let _ = self.consume(TokenType::Semicolon, "expect ';' after value.");
+        self.emit_byte(OpCode::Print);
+    }
+
+    fn echo_statement(&mut self) {
+        self.expression();
+        let _ = self.consume(
+            TokenType::Semicolon,
+            "expect '}}' after template expression.",
+        );
+        self.emit_byte(OpCode::Echo);
+    }
+
+    /// Compiles `static_assert(condition, "message");`, which is checked as it's compiled and
+    /// leaves no code. The condition has to fold to a constant, so it can only be made of
+    /// literals, and at `OptLevel::O2` locals initialized with them.
+    fn static_assert_statement(&mut self) {
+        let keyword = self.parser.previous.clone().unwrap();
+        let _ = self.consume(TokenType::LeftParen, "expect '(' after 'static_assert'.");
+        // The assertion is folded whatever the optimization level
+        let opt_level = self.opt_level;
+        if opt_level < OptLevel::O1 {
+            self.opt_level = OptLevel::O1;
+        }
+        let condition = self.constant_expression();
+        let _ = self.consume(TokenType::Comma, "expect ',' after assertion condition.");
+        let message = self.constant_expression();
+        self.opt_level = opt_level;
+        let _ = self.consume(TokenType::RightParen, "expect ')' after assertion message.");
+        let _ = self.consume(TokenType::Semicolon, "expect ';' after static assertion.");
+        if self.parser.panic_mode {
+            return;
+        }
+
+        match (condition, message) {
+            (Some(condition), Some(Constant::String(message))) => {
+                if Value::from(&condition).is_falsey() {
+                    self.report_at(
+                        diagnostic::ASSERTION_ERROR,
+                        &keyword,
+                        &format!("static assertion failed: {}", message),
+                    );
+                }
+            }
+            (None, _) => self.error_at(
+                &keyword,
+                "static assertion condition must be known at compile time.",
+            ),
+            (Some(_), _) => self.error_at(&keyword, "static assertion message must be a string."),
+        }
+    }
+
+    /// Compiles an expression only for its value, which is known if it folds to a constant. Its
+    /// code is taken back out.
+    fn constant_expression(&mut self) -> Option<Constant> {
+        let start = self.compiling_chunk.code.len();
+        self.expression();
+        let constant = self
+            .produced_constant()
+            .filter(|(constant_start, _)| *constant_start == start)
+            .map(|(_, constant)| constant);
+        self.compiling_chunk.split_off(start);
+        self.known = None;
+        self.callee = None;
+        constant
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        let _ = self.consume(TokenType::Semicolon, "expect ';' after value.");
+        self.emit_byte(OpCode::Pop);
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    /// The left operand is on the stack. If it's falsey it is the result, otherwise it's popped
+    /// and the right operand is the result.
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    /// Like `and`, but the left operand is the result when it's truthy.
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let mut call = self.begin_call();
+        self.argument_list(&mut call);
+        self.end_call(call);
+    }
+
+    /// Checks the callee of a call whose `(` was just consumed, noting the global called and
+    /// the function to inline in place of the call, if there is one.
+    fn begin_call(&mut self) -> Call {
+        let paren = self.parser.previous.clone().unwrap();
+        if let Err(message) = lint::call(self.produced_type()) {
+            self.lint_at(&paren, &message);
+        }
+
+        // Only a callee that's just the global's name is known to be it
+        let code_len = self.compiling_chunk.code.len();
+        let callee = self
+            .callee
+            .take()
+            .filter(|(start, _)| start + 2 == code_len);
+        let inline = callee.as_ref().and_then(|(start, name)| {
+            let inline = self.inlinable.get(name)?.clone();
+            Some((*start, inline))
+        });
+        Call {
+            inline,
+            callee: callee.map(|(_, name)| name),
+            ..Default::default()
+        }
+    }
+
+    /// Emits a call once its arguments are compiled.
+    fn end_call(&mut self, mut call: Call) {
+        match call.inline.take() {
+            // Inlined bodies take their arguments in order
+            Some((start, inline)) if inline.arity == call.count && call.keywords.is_empty() => {
+                self.emit_inline(start, inline)
+            }
+            // A call with the wrong number of arguments is left to fail as it runs
+            _ => self.emit_call(call),
+        }
+    }
+
+    /// Emits the instruction making a call, following its arguments with the names of any
+    /// keyword arguments.
+    fn emit_call(&mut self, call: Call) {
+        if call.keywords.is_empty() {
+            self.emit_bytes(OpCode::Call, call.count);
+            return;
+        }
+
+        for name in &call.keywords {
+            self.emit_constant(Constant::String(intern(&name.lexeme)));
+        }
+        self.emit_byte(OpCode::CallKeywords);
+        self.emit_bytes(call.count, call.keywords.len() as u8);
+        if let Some(callee) = call.callee {
+            self.keyword_calls.push(KeywordCall {
+                callee,
+                positional: call.positional,
+                keywords: call.keywords,
+            });
+        }
+    }
+
+    /// Reports keyword arguments naming no parameter, or one already given positionally, in
+    /// calls to functions declared at the top level and defined nowhere else. Calls to anything
+    /// else are only checked as they run.
+    fn check_keyword_calls(&mut self) {
+        for call in std::mem::take(&mut self.keyword_calls) {
+            if self.global_writes.get(&call.callee) != Some(&1) {
+                continue;
+            }
+            let Some(params) = self.signatures.get(&call.callee).cloned() else {
+                continue;
+            };
+            for name in &call.keywords {
+                let message = match params.iter().position(|param| *param == name.lexeme) {
+                    None => format!("{}() has no parameter '{}'.", call.callee, name.lexeme),
+                    Some(i) if i < call.positional as usize => {
+                        format!("argument '{}' is already given by position.", name.lexeme)
+                    }
+                    Some(_) => continue,
+                };
+                // Each call is checked on its own, long after any error it was part of
+                self.parser.panic_mode = false;
+                self.error_at(name, &message);
+            }
+        }
+    }
+
+    /// Replaces a call with the body of the function called. Its arguments are already on the
+    /// stack where the body loads its parameters, so only the code loading the function itself,
+    /// at `start`, is taken out.
+    fn emit_inline(&mut self, start: usize, inline: Inline) {
+        let (code, lines) = self.compiling_chunk.split_off(start);
+        self.compiling_chunk.append(&code[2..], &lines[2..]);
+        for inlined in inline.body {
+            match inlined {
+                Inlined::Op(op) => self.emit_byte(op),
+                Inlined::Constant(constant) => self.emit_constant(constant),
+                Inlined::Named(op, name) => {
+                    let name =
+                        self.identifier_constant(&Token::new(TokenType::Identifier, name, 0, None));
+                    self.emit_bytes(op, name);
+                }
+            }
+        }
+        self.known = None;
+    }
+
+    /// Compiles the right of `value |> callee(args)`, calling `callee` with the piped value
+    /// before `args`. A bare `value |> callee` passes the value alone.
+    fn pipe(&mut self, _can_assign: bool) {
+        // The callee is a primary and any property accesses and calls on it, leaving the final
+        // call's arguments to be compiled after the callee
+        self.parse_precedence(Precedence::Primary);
+        loop {
+            if self.current_token_type_is(TokenType::Dot) {
+                self.dot(false);
+            } else if self.current_token_type_is(TokenType::LeftParen) {
+                let mut call = Call::default();
+                self.argument_list(&mut call);
+                if !self.check(TokenType::Dot) && !self.check(TokenType::LeftParen) {
+                    if let Some(name) = call.keywords.first() {
+                        let name = name.clone();
+                        self.error_at(&name, "can't pass keyword arguments to a piped call.");
+                    }
+                    self.emit_bytes(OpCode::Pipe, call.count);
+                    return;
+                }
+                self.emit_call(call);
+            } else {
+                break;
+            }
+        }
+        self.emit_bytes(OpCode::Pipe, 0);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        if self
+            .consume(TokenType::Identifier, "expect property name after '.'.")
+            .is_err()
+        {
+            return;
+        }
+        let name = self.identifier_constant(&self.parser.previous.clone().unwrap());
+
+        if can_assign && self.current_token_type_is(TokenType::Equal) {
+            self.expression();
+            self.emit_bytes(OpCode::SetProperty, name);
+        } else {
+            self.emit_bytes(OpCode::GetProperty, name);
+        }
+    }
+
+    /// Compiles `?.name` or `?.name(args)`, which are nil rather than an error when the
+    /// receiver is nil. Only the access itself is skipped, so in `a?.b.c` a nil `a` still fails
+    /// at `.c`. Properties can't be assigned through it.
+    fn safe_dot(&mut self, can_assign: bool) {
+        if self
+            .consume(TokenType::Identifier, "expect property name after '?.'.")
+            .is_err()
+        {
+            return;
+        }
+        let name = self.identifier_constant(&self.parser.previous.clone().unwrap());
+
+        if can_assign && self.current_token_type_is(TokenType::Equal) {
+            self.error("can't assign to a property through '?.'.");
+            self.expression();
+            return;
+        }
+
+        self.emit_byte(OpCode::Nil);
+        self.emit_byte(OpCode::Over);
+        self.emit_byte(OpCode::Equal);
+        let access_jump = self.emit_jump(OpCode::JumpIfFalse);
+        // The receiver is nil, and is left as the result
+        self.emit_byte(OpCode::Pop);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(access_jump);
+        self.emit_byte(OpCode::Pop);
+        self.emit_bytes(OpCode::GetProperty, name);
+        if self.current_token_type_is(TokenType::LeftParen) {
+            let mut call = Call::default();
+            self.argument_list(&mut call);
+            self.emit_call(call);
+        }
+        self.patch_jump(end_jump);
+    }
+
+    /// Compiles `[index]`, an assignment to one, or a `[start:end]` slice where either bound can
+    /// be left out.
+    fn index(&mut self, can_assign: bool) {
+        if self.check(TokenType::Colon) {
+            self.emit_byte(OpCode::Nil);
+        } else {
+            self.expression();
+        }
+
+        if self.current_token_type_is(TokenType::Colon) {
+            if self.check(TokenType::RightBracket) {
+                self.emit_byte(OpCode::Nil);
+            } else {
+                self.expression();
+            }
+            let _ = self.consume(TokenType::RightBracket, "expect ']' after slice.");
+            self.emit_byte(OpCode::Slice);
+        } else {
+            let _ = self.consume(TokenType::RightBracket, "expect ']' after index.");
+            if can_assign && self.current_token_type_is(TokenType::Equal) {
+                self.expression();
+                self.emit_byte(OpCode::IndexSet);
+            } else {
+                self.emit_byte(OpCode::IndexGet);
+            }
+        }
+    }
+
+    /// Compiles a `[a, b, c]` list literal, which may have a trailing comma.
+    fn list(&mut self, _can_assign: bool) {
+        if !self.lang.allows(Extension::Lists) {
+            self.error(&ParseError::ExtensionDisabled(Extension::Lists).to_string());
+        }
+
+        let mut count: u8 = 0;
+        if self.list_continues() {
+            loop {
+                self.expression();
+                if !self.after_element(&mut count) {
+                    break;
+                }
+            }
+        }
+        self.end_list(count);
+    }
+
+    /// Whether another list element follows.
+    fn list_continues(&self) -> bool {
+        !self.check(TokenType::RightBracket) && !self.check(TokenType::Eof)
+    }
+
+    /// Counts the list element just compiled, and says whether another follows.
+    fn after_element(&mut self, count: &mut u8) -> bool {
+        if *count == u8::MAX {
+            self.limit_error("can't have more than 255 elements in a list literal.");
+        } else {
+            *count += 1;
+        }
+        self.current_token_type_is(TokenType::Comma) && self.list_continues()
+    }
+
+    fn end_list(&mut self, count: u8) {
+        let _ = self.consume(TokenType::RightBracket, "expect ']' after list elements.");
+        self.emit_bytes(OpCode::BuildList, count);
+    }
+
+    /// Compiles a `{key: value, ...}` map literal, which may have a trailing comma.
+    fn map(&mut self, _can_assign: bool) {
+        if !self.lang.allows(Extension::Maps) {
+            self.error(&ParseError::ExtensionDisabled(Extension::Maps).to_string());
+        }
+
+        let mut count: u8 = 0;
+        if self.map_continues() {
+            loop {
+                self.expression();
+                self.after_map_key();
+                self.expression();
+                if !self.after_map_value(&mut count) {
+                    break;
+                }
+            }
+        }
+        self.end_map(count);
+    }
+
+    /// Whether another map entry follows.
+    fn map_continues(&self) -> bool {
+        !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof)
+    }
+
+    fn after_map_key(&mut self) {
+        let _ = self.consume(TokenType::Colon, "expect ':' after map key.");
+    }
+
+    /// Counts the map entry just compiled, and says whether another follows.
+    fn after_map_value(&mut self, count: &mut u8) -> bool {
+        if *count == u8::MAX {
+            self.limit_error("can't have more than 255 entries in a map literal.");
+        } else {
+            *count += 1;
+        }
+        self.current_token_type_is(TokenType::Comma) && self.map_continues()
+    }
+
+    fn end_map(&mut self, count: u8) {
+        let _ = self.consume(TokenType::RightBrace, "expect '}' after map entries.");
+        self.emit_bytes(OpCode::BuildMap, count);
+    }
+
+    fn argument_list(&mut self, call: &mut Call) {
+        if !self.check(TokenType::RightParen) {
+            loop {
+                let was_panicking = self.parser.panic_mode;
+                self.argument_name(call);
+                self.expression();
+                match self.after_argument(&mut call.count, was_panicking) {
+                    AfterArgument::Another => {}
+                    AfterArgument::Close => break,
+                    AfterArgument::Closed => return,
+                }
+            }
+        }
+        self.close_arguments();
+    }
+
+    /// Consumes the `name:` starting a keyword argument, if the next argument is one. Once
+    /// there's been one, the rest of the arguments must be too.
+    fn argument_name(&mut self, call: &mut Call) {
+        if !self.check_label() {
+            if let Some(previous) = call.keywords.last() {
+                let message = format!(
+                    "expect a keyword argument after '{}', not a positional one.",
+                    previous.lexeme
+                );
+                self.error_at_current(&message);
+            }
+            return;
+        }
+
+        let _ = self.advance();
+        let name = self.parser.previous.clone().unwrap();
+        let _ = self.advance();
+        if !self.lang.allows(Extension::KeywordArguments) {
+            let message = ParseError::ExtensionDisabled(Extension::KeywordArguments).to_string();
+            self.error_at(&name, &message);
+        }
+        if call.keywords.iter().any(|k| k.lexeme == name.lexeme) {
+            self.error_at(
+                &name,
+                &format!("argument '{}' is given twice.", name.lexeme),
+            );
+        }
+        if call.keywords.is_empty() {
+            call.positional = call.count;
+        }
+        call.keywords.push(name);
+    }
+
+    /// Counts the argument just compiled and says what comes after it, recovering from an error
+    /// in it so the arguments after it are still checked.
+    fn after_argument(&mut self, arg_count: &mut u8, was_panicking: bool) -> AfterArgument {
+        if *arg_count == u8::MAX {
+            self.limit_error("can't have more than 255 arguments.");
+        } else {
+            *arg_count += 1;
+        }
+
+        if self.new_error(was_panicking) {
+            if self.previous_is(TokenType::Comma) {
+                self.parser.panic_mode = false;
+                return AfterArgument::Another;
+            } else if self.previous_is(TokenType::RightParen) {
+                self.parser.panic_mode = false;
+                return AfterArgument::Closed;
+            } else if !self.skip_in_parens(true) {
+                return AfterArgument::Closed;
+            }
+            self.parser.panic_mode = false;
+        }
+        if self.current_token_type_is(TokenType::Comma) {
+            AfterArgument::Another
+        } else {
+            AfterArgument::Close
+        }
+    }
+
+    fn close_arguments(&mut self) {
+        let _ = self.consume(TokenType::RightParen, "expect ')' after arguments.");
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        let was_panicking = self.parser.panic_mode;
+        self.expression();
+        self.close_grouping(was_panicking);
+    }
+
+    /// Consumes the `)` ending a grouping, first skipping the rest of it if there was an error
+    /// inside, so what follows is still checked.
+    fn close_grouping(&mut self, was_panicking: bool) {
+        if self.new_error(was_panicking) {
+            if self.previous_is(TokenType::RightParen) {
+                self.parser.panic_mode = false;
+                return;
+            } else if !self.skip_in_parens(false) {
+                return;
+            }
+            self.parser.panic_mode = false;
+        }
+        let _ = self.consume(TokenType::RightParen, "expected ')' after expression)");
+    }
+
+    /// Whether an error was reported since `was_panicking` was taken from the parser, rather
+    /// than one that was already being recovered from.
+    fn new_error(&self, was_panicking: bool) -> bool {
+        !was_panicking && self.parser.panic_mode && !self.abandoned
+    }
+
+    fn previous_is(&self, tt: TokenType) -> bool {
+        self.parser
+            .previous
+            .as_ref()
+            .is_some_and(|token| token.token_type == tt)
+    }
+
+    /// Skips to the `)` closing the parentheses being parsed, or to a `,` separating their
+    /// contents if `commas`, leaving it to be consumed. Returns false without reaching one if
+    /// the statement ends first, leaving the recovery to `synchronize`.
+    fn skip_in_parens(&mut self, commas: bool) -> bool {
+        if self.previous_is(TokenType::Semicolon) {
+            return false;
+        }
+        let mut depth = 0;
+        loop {
+            match self.parser.current.clone().unwrap().token_type {
+                TokenType::RightParen if depth == 0 => return true,
+                TokenType::Comma if depth == 0 && commas => return true,
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                TokenType::Semicolon | TokenType::RightBrace | TokenType::Eof => return false,
+                _ => {}
+            }
+            let _ = self.advance();
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self
+            .parser
+            .previous
+            .clone()
+            .expect("expected previous token");
+
+        self.parse_precedence(Precedence::Unary);
+        self.unary_operator(operator);
+    }
+
+    fn unary_operator(&mut self, operator: Token) {
+        if let Some((start, operand)) = self.produced_constant() {
+            if let Some(result) = self.fold_unary(&operator.token_type, &operand) {
+                let _ = self.compiling_chunk.split_off(start);
+                self.emit_value(result);
+                return;
+            }
+        }
+
+        let operand = self.produced_type();
+        match operator.token_type {
+            TokenType::Minus => {
+                self.emit_byte(OpCode::Negate);
+                match lint::negate(operand) {
+                    Ok(result) => self.produces(result),
+                    Err(message) => self.lint_at(&operator, &message),
+                }
+            }
+            TokenType::Bang => {
+                self.emit_byte(OpCode::Not);
+                self.produces(Some(ValueType::Bool));
+            }
             _ => unreachable!(),
         }
     }
 
-    fn binary(&mut self, can_assign: bool) {
-        let operator_type = self
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self
             .parser
             .previous
             .clone()
-            .expect("expected previous token")
-            .token_type;
-        let rule = self.get_rule(&operator_type);
+            .expect("expected previous token");
+        let left = self.produced();
+        let rule = self.get_rule(&operator.token_type);
 
         self.parse_precedence(rule.precedence.next()); // TODO: Offset by one (?)
+        self.binary_operator(operator, left);
+    }
+
+    /// Emits a binary operator once both its operands are on the stack, or continues a chain
+    /// of comparisons.
+    fn binary_operator(&mut self, operator: Token, left: Option<Known>) {
+        let operator_type = operator.token_type.clone();
+        let rule = self.get_rule(&operator_type);
+        if rule.precedence == Precedence::Comparison
+            && self.lang.allows(Extension::ChainedComparison)
+            && self.check_comparison()
+        {
+            self.comparison_chain(operator_type);
+            return;
+        }
+        let right = self.produced();
+        if let (Some(left), Some(right)) = (&left, &right) {
+            // Only when the operands are the two instructions just emitted
+            if let (Some((start, a)), Some((right_start, b))) = (&left.constant, &right.constant) {
+                if *right_start == left.end {
+                    if let Some(result) = self.fold_binary(&operator_type, a, b) {
+                        let _ = self.compiling_chunk.split_off(*start);
+                        self.emit_value(result);
+                        return;
+                    }
+                }
+            }
+        }
+
+        let checked = lint::binary(
+            &operator_type,
+            left.map(|left| left.value_type),
+            right.map(|right| right.value_type),
+        );
+        self.emit_operator(operator_type);
+        match checked {
+            Ok(result) => self.produces(result),
+            Err(message) => self.lint_at(&operator, &message),
+        }
+    }
+
+    /// Evaluates `a operator b` as the VM would, for `OptLevel::O1`. Operations that would fail
+    /// are left to fail at runtime, as are comparisons of anything but numbers, and repeating a string, byte array or list is left to run
+    /// rather than storing its result.
+    // `>=` and `<=` are negated comparisons, as they're compiled, so NaN folds as it would run
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    fn fold_binary(&self, operator: &TokenType, a: &Constant, b: &Constant) -> Option<Constant> {
+        if self.opt_level < OptLevel::O1 {
+            return None;
+        }
+        let (a, b) = (Value::from(a), Value::from(b));
+        let result = match operator {
+            TokenType::Plus => (a + b).ok()?,
+            TokenType::Minus => (a - b).ok()?,
+            TokenType::Star if matches!((&a, &b), (Value::Number(_), Value::Number(_))) => {
+                (a * b).ok()?
+            }
+            TokenType::Slash => (a / b).ok()?,
+            TokenType::EqualEqual => Value::Bool(a == b),
+            TokenType::BangEqual => Value::Bool(a != b),
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => {
+                let (Value::Number(a), Value::Number(b)) = (a, b) else {
+                    return None;
+                };
+                Value::Bool(match operator {
+                    TokenType::Greater => a > b,
+                    TokenType::GreaterEqual => !(a < b),
+                    TokenType::Less => a < b,
+                    _ => !(a > b),
+                })
+            }
+            _ => return None,
+        };
+        constant_of(result)
+    }
+
+    fn fold_unary(&self, operator: &TokenType, operand: &Constant) -> Option<Constant> {
+        if self.opt_level < OptLevel::O1 {
+            return None;
+        }
+        match (operator, operand) {
+            (TokenType::Minus, Constant::Number(n)) => Some(Constant::Number(-n)),
+            (TokenType::Bang, operand) => Some(Constant::Bool(Value::from(operand).is_falsey())),
+            _ => None,
+        }
+    }
+
+    /// Emits code loading `value`, which is known to the operators around it.
+    fn emit_value(&mut self, value: Constant) {
+        let start = self.compiling_chunk.code.len();
+        match value {
+            Constant::Nil => self.emit_byte(OpCode::Nil),
+            Constant::Bool(true) => self.emit_byte(OpCode::True),
+            Constant::Bool(false) => self.emit_byte(OpCode::False),
+            _ => self.emit_constant(value.clone()),
+        }
+        self.known = Some(Known {
+            end: self.compiling_chunk.code.len(),
+            value_type: ValueType::of(&value),
+            constant: Some((start, value)),
+        });
+    }
+
+    /// Records the type of value left by the code just emitted, if it's known.
+    fn produces(&mut self, value_type: Option<ValueType>) {
+        let end = self.compiling_chunk.code.len();
+        self.known = value_type.map(|value_type| Known {
+            end,
+            value_type,
+            constant: None,
+        });
+    }
 
+    /// What's known of the value left by the code just emitted.
+    fn produced(&self) -> Option<Known> {
+        let end = self.compiling_chunk.code.len();
+        self.known.clone().filter(|known| known.end == end)
+    }
+
+    fn produced_type(&self) -> Option<ValueType> {
+        self.produced().map(|known| known.value_type)
+    }
+
+    /// The constant the code just emitted loads, and where that code starts, if it only loads a
+    /// constant.
+    fn produced_constant(&self) -> Option<(usize, Constant)> {
+        self.produced().and_then(|known| known.constant)
+    }
+
+    /// Warns about an operation `lint` found will fail, when linting is enabled. Operands
+    /// compiled while recovering from an error aren't worth second-guessing.
+    fn lint_at(&mut self, token: &Token, message: &str) {
+        if self.lint && !self.parser.panic_mode {
+            self.warn_at(diagnostic::TYPE_WARNING, token, message);
+        }
+    }
+
+    fn emit_operator(&mut self, operator_type: TokenType) {
         match operator_type {
             TokenType::Plus => self.emit_byte(OpCode::Add),
             TokenType::Minus => self.emit_byte(OpCode::Subtract),
@@ -278,7 +2563,7 @@ impl Compiler {
             TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
             TokenType::Greater => self.emit_byte(OpCode::Greater),
             TokenType::GreaterEqual => self.emit_bytes(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.emit_byte(OpCode::Equal),
+            TokenType::Less => self.emit_byte(OpCode::Less),
             TokenType::LessEqual => self.emit_bytes(OpCode::Greater, OpCode::Not),
             _ => {
                 dbg!(operator_type);
@@ -287,6 +2572,43 @@ impl Compiler {
         }
     }
 
+    fn check_comparison(&self) -> bool {
+        self.check(TokenType::Greater)
+            || self.check(TokenType::GreaterEqual)
+            || self.check(TokenType::Less)
+            || self.check(TokenType::LessEqual)
+    }
+
+    /// Compiles `a < b < c` as `a < b and b < c`, evaluating `b` once. With the left operand
+    /// and the first middle operand on the stack, each middle operand is kept beneath its
+    /// comparison's result until the chain is known to continue.
+    fn comparison_chain(&mut self, mut operator_type: TokenType) {
+        let mut short_circuits = Vec::new();
+        loop {
+            // [left, middle] -> [middle, left, middle]
+            self.emit_bytes(OpCode::Swap, OpCode::Over);
+            self.emit_operator(operator_type);
+            short_circuits.push(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop);
+
+            let _ = self.advance();
+            operator_type = self.parser.previous.clone().unwrap().token_type;
+            self.parse_precedence(Precedence::Term);
+            if !self.check_comparison() {
+                self.emit_operator(operator_type);
+                break;
+            }
+        }
+
+        let end = self.emit_jump(OpCode::Jump);
+        for jump in short_circuits {
+            self.patch_jump(jump);
+        }
+        // Drop the middle operand beneath the false result
+        self.emit_bytes(OpCode::Swap, OpCode::Pop);
+        self.patch_jump(end);
+    }
+
     fn emit_byte<T>(&mut self, byte: T)
     where
         T: Into<u8> + std::fmt::Debug,
@@ -310,26 +2632,292 @@ impl Compiler {
         self.emit_byte(byte2);
     }
 
-    fn emit_constant(&mut self, value: Value) -> Result<()> {
-        let constant = self.compiling_chunk.add_constant(value)?;
+    /// Emits a jump with a placeholder operand, returning the operand's offset for
+    /// `patch_jump` once the target is known.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.compiling_chunk.code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // Code reached by a jump could leave a different value than the code before it
+        self.known = None;
+        // -2 to adjust for the bytecode for the jump offset itself
+        let jump = self.compiling_chunk.code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.limit_error("too much code to jump over, the limit is 65535 bytes.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.compiling_chunk.code[offset] = high;
+        self.compiling_chunk.code[offset + 1] = low;
+    }
 
-        self.emit_bytes(OpCode::Constant, constant);
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop);
 
-        Ok(())
+        // +2 to skip over the operand as well
+        let offset = self.compiling_chunk.code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.limit_error("loop body too large, the limit is 65535 bytes.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_byte(high);
+        self.emit_byte(low);
     }
 
+    /// Adds `value` to the chunk's constant pool, reporting an error if the pool is full.
+    fn make_constant(&mut self, value: Constant) -> usize {
+        let key = ConstantKey::of(&value);
+        if let Some(constant) = key.as_ref().and_then(|key| self.constants.get(key)) {
+            return *constant;
+        }
+        match self.compiling_chunk.add_constant(value) {
+            Ok(constant) => {
+                if let Some(key) = key {
+                    self.constants.insert(key, constant);
+                }
+                constant
+            }
+            Err(_) => {
+                self.limit_error(&format!(
+                    "too many constants in one chunk, the limit is {}.",
+                    MAX_CONSTANTS
+                ));
+                0
+            }
+        }
+    }
+
+    fn emit_constant(&mut self, value: Constant) {
+        let constant = self.make_constant(value);
+        match u8::try_from(constant) {
+            Ok(constant) => self.emit_bytes(OpCode::Constant, constant),
+            Err(_) => {
+                self.emit_byte(OpCode::ConstantLong);
+                let [_, high, middle, low] = (constant as u32).to_be_bytes();
+                self.emit_bytes(high, middle);
+                self.emit_byte(low);
+            }
+        }
+    }
+
+    /// Returns `nil`, for the end of a function body or a bare `return;`.
     fn emit_return(&mut self) {
+        if self.function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal, 0);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
         self.emit_byte(OpCode::Return);
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if self.expression_depth >= self.limits.expression_depth {
+            self.nesting_error("expression nested too deeply.");
+            return;
+        }
+        self.expression_depth += 1;
+        if self.iterative {
+            self.parse_iteratively(precedence);
+        } else {
+            self.parse_operators(precedence);
+        }
+        self.expression_depth -= 1;
+    }
+
+    fn parse_operators(&mut self, precedence: Precedence) {
+        let was_panicking = self.parser.panic_mode;
         let _ = self.advance();
 
         let prefix_rule = self.get_rule(&self.parser.previous.clone().unwrap().token_type);
 
-        let can_assign = precedence <= Precedence::Assignment;
+        let can_assign = precedence <= Precedence::Assignment;
+
+        self.apply(prefix_rule.prefix, can_assign);
+        // After an error, leave the rest of the expression for whatever recovers from it
+        if self.new_error(was_panicking) {
+            return;
+        }
+
+        if can_assign && self.check(TokenType::Equal) {
+            let _ = self.advance();
+            self.error("invalid assignment target");
+        }
+
+        while !self.new_error(was_panicking) {
+            let current_rule = self.get_rule(&self.parser.current.clone().unwrap().token_type);
+
+            if precedence > current_rule.precedence {
+                break;
+            }
+
+            let _ = self.advance();
+            self.apply(current_rule.infix, can_assign);
+        }
+    }
+
+    /// Parses the same expressions as `parse_operators`, but keeps the groupings, unary
+    /// operators, binary operands, calls and list and map literals still to be finished on a
+    /// work stack rather than recursing into them, so only the other nested expressions count
+    /// towards the depth limit.
+    fn parse_iteratively(&mut self, precedence: Precedence) {
+        let mut pending = Vec::new();
+        let mut operand = Some(precedence);
+        loop {
+            if let Some(precedence) = operand.take() {
+                let was_panicking = self.parser.panic_mode;
+                let _ = self.advance();
+                let token_type = self.parser.previous.clone().unwrap().token_type;
+                let can_assign = precedence <= Precedence::Assignment;
+                pending.push(Pending::Operators {
+                    precedence,
+                    can_assign,
+                    prefixed: true,
+                    was_panicking,
+                });
+
+                match self.get_rule(&token_type).prefix {
+                    ParseFn::Grouping => {
+                        pending.push(Pending::Grouping {
+                            was_panicking: self.parser.panic_mode,
+                        });
+                        operand = Some(Precedence::Assignment);
+                    }
+                    ParseFn::Unary => {
+                        let operator = self.parser.previous.clone().unwrap();
+                        pending.push(Pending::Unary(operator));
+                        operand = Some(Precedence::Unary);
+                    }
+                    ParseFn::List if self.lang.allows(Extension::Lists) => {
+                        if self.list_continues() {
+                            pending.push(Pending::Element { count: 0 });
+                            operand = Some(Precedence::Assignment);
+                        } else {
+                            self.end_list(0);
+                        }
+                    }
+                    ParseFn::Map if self.lang.allows(Extension::Maps) => {
+                        if self.map_continues() {
+                            pending.push(Pending::MapKey { count: 0 });
+                            operand = Some(Precedence::Assignment);
+                        } else {
+                            self.end_map(0);
+                        }
+                    }
+                    prefix => self.apply(prefix, can_assign),
+                }
+                continue;
+            }
+
+            // The innermost operand is complete, so pick up where its enclosing one left off
+            match pending.pop() {
+                None => return,
+                Some(Pending::Grouping { was_panicking }) => self.close_grouping(was_panicking),
+                Some(Pending::Unary(operator)) => self.unary_operator(operator),
+                Some(Pending::Binary(operator, left)) => self.binary_operator(operator, left),
+                Some(Pending::Argument {
+                    mut call,
+                    was_panicking,
+                }) => match self.after_argument(&mut call.count, was_panicking) {
+                    AfterArgument::Another => {
+                        let was_panicking = self.parser.panic_mode;
+                        self.argument_name(&mut call);
+                        pending.push(Pending::Argument {
+                            call,
+                            was_panicking,
+                        });
+                        operand = Some(Precedence::Assignment);
+                    }
+                    AfterArgument::Close => {
+                        self.close_arguments();
+                        self.end_call(call);
+                    }
+                    AfterArgument::Closed => self.end_call(call),
+                },
+                Some(Pending::Element { mut count }) => {
+                    if self.after_element(&mut count) {
+                        pending.push(Pending::Element { count });
+                        operand = Some(Precedence::Assignment);
+                    } else {
+                        self.end_list(count);
+                    }
+                }
+                Some(Pending::MapKey { count }) => {
+                    self.after_map_key();
+                    pending.push(Pending::MapValue { count });
+                    operand = Some(Precedence::Assignment);
+                }
+                Some(Pending::MapValue { mut count }) => {
+                    if self.after_map_value(&mut count) {
+                        pending.push(Pending::MapKey { count });
+                        operand = Some(Precedence::Assignment);
+                    } else {
+                        self.end_map(count);
+                    }
+                }
+                Some(Pending::Operators {
+                    precedence,
+                    can_assign,
+                    prefixed,
+                    was_panicking,
+                }) => {
+                    if self.new_error(was_panicking) {
+                        continue;
+                    }
+                    if prefixed && can_assign && self.check(TokenType::Equal) {
+                        let _ = self.advance();
+                        self.error("invalid assignment target");
+                    }
+
+                    let operator_type = self.parser.current.clone().unwrap().token_type;
+                    let rule = self.get_rule(&operator_type);
+                    if precedence > rule.precedence {
+                        continue;
+                    }
+                    let _ = self.advance();
+
+                    let infix = rule.infix;
+                    let resumed = Pending::Operators {
+                        precedence,
+                        can_assign,
+                        prefixed: false,
+                        was_panicking,
+                    };
+                    if let ParseFn::Binary = infix {
+                        pending.push(resumed);
+                        let operator = self.parser.previous.clone().unwrap();
+                        pending.push(Pending::Binary(operator, self.produced()));
+                        operand = Some(rule.precedence.next());
+                    } else if let ParseFn::Call = infix {
+                        pending.push(resumed);
+                        let mut call = self.begin_call();
+                        if self.check(TokenType::RightParen) {
+                            self.close_arguments();
+                            self.end_call(call);
+                        } else {
+                            let was_panicking = self.parser.panic_mode;
+                            self.argument_name(&mut call);
+                            pending.push(Pending::Argument {
+                                call,
+                                was_panicking,
+                            });
+                            operand = Some(Precedence::Assignment);
+                        }
+                    } else {
+                        self.apply(infix, can_assign);
+                        pending.push(resumed);
+                    }
+                }
+            }
+        }
+    }
 
-        match prefix_rule.prefix {
+    fn apply(&mut self, parse_fn: ParseFn, can_assign: bool) {
+        match parse_fn {
             ParseFn::None => {
                 self.error("expected expression");
             }
@@ -340,55 +2928,209 @@ impl Compiler {
             ParseFn::Binary => self.binary(can_assign),
             ParseFn::Unary => self.unary(can_assign),
             ParseFn::Grouping => self.grouping(can_assign),
+            ParseFn::And => self.and(can_assign),
+            ParseFn::Or => self.or(can_assign),
+            ParseFn::Call => self.call(can_assign),
+            ParseFn::Dot => self.dot(can_assign),
+            ParseFn::SafeDot => self.safe_dot(can_assign),
+            ParseFn::This => self.this(can_assign),
+            ParseFn::Bytes => self.bytes(can_assign),
+            ParseFn::List => self.list(can_assign),
+            ParseFn::Map => self.map(can_assign),
+            ParseFn::Index => self.index(can_assign),
+            ParseFn::Pipe => self.pipe(can_assign),
         }
+    }
 
-        if can_assign && self.current_token_type_is(TokenType::Equal) {
-            self.error("invalid assignment target");
-        }
+    fn get_rule(&self, tt: &TokenType) -> ParseRule {
+        parse::parse_rule(tt)
+    }
+}
 
-        loop {
-            let current_rule = self.get_rule(&self.parser.current.clone().unwrap().token_type);
+/// The constant for a value folded from constants. Numbers that overflowed are left to be
+/// computed at runtime, where `checked_arithmetic` may want to report them.
+fn constant_of(value: Value) -> Option<Constant> {
+    match value {
+        Value::Nil => Some(Constant::Nil),
+        Value::Bool(b) => Some(Constant::Bool(b)),
+        Value::Number(n) if n.is_finite() => Some(Constant::Number(n)),
+        Value::Number(_) => None,
+        value => value
+            .as_symbol()
+            .map(|s| Constant::String(s.clone()))
+            .or_else(|| value.as_bytes().map(|b| Constant::Bytes(b.to_vec()))),
+    }
+}
 
-            if precedence > current_rule.precedence {
-                break;
-            }
+fn span_of(token: &Token) -> Span {
+    // A token spanning lines is reported at its last line, which its column isn't on
+    let column = match token.lexeme.contains('\n') {
+        true => 0,
+        false => token.column,
+    };
+    Span {
+        file: token.file.as_deref().map(String::from),
+        line: token.line,
+        column,
+        len: token.len,
+    }
+}
 
-            let _ = self.advance();
+/// Points at a token in a message, e.g. ` at 'print'` or ` at end`.
+fn location_of(token: &Token) -> String {
+    match token.token_type {
+        TokenType::Eof => String::from(" at end"),
+        _ => format!(" at '{}'", token.lexeme),
+    }
+}
 
-            match current_rule.infix {
-                ParseFn::None => {
-                    self.error("expected expression");
+/// Decodes the body of a `b"..."` literal. Characters stand for their UTF-8 encoding, and
+/// `\xNN` gives a byte in hex.
+fn unescape_bytes(body: &str) -> std::result::Result<Vec<u8>, &'static str> {
+    let mut bytes = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            Some('\'') => b'\'',
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                match crate::natives::decode_hex(&digits) {
+                    Some(byte) if byte.len() == 1 => byte[0],
+                    _ => return Err("expect two hex digits after '\\x'."),
                 }
-                ParseFn::Number => self.number(can_assign),
-                ParseFn::Literal => self.literal(can_assign),
-                ParseFn::String => self.string(can_assign),
-                ParseFn::Variable => self.variable(can_assign),
-                ParseFn::Binary => self.binary(can_assign),
-                ParseFn::Unary => self.unary(can_assign),
-                ParseFn::Grouping => self.grouping(can_assign),
             }
-        }
+            _ => return Err("unknown escape in byte array literal."),
+        };
+        bytes.push(byte);
     }
+    Ok(bytes)
+}
 
-    fn get_rule(&self, tt: &TokenType) -> ParseRule {
-        parse::parse_rule(tt)
+/// Compiles `source` into the function for its top level. If any errors are found, fails with
+/// a `CompileError` holding every diagnostic, for the caller to report.
+pub fn compile(source: String, options: &CompileOptions) -> LoxResult<Program> {
+    Ok(compile_files(vec![(None, source)], options)?)
+}
+
+/// Scans `source` into tokens without compiling them, returning how many there were, to time
+/// the scanner alone. Scanning stops at the first error, which compiling would report.
+pub fn scan(source: String, options: &CompileOptions) -> usize {
+    let mut scanner = crate::scanner::Scanner::new(source);
+    scanner.lang = options.lang;
+    scanner.template = options.template;
+    let mut tokens = 0;
+    while let Ok(token) = scanner.scan_token() {
+        if token.token_type == TokenType::Eof {
+            break;
+        }
+        tokens += 1;
     }
+    tokens
+}
+
+/// Compiles `source` as the file `name`, so its diagnostics say which file they're about.
+pub fn compile_file(name: String, source: String, options: &CompileOptions) -> LoxResult<Program> {
+    Ok(compile_files(vec![(Some(name), source)], options)?)
+}
+
+/// Compiles `source`, also reporting whether any errors were found, so tests can inspect the
+/// code compiled around them.
+#[cfg(test)]
+pub(crate) fn compile_with_status(
+    source: String,
+    options: &CompileOptions,
+) -> Result<(Program, bool)> {
+    let (script, diagnostics) = compile_unchecked(vec![(None, source)], options)?;
+    Ok((script, has_errors(&diagnostics)))
 }
 
-pub fn compile(source: String) -> Result<Chunk> {
-    let mut compiler = Compiler::new(source);
+/// Compiles a lone expression, with no trailing `;`, into a script that returns its value.
+pub fn compile_expression(source: String, options: &CompileOptions) -> Result<Program> {
+    let mut compiler = Compiler::new(source, options);
     compiler.advance()?;
 
-    loop {
-        if compiler.current_token_type_is(TokenType::Eof) {
-            break;
+    compiler.expression();
+    let _ = compiler.consume(TokenType::Eof, "expected end of expression");
+
+    compiler.emit_byte(OpCode::Return);
+    checked(
+        Function::script(compiler.compiling_chunk).into(),
+        compiler.diagnostics,
+    )
+}
+
+/// Compiles several sources, in order, into a single chunk. Each source may be named so errors
+/// can be attributed to the file they came from.
+pub fn compile_files(
+    sources: Vec<(Option<String>, String)>,
+    options: &CompileOptions,
+) -> Result<Program> {
+    let (script, diagnostics) = compile_unchecked(sources, options)?;
+    checked(script, diagnostics)
+}
+
+/// Compiles `sources` even if they have errors, returning everything found in them.
+fn compile_unchecked(
+    sources: Vec<(Option<String>, String)>,
+    options: &CompileOptions,
+) -> Result<(Program, Vec<Diagnostic>)> {
+    let mut compiler = Compiler::new(String::new(), options);
+    if options.opt_level >= OptLevel::O2 {
+        // Whether a local or global is assigned to is only known once the code that could has
+        // been compiled, so a first pass finds out for the second to propagate and inline the
+        // others
+        let mut first_pass = Compiler::new(String::new(), options);
+        for (file, source) in sources.iter().cloned() {
+            first_pass.compile_unit(file, source)?;
+        }
+        compiler.assigned = first_pass.assigned;
+        compiler.global_writes = first_pass.global_writes;
+        compiler.second_pass = true;
+    }
+    let mut files = Vec::new();
+
+    for (file, source) in sources {
+        files.extend(file.clone());
+        compiler.compile_unit(file, source)?;
+        for included in compiler.scanner.included() {
+            if !files.contains(included) {
+                files.push(included.clone());
+            }
         }
-        compiler.declaration();
     }
 
+    compiler.check_keyword_calls();
+    compiler.emit_deferred(0);
     compiler.emit_return();
 
-    Ok(compiler.compiling_chunk)
+    let script = Function::script(compiler.compiling_chunk);
+    let program = Program::new(script, files).with_probes(compiler.probes.unwrap_or_default());
+    Ok((program, compiler.diagnostics))
+}
+
+/// Only hands out a program that compiled without errors, so one never reaches the VM.
+fn checked(script: Program, diagnostics: Vec<Diagnostic>) -> Result<Program> {
+    if has_errors(&diagnostics) {
+        return Err(CompileError { diagnostics }.into());
+    }
+    Ok(script.with_warnings(diagnostics))
+}
+
+fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
 }
 
 #[cfg(test)]
@@ -397,33 +3139,33 @@ mod test {
     #[test]
     fn basic() {
         let source = String::from("1");
-        let chunk = compile(source).unwrap();
+        let (script, _) = compile_with_status(source, &CompileOptions::default()).unwrap();
 
-        assert_eq!(vec![1, 0, 15, 0], chunk.code);
+        assert_eq!(vec![1, 0, 15, 2, 0], script.script().chunk.code);
 
         let source = String::from("-12");
-        let chunk = compile(source).unwrap();
+        let (script, _) = compile_with_status(source, &CompileOptions::default()).unwrap();
 
-        assert_eq!(vec![1, 0, 5, 15, 0], chunk.code);
+        assert_eq!(vec![1, 0, 5, 15, 2, 0], script.script().chunk.code);
     }
     #[test]
     fn arithmatic() {
         let source = String::from("1 + 2");
-        let chunk = compile(source).unwrap();
+        let (script, _) = compile_with_status(source, &CompileOptions::default()).unwrap();
 
-        assert_eq!(vec![1, 0, 1, 1, 7, 15, 0], chunk.code);
+        assert_eq!(vec![1, 0, 1, 1, 7, 15, 2, 0], script.script().chunk.code);
 
         let source = String::from("-1 + 2");
-        let chunk = compile(source).unwrap();
+        let (script, _) = compile_with_status(source, &CompileOptions::default()).unwrap();
 
-        assert_eq!(vec![1, 0, 5, 1, 1, 7, 15, 0], chunk.code);
+        assert_eq!(vec![1, 0, 5, 1, 1, 7, 15, 2, 0], script.script().chunk.code);
 
         let source = String::from("(-1 + 2) * 3 - -4");
-        let chunk = compile(source).unwrap();
+        let (script, _) = compile_with_status(source, &CompileOptions::default()).unwrap();
 
         assert_eq!(
-            vec![1, 0, 5, 1, 1, 7, 1, 2, 9, 1, 3, 5, 8, 15, 0],
-            chunk.code
+            vec![1, 0, 5, 1, 1, 7, 1, 2, 9, 1, 3, 5, 8, 15, 2, 0],
+            script.script().chunk.code
         );
     }
 
@@ -431,11 +3173,757 @@ mod test {
     fn logic() {
         let source = String::from("!(5 - 4 > 3 * 2 == !nil)");
 
-        let chunk = compile(source).unwrap();
+        let (script, _) = compile_with_status(source, &CompileOptions::default()).unwrap();
+
+        assert_eq!(
+            vec![1, 0, 1, 1, 8, 1, 2, 1, 3, 9, 12, 2, 6, 11, 6, 15, 2, 0],
+            script.script().chunk.code
+        );
+    }
+
+    #[test]
+    fn locals() {
+        let source = String::from("{ var a = 1; { var b = a; b = 2; } }");
+        let script = compile(source, &CompileOptions::default()).unwrap();
+
+        // CONSTANT 1, GET_LOCAL a, CONSTANT 2, SET_LOCAL b, POP, POP b, POP a, NIL, RETURN.
+        // Slot zero belongs to the script itself.
+        assert_eq!(
+            vec![1, 0, 20, 1, 1, 1, 21, 2, 15, 15, 15, 2, 0],
+            script.script().chunk.code
+        );
+
+        let errors = [
+            "{ var a = 1; var a = 2; }",
+            "{ var a = a; }",
+            "{ var a = 1;",
+        ];
+        for source in errors {
+            let (_, had_error) =
+                compile_with_status(source.to_string(), &CompileOptions::default()).unwrap();
+            assert!(had_error, "{}", source);
+        }
+    }
+
+    #[test]
+    fn logical() {
+        let source = String::from("true and false or nil;");
+        let script = compile(source, &CompileOptions::default()).unwrap();
+
+        // TRUE, JUMP_IF_FALSE +2, POP, FALSE, JUMP_IF_FALSE +3, JUMP +2, POP, NIL, POP, NIL,
+        // RETURN
+        assert_eq!(
+            vec![3, 23, 0, 2, 15, 4, 23, 0, 3, 22, 0, 2, 15, 2, 15, 2, 0],
+            script.script().chunk.code
+        );
+    }
+
+    #[test]
+    fn loop_control() {
+        let errors = [
+            "break;",
+            "{ continue; }",
+            "while (true) { break nowhere; }",
+            "outer: print 1;",
+            "outer: while (true) { outer: while (true) {} }",
+            "while (true) { defer break; }",
+        ];
+        for source in errors {
+            let (_, had_error) =
+                compile_with_status(source.to_string(), &CompileOptions::default()).unwrap();
+            assert!(had_error, "{}", source);
+        }
+
+        let options = CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        let (_, had_error) =
+            compile_with_status(String::from("var break = 1; print break;"), &options).unwrap();
+        assert!(!had_error);
+    }
+
+    fn diagnostics(source: String) -> Vec<Diagnostic> {
+        let mut compiler = Compiler::new(String::new(), &CompileOptions::default());
+        compiler.compile_unit(None, source).unwrap();
+        compiler.diagnostics
+    }
+
+    fn list(count: usize, item: impl Fn(usize) -> String) -> String {
+        (0..count).map(item).collect::<Vec<_>>().join(", ")
+    }
+
+    #[test]
+    fn unexpected_tokens() {
+        let errors = diagnostics(String::from("var a print 1;"));
+        assert_eq!("expected '=' or ';' but found 'print'", errors[0].message);
+        assert_eq!(Some(String::from("print")), errors[0].found);
+        assert_eq!(vec!["=", ";"], errors[0].expected);
+
+        // Only one token could have come next, so the message says what it was to follow
+        let errors = diagnostics(String::from("print 1"));
+        assert_eq!("expect ';' after value.", errors[0].message);
+        assert_eq!(Some(String::from("EOF")), errors[0].found);
+        assert_eq!(vec![";"], errors[0].expected);
+
+        let errors = diagnostics(String::from("fun () {}"));
+        assert_eq!(vec!["IDENTIFIER"], errors[0].expected);
+    }
+
+    #[test]
+    fn expression_recovery() {
+        let messages = |source: &str| {
+            diagnostics(source.to_string())
+                .into_iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                "[line 1] Error at 'this': can't use 'this' outside of a class.",
+                "[line 1] Error at ',': expected expression",
+                "[line 1] Error at ')': expected expression",
+            ],
+            messages("f(this, 1 +, g(2, ), 3);")
+        );
+        assert_eq!(
+            vec![
+                "[line 1] Error at ')': expected expression",
+                "[line 1] Error at 'this': can't use 'this' outside of a class.",
+            ],
+            messages("print (1 + ) * ((this) + 2);")
+        );
+        // Recovery stops at the end of the statement, leaving the rest to `synchronize`
+        assert_eq!(
+            vec![
+                "[line 1] Error at ';': expected expression",
+                "[line 2] Error at ')': expected expression",
+            ],
+            messages("print f(1 + ;\nprint (2 + );")
+        );
+    }
+
+    #[test]
+    fn type_annotations() {
+        let errors = diagnostics(String::from("fun f(a: numbr, b:) {}"));
+        assert_eq!("unknown type 'numbr'.", errors[0].message);
+        assert_eq!(1, errors.len());
+
+        let strict = CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        assert!(compile(String::from("fun f(a: number) {}"), &strict).is_err());
+    }
+
+    #[test]
+    fn keyword_arguments() {
+        // Calls are checked against the functions they name once everything is compiled
+        let mut compiler = Compiler::new(String::new(), &CompileOptions::default());
+        let source = String::from(
+            "fun f(a, b) {}
+            f(1, c: 2);
+            f(1, a: 2);
+            f(b: 1, b: 2);
+            f(b: 1, 2);
+            fun g() { h(c: 1); }
+            fun h(a) {}
+            fun k(a) {}
+            k(b: 1);
+            k = h;",
+        );
+        compiler.compile_unit(None, source).unwrap();
+        compiler.check_keyword_calls();
+        let messages: Vec<_> = compiler
+            .diagnostics
+            .iter()
+            .map(|e| e.message.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "argument 'b' is given twice.",
+                "expect a keyword argument after 'b', not a positional one.",
+                "f() has no parameter 'c'.",
+                "argument 'a' is already given by position.",
+                "h() has no parameter 'c'.",
+            ],
+            messages
+        );
+
+        let strict = CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        assert!(compile(String::from("fun f(a) {} f(a: 1);"), &strict).is_err());
+    }
+
+    #[test]
+    fn safe_navigation() {
+        let errors = diagnostics(String::from("var a; a?.b = 1; a?.;"));
+        assert_eq!(
+            "can't assign to a property through '?.'.",
+            errors[0].message
+        );
+        assert_eq!("expect property name after '?.'.", errors[1].message);
+
+        let strict = CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        assert!(compile(String::from("var a; a?.b;"), &strict).is_err());
+    }
+
+    #[test]
+    fn lint() {
+        let warnings = |source: &str, iterative: bool| {
+            let options = CompileOptions {
+                lint: true,
+                recursive_expressions: !iterative,
+                ..Default::default()
+            };
+            let mut compiler = Compiler::new(String::new(), &options);
+            compiler.compile_unit(None, source.to_string()).unwrap();
+            compiler
+                .diagnostics
+                .into_iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let source = "print \"a\" - 1;
+            print (1 + 2) * -true;
+            nil();
+            print \"ab\" * 2 + \"c\";
+            print (x or \"s\") - 1;
+            \"s\"; fun f() { g - 1; }";
+        for iterative in [false, true] {
+            assert_eq!(
+                vec![
+                    "[line 1] Warning at '-': can't subtract a string and a number.",
+                    "[line 2] Warning at '-': can't negate a bool.",
+                    "[line 3] Warning at '(': can't call nil.",
+                ],
+                warnings(source, iterative)
+            );
+        }
+        assert!(diagnostics(source.to_string()).is_empty());
+    }
+
+    #[test]
+    fn excerpts() {
+        let options = CompileOptions {
+            lint: true,
+            ..Default::default()
+        };
+        let source = "var a = 1;\nprint a +;\nprint \"a\" - 1;";
+        let (_, diagnostics) =
+            compile_unchecked(vec![(None, source.to_string())], &options).unwrap();
+        let excerpts: Vec<_> = diagnostics.iter().map(|d| d.excerpt.as_deref()).collect();
+
+        assert_eq!(vec![Some("print a +;"), Some("print \"a\" - 1;")], excerpts);
+    }
+
+    #[test]
+    fn named_files() {
+        let source = "print 1;\nprint 1 +;";
+        let name = String::from("scripts/b.lox");
+        match compile_file(name, source.to_string(), &CompileOptions::default()) {
+            Err(crate::error::LoxError::Compile(e)) => {
+                let spans: Vec<_> = e.diagnostics.iter().map(|d| &d.span).collect();
+                assert_eq!(1, spans.len());
+                assert_eq!(Some("scripts/b.lox"), spans[0].file.as_deref());
+                assert_eq!(2, spans[0].line);
+            }
+            result => panic!("expected a compile error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn errors_are_returned_not_printed() {
+        let Err(crate::error::LoxError::Compile(e)) =
+            compile("print ;\nvar = 1;".to_string(), &CompileOptions::default())
+        else {
+            panic!("expected a compile error");
+        };
+        let reported: Vec<_> = e
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            vec![(1, "expected expression"), (2, "expected variable name")],
+            reported
+        );
+
+        // The test harness captures what tests print, so check stderr from a run of this test in
+        // a process of its own, which stops here
+        if std::env::var_os("LOX_TEST_CHILD").is_some() {
+            return;
+        }
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["compiler::test::errors_are_returned_not_printed", "--exact"])
+            .arg("--nocapture")
+            .env("LOX_TEST_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!("", String::from_utf8_lossy(&output.stderr));
+    }
+
+    #[test]
+    fn unexpected_characters() {
+        let source = "var a = 1;\nprint a @;\nprint @ 2;\nprint a;";
+        let (_, diagnostics) =
+            compile_unchecked(vec![(None, source.to_string())], &CompileOptions::default())
+                .unwrap();
+        let reported: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.code, d.span.line, d.span.column, d.message.as_str()))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (diagnostic::SCAN_ERROR, 2, 9, "unexpected character '@'"),
+                (diagnostic::SCAN_ERROR, 3, 7, "unexpected character '@'")
+            ],
+            reported
+        );
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let source = "print 1;\n/* never\nclosed";
+        let (_, diagnostics) =
+            compile_unchecked(vec![(None, source.to_string())], &CompileOptions::default())
+                .unwrap();
+        let reported: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+
+        assert_eq!(
+            vec!["[line 2] Error: Unterminated block comment."],
+            reported
+        );
+    }
+
+    #[test]
+    fn malformed_class_bodies() {
+        for source in [
+            "class A { 1 }",
+            "class A { var x; }",
+            "class A { m() {} ; }",
+            "class A < B {}",
+            "class A { m() {}",
+        ] {
+            assert!(
+                compile(String::from(source), &CompileOptions::default()).is_err(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn precision_warnings() {
+        let warnings = diagnostics(String::from("print 9007199254740993;\nprint 0.1;"));
+        assert_eq!(1, warnings.len());
+        assert_eq!(Severity::Warning, warnings[0].severity);
+        assert_eq!(
+            "[line 1] Warning at '9007199254740993': number can't be represented exactly, it will be 9007199254740992.",
+            warnings[0].to_string()
+        );
+
+        // The value stored, not the shortest form that would parse back to it
+        let warnings = diagnostics(String::from(
+            "print 12345678901234567890;\nprint 3.141592653589793238;",
+        ));
+        let messages: Vec<_> = warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(
+            vec![
+                "number can't be represented exactly, it will be 12345678901234567168.",
+                "number can't be represented exactly, it will be \
+                 3.141592653589793115997963468544185161590576171875.",
+            ],
+            messages
+        );
+    }
+
+    #[test]
+    fn limits() {
+        // Every constant but the last is a number, so the last is a name after them
+        let names = |count| {
+            let numbers: String = (1..count).map(|i| format!("print {};", i)).collect();
+            format!("{} print x;", numbers)
+        };
+        let parameters = |count| format!("fun f({}) {{}}", list(count, |i| format!("p{}", i)));
+        let arguments = |count| format!("fun f() {{}} f({});", list(count, |_| "nil".into()));
+        let locals = |count| {
+            let locals: String = (0..count).map(|i| format!("var v{};", i)).collect();
+            format!("{{ {} }}", locals)
+        };
+
+        let programs: [(&dyn Fn(usize) -> String, usize); 4] = [
+            (&names, UINT8_COUNT),
+            (&parameters, 255),
+            (&arguments, 255),
+            (&locals, 255),
+        ];
+        for (program, limit) in programs {
+            assert_eq!(Vec::<Diagnostic>::new(), diagnostics(program(limit)));
+
+            let errors = diagnostics(program(limit + 1));
+            assert_eq!(1, errors.len(), "{:?}", errors);
+            assert_eq!(diagnostic::LIMIT_ERROR, errors[0].code);
+        }
+    }
+
+    #[test]
+    fn shared_constants() {
+        let source = "var a = 1; print a; print a + 1; print \"a\"; print -0; print 0;
+            fun f() { print a; } fun g() {}";
+        let script = compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let constants = script.script().chunk.constants();
+        // "a", 1, 0 (negated for -0), "f", f, "g" and g
+        assert_eq!(7, constants.len(), "{:?}", constants);
+        let Constant::Function(f) = &constants[4] else {
+            panic!("expected f, got {:?}", constants[4]);
+        };
+        assert_eq!(1, f.chunk.constants().len());
+
+        // Reusing a name doesn't count towards the limit
+        let uses: String = (0..1000).map(|_| "print a;").collect();
+        assert!(diagnostics(uses).is_empty());
+    }
+
+    #[test]
+    fn long_constants() {
+        let source: String = (0..300).map(|i| format!("print {};", i)).collect();
+        let script = compile(source, &CompileOptions::default()).unwrap();
+        let chunk = &script.script().chunk;
+
+        assert_eq!(300, chunk.constants().len());
+        // The 257th constant is the first loaded with a 24 bit index
+        let long = 256 * 3;
+        assert_eq!(
+            vec![u8::from(OpCode::ConstantLong), 0, 1, 0],
+            chunk.code[long..long + 4]
+        );
+        assert_eq!(256, chunk.read_long(long + 1));
+    }
+
+    #[test]
+    fn constant_folding() {
+        let compile_at = |source: &str, opt_level| {
+            let options = CompileOptions {
+                opt_level,
+                ..Default::default()
+            };
+            let program = compile(source.to_string(), &options).unwrap();
+            let chunk = &program.script().chunk;
+            (chunk.code.clone(), chunk.constants().to_vec())
+        };
+        let folded = |source: &str| {
+            let (code, constants) = compile_at(source, OptLevel::O1);
+            let (value, len) = match OpCode::try_from(code[0]).unwrap() {
+                OpCode::Constant => (constants[code[1] as usize].clone(), 2),
+                OpCode::True => (Constant::Bool(true), 1),
+                OpCode::False => (Constant::Bool(false), 1),
+                op => panic!("{} compiled to {:?}", source, op),
+            };
+            // Then print, and return from the script
+            assert_eq!(len + 3, code.len(), "{} compiled to {:?}", source, code);
+            value
+        };
+
+        assert_eq!(Constant::Number(7.0), folded("print 1 + 2 * 3;"));
+        assert_eq!(Constant::Number(2.0), folded("print -(1 - 3);"));
+        assert_eq!(
+            Constant::String(intern("ab")),
+            folded("print \"a\" + \"b\";")
+        );
+        assert_eq!(Constant::Bool(true), folded("print !nil == (2 >= 1);"));
+        assert_eq!(Constant::Bool(false), folded("print 2 <= 1;"));
+        for unfolded in ["print \"a\" * 3;", "print 1 / 0;", "print \"a\" - 1;"] {
+            assert!(
+                compile_at(unfolded, OptLevel::O1).0.len() > 5,
+                "{}",
+                unfolded
+            );
+        }
+        assert_eq!(8, compile_at("print 1 + 2;", OptLevel::O0).0.len());
+
+        // Locals never assigned to are replaced by their values at -O2
+        let locals = "{ var w = 2; var h = 3; var area = w * h; print area + 1; }";
+        let (code, constants) = compile_at(locals, OptLevel::O2);
+        assert!(!code.contains(&u8::from(OpCode::GetLocal)), "{:?}", code);
+        assert!(constants.contains(&Constant::Number(7.0)));
+        let (code, _) = compile_at(locals, OptLevel::O1);
+        assert!(code.contains(&u8::from(OpCode::GetLocal)));
+        let assigned = "{ var x = 1; print x + 1; x = 2; }";
+        let (code, _) = compile_at(assigned, OptLevel::O2);
+        assert!(code.contains(&u8::from(OpCode::GetLocal)));
+    }
+
+    #[test]
+    fn static_assertions() {
+        let errors = |source: &str, opt_level| {
+            let options = CompileOptions {
+                opt_level,
+                ..Default::default()
+            };
+            let (program, diagnostics) =
+                compile_unchecked(vec![(None, source.to_string())], &options).unwrap();
+            let messages: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+            (program.script().chunk.code.clone(), messages)
+        };
+
+        // Assertions that hold leave no code behind
+        let (code, messages) = errors(
+            "static_assert(1 + 1 == 2, \"math\"); print 1;",
+            OptLevel::O0,
+        );
+        assert!(messages.is_empty(), "{:?}", messages);
+        assert_eq!(errors("print 1;", OptLevel::O0).0, code);
+
+        assert_eq!(
+            vec!["[line 1] Error at 'static_assert': static assertion failed: too small"],
+            errors("static_assert(1 > 2, \"too\" + \" small\");", OptLevel::O0).1
+        );
+        assert_eq!(
+            vec!["[line 1] Error at 'static_assert': static assertion condition must be known at compile time."],
+            errors("var x = 1; static_assert(x, \"x\");", OptLevel::O0).1
+        );
+        assert_eq!(
+            vec!["[line 1] Error at 'static_assert': static assertion message must be a string."],
+            errors("static_assert(true, 1);", OptLevel::O0).1
+        );
+
+        // Locals are only known at -O2
+        let local = "{ var size = 4; static_assert(size * 2 == 8, \"size\"); }";
+        assert!(errors(local, OptLevel::O2).1.is_empty());
+        assert_eq!(1, errors(local, OptLevel::O0).1.len());
+    }
+
+    #[test]
+    fn inlining() {
+        let profiled_calls = |source: &str, opt_level, profile: Option<Profile>| {
+            let options = CompileOptions {
+                opt_level,
+                profile,
+                ..Default::default()
+            };
+            let program = compile(source.to_string(), &options).unwrap();
+            let code = &program.script().chunk.code;
+            code.iter()
+                .filter(|&&op| op == u8::from(OpCode::Call))
+                .count()
+        };
+        let calls = |source: &str, opt_level| profiled_calls(source, opt_level, None);
+
+        let small = "fun area(w, h) { return w * h; } print area(2, 3) + area(4, 5);";
+        assert_eq!(2, calls(small, OptLevel::O1));
+        assert_eq!(0, calls(small, OptLevel::O2));
+        // Calls with the wrong number of arguments are kept to fail when they run
+        assert_eq!(1, calls("fun id(x) { return x; } id(1, 2);", OptLevel::O2));
+
+        for kept in [
+            // Called before it's declared
+            "id(1); fun id(x) { return x; }",
+            // Redefined
+            "fun id(x) { return x; } id(1); id = nil;",
+            // Recursive
+            "fun f(n) { return f(n); } f(1);",
+            // Parameters used out of order
+            "fun sub(a, b) { return b - a; } sub(1, 2);",
+            // Too big
+            "fun f(x) { return x + 1 + 2 + 3 + 4 + 5 + 6 + 7 + 8 + 9; } f(1);",
+            // Not at the top level
+            "{ fun id(x) { return x; } id(1); }",
+        ] {
+            assert_eq!(1, calls(kept, OptLevel::O2), "{}", kept);
+        }
+
+        // A profile lets hot functions be bigger, and keeps calls that never ran as they are
+        let big = "fun f(x) { return x + 1 + 2 + 3 + 4 + 5 + 6 + 7 + 8 + 9; } f(1);";
+        let profile: Profile = "calls f 5000".parse().unwrap();
+        assert_eq!(0, profiled_calls(big, OptLevel::O2, Some(profile)));
+        let profile: Profile = "calls other 5000".parse().unwrap();
+        assert_eq!(2, profiled_calls(small, OptLevel::O2, Some(profile)));
+    }
+
+    #[test]
+    fn nesting_limits() {
+        let compile = |source: String, limits: Limits| {
+            let options = CompileOptions {
+                limits,
+                ..Default::default()
+            };
+            let mut compiler = Compiler::new(String::new(), &options);
+            compiler.compile_unit(None, source).unwrap();
+            compiler.diagnostics
+        };
+        // Groupings don't count unless they're parsed by recursion, but indexes always do
+        let indexes = |n| format!("print {}1{};", "a[".repeat(n), "]".repeat(n));
+        let blocks = |n| format!("{}print 1;{}", "{".repeat(n), "}".repeat(n));
+        let ifs = |n| format!("{}print 1;", "if (true) ".repeat(n));
+        let small = Limits {
+            source_len: 40,
+            expression_depth: 4,
+            block_depth: 4,
+        };
+
+        assert!(compile(indexes(3), small.clone()).is_empty());
+        assert!(compile(blocks(3), small.clone()).is_empty());
+        assert!(compile(ifs(3), small.clone()).is_empty());
+        assert!(compile("print 1;".repeat(5), small.clone()).is_empty());
+        for source in [indexes(4), blocks(4), ifs(4), "print 1;".repeat(5) + " "] {
+            let errors = compile(source, small.clone());
+            assert_eq!(1, errors.len(), "{:?}", errors);
+            assert_eq!(diagnostic::LIMIT_ERROR, errors[0].code);
+        }
+
+        // Deeper than the defaults allow
+        for source in [indexes(500), blocks(500), ifs(500)] {
+            let errors = compile(source, Limits::default());
+            assert_eq!(1, errors.len(), "{:?}", errors);
+        }
+        let nested = |n| format!("print {}1{};", "[".repeat(n), "]".repeat(n));
+        assert!(compile(nested(250), Limits::default()).is_empty());
+        assert!(compile(blocks(250), Limits::default()).is_empty());
+    }
+
+    #[test]
+    fn iterative_expressions() {
+        let compile = |source: &str, iterative: bool| {
+            let options = CompileOptions {
+                recursive_expressions: !iterative,
+                ..Default::default()
+            };
+            let mut compiler = Compiler::new(String::new(), &options);
+            compiler.compile_unit(None, source.to_string()).unwrap();
+            (compiler.compiling_chunk.code, compiler.diagnostics)
+        };
+
+        for source in [
+            "print -(1 + 2) * 3 - -4 / 2;",
+            "var a = 1; a = !(a < 2 <= 3) == false or a and nil;",
+            "var l = [1, 2]; l[0] = -l[1] + f(1, (2), 3).x;",
+            "print 1 < 2 < 3 |> f(4) + 5;",
+            "(a) = 1;",
+            "print (1 + ;",
+            "print (1 + ) + (this) + (2;",
+            "print f(g(1, -2), [3, [h()], {4: 5, \"a\": [6,],},], {});",
+            "print f(1 +, 2)(3) + [1, 2 3];",
+            "print {1: 2, 3 4};",
+        ] {
+            assert_eq!(compile(source, false), compile(source, true), "{}", source);
+        }
+
+        // Literals rather than numbers, which would each need a constant
+        let deep = format!(
+            "print {}nil{};",
+            "-(true + !(".repeat(1000),
+            "))".repeat(1000)
+        );
+        let (_, diagnostics) = compile(&deep, true);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(1, compile(&deep, false).1.len());
+
+        let nested = |open: &str, close: &str| {
+            format!("print {}nil{};", open.repeat(500), close.repeat(500))
+        };
+        for deep in [
+            nested("(", ")"),
+            nested("f(", ")"),
+            nested("f(true, ", ", false)"),
+            nested("[", "]"),
+            nested("{nil: ", "}"),
+            nested("{f([", "]): nil}"),
+        ] {
+            let (_, diagnostics) = compile(&deep, true);
+            assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+            assert_eq!(1, compile(&deep, false).1.len());
+        }
+    }
+
+    #[test]
+    fn jump_limits() {
+        let jump = |size: usize, emit: fn(&mut Compiler, usize)| {
+            let mut compiler = Compiler::new(String::new(), &CompileOptions::default());
+            compiler.compile_unit(None, String::new()).unwrap();
+            emit(&mut compiler, size);
+            compiler.diagnostics
+        };
+        let forward = |compiler: &mut Compiler, size| {
+            let offset = compiler.emit_jump(OpCode::Jump);
+            compiler
+                .compiling_chunk
+                .append(&vec![OpCode::Nil as u8; size], &vec![1; size]);
+            compiler.patch_jump(offset);
+        };
+        let backward = |compiler: &mut Compiler, size| {
+            compiler
+                .compiling_chunk
+                .append(&vec![OpCode::Nil as u8; size], &vec![1; size]);
+            // The loop instruction and its operand count towards the distance
+            compiler.emit_loop(0);
+        };
 
+        assert!(jump(u16::MAX as usize, forward).is_empty());
+        assert_eq!(
+            diagnostic::LIMIT_ERROR,
+            jump(u16::MAX as usize + 1, forward)[0].code
+        );
+        assert!(jump(u16::MAX as usize - 3, backward).is_empty());
         assert_eq!(
-            vec![1, 0, 1, 1, 8, 1, 2, 1, 3, 9, 12, 2, 6, 11, 6, 15, 0],
-            chunk.code
+            diagnostic::LIMIT_ERROR,
+            jump(u16::MAX as usize - 2, backward)[0].code
         );
     }
+
+    #[test]
+    fn this() {
+        let errors = [
+            "print this;",
+            "fun f() { return this; }",
+            "class A { m() { this = 1; } }",
+            "class A { init() { return 1; } }",
+        ];
+        for source in errors {
+            let (_, had_error) =
+                compile_with_status(source.to_string(), &CompileOptions::default()).unwrap();
+            assert!(had_error, "{}", source);
+        }
+
+        let source = String::from("class A { m() { return this; } init() { return; } }");
+        let (_, had_error) = compile_with_status(source, &CompileOptions::default()).unwrap();
+        assert!(!had_error);
+    }
+
+    #[test]
+    fn generators() {
+        let errors = ["yield 1;", "class A { init() { yield 1; } }"];
+        for source in errors {
+            let (_, had_error) =
+                compile_with_status(source.to_string(), &CompileOptions::default()).unwrap();
+            assert!(had_error, "{}", source);
+        }
+
+        let (script, _) = compile_with_status(
+            String::from("fun f() { yield; }"),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let Constant::Function(f) = &script.script().chunk.constants()[1] else {
+            panic!("expected a function constant");
+        };
+        assert!(f.generator);
+
+        let options = CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        let (_, had_error) =
+            compile_with_status(String::from("var yield = 1; print yield;"), &options).unwrap();
+        assert!(!had_error);
+    }
 }
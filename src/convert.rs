@@ -0,0 +1,211 @@
+//! Conversions between Lox values and Rust types, so hosts can pass values in and read results
+//! and arguments out without matching on `Value` themselves.
+
+use crate::chunk::Value;
+use crate::error::{ConversionError, RuntimeError};
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::from_string(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::from_string(s.to_string())
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Nil
+    }
+}
+
+/// `None` becomes `nil`.
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::from_list(items.into_iter().map(Into::into).collect())
+    }
+}
+
+fn wrong_type(expected: &'static str, value: &Value) -> ConversionError {
+    ConversionError::WrongType(expected, value.to_string())
+}
+
+/// Any value, unchanged, so functions can take arguments of any type.
+impl TryFrom<&Value> for Value {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        Ok(value.clone())
+    }
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(n) => Ok(*n),
+            value => Err(wrong_type("a number", value)),
+        }
+    }
+}
+
+/// Only `true` and `false` convert, not other values by their truthiness.
+impl TryFrom<&Value> for bool {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(b) => Ok(*b),
+            value => Err(wrong_type("true or false", value)),
+        }
+    }
+}
+
+impl TryFrom<&Value> for String {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        value
+            .as_string()
+            .map(str::to_string)
+            .ok_or_else(|| wrong_type("a string", value))
+    }
+}
+
+impl TryFrom<&Value> for Vec<u8> {
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        value
+            .as_bytes()
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| wrong_type("bytes", value))
+    }
+}
+
+/// `nil` becomes `None`, anything else must convert to `T`.
+impl<T> TryFrom<&Value> for Option<T>
+where
+    T: for<'a> TryFrom<&'a Value, Error = ConversionError>,
+{
+    type Error = ConversionError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::try_from(value).map(Some),
+        }
+    }
+}
+
+/// Converts owned values the same way as borrowed ones.
+macro_rules! try_from_owned {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<Value> for $t {
+                type Error = ConversionError;
+
+                fn try_from(value: Value) -> Result<Self, Self::Error> {
+                    <$t>::try_from(&value)
+                }
+            }
+        )*
+    };
+}
+
+try_from_owned!(f64, bool, String, Vec<u8>);
+
+/// The arguments a native function takes, converted from the values it was called with, so a
+/// host function can start with `let (name, count) = <(String, f64)>::from_args(args)?;`.
+/// Implemented for tuples of up to four types that convert from a `&Value`, and for `()`.
+pub trait FromLoxArgs: Sized {
+    /// How many arguments there are, to register the function with.
+    const ARITY: u8;
+
+    fn from_args(args: &[Value]) -> anyhow::Result<Self>;
+}
+
+macro_rules! from_lox_args {
+    ($arity:literal; $($t:ident $i:tt),*) => {
+        impl<$($t),*> FromLoxArgs for ($($t,)*)
+        where
+            $($t: for<'a> TryFrom<&'a Value, Error = ConversionError>,)*
+        {
+            const ARITY: u8 = $arity;
+
+            #[allow(unused_variables)]
+            fn from_args(args: &[Value]) -> anyhow::Result<Self> {
+                if args.len() != $arity {
+                    return Err(RuntimeError::Arity(None, $arity, args.len()).into());
+                }
+                Ok(($(
+                    $t::try_from(&args[$i])
+                        .map_err(|e| ConversionError::Argument($i + 1, Box::new(e)))?,
+                )*))
+            }
+        }
+    };
+}
+
+from_lox_args!(0;);
+from_lox_args!(1; A 0);
+from_lox_args!(2; A 0, B 1);
+from_lox_args!(3; A 0, B 1, C 2);
+from_lox_args!(4; A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Value::Number(1.5), 1.5.into());
+        assert_eq!(Value::Bool(true), true.into());
+        assert_eq!(Value::from_string("lox".to_string()), "lox".into());
+        assert_eq!(Value::Nil, Option::<f64>::None.into());
+        assert_eq!("[1, 2]", Value::from(vec![1.0, 2.0]).to_string());
+
+        assert_eq!(Ok(1.5), f64::try_from(Value::Number(1.5)));
+        assert_eq!(Ok("lox".to_string()), String::try_from(Value::from("lox")));
+        assert_eq!(Ok(None), Option::<bool>::try_from(&Value::Nil));
+        assert_eq!(
+            Err(ConversionError::WrongType("a number", "true".to_string())),
+            f64::try_from(Value::Bool(true))
+        );
+        assert!(bool::try_from(Value::Nil).is_err());
+    }
+
+    #[test]
+    fn arguments() {
+        let args = [Value::from("x"), Value::Number(2.0), Value::Nil];
+        let (s, n, v) = <(String, f64, Value)>::from_args(&args).unwrap();
+        assert_eq!(("x", 2.0, Value::Nil), (s.as_str(), n, v));
+        assert_eq!(3, <(String, f64, Value)>::ARITY);
+
+        let e = <(String, String)>::from_args(&args[..2]).unwrap_err();
+        assert_eq!("argument 2: expected a string, got '2'", e.to_string());
+        assert!(<()>::from_args(&args).is_err());
+    }
+}
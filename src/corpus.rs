@@ -0,0 +1,243 @@
+/// Generates random but well-formed Lox programs for stressing the scanner and compiler.
+///
+/// Output is fully determined by the seed, so a failing program can be reproduced from the seed
+/// alone. Expressions are generated per type (number, string, boolean) and only reference
+/// variables that have already been declared, so the programs also run without runtime errors.
+pub struct Generator {
+    state: u64,
+    /// Deepest expression nesting to generate.
+    pub max_depth: usize,
+    /// Most statements to generate per program.
+    pub max_statements: usize,
+    numbers: Vec<String>,
+    strings: Vec<String>,
+}
+
+const WHITESPACE: &[&str] = &[" ", "  ", "\t", " \r"];
+const WORDS: &[&str] = &[
+    "lox",
+    "clox",
+    "beignets",
+    "cafe au lait",
+    "",
+    "a b c",
+    "1 + 2",
+];
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            state: seed,
+            max_depth: 4,
+            max_statements: 24,
+            numbers: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    pub fn program(&mut self) -> String {
+        self.numbers.clear();
+        self.strings.clear();
+
+        let mut out = String::new();
+        let count = self.below(self.max_statements + 1);
+        for _ in 0..count {
+            self.statement(&mut out);
+            out.push_str(if self.chance(4) { "\n\n" } else { "\n" });
+        }
+        out
+    }
+
+    fn statement(&mut self, out: &mut String) {
+        match self.below(6) {
+            0 => {
+                let name = format!("n{}", self.numbers.len());
+                let value = self.number(0);
+                out.push_str(&format!("var {} = {};", name, value));
+                self.numbers.push(name);
+            }
+            1 => {
+                let name = format!("s{}", self.strings.len());
+                let value = self.string(0);
+                out.push_str(&format!("var {} = {};", name, value));
+                self.strings.push(name);
+            }
+            2 if !self.numbers.is_empty() => {
+                let i = self.below(self.numbers.len());
+                let name = self.numbers[i].clone();
+                let value = self.number(0);
+                out.push_str(&format!("{} = {};", name, value));
+            }
+            3 => {
+                let value = self.any(0);
+                out.push_str(&format!("print{}{};", self.whitespace(), value));
+            }
+            4 => out.push_str(&format!("// {}", self.word())),
+            _ => {
+                let value = self.any(0);
+                out.push_str(&format!("{};", value));
+            }
+        }
+    }
+
+    fn any(&mut self, depth: usize) -> String {
+        match self.below(3) {
+            0 => self.number(depth),
+            1 => self.string(depth),
+            _ => self.boolean(depth),
+        }
+    }
+
+    fn number(&mut self, depth: usize) -> String {
+        if depth >= self.max_depth || self.chance(3) {
+            return match self.below(3) {
+                0 if !self.numbers.is_empty() => {
+                    let i = self.below(self.numbers.len());
+                    self.numbers[i].clone()
+                }
+                1 => format!("{}.{}", self.below(1000), self.below(100)),
+                _ => format!("{}", self.below(100)),
+            };
+        }
+
+        match self.below(3) {
+            0 => format!("-{}", self.number(depth + 1)),
+            1 => format!("({})", self.number(depth + 1)),
+            _ => {
+                let op = ["+", "-", "*", "/"][self.below(4)];
+                let ws = self.whitespace();
+                format!(
+                    "{}{}{}{}{}",
+                    self.number(depth + 1),
+                    ws,
+                    op,
+                    ws,
+                    self.number(depth + 1)
+                )
+            }
+        }
+    }
+
+    fn string(&mut self, depth: usize) -> String {
+        if depth >= self.max_depth || self.chance(2) {
+            return match self.below(2) {
+                0 if !self.strings.is_empty() => {
+                    let i = self.below(self.strings.len());
+                    self.strings[i].clone()
+                }
+                _ => format!("\"{}\"", self.word()),
+            };
+        }
+
+        format!("{} + {}", self.string(depth + 1), self.string(depth + 1))
+    }
+
+    fn boolean(&mut self, depth: usize) -> String {
+        if depth >= self.max_depth || self.chance(3) {
+            return ["true", "false", "nil"][self.below(3)].to_string();
+        }
+
+        match self.below(4) {
+            0 => format!("!({})", self.boolean(depth + 1)),
+            1 => format!("({})", self.boolean(depth + 1)),
+            2 => {
+                let op = ["==", "!=", ">", ">=", "<", "<="][self.below(6)];
+                format!(
+                    "{} {} {}",
+                    self.number(depth + 1),
+                    op,
+                    self.number(depth + 1)
+                )
+            }
+            _ => format!("{} == {}", self.string(depth + 1), self.string(depth + 1)),
+        }
+    }
+
+    fn word(&mut self) -> &'static str {
+        WORDS[self.below(WORDS.len())]
+    }
+
+    fn whitespace(&mut self) -> &'static str {
+        WHITESPACE[self.below(WHITESPACE.len())]
+    }
+
+    fn chance(&mut self, one_in: usize) -> bool {
+        self.below(one_in) == 0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// SplitMix64, which is plenty for test input and keeps this module dependency free.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile_with_status, CompileOptions};
+    use crate::scanner::Scanner;
+    use crate::token::TokenType;
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(Generator::new(7).program(), Generator::new(7).program());
+        assert_ne!(Generator::new(7).program(), Generator::new(8).program());
+    }
+
+    #[test]
+    fn size_bounded() {
+        let mut generator = Generator::new(1);
+        generator.max_depth = 0;
+        generator.max_statements = 3;
+        for _ in 0..100 {
+            assert!(
+                generator
+                    .program()
+                    .lines()
+                    .filter(|l| !l.is_empty())
+                    .count()
+                    <= 3
+            );
+        }
+    }
+
+    #[test]
+    fn programs_scan() {
+        for seed in 0..200 {
+            let mut scanner = Scanner::new(Generator::new(seed).program());
+            loop {
+                let token = scanner
+                    .scan_token()
+                    .expect("generated program failed to scan");
+                if token.token_type == TokenType::Eof {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn programs_compile_and_run() {
+        for seed in 0..200 {
+            let program = Generator::new(seed).program();
+            let (script, had_error) =
+                compile_with_status(program.clone(), &CompileOptions::default()).unwrap();
+
+            assert!(!had_error, "seed {} failed to compile:\n{}", seed, program);
+            assert!(
+                crate::vm::VM::execute(&script, &[]).is_ok(),
+                "seed {} failed to run:\n{}",
+                seed,
+                program
+            );
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use std::io::IsTerminal;
+use std::str::FromStr;
+
+use crate::error::ParseError;
+
+/// Stable identifiers for each kind of diagnostic, so tools can match on them without parsing
+/// the message text.
+pub const SYNTAX_ERROR: &str = "E0001";
+pub const SCAN_ERROR: &str = "E0002";
+pub const LIMIT_ERROR: &str = "E0003";
+pub const ASSERTION_ERROR: &str = "E0004";
+pub const PRECISION_WARNING: &str = "W0001";
+pub const TYPE_WARNING: &str = "W0002";
+
+/// Short descriptions of every code, published as the rule table in SARIF output.
+const RULES: &[(&str, &str)] = &[
+    (SYNTAX_ERROR, "Syntax error"),
+    (SCAN_ERROR, "Invalid token or directive"),
+    (LIMIT_ERROR, "Compiler limit exceeded"),
+    (ASSERTION_ERROR, "Static assertion failed"),
+    (
+        PRECISION_WARNING,
+        "Number literal can't be represented exactly",
+    ),
+    (TYPE_WARNING, "Operation will fail on values of these types"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    /// Something that compiles but probably doesn't do what was meant.
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Where a diagnostic applies in the source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Span {
+    pub file: Option<String>,
+    pub line: usize,
+    /// Column of the first character, counted from 1, or 0 when only the line is known.
+    pub column: usize,
+    /// Length in bytes of the source text the diagnostic is about.
+    pub len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    /// Human readable pointer at the offending token, e.g. ` at 'print'` or ` at end`.
+    pub location: String,
+    /// For an unexpected token, the token found and the tokens that would have been accepted,
+    /// as their `TokenType` display forms, so editors can offer them as fixes.
+    pub found: Option<String>,
+    pub expected: Vec<String>,
+    /// The source line the diagnostic is on, when the source was at hand to quote.
+    pub excerpt: Option<String>,
+}
+
+/// How diagnostics are written out, selected with `--message-format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MessageFormat {
+    /// `[line 1] Error at 'x': message`, as in the book.
+    #[default]
+    Human,
+    /// One JSON object per line.
+    Json,
+    /// A single SARIF 2.1.0 log covering every diagnostic, for code scanning dashboards.
+    Sarif,
+}
+
+impl FromStr for MessageFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            "sarif" => Ok(MessageFormat::Sarif),
+            _ => Err(ParseError::UnknownMessageFormat(s.to_string())),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn render(&self, format: MessageFormat) -> String {
+        match format {
+            MessageFormat::Human => self.to_string(),
+            MessageFormat::Json => self.to_json(),
+            MessageFormat::Sarif => self.to_sarif_result(),
+        }
+    }
+
+    /// Renders the diagnostic for a terminal: the one-line human form, then the line it's on
+    /// with the offending code underlined. `color` adds ANSI colors.
+    pub fn render_excerpt(&self, color: bool) -> String {
+        let style = Style { color };
+        let severity = match self.severity {
+            Severity::Error => style.paint(Color::Red, "Error"),
+            Severity::Warning => style.paint(Color::Yellow, "Warning"),
+        };
+        let mut out = match &self.span.file {
+            Some(file) => format!("[{} line {}] ", file, self.span.line),
+            None => format!("[line {}] ", self.span.line),
+        };
+        out.push_str(&format!("{}{}: {}", severity, self.location, self.message));
+
+        let Some(excerpt) = &self.excerpt else {
+            return out;
+        };
+        let number = self.span.line.to_string();
+        let gutter = style.paint(Color::Blue, &format!("{} |", " ".repeat(number.len())));
+        let code = excerpt.trim_end();
+        let (indent, width) = self.underlined(code);
+        let underline = "^".repeat(width);
+        let underline = match self.severity {
+            Severity::Error => style.paint(Color::Red, &underline),
+            Severity::Warning => style.paint(Color::Yellow, &underline),
+        };
+        out.push_str(&format!(
+            "\n{}\n{} {}\n{} {}{}",
+            gutter,
+            style.paint(Color::Blue, &format!("{} |", number)),
+            code,
+            gutter,
+            " ".repeat(indent),
+            underline
+        ));
+        out
+    }
+
+    /// The characters of `code` before the part to underline, and how many to underline. That's
+    /// the span when its column is known, and otherwise the whole line.
+    fn underlined(&self, code: &str) -> (usize, usize) {
+        if self.span.column == 0 {
+            let indent = code.chars().count() - code.trim_start().chars().count();
+            return (indent, code.trim_start().chars().count());
+        }
+        let indent = self.span.column - 1;
+        let start = code
+            .char_indices()
+            .nth(indent)
+            .map_or(code.len(), |(offset, _)| offset);
+        let end = (start + self.span.len).min(code.len());
+        let width = code.get(start..end).map_or(0, |text| text.chars().count());
+        // Even the end of the source gets a caret
+        (indent, width.max(1))
+    }
+
+    fn to_sarif_result(&self) -> String {
+        let location = match &self.span.file {
+            Some(file) => format!(
+                r#"{{"physicalLocation":{{"artifactLocation":{{"uri":{}}},"region":{{"startLine":{}{}}}}}}}"#,
+                json_string(file),
+                self.span.line,
+                self.start_column()
+            ),
+            None => format!(
+                r#"{{"physicalLocation":{{"region":{{"startLine":{}{}}}}}}}"#,
+                self.span.line,
+                self.start_column()
+            ),
+        };
+        format!(
+            r#"{{"ruleId":{},"level":{},"message":{{"text":{}}},"locations":[{}]}}"#,
+            json_string(self.code),
+            json_string(&self.severity.to_string()),
+            json_string(&self.message),
+            location
+        )
+    }
+
+    fn start_column(&self) -> String {
+        match self.span.column {
+            0 => String::new(),
+            column => format!(r#","startColumn":{}"#, column),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let file = match &self.span.file {
+            Some(file) => json_string(file),
+            None => String::from("null"),
+        };
+        let found = match &self.found {
+            Some(found) => {
+                let expected = self
+                    .expected
+                    .iter()
+                    .map(|e| json_string(e))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(
+                    r#","found":{},"expected":[{}]"#,
+                    json_string(found),
+                    expected
+                )
+            }
+            None => String::new(),
+        };
+        let column = match self.span.column {
+            0 => String::new(),
+            column => format!(r#","column":{},"len":{}"#, column, self.span.len),
+        };
+        format!(
+            r#"{{"code":{},"severity":{},"message":{},"span":{{"file":{},"line":{}{}}}{}}}"#,
+            json_string(self.code),
+            json_string(&self.severity.to_string()),
+            json_string(&self.message),
+            file,
+            self.span.line,
+            column,
+            found
+        )
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.span.file {
+            Some(file) => write!(f, "[{} line {}] ", file, self.span.line)?,
+            None => write!(f, "[line {}] ", self.span.line)?,
+        }
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(f, "{}{}: {}", severity, self.location, self.message)
+    }
+}
+
+/// Writes all diagnostics to stderr, keeping stdout free for program output. Human readable
+/// ones quote the source they're about.
+pub fn emit(diagnostics: &[Diagnostic], format: MessageFormat) {
+    match format {
+        MessageFormat::Sarif => eprintln!("{}", sarif(diagnostics)),
+        MessageFormat::Human => {
+            let color = use_color();
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic.render_excerpt(color));
+            }
+        }
+        MessageFormat::Json => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic.render(format));
+            }
+        }
+    }
+}
+
+/// Renders an error that stopped a running script as the book reports it: the message, then the
+/// line each call in progress had reached, innermost first, along with its function's name.
+pub fn render_runtime_error(message: &str, trace: &[(usize, Option<&str>)], color: bool) -> String {
+    let mut out = Style { color }.paint(Color::Red, message);
+    for (line, function) in trace {
+        match function {
+            Some(name) => out.push_str(&format!("\n[line {}] in {}()", line, name)),
+            None => out.push_str(&format!("\n[line {}] in script", line)),
+        }
+    }
+    out
+}
+
+/// Whether to color what's written to stderr: only for a terminal, and not when the `NO_COLOR`
+/// convention asks otherwise.
+pub fn use_color() -> bool {
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    Red,
+    Yellow,
+    Blue,
+}
+
+/// Applies colors to text, or leaves it plain.
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn paint(&self, color: Color, text: &str) -> String {
+        if !self.color {
+            return text.to_string();
+        }
+        let code = match color {
+            Color::Red => 31,
+            Color::Yellow => 33,
+            Color::Blue => 34,
+        };
+        format!("\x1b[1;{}m{}\x1b[0m", code, text)
+    }
+}
+
+/// Wraps the diagnostics in a complete SARIF log, which is written even when there are no
+/// results so that a clean run can still be uploaded.
+pub fn sarif(diagnostics: &[Diagnostic]) -> String {
+    let rules = RULES
+        .iter()
+        .map(|(id, description)| {
+            format!(
+                r#"{{"id":{},"shortDescription":{{"text":{}}}}}"#,
+                json_string(id),
+                json_string(description)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let results = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render(MessageFormat::Sarif))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        r#"{{"$schema":"https://json.schemastore.org/sarif-2.1.0.json","version":"2.1.0","runs":[{{"tool":{{"driver":{{"name":"lox","version":{},"rules":[{}]}}}},"results":[{}]}}]}}"#,
+        json_string(env!("CARGO_PKG_VERSION")),
+        rules,
+        results
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn diagnostic(file: Option<&str>) -> Diagnostic {
+        Diagnostic {
+            code: SYNTAX_ERROR,
+            severity: Severity::Error,
+            message: String::from("expected \"';'\""),
+            span: Span {
+                file: file.map(String::from),
+                line: 3,
+                ..Default::default()
+            },
+            location: String::from(" at 'x'"),
+            found: None,
+            expected: Vec::new(),
+            excerpt: None,
+        }
+    }
+
+    #[test]
+    fn excerpt() {
+        let quoted = Diagnostic {
+            excerpt: Some(String::from("  print x  ")),
+            ..diagnostic(None)
+        };
+        assert_eq!(
+            r#"[line 3] Error at 'x': expected "';'"
+  |
+3 |   print x
+  |   ^^^^^^^"#,
+            quoted.render_excerpt(false)
+        );
+        assert_eq!(
+            "[line 3] \x1b[1;31mError\x1b[0m at 'x': expected \"';'\"\n\x1b[1;34m  |\x1b[0m\n\x1b[1;34m3 |\x1b[0m   print x\n\x1b[1;34m  |\x1b[0m   \x1b[1;31m^^^^^^^\x1b[0m",
+            quoted.render_excerpt(true)
+        );
+
+        // With a column, only the span is underlined
+        let pointed = Diagnostic {
+            span: Span {
+                column: 9,
+                len: 1,
+                ..quoted.span.clone()
+            },
+            ..quoted.clone()
+        };
+        assert_eq!(
+            r#"[line 3] Error at 'x': expected "';'"
+  |
+3 |   print x
+  |         ^"#,
+            pointed.render_excerpt(false)
+        );
+        assert!(pointed
+            .render(MessageFormat::Json)
+            .ends_with(r#""span":{"file":null,"line":3,"column":9,"len":1}}"#));
+        assert!(pointed
+            .render(MessageFormat::Sarif)
+            .ends_with(r#""region":{"startLine":3,"startColumn":9}}}]}"#));
+
+        // Without the source there's only the message
+        assert_eq!(
+            diagnostic(None).to_string(),
+            diagnostic(None).render_excerpt(false)
+        );
+    }
+
+    #[test]
+    fn runtime_error() {
+        assert_eq!(
+            "operands must be numbers\n[line 2] in f()\n[line 5] in script",
+            render_runtime_error(
+                "operands must be numbers",
+                &[(2, Some("f")), (5, None)],
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn human() {
+        assert_eq!(
+            r#"[line 3] Error at 'x': expected "';'""#,
+            diagnostic(None).render(MessageFormat::Human)
+        );
+        assert_eq!(
+            r#"[a.lox line 3] Error at 'x': expected "';'""#,
+            diagnostic(Some("a.lox")).render(MessageFormat::Human)
+        );
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            r#"{"code":"E0001","severity":"error","message":"expected \"';'\"","span":{"file":null,"line":3}}"#,
+            diagnostic(None).render(MessageFormat::Json)
+        );
+        assert_eq!(
+            r#"{"code":"E0001","severity":"error","message":"expected \"';'\"","span":{"file":"dir\\a.lox","line":3}}"#,
+            diagnostic(Some("dir\\a.lox")).render(MessageFormat::Json)
+        );
+
+        let unexpected = Diagnostic {
+            found: Some(String::from("print")),
+            expected: vec![String::from("="), String::from(";")],
+            ..diagnostic(None)
+        };
+        assert!(unexpected
+            .render(MessageFormat::Json)
+            .ends_with(r#""line":3},"found":"print","expected":["=",";"]}"#));
+    }
+
+    #[test]
+    fn sarif_log() {
+        let log = sarif(&[diagnostic(Some("a.lox"))]);
+
+        assert!(log.starts_with(r#"{"$schema":"https://json.schemastore.org/sarif-2.1.0.json","version":"2.1.0","runs":[{"tool":{"driver":{"name":"lox""#));
+        assert!(log.contains(r#"{"id":"E0001","shortDescription":{"text":"Syntax error"}}"#));
+        assert!(log.ends_with(r#""results":[{"ruleId":"E0001","level":"error","message":{"text":"expected \"';'\""},"locations":[{"physicalLocation":{"artifactLocation":{"uri":"a.lox"},"region":{"startLine":3}}}]}]}]}"#));
+
+        assert!(sarif(&[]).ends_with(r#""results":[]}]}"#));
+    }
+}
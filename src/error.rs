@@ -1,4 +1,8 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lang::Extension;
+use crate::natives::Capability;
 use crate::token::TokenType;
+use crate::vm::ResourceUsage;
 use thiserror::Error;
 
 #[derive(Debug, PartialEq)]
@@ -9,46 +13,349 @@ pub struct ErrorLoc {
 
 #[derive(Error, Debug, PartialEq)]
 pub enum ParseError {
-    #[error("expected token {0}")]
-    ExpectedToken(TokenType),
+    /// A token the grammar doesn't allow where it was found.
+    #[error("expected {} but found {}", describe_all(.expected), describe(.found, .lexeme))]
+    UnexpectedToken {
+        span: Span,
+        found: TokenType,
+        lexeme: String,
+        /// Every token the parser would have accepted instead.
+        expected: Vec<TokenType>,
+    },
     #[error("unterminated string {0}")]
     UnterminatedString(ErrorLoc),
+    /// A block comment still open at the end of the source, which started on `line`.
+    #[error("Unterminated block comment.")]
+    UnterminatedComment { line: usize },
+    /// A character that can't start a token, at a column counted from 1.
+    #[error("unexpected character '{ch}'")]
+    UnexpectedCharacter {
+        ch: char,
+        line: usize,
+        column: usize,
+    },
     #[error("unknown token type")]
     UnknownTokenType,
+    #[error("unknown directive '#{0}' {1}")]
+    UnknownDirective(String, ErrorLoc),
+    #[error("malformed include directive {0}")]
+    MalformedInclude(ErrorLoc),
+    #[error("unable to include '{0}': {1}")]
+    IncludeFailed(String, String),
+    #[error("'{0}' includes itself")]
+    RecursiveInclude(String),
+    #[error("unable to include '{0}': includes are nested more than {1} deep")]
+    IncludeTooDeep(String, usize),
+    #[error("unknown language mode '{0}', expected 'strict' or 'extended'")]
+    UnknownLang(String),
+    #[error("unknown message format '{0}', expected 'human', 'json' or 'sarif'")]
+    UnknownMessageFormat(String),
+    #[error("unknown syntax format '{0}', expected 'textmate' or 'tree-sitter'")]
+    UnknownSyntaxFormat(String),
+    #[error("unknown optimization level '{0}', expected 0, 1 or 2")]
+    UnknownOptLevel(String),
+    #[error("invalid profile entry on line {0}, expected 'calls <function> <count>'")]
+    InvalidProfile(usize),
+    #[error("unknown capability '{0}', expected 'time', 'threads' or 'process'")]
+    UnknownCapability(String),
+    #[error("{0} is a language extension, enable it with --lang=extended")]
+    ExtensionDisabled(Extension),
 }
 
 #[derive(Error, Debug, PartialEq)]
 pub enum EvaluationError {
-    #[error("operands must be numbers {0}")]
+    #[error("operands of '{0}' must be numbers")]
     Comparision(String),
     #[error("operand must be number")]
     Negation,
     #[error("cannot perform {0} on non-numeric values")]
     Arithmatic(String),
+    #[allow(dead_code)]
     #[error("cannot concatinate non-string with string")]
     StringConcatination,
+    #[error("can only repeat a whole, non-negative number of times, got {0}")]
+    RepeatCount(f64),
+    #[error("repeated value would be longer than {0} bytes")]
+    RepeatTooLong(usize),
+    #[error("concatenated value would be longer than {0} bytes")]
+    ConcatTooLong(usize),
 }
 
 #[derive(Error, Debug, PartialEq)]
 pub enum RuntimeError {
     #[error("undefined variable: '{0}'")]
     UndefinedVariable(String),
-    #[error("unexpected token: '{0}'")]
-    UnexpectedToken(crate::token::Token),
+    /// A call with the wrong number of arguments: the callee's name, if it has one, then the
+    /// arguments it takes and the arguments it was given.
+    #[error("{}expected {1} {} but got {2}", callee(.0), arguments(*.1))]
+    Arity(Option<String>, u8, usize),
+    #[error("can only call functions and classes")]
+    NotCallable,
+    #[error("only functions, methods and classes with an initializer take keyword arguments")]
+    NoKeywordArguments,
+    /// A keyword argument naming none of the callee's parameters.
+    #[error("{}has no parameter '{1}'", callee(.0))]
+    UnknownParameter(Option<String>, String),
+    /// A keyword argument for a parameter already given an argument.
+    #[error("{}was given argument '{1}' twice", callee(.0))]
+    DuplicateArgument(Option<String>, String),
+    #[error("only instances have properties")]
+    NotAnInstance,
+    #[error("undefined property '{0}'")]
+    UndefinedProperty(String),
+    #[error("stack overflow")]
+    StackOverflow,
+    #[error("condition must be true or false, got '{0}'")]
+    NonBooleanCondition(String),
+    #[error("can only index bytes, lists and maps")]
+    NotIndexable,
+    #[error("can only assign to elements of a list or map")]
+    NotAssignable,
+    /// A change to an instance, list or map, named here, that `freeze()` made read-only.
+    #[error("can't modify a frozen {0}")]
+    Frozen(&'static str),
+    #[error("map keys must be numbers, strings or booleans, got '{0}'")]
+    InvalidKey(String),
+    #[error("can only loop over generators, lists and maps")]
+    NotIterable,
+    #[error("uncaught exception: {0}")]
+    Uncaught(String),
+    #[error("index must be a whole number, got '{0}'")]
+    InvalidIndex(String),
+    #[error("index {0} is out of range for length {1}")]
+    IndexOutOfRange(f64, usize),
+    #[error("only generators and cursors report whether they're done")]
+    NotAGenerator,
+    #[error("generator is already running")]
+    GeneratorRunning,
+    #[error("slice start {0} is after its end {1}")]
+    InvalidSlice(usize, usize),
+    #[error("arithmetic produced {0} on line {1}")]
+    NonFinite(f64, usize),
+    #[error("interrupted")]
+    Interrupted,
+    /// A script went over one of the limits set by `VmOptions`, named here, having used what's
+    /// reported up to then.
+    #[error("{0} limit exceeded after {1}")]
+    LimitExceeded(&'static str, ResourceUsage),
 }
 
+/// Everything that can go wrong in the library's entry points, so hosts can match on what it
+/// was rather than downcasting.
+#[derive(Error, Debug)]
+pub enum LoxError {
+    /// The source has errors, so none of it was run.
+    #[error(transparent)]
+    Compile(#[from] CompileError),
+    /// A script stopped on an error no `catch` block handled. With it comes a trace of the calls
+    /// in progress, innermost first: the line each had reached and its function's name, `None`
+    /// for the top level. `diagnostic::render_runtime_error` reports them as the book does.
+    #[error("{0}")]
+    Runtime(ScriptError, Vec<(usize, Option<String>)>),
+    /// The script called `exit()` with this code.
+    #[error("script exited with code {0}")]
+    Exit(i32),
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    Encoding(#[from] EncodingError),
+    #[error(transparent)]
+    Project(#[from] ProjectError),
+    #[error(transparent)]
+    Chunk(#[from] ChunkError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type LoxResult<T> = Result<T, LoxError>;
+
+/// The error that stopped a script.
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    /// Misusing the language, e.g. reading an undefined variable or calling a non-function.
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+    /// Applying an operator to values of the wrong type.
+    #[error(transparent)]
+    Evaluation(#[from] EvaluationError),
+    #[error(transparent)]
+    Native(#[from] NativeError),
+    #[error(transparent)]
+    Conversion(#[from] ConversionError),
+    /// An error returned by a function the host registered.
+    #[error(transparent)]
+    Host(anyhow::Error),
+}
+
+/// Raised by the `exit()` native to stop the script with an exit code, past any `catch` blocks.
+#[derive(Error, Debug)]
+#[error("exit({0})")]
+pub(crate) struct Exit(pub i32);
+
+/// Carries the error that stopped a script, and the trace of where it stopped, until it leaves
+/// the library as a `LoxError::Runtime`.
+#[derive(Debug)]
+pub(crate) struct Unhandled {
+    pub error: anyhow::Error,
+    pub trace: Vec<(usize, Option<String>)>,
+}
+
+impl std::fmt::Display for Unhandled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Unhandled {}
+
+/// Returns the error out of `$error` as `$variant` if it's a `$t`, and otherwise carries on with
+/// it.
+macro_rules! downcast_into {
+    ($error:ident, $($t:ty => $variant:expr),* $(,)?) => {
+        $(
+            let $error = match $error.downcast::<$t>() {
+                Ok(e) => return $variant(e),
+                Err(e) => e,
+            };
+        )*
+    };
+}
+
+impl From<anyhow::Error> for LoxError {
+    fn from(error: anyhow::Error) -> Self {
+        downcast_into!(
+            error,
+            LoxError => std::convert::identity,
+            Unhandled => |Unhandled { error, trace }| LoxError::Runtime(error.into(), trace),
+            Exit => |Exit(code)| LoxError::Exit(code),
+            CompileError => LoxError::Compile,
+            ParseError => LoxError::Parse,
+            EncodingError => LoxError::Encoding,
+            ProjectError => LoxError::Project,
+            ChunkError => LoxError::Chunk,
+            std::io::Error => LoxError::Io,
+        );
+        LoxError::Other(error)
+    }
+}
+
+impl From<anyhow::Error> for ScriptError {
+    fn from(error: anyhow::Error) -> Self {
+        downcast_into!(
+            error,
+            RuntimeError => ScriptError::Runtime,
+            EvaluationError => ScriptError::Evaluation,
+            NativeError => ScriptError::Native,
+            ConversionError => ScriptError::Conversion,
+        );
+        ScriptError::Host(error)
+    }
+}
+
+/// A Lox value couldn't be converted to the Rust type asked for.
 #[derive(Error, Debug, PartialEq)]
-pub enum InterpretError {
-    #[error("compile error")]
-    Compile,
-    #[error("runtime error")]
-    Runtime,
+pub enum ConversionError {
+    #[error("expected {0}, got '{1}'")]
+    WrongType(&'static str, String),
+    #[error("argument {0}: {1}")]
+    Argument(usize, Box<ConversionError>),
+}
+
+/// Why a source file couldn't be read as text.
+#[derive(Error, Debug, PartialEq)]
+pub enum EncodingError {
+    #[error("invalid UTF-8 at byte {offset} (line {line}), source files must be UTF-8")]
+    InvalidUtf8 { offset: usize, line: usize },
+    #[error("source is UTF-16, save it as UTF-8 instead")]
+    Utf16,
+}
+
+/// Compiling failed, finding these errors. They haven't been reported, so callers can render
+/// them however suits them, e.g. with `diagnostic::emit`.
+#[derive(Error, Debug, PartialEq)]
+#[error("compile error")]
+pub struct CompileError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ProjectError {
+    #[error("manifest has no entry point")]
+    MissingEntry,
+    #[error("manifest declares more than one entry point")]
+    DuplicateEntry,
+    #[error("invalid manifest line {0}, expected 'key = value'")]
+    InvalidLine(usize),
+    #[error("unknown manifest key: '{0}'")]
+    UnknownKey(String),
+    #[error("unable to read {0}: {1}")]
+    Read(String, String),
 }
 
 #[derive(Error, Debug)]
 pub enum ChunkError {
     #[error("unknown opcode: '{0}'")]
     UnknownOpCode(u8),
+    #[error("malformed chunk: {0}")]
+    Malformed(&'static str),
+    /// Bytes to load as a chunk don't start with the bytecode magic number.
+    #[error("not compiled Lox bytecode")]
+    NotBytecode,
+    /// Bytecode saved in a format other than the one this build reads: the version found, then
+    /// the version expected.
+    #[error("bytecode is format version {0}, but this version of lox only runs version {1}")]
+    Version(u8, u8),
+    /// A patch that would break the instruction at the given index.
+    #[error("can't patch instruction {0}: {1}")]
+    Patch(usize, &'static str),
+    /// An instruction used more values than were on the stack, in the named function on the
+    /// given line.
+    #[error("internal error: stack underflow in {0} on line {1}")]
+    StackUnderflow(String, usize),
+}
+
+/// Names a token in a message: by its lexeme, or by its kind when that varies.
+fn describe(token_type: &TokenType, lexeme: &str) -> String {
+    match token_type {
+        TokenType::Eof => String::from("end"),
+        _ => format!("'{}'", lexeme),
+    }
+}
+
+/// Names the function in an arity error, e.g. `add() `.
+fn callee(name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("{}() ", name),
+        None => String::new(),
+    }
+}
+
+fn arguments(count: u8) -> &'static str {
+    match count {
+        1 => "argument",
+        _ => "arguments",
+    }
+}
+
+/// Lists tokens as `'a'`, `'a' or 'b'`, or `'a', 'b' or 'c'`.
+fn describe_all(token_types: &[TokenType]) -> String {
+    let names = token_types
+        .iter()
+        .map(|token_type| match token_type {
+            TokenType::Identifier => String::from("a name"),
+            TokenType::String => String::from("a string"),
+            TokenType::Number => String::from("a number"),
+            TokenType::Bytes => String::from("bytes"),
+            token_type => describe(token_type, &token_type.to_string()),
+        })
+        .collect::<Vec<_>>();
+    match names.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::from("nothing"),
+    }
 }
 
 impl std::fmt::Display for ErrorLoc {
@@ -56,3 +363,78 @@ impl std::fmt::Display for ErrorLoc {
         write!(f, "line: {}@{}", self.line, self.at)
     }
 }
+
+/// Errors raised by native functions, reported as runtime errors by the VM.
+#[derive(Error, Debug)]
+pub enum NativeError {
+    #[error("{0}: {1}")]
+    InvalidArgument(&'static str, String),
+    #[error("{0}() needs the '{1}' capability, grant it with --allow={1}")]
+    CapabilityDenied(&'static str, Capability),
+    #[error("{0}: {1}")]
+    Failed(&'static str, String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile, CompileOptions};
+    use crate::vm::VM;
+
+    fn run(source: &str) -> LoxError {
+        let program = compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+        vm.run(&program).unwrap_err()
+    }
+
+    #[test]
+    fn variants() {
+        let e = compile("print ;".to_string(), &CompileOptions::default()).unwrap_err();
+        let LoxError::Compile(CompileError { diagnostics }) = e else {
+            panic!("expected a compile error, got {:?}", e);
+        };
+        assert_eq!(1, diagnostics.len());
+
+        let e = run("print missing;");
+        assert!(
+            matches!(
+                &e,
+                LoxError::Runtime(ScriptError::Runtime(RuntimeError::UndefinedVariable(name)), _)
+                    if name == "missing"
+            ),
+            "{:?}",
+            e
+        );
+        // The message and where it happened come with the error, rather than going to stderr
+        let e = run("fun f() {\n  return nil - 1;\n}\nf();");
+        let LoxError::Runtime(_, trace) = &e else {
+            panic!("expected a runtime error, got {:?}", e);
+        };
+        assert_eq!(
+            "cannot perform subtract on non-numeric values",
+            e.to_string()
+        );
+        assert_eq!(vec![(2, Some("f".to_string())), (4, None)], *trace);
+
+        let e = run("print nil - 1;");
+        assert!(
+            matches!(
+                e,
+                LoxError::Runtime(ScriptError::Evaluation(EvaluationError::Arithmatic(_)), _)
+            ),
+            "{:?}",
+            e
+        );
+
+        let missing = std::env::temp_dir().join(format!("lox-missing-{}.lox", std::process::id()));
+        let e = crate::source::read(&missing).unwrap_err();
+        let LoxError::Io(e) = e else {
+            panic!("expected an I/O error, got {:?}", e);
+        };
+        assert_eq!(std::io::ErrorKind::NotFound, e.kind());
+        assert!(matches!(
+            crate::program::Program::load(&missing),
+            Err(LoxError::Io(_))
+        ));
+    }
+}
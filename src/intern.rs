@@ -0,0 +1,120 @@
+//! The table of interned strings, shared by the compiler and every VM in the process, so each
+//! distinct string is stored once and strings compare and hash by address rather than contents.
+
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Strings are swept from the table once it has grown this big since it was last swept.
+const MIN_SWEEP: usize = 1024;
+
+/// A string from the intern table. Two symbols with the same contents are the same string, so
+/// comparing them compares pointers.
+#[derive(Clone)]
+pub struct Symbol(Arc<str>);
+
+struct Table {
+    strings: HashSet<Arc<str>>,
+    /// How big the table can grow before strings only it refers to are swept.
+    sweep_at: usize,
+}
+
+static TABLE: OnceLock<Mutex<Table>> = OnceLock::new();
+
+/// The symbol for `s`, adding it to the table if it's new.
+pub fn intern(s: &str) -> Symbol {
+    let mut table = TABLE
+        .get_or_init(|| {
+            Mutex::new(Table {
+                strings: HashSet::new(),
+                sweep_at: MIN_SWEEP,
+            })
+        })
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(string) = table.strings.get(s) {
+        return Symbol(Arc::clone(string));
+    }
+
+    // A string only the table refers to can't be handed out again but by the table, which is
+    // locked, so it's safe to drop
+    if table.strings.len() >= table.sweep_at {
+        table.strings.retain(|string| Arc::strong_count(string) > 1);
+        table.sweep_at = MIN_SWEEP.max(table.strings.len() * 2);
+    }
+    let string: Arc<str> = Arc::from(s);
+    table.strings.insert(Arc::clone(&string));
+    Symbol(string)
+}
+
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(Arc::as_ptr(&self.0) as *const u8, state)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.0.cmp(&other.0))
+    }
+}
+
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", &*self.0)
+    }
+}
+
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", &*self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let a = intern("interned");
+        let b = intern(&(String::from("intern") + "ed"));
+        assert_eq!(a, b);
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_ne!(a, intern("other"));
+        assert_eq!("interned", a.as_str());
+
+        // Strings nobody uses any more are swept as the table grows
+        let swept = Arc::downgrade(&intern("swept").0);
+        for n in 0..MIN_SWEEP * 64 {
+            if swept.upgrade().is_none() {
+                break;
+            }
+            intern(&format!("filler {}", n));
+        }
+        assert!(swept.upgrade().is_none());
+        assert_eq!(a, intern("interned"));
+    }
+}
@@ -0,0 +1,134 @@
+use std::str::FromStr;
+
+use crate::error::ParseError;
+
+/// Which dialect of Lox the frontend accepts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Lang {
+    /// Only the language described in Crafting Interpreters, for following along with the book.
+    Strict,
+    /// The book's language plus the extensions implemented here.
+    #[default]
+    Extended,
+}
+
+impl Lang {
+    pub fn allows(&self, _extension: Extension) -> bool {
+        *self == Lang::Extended
+    }
+}
+
+impl FromStr for Lang {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Lang::Strict),
+            "extended" => Ok(Lang::Extended),
+            _ => Err(ParseError::UnknownLang(s.to_string())),
+        }
+    }
+}
+
+/// Language features that go beyond the book and are rejected under `--lang=strict`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Extension {
+    Include,
+    Template,
+    Defer,
+    /// `break`, `continue` and labelled loops.
+    LoopControl,
+    /// `return` at the top level of a script, ending it early.
+    ScriptReturn,
+    /// `b"..."` and `x"..."` byte array literals.
+    Bytes,
+    /// Functions that `yield` values.
+    Generators,
+    /// `for (var x in generator)` loops.
+    ForIn,
+    /// The `|>` operator.
+    Pipeline,
+    /// The `?.` operator.
+    SafeNavigation,
+    /// `f(x: 1)` arguments passed by parameter name.
+    KeywordArguments,
+    /// `a < b < c` meaning `a < b and b < c`, rather than comparing a boolean with `c`.
+    ChainedComparison,
+    /// `/* ... */` comments, which can be nested.
+    BlockComments,
+    /// `switch` statements.
+    Switch,
+    /// `[a, b, c]` list literals.
+    Lists,
+    /// `{key: value}` map literals.
+    Maps,
+    /// `try`/`catch` and `throw`.
+    Exceptions,
+    /// `fun f(a: number)` parameter types.
+    TypeAnnotations,
+    /// `static_assert(condition, "message");` checked as the source compiles.
+    StaticAssert,
+}
+
+impl std::fmt::Display for Extension {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Include => write!(f, "#include"),
+            Self::Template => write!(f, "template mode"),
+            Self::Defer => write!(f, "defer"),
+            Self::LoopControl => write!(f, "break and continue"),
+            Self::ScriptReturn => write!(f, "return from top-level code"),
+            Self::Bytes => write!(f, "byte array literals"),
+            Self::Generators => write!(f, "yield"),
+            Self::ForIn => write!(f, "for-in loops"),
+            Self::Pipeline => write!(f, "the pipeline operator"),
+            Self::SafeNavigation => write!(f, "safe navigation"),
+            Self::KeywordArguments => write!(f, "keyword arguments"),
+            Self::ChainedComparison => write!(f, "chained comparisons"),
+            Self::BlockComments => write!(f, "block comments"),
+            Self::Switch => write!(f, "switch"),
+            Self::Lists => write!(f, "list literals"),
+            Self::Maps => write!(f, "map literals"),
+            Self::Exceptions => write!(f, "exceptions"),
+            Self::TypeAnnotations => write!(f, "type annotations"),
+            Self::StaticAssert => write!(f, "static_assert"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_lang() {
+        assert_eq!(Ok(Lang::Strict), "strict".parse());
+        assert_eq!(Ok(Lang::Extended), "extended".parse());
+        assert!("loose".parse::<Lang>().is_err());
+    }
+
+    #[test]
+    fn strict_include() {
+        let source = String::from("#include \"lib.lox\"\nprint 1;");
+        let options = crate::compiler::CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        let (_, had_error) = crate::compiler::compile_with_status(source, &options).unwrap();
+
+        assert!(had_error);
+    }
+
+    #[test]
+    fn strict_defer() {
+        // Without the extension `defer` is an ordinary identifier, as in the book
+        let source = String::from("var defer = 1; print defer;");
+        let options = crate::compiler::CompileOptions {
+            lang: Lang::Strict,
+            ..Default::default()
+        };
+        let (_, had_error) = crate::compiler::compile_with_status(source, &options).unwrap();
+
+        assert!(!had_error);
+    }
+}
@@ -0,0 +1,69 @@
+//! A bytecode virtual machine for Lox, the language from Crafting Interpreters.
+//!
+//! To run Lox from a Rust program, create a [`Vm`] and hand it source with
+//! [`Vm::interpret`]. Globals persist between calls, so a host can define functions in one
+//! call and use them in later ones:
+//!
+//! ```
+//! let mut vm = lox::Vm::new();
+//! vm.interpret("fun double(n) { return n * 2; }").unwrap();
+//! assert_eq!(lox::Value::Number(42.0), vm.eval_expression("double(21)").unwrap());
+//! ```
+//!
+//! Errors are [`LoxError`]s, telling a source that didn't compile from a script that failed
+//! while running, and a failing script's [`ScriptError`] says what stopped it. A
+//! [`CompileError`] holds every problem found in the source as a [`Diagnostic`], which the host
+//! reports however it likes.
+
+pub mod cache;
+mod chunk;
+mod compiler;
+mod convert;
+pub mod corpus;
+pub mod diagnostic;
+pub mod error;
+mod intern;
+mod lang;
+mod lint;
+mod math;
+mod natives;
+mod number;
+mod parse;
+pub mod patch;
+mod pool;
+pub mod profile;
+mod program;
+pub mod project;
+mod scanner;
+mod scheduler;
+pub mod source;
+pub mod syntax;
+mod token;
+mod vm;
+mod worker;
+
+pub use crate::chunk::Value;
+pub use crate::compiler::{compile, compile_file, scan, CompileOptions, Limits, OptLevel};
+pub use crate::convert::FromLoxArgs;
+pub use crate::diagnostic::{Diagnostic, MessageFormat, Span};
+pub use crate::error::{
+    CompileError, ConversionError, LoxError, LoxResult, RuntimeError, ScriptError,
+};
+pub use crate::intern::{intern, Symbol};
+pub use crate::lang::Lang;
+pub use crate::natives::Capability;
+pub use crate::profile::Profile;
+pub use crate::program::Program;
+pub use crate::scheduler::{Scheduler, TaskId};
+pub use crate::vm::{Progress, ReloadReport, RunStats, Truthiness, VmOptions, STACK_MAX, VM as Vm};
+
+use std::env;
+use std::sync::OnceLock;
+
+const LOX_TRACE_EXECUTION_VAR: &str = "LOX_TRACE_EXECUTION";
+static LOX_TRACE_EXECUTION: OnceLock<bool> = OnceLock::new();
+
+/// Whether to print every instruction as it runs, which setting `LOX_TRACE_EXECUTION` enables.
+fn trace_execution() -> bool {
+    *LOX_TRACE_EXECUTION.get_or_init(|| env::var(LOX_TRACE_EXECUTION_VAR).is_ok())
+}
@@ -0,0 +1,165 @@
+//! Finds operations that are bound to fail at runtime from the types of literal operands, for
+//! `CompileOptions::lint`. Operands that aren't literals could be anything, so an operation is
+//! only reported when no value in their place would make it work.
+
+use crate::chunk::ValueType;
+use crate::token::TokenType;
+
+/// Checks `left operator right`, returning the type of the result if it's known, or what's wrong
+/// with it.
+pub(crate) fn binary(
+    operator: &TokenType,
+    left: Option<ValueType>,
+    right: Option<ValueType>,
+) -> Result<Option<ValueType>, String> {
+    let (verb, result) = match operator {
+        TokenType::Plus => ("add", add(left, right)),
+        TokenType::Minus => ("subtract", numeric(left, right)),
+        TokenType::Star => ("multiply", multiply(left, right)),
+        TokenType::Slash => ("divide", numeric(left, right)),
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => (
+            "compare",
+            numeric(left, right).map(|_| Some(ValueType::Bool)),
+        ),
+        // Any two values can be tested for equality
+        _ => return Ok(Some(ValueType::Bool)),
+    };
+    result.ok_or_else(|| match (left, right) {
+        (Some(left), Some(right)) => format!(
+            "can't {} {} and {}.",
+            verb,
+            left.description(),
+            right.description()
+        ),
+        (Some(operand), None) | (None, Some(operand)) => {
+            format!("can't {} {}.", verb, operand.description())
+        }
+        (None, None) => unreachable!("unknown operands always work"),
+    })
+}
+
+/// Checks `-operand`.
+pub(crate) fn negate(operand: Option<ValueType>) -> Result<Option<ValueType>, String> {
+    match operand {
+        None | Some(ValueType::Number) => Ok(Some(ValueType::Number)),
+        Some(operand) => Err(format!("can't negate {}.", operand.description())),
+    }
+}
+
+/// Checks calling `callee`.
+pub(crate) fn call(callee: Option<ValueType>) -> Result<(), String> {
+    match callee {
+        Some(
+            callee @ (ValueType::Nil
+            | ValueType::Bool
+            | ValueType::Number
+            | ValueType::String
+            | ValueType::Bytes
+            | ValueType::List
+            | ValueType::Map),
+        ) => Err(format!("can't call {}.", callee.description())),
+        _ => Ok(()),
+    }
+}
+
+/// Numbers are added, and strings, bytes and lists concatenated with their own kind.
+fn add(left: Option<ValueType>, right: Option<ValueType>) -> Option<Option<ValueType>> {
+    let addable = |t| {
+        matches!(
+            t,
+            ValueType::Number | ValueType::String | ValueType::Bytes | ValueType::List
+        )
+    };
+    match (left, right) {
+        (Some(left), Some(right)) if left == right && addable(left) => Some(Some(left)),
+        (Some(t), None) | (None, Some(t)) if addable(t) => Some(Some(t)),
+        (None, None) => Some(None),
+        _ => None,
+    }
+}
+
+/// Numbers are multiplied, and strings, bytes and lists repeated by a number on either side.
+fn multiply(left: Option<ValueType>, right: Option<ValueType>) -> Option<Option<ValueType>> {
+    let sequence = |t| matches!(t, ValueType::String | ValueType::Bytes | ValueType::List);
+    match (left, right) {
+        (Some(ValueType::Number), Some(ValueType::Number)) => Some(Some(ValueType::Number)),
+        (Some(ValueType::Number), Some(t)) | (Some(t), Some(ValueType::Number)) if sequence(t) => {
+            Some(Some(t))
+        }
+        (Some(t), None) | (None, Some(t)) if t == ValueType::Number || sequence(t) => Some(None),
+        (None, None) => Some(None),
+        _ => None,
+    }
+}
+
+/// Subtraction, division and comparisons only take numbers.
+fn numeric(left: Option<ValueType>, right: Option<ValueType>) -> Option<Option<ValueType>> {
+    match (left, right) {
+        (Some(ValueType::Number) | None, Some(ValueType::Number) | None) => {
+            Some(Some(ValueType::Number))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn operands() {
+        assert_eq!(
+            Ok(Some(ValueType::String)),
+            binary(&TokenType::Plus, Some(ValueType::String), None)
+        );
+        assert_eq!(
+            Err("can't add a string and a number.".to_string()),
+            binary(
+                &TokenType::Plus,
+                Some(ValueType::String),
+                Some(ValueType::Number)
+            )
+        );
+        assert_eq!(
+            Err("can't subtract a string.".to_string()),
+            binary(&TokenType::Minus, None, Some(ValueType::String))
+        );
+        assert_eq!(
+            Ok(Some(ValueType::List)),
+            binary(
+                &TokenType::Star,
+                Some(ValueType::Number),
+                Some(ValueType::List)
+            )
+        );
+        assert_eq!(
+            Ok(None),
+            binary(&TokenType::Star, Some(ValueType::String), None)
+        );
+        assert!(binary(
+            &TokenType::Star,
+            Some(ValueType::String),
+            Some(ValueType::String)
+        )
+        .is_err());
+        assert!(binary(&TokenType::Slash, Some(ValueType::Nil), None).is_err());
+        assert_eq!(
+            Err("can't compare nil.".to_string()),
+            binary(&TokenType::Less, Some(ValueType::Nil), None)
+        );
+        assert_eq!(
+            Ok(Some(ValueType::Bool)),
+            binary(&TokenType::GreaterEqual, Some(ValueType::Number), None)
+        );
+        assert_eq!(
+            Ok(Some(ValueType::Bool)),
+            binary(&TokenType::EqualEqual, Some(ValueType::Nil), None)
+        );
+        assert_eq!(Ok(None), binary(&TokenType::Plus, None, None));
+
+        assert!(negate(Some(ValueType::Bool)).is_err());
+        assert!(call(Some(ValueType::Number)).is_err());
+        assert!(call(Some(ValueType::Class)).is_ok());
+        assert!(call(None).is_ok());
+    }
+}
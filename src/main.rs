@@ -1,40 +1,538 @@
-mod chunk;
-mod compiler;
-mod error;
-mod parse;
-mod scanner;
-mod token;
-mod vm;
-
-use crate::chunk::{Chunk, OpCode};
+use lox::diagnostic::Severity;
+use lox::error::{ChunkError, RuntimeError, ScriptError};
+use lox::{
+    Capability, CompileOptions, LoxError, LoxResult, MessageFormat, Profile, Program, RunStats, Vm,
+};
+use std::cell::RefCell;
 use std::env;
-use std::sync::OnceLock;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
-const LOX_TRACE_EXECUTION_VAR: &str = "LOX_TRACE_EXECUTION";
-static LOX_TRACE_EXECUTION: OnceLock<bool> = OnceLock::new();
+/// Exit codes from BSD sysexits.h, as used by clox.
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+/// What shells report for a process stopped by SIGINT.
+const EX_INTERRUPTED: i32 = 130;
+
+/// How often `--watch` checks whether the files it watches have changed.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Set by Ctrl-C, and cleared by the VM when it stops the script.
+static INTERRUPTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
 
 fn main() {
-    let _ = LOX_TRACE_EXECUTION.set(env::var(LOX_TRACE_EXECUTION_VAR).is_ok());
+    let mut options = CompileOptions::default();
+    let mut run_options = RunOptions::default();
+    let mut path = None;
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // `lox check <paths>` compiles every script given without running any
+    let check = args.first().is_some_and(|arg| arg == "check");
+    let mut check_paths = Vec::new();
+    // `lox run <path>` is the same as `lox <path>`
+    if check || args.first().is_some_and(|arg| arg == "run") {
+        args.remove(0);
+    }
+    let mut watch = false;
+    let mut compile_to = None;
+    for arg in args {
+        if let Some(lang) = arg.strip_prefix("--lang=") {
+            match lang.parse() {
+                Ok(lang) => options.lang = lang,
+                Err(e) => usage_error(e),
+            }
+        } else if let Some(format) = arg.strip_prefix("--message-format=") {
+            match format.parse() {
+                Ok(format) => options.message_format = format,
+                Err(e) => usage_error(e),
+            }
+        } else if let Some(format) = arg.strip_prefix("--syntax=") {
+            match format.parse() {
+                Ok(format) => {
+                    println!("{}", lox::syntax::generate(format));
+                    return;
+                }
+                Err(e) => usage_error(e),
+            }
+        } else if let Some(allowed) = arg.strip_prefix("--allow=") {
+            for capability in allowed.split(',') {
+                match capability.parse() {
+                    Ok(capability) => run_options.capabilities.push(capability),
+                    Err(e) => usage_error(e),
+                }
+            }
+        } else if arg == "--template" {
+            options.template = true;
+        } else if arg == "--check-types" {
+            options.check_types = true;
+        } else if arg == "--lint" {
+            options.lint = true;
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            match level.parse() {
+                Ok(level) => options.opt_level = level,
+                Err(e) => usage_error(e),
+            }
+        } else if arg == "--disassemble" {
+            run_options.disassemble = true;
+        } else if arg == "--time" {
+            run_options.time = true;
+        } else if arg == "--watch" {
+            watch = true;
+        } else if arg == "--coverage" {
+            options.probes = true;
+            run_options.coverage = true;
+        } else if let Some(out) = arg.strip_prefix("--compile=") {
+            compile_to = Some(PathBuf::from(out));
+        } else if let Some(profile) = arg.strip_prefix("--profile-generate=") {
+            run_options.profile_path = Some(PathBuf::from(profile));
+        } else if let Some(profile) = arg.strip_prefix("--profile-use=") {
+            match Profile::load(profile) {
+                Ok(profile) => options.profile = Some(profile),
+                Err(e) => exit_with(e, options.message_format),
+            }
+        } else if let Some(expression) = arg.strip_prefix("--eval=") {
+            match Vm::new().eval_expression(expression) {
+                Ok(value) => {
+                    println!("{}", value);
+                    return;
+                }
+                Err(e) => exit_with(e, options.message_format),
+            }
+        } else if let Some(seed) = arg.strip_prefix("--generate=") {
+            match seed.parse() {
+                Ok(seed) => {
+                    print!("{}", lox::corpus::Generator::new(seed).program());
+                    return;
+                }
+                Err(e) => usage_error(format!("invalid seed '{}': {}", seed, e)),
+            }
+        } else if check {
+            check_paths.push(PathBuf::from(arg));
+        } else if path.is_none() {
+            path = Some(PathBuf::from(arg));
+        } else {
+            usage_error(USAGE);
+        }
+    }
+
+    if check {
+        if check_paths.is_empty() {
+            usage_error(USAGE);
+        }
+        std::process::exit(check_files(&check_paths, &options));
+    }
+
+    let path = path.unwrap_or_else(|| usage_error(USAGE));
+    if let Some(out) = compile_to {
+        if options.probes {
+            usage_error("--coverage can't be saved with --compile");
+        }
+        if let Err(e) = compile(&path, &options, false).and_then(|script| script.save(out)) {
+            exit_with(e, options.message_format);
+        }
+        return;
+    }
+    handle_interrupts();
+    if watch {
+        watch_and_run(&path, &options, &run_options);
+    }
+    if let Err(e) = run(&path, &options, &run_options) {
+        exit_with(e, options.message_format);
+    }
+}
+
+const USAGE: &str =
+    "Usage: lox [run] [options] <script.lox | script.loxc | lox.pkg | project directory>
+       lox --compile=<out.loxc> [options] <script.lox | lox.pkg | project directory>
+       lox check [options] <script.lox | directory>...";
+
+/// What to do with a compiled program besides running it.
+#[derive(Default)]
+struct RunOptions {
+    capabilities: Vec<Capability>,
+    /// Where to save the counts of the calls the program makes.
+    profile_path: Option<PathBuf>,
+    /// Whether to list the program's code before running it.
+    disassemble: bool,
+    /// Whether to report the statements that never ran.
+    coverage: bool,
+    /// Whether to report how long each phase took.
+    time: bool,
+}
+
+/// How long each phase of a run took, for `--time`.
+#[derive(Default)]
+struct Timing {
+    /// How long scanning the source alone took, and how many tokens it had. Projects aren't
+    /// scanned separately.
+    scan: Option<(Duration, usize)>,
+    compile: Duration,
+    execute: Duration,
+    stats: RunStats,
+}
+
+impl Timing {
+    fn report(&self) {
+        if let Some((duration, tokens)) = self.scan {
+            eprintln!("scan     {:>10.2?}  {} tokens", duration, tokens);
+        }
+        eprintln!("compile  {:>10.2?}  including scanning", self.compile);
+        eprintln!(
+            "execute  {:>10.2?}  {} instructions, peak stack depth {}",
+            self.execute, self.stats.instructions, self.stats.peak_stack
+        );
+    }
+}
+
+/// Runs a script, a template, or a project given either as its manifest or its directory.
+fn run(path: &Path, options: &CompileOptions, run_options: &RunOptions) -> LoxResult<()> {
+    if run_options.time {
+        return run_timed(path, options, run_options);
+    }
+    let script = compile(path, options, true)?;
+    execute(&script, run_options, None)
+}
+
+/// Runs like `run`, then reports how long each phase took on stderr. The cache is skipped, so
+/// compiling takes as long as it really does.
+fn run_timed(path: &Path, options: &CompileOptions, run_options: &RunOptions) -> LoxResult<()> {
+    let mut timing = Timing::default();
+    if manifest_of(path, options).is_none() && !is_bytecode(path) {
+        let source = lox::source::read(path)?;
+        let started = Instant::now();
+        let tokens = lox::scan(source, options);
+        timing.scan = Some((started.elapsed(), tokens));
+    }
+
+    let started = Instant::now();
+    let script = compile(path, options, false)?;
+    timing.compile = started.elapsed();
+
+    let result = execute(&script, run_options, Some(&mut timing));
+    timing.report();
+    result
+}
+
+/// Compiles what `run` runs, reporting any warnings. Scripts are only cached if `cache` is
+/// set, since a cached script doesn't know which files it includes. Bytecode saved with
+/// `--compile` is loaded as it is.
+fn compile(path: &Path, options: &CompileOptions, cache: bool) -> LoxResult<Program> {
+    if is_bytecode(path) {
+        return Program::load(path);
+    }
+    let name = path.display().to_string();
+    let script = match manifest_of(path, options) {
+        Some(manifest) => lox::project::Manifest::load(manifest)?.compile(options)?,
+        None if cache => lox::cache::load_or_compile(name, lox::source::read(path)?, options)?,
+        None => lox::compile_file(name, lox::source::read(path)?, options)?,
+    };
+    lox::diagnostic::emit(script.warnings(), options.message_format);
+    Ok(script)
+}
+
+/// Whether `path` is a program saved by `--compile`, going by its extension.
+fn is_bytecode(path: &Path) -> bool {
+    path.extension() == Some(std::ffi::OsStr::new("loxc"))
+}
+
+/// The manifest of the project at `path`, if it's a project rather than a script or template.
+fn manifest_of(path: &Path, options: &CompileOptions) -> Option<PathBuf> {
+    if options.template {
+        None
+    } else if path.is_dir() {
+        Some(path.join(lox::project::MANIFEST_NAME))
+    } else if path.file_name() == Some(std::ffi::OsStr::new(lox::project::MANIFEST_NAME)) {
+        Some(path.to_path_buf())
+    } else {
+        None
+    }
+}
+
+/// Runs what's at `path` again each time it or a file compiled into it changes, until the
+/// process is stopped. Errors are reported without stopping.
+fn watch_and_run(path: &Path, options: &CompileOptions, run_options: &RunOptions) -> ! {
+    let root = manifest_of(path, options).unwrap_or_else(|| path.to_path_buf());
+    // What to watch is only known once the program compiles, so until then it's just the root
+    let mut watched = vec![root.clone()];
+    loop {
+        let started = Instant::now();
+        let result = compile(path, options, false).and_then(|script| {
+            watched.truncate(1);
+            let files = script.files().iter().map(PathBuf::from);
+            watched.extend(files.filter(|file| *file != root));
+            execute(&script, run_options, None)
+        });
+        let outcome = match result {
+            Ok(()) => "finished",
+            Err(e) => {
+                report(e, options.message_format);
+                "failed"
+            }
+        };
+        eprintln!(
+            "--- {} in {:.2?}, watching {} file(s) for changes ---",
+            outcome,
+            started.elapsed(),
+            watched.len()
+        );
+        let changed = wait_for_change(&watched);
+        eprintln!("=== {} changed, running again ===", changed.display());
+    }
+}
+
+/// Waits until one of `files` is modified, created or removed, returning which.
+fn wait_for_change(files: &[PathBuf]) -> PathBuf {
+    let modified = |file: &PathBuf| std::fs::metadata(file).and_then(|m| m.modified()).ok();
+    let before: Vec<_> = files.iter().map(modified).collect();
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        // Nothing is running to interrupt, so Ctrl-C stops watching
+        if INTERRUPTED
+            .get()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+        {
+            std::process::exit(EX_INTERRUPTED);
+        }
+        for (file, before) in files.iter().zip(&before) {
+            if modified(file) != *before {
+                return file.clone();
+            }
+        }
+    }
+}
+
+/// Makes Ctrl-C stop the running script with an `interrupted` runtime error rather than killing
+/// the process, so it reports where the script was. A second Ctrl-C before the script stops,
+/// when it's stuck somewhere the VM can't check, exits straight away.
+#[cfg(unix)]
+fn handle_interrupts() {
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+    extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+        fn _exit(status: c_int) -> !;
+    }
+    // Only async-signal-safe things happen here: an atomic swap and _exit
+    extern "C" fn on_interrupt(_: c_int) {
+        if let Some(flag) = INTERRUPTED.get() {
+            if flag.swap(true, Ordering::Relaxed) {
+                unsafe { _exit(EX_INTERRUPTED) }
+            }
+        }
+    }
+
+    INTERRUPTED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    unsafe {
+        signal(SIGINT, on_interrupt);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_interrupts() {}
+
+/// Runs `script`, recording how long it took and what it did in `timing` if given.
+fn execute(
+    script: &Program,
+    run_options: &RunOptions,
+    timing: Option<&mut Timing>,
+) -> LoxResult<()> {
+    let mut vm = Vm::new();
+    for capability in &run_options.capabilities {
+        vm.grant(*capability);
+    }
+    vm.set_disassemble(run_options.disassemble);
+    if let Some(flag) = INTERRUPTED.get() {
+        vm.set_interrupt(Arc::clone(flag));
+    }
+    if run_options.profile_path.is_some() {
+        vm.start_profile();
+    }
+    let hits = Rc::new(RefCell::new(vec![false; script.probes().len()]));
+    if run_options.coverage {
+        let hits = Rc::clone(&hits);
+        vm.set_probe_handler(move |id| hits.borrow_mut()[id] = true);
+    }
+    if timing.is_some() {
+        vm.start_stats();
+    }
+    let started = Instant::now();
+    let result = vm.run(script).map(|_| ());
+    if let (Some(timing), Some(stats)) = (timing, vm.stats()) {
+        timing.execute = started.elapsed();
+        timing.stats = stats.clone();
+    }
+    // A run that fails part way is still worth profiling
+    if let (Some(profile), Some(path)) = (vm.profile(), &run_options.profile_path) {
+        profile.save(path)?;
+    }
+    if run_options.coverage {
+        report_coverage(script, &hits.borrow());
+    }
+    result
+}
+
+/// Lists the lines with statements that never ran on stderr.
+fn report_coverage(script: &Program, hits: &[bool]) {
+    let run = hits.iter().filter(|&&hit| hit).count();
+    eprintln!("coverage: {} of {} statements run", run, hits.len());
+    let mut missed = Vec::new();
+    for (span, _) in script.probes().iter().zip(hits).filter(|(_, &hit)| !hit) {
+        let location = match &span.file {
+            Some(file) => format!("{}:{}", file, span.line),
+            None => format!("line {}", span.line),
+        };
+        if !missed.contains(&location) {
+            eprintln!("  not run: {}", location);
+            missed.push(location);
+        }
+    }
+}
 
-    // let source = String::from("(-1 + 2) * 3 - -4");
-    // let source = String::from("!(5 - 4 > 3 * 2 == !nil)");
-    // let source = String::from(r#"print "hello" + " world";"#);
-    // let source = String::from("var a_unmber = 1;");
-    //
-    let source = r#"var breakfast = "beignets";
-var beverage = "cafe au lait";
-breakfast = "beignets with " + beverage;
-monkey = "bar";
+/// How checking one file went.
+enum Checked {
+    Compiled { errors: usize, warnings: usize },
+    Unreadable(LoxError),
+}
+
+/// Compiles each script, and every `.lox` file in each directory, reporting the diagnostics of
+/// them all together and then, for people reading them, a table of them all, so one bad script
+/// doesn't hide the rest. Returns the exit code, which is nonzero if any script failed.
+fn check_files(paths: &[PathBuf], options: &CompileOptions) -> i32 {
+    let mut files = Vec::new();
+    for path in paths {
+        collect_scripts(path, &mut files);
+    }
+
+    let mut results = Vec::new();
+    // Reported at once, since a SARIF log has to cover every file
+    let mut all_diagnostics = Vec::new();
+    for file in files {
+        let name = file.display().to_string();
+        let result = lox::source::read(&file)
+            .and_then(|source| lox::compile_file(name.clone(), source, options));
+        let diagnostics = match result {
+            Ok(program) => program.warnings().to_vec(),
+            Err(LoxError::Compile(e)) => e.diagnostics,
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                results.push((name, Checked::Unreadable(e)));
+                continue;
+            }
+        };
+        let errors = diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .count();
+        let warnings = diagnostics.len() - errors;
+        results.push((name, Checked::Compiled { errors, warnings }));
+        all_diagnostics.extend(diagnostics);
+    }
+    lox::diagnostic::emit(&all_diagnostics, options.message_format);
+
+    let failed = results
+        .iter()
+        .filter(|(_, checked)| match checked {
+            Checked::Compiled { errors, .. } => *errors > 0,
+            Checked::Unreadable(_) => true,
+        })
+        .count();
+    if options.message_format == MessageFormat::Human {
+        print_summary(&results, failed);
+    }
+
+    match failed {
+        0 => 0,
+        _ => EX_DATAERR,
+    }
+}
+
+/// Prints a table of how checking each file went.
+fn print_summary(results: &[(String, Checked)], failed: usize) {
+    let width = results
+        .iter()
+        .map(|(name, _)| name.len())
+        .chain(std::iter::once("file".len()))
+        .max()
+        .unwrap_or_default();
+    println!("{:<width$}  {:>6}  {:>8}", "file", "errors", "warnings");
+    for (name, checked) in results {
+        match checked {
+            Checked::Compiled { errors, warnings } => {
+                println!("{:<width$}  {:>6}  {:>8}", name, errors, warnings);
+            }
+            Checked::Unreadable(e) => println!("{:<width$}  {}", name, e),
+        }
+    }
+    println!("{} of {} files failed", failed, results.len());
+}
+
+/// Adds `path` to `files`, or if it's a directory, every `.lox` file beneath it, in order.
+fn collect_scripts(path: &Path, files: &mut Vec<PathBuf>) {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return;
+    }
+    // An unreadable directory is reported like an unreadable file
+    let Ok(entries) = std::fs::read_dir(path) else {
+        files.push(path.to_path_buf());
+        return;
+    };
+    let mut entries: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir()
+            || entry
+                .extension()
+                .is_some_and(|extension| extension == "lox")
+        {
+            collect_scripts(&entry, files);
+        }
+    }
+}
+
+fn usage_error<T: std::fmt::Display>(message: T) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(EX_USAGE)
+}
+
+fn exit_with(e: LoxError, format: MessageFormat) -> ! {
+    std::process::exit(report(e, format))
+}
 
-print breakfast;
-    "#
-    .to_string();
-    match crate::vm::VM::interpret(source) {
-        Ok(()) => {
-            println!("execution finished successfully")
+/// Reports `e` on stderr, returning the exit code it calls for.
+fn report(e: LoxError, format: MessageFormat) -> i32 {
+    let code = match &e {
+        LoxError::Exit(code) => return *code,
+        LoxError::Runtime(ScriptError::Runtime(RuntimeError::Interrupted), _) => EX_INTERRUPTED,
+        LoxError::Compile(e) => {
+            // The diagnostics are the whole report, and anything more would break them as JSON
+            // or SARIF
+            lox::diagnostic::emit(&e.diagnostics, format);
+            return EX_DATAERR;
         }
-        Err(_e) => {
-            println!("error in execution")
+        LoxError::Parse(_) | LoxError::Encoding(_) => EX_DATAERR,
+        LoxError::Chunk(ChunkError::NotBytecode | ChunkError::Version(..)) => EX_DATAERR,
+        LoxError::Io(_) | LoxError::Project(_) => EX_IOERR,
+        LoxError::Runtime(..) | LoxError::Chunk(_) | LoxError::Other(_) => EX_SOFTWARE,
+    };
+    match &e {
+        LoxError::Runtime(error, trace) => {
+            let trace: Vec<_> = trace
+                .iter()
+                .map(|(line, function)| (*line, function.as_deref()))
+                .collect();
+            let color = lox::diagnostic::use_color();
+            let message = lox::diagnostic::render_runtime_error(&error.to_string(), &trace, color);
+            eprintln!("{}", message);
         }
+        _ => eprintln!("{}", e),
     }
+    code
 }
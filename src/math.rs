@@ -0,0 +1,376 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::chunk::{Native, NativeFn, Value};
+use crate::natives::number;
+use crate::vm::NativeContext;
+
+/// Seed for `random` in deterministic mode.
+pub(crate) const DETERMINISTIC_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Maths natives. `sqrt` and `floor` give the same results everywhere, since IEEE 754 requires
+/// them to be exact, but the others depend on the platform's libm and clock.
+pub const NATIVES: &[Native] = &[
+    Native {
+        name: "sqrt",
+        arity: 1,
+        function: NativeFn::Args(sqrt),
+        capability: None,
+    },
+    Native {
+        name: "floor",
+        arity: 1,
+        function: NativeFn::Args(floor),
+        capability: None,
+    },
+    Native {
+        name: "pow",
+        arity: 2,
+        function: NativeFn::Args(pow),
+        capability: None,
+    },
+    Native {
+        name: "sin",
+        arity: 1,
+        function: NativeFn::Args(sin),
+        capability: None,
+    },
+    Native {
+        name: "cos",
+        arity: 1,
+        function: NativeFn::Args(cos),
+        capability: None,
+    },
+    Native {
+        name: "random",
+        arity: 0,
+        function: NativeFn::Context(random),
+        capability: None,
+    },
+];
+
+/// Replacements for the platform-dependent `NATIVES`, computed using only basic arithmetic, so
+/// every platform gives bit-for-bit identical results. Used by VMs in deterministic mode.
+pub const DETERMINISTIC: &[Native] = &[
+    Native {
+        name: "pow",
+        arity: 2,
+        function: NativeFn::Args(soft_pow),
+        capability: None,
+    },
+    Native {
+        name: "sin",
+        arity: 1,
+        function: NativeFn::Args(soft_sin),
+        capability: None,
+    },
+    Native {
+        name: "cos",
+        arity: 1,
+        function: NativeFn::Args(soft_cos),
+        capability: None,
+    },
+];
+
+/// How many seeds `random_seed` has handed out, so VMs created within the clock's resolution
+/// of each other still get different sequences.
+static SEEDS: AtomicU64 = AtomicU64::new(0);
+
+/// A seed for the `random` of a new VM, which differs from run to run.
+pub(crate) fn random_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    nanos
+        ^ SEEDS
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+/// Advances a SplitMix64 generator, returning a number in `[0, 1)`.
+fn next_random(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn sqrt(args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(number("sqrt", &args[0])?.sqrt()))
+}
+
+fn floor(args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(number("floor", &args[0])?.floor()))
+}
+
+fn pow(args: &[Value]) -> Result<Value> {
+    let (x, y) = (number("pow", &args[0])?, number("pow", &args[1])?);
+    Ok(Value::Number(x.powf(y)))
+}
+
+fn sin(args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(number("sin", &args[0])?.sin()))
+}
+
+fn cos(args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(number("cos", &args[0])?.cos()))
+}
+
+/// Draws from the generator of the VM calling it, so VMs sharing a thread don't disturb each
+/// other's sequences.
+fn random(context: &mut NativeContext, _args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(next_random(context.random)))
+}
+
+fn soft_pow(args: &[Value]) -> Result<Value> {
+    let (x, y) = (number("pow", &args[0])?, number("pow", &args[1])?);
+    Ok(Value::Number(soft::pow(x, y)))
+}
+
+fn soft_sin(args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(soft::sin(number("sin", &args[0])?)))
+}
+
+fn soft_cos(args: &[Value]) -> Result<Value> {
+    Ok(Value::Number(soft::cos(number("cos", &args[0])?)))
+}
+
+/// Elementary functions built from `+`, `-`, `*`, `/` and exact operations like `round`, which
+/// IEEE 754 defines precisely. The polynomials and constants are fdlibm's, simplified where that
+/// costs an ulp or so of accuracy.
+#[allow(clippy::excessive_precision)] // Constants are given to fdlibm's precision
+mod soft {
+    const PIO2_1: f64 = 1.570_796_326_734_125_6e0;
+    const PIO2_2: f64 = 6.077_100_506_303_966e-11;
+    const PIO2_3: f64 = 2.022_266_248_711_166_5e-21;
+
+    const LN2_HI: f64 = 6.931_471_803_691_238e-1;
+    const LN2_LO: f64 = 1.908_214_929_270_587_7e-10;
+
+    /// Reduces `x` to `r` in `[-pi/4, pi/4]` with `x = r + n * pi/2`, returning `n` mod 4.
+    fn reduce(x: f64) -> (f64, u8) {
+        let n = (x * std::f64::consts::FRAC_2_PI).round();
+        let r = x - n * PIO2_1 - n * PIO2_2 - n * PIO2_3;
+        (r, n.rem_euclid(4.0) as u8)
+    }
+
+    fn kernel_sin(x: f64) -> f64 {
+        const S: [f64; 6] = [
+            -1.666_666_666_666_663_2e-1,
+            8.333_333_333_322_49e-3,
+            -1.984_126_982_985_795e-4,
+            2.755_731_370_707_006_8e-6,
+            -2.505_076_025_340_686_3e-8,
+            1.589_690_995_211_55e-10,
+        ];
+        let z = x * x;
+        let p = S[0] + z * (S[1] + z * (S[2] + z * (S[3] + z * (S[4] + z * S[5]))));
+        x + x * z * p
+    }
+
+    fn kernel_cos(x: f64) -> f64 {
+        const C: [f64; 6] = [
+            4.166_666_666_666_660_2e-2,
+            -1.388_888_888_887_411e-3,
+            2.480_158_728_947_673e-5,
+            -2.755_731_435_139_066_3e-7,
+            2.087_572_321_298_175e-9,
+            -1.135_964_755_778_819_5e-11,
+        ];
+        let z = x * x;
+        let p = C[0] + z * (C[1] + z * (C[2] + z * (C[3] + z * (C[4] + z * C[5]))));
+        1.0 - (0.5 * z - z * z * p)
+    }
+
+    pub fn sin(x: f64) -> f64 {
+        if !x.is_finite() {
+            return f64::NAN;
+        }
+        let (r, n) = reduce(x);
+        match n {
+            0 => kernel_sin(r),
+            1 => kernel_cos(r),
+            2 => -kernel_sin(r),
+            _ => -kernel_cos(r),
+        }
+    }
+
+    pub fn cos(x: f64) -> f64 {
+        if !x.is_finite() {
+            return f64::NAN;
+        }
+        let (r, n) = reduce(x);
+        match n {
+            0 => kernel_cos(r),
+            1 => -kernel_sin(r),
+            2 => -kernel_cos(r),
+            _ => kernel_sin(r),
+        }
+    }
+
+    /// Multiplies `x` by `2^k`, in steps so the power of two itself never overflows.
+    fn scale(mut x: f64, mut k: i32) -> f64 {
+        while k > 1000 {
+            x *= f64::from_bits(2023 << 52); // 2^1000
+            k -= 1000;
+        }
+        while k < -1000 {
+            x *= f64::from_bits(23 << 52); // 2^-1000
+            k += 1000;
+        }
+        x * f64::from_bits(((1023 + k) as u64) << 52)
+    }
+
+    pub fn exp(x: f64) -> f64 {
+        const P: [f64; 5] = [
+            1.666_666_666_666_660_2e-1,
+            -2.777_777_777_701_559_3e-3,
+            6.613_756_321_437_934e-5,
+            -1.653_390_220_546_525_3e-6,
+            4.138_136_797_057_238_5e-8,
+        ];
+        if x.is_nan() {
+            return x;
+        }
+        if x > 709.782_712_893_384 {
+            return f64::INFINITY;
+        }
+        if x < -745.133_219_101_941_1 {
+            return 0.0;
+        }
+
+        let k = (x / LN2_HI).round();
+        let hi = x - k * LN2_HI;
+        let lo = k * LN2_LO;
+        let r = hi - lo;
+        let t = r * r;
+        let c = r - t * (P[0] + t * (P[1] + t * (P[2] + t * (P[3] + t * P[4]))));
+        let y = 1.0 - ((lo - (r * c) / (2.0 - c)) - hi);
+        scale(y, k as i32)
+    }
+
+    pub fn ln(x: f64) -> f64 {
+        const LG: [f64; 7] = [
+            6.666_666_666_666_735e-1,
+            3.999_999_999_940_942e-1,
+            2.857_142_874_366_239e-1,
+            2.222_219_843_214_978_4e-1,
+            1.818_357_216_161_805e-1,
+            1.531_383_769_920_937_3e-1,
+            1.479_819_860_511_658_6e-1,
+        ];
+        if x.is_nan() || x < 0.0 {
+            return f64::NAN;
+        }
+        if x == 0.0 {
+            return f64::NEG_INFINITY;
+        }
+        if x.is_infinite() {
+            return x;
+        }
+
+        // Split x into 2^k * m with m in [sqrt(2)/2, sqrt(2))
+        let (x, mut k) = if x < f64::MIN_POSITIVE {
+            (x * f64::from_bits(1077 << 52), -54) // Subnormals are scaled by 2^54 first
+        } else {
+            (x, 0)
+        };
+        let bits = x.to_bits();
+        k += ((bits >> 52) as i32) - 1023;
+        let mut m = f64::from_bits((bits & 0x000f_ffff_ffff_ffff) | (1023 << 52));
+        if m >= std::f64::consts::SQRT_2 {
+            m /= 2.0;
+            k += 1;
+        }
+
+        let f = m - 1.0;
+        let s = f / (2.0 + f);
+        let z = s * s;
+        let w = z * z;
+        let t1 = w * (LG[1] + w * (LG[3] + w * LG[5]));
+        let t2 = z * (LG[0] + w * (LG[2] + w * (LG[4] + w * LG[6])));
+        let hfsq = 0.5 * f * f;
+        let k = k as f64;
+        k * LN2_HI - ((hfsq - (s * (hfsq + t1 + t2) + k * LN2_LO)) - f)
+    }
+
+    pub fn pow(x: f64, y: f64) -> f64 {
+        if y == 0.0 {
+            return 1.0;
+        }
+        if x.is_nan() || y.is_nan() {
+            return f64::NAN;
+        }
+
+        let integer = y == y.trunc();
+        if integer && y.abs() <= 1024.0 {
+            // Exact for small powers, and closer than going through ln and exp
+            let mut n = y.abs() as u32;
+            let (mut base, mut result) = (x, 1.0);
+            while n > 0 {
+                if n & 1 == 1 {
+                    result *= base;
+                }
+                base *= base;
+                n >>= 1;
+            }
+            return if y < 0.0 { 1.0 / result } else { result };
+        }
+        if x < 0.0 {
+            if !integer {
+                return f64::NAN;
+            }
+            let odd = y % 2.0 != 0.0;
+            let magnitude = pow(-x, y);
+            return if odd { -magnitude } else { magnitude };
+        }
+        exp(y * ln(x))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn close(expected: f64, actual: f64) -> bool {
+        (expected - actual).abs() <= expected.abs() * 1e-14 + 1e-300
+    }
+
+    #[test]
+    fn software_functions() {
+        for i in -200..200 {
+            let x = i as f64 * 0.173;
+            assert!(close(x.sin(), soft::sin(x)), "sin({})", x);
+            assert!(close(x.cos(), soft::cos(x)) || x.cos().abs() < 1e-15);
+            assert!(close(x.exp(), soft::exp(x)), "exp({})", x);
+            if x > 0.0 {
+                assert!(close(x.ln(), soft::ln(x)), "ln({})", x);
+                assert!(close(x.powf(2.5), soft::pow(x, 2.5)), "pow({}, 2.5)", x);
+            }
+        }
+        assert_eq!(1024.0, soft::pow(2.0, 10.0));
+        assert_eq!(-0.125, soft::pow(-2.0, -3.0));
+        assert!(soft::pow(-2.0, 0.5).is_nan());
+        assert_eq!(0.0, soft::pow(0.0, 3.5));
+        assert_eq!(f64::INFINITY, soft::pow(10.0, 400.0));
+        assert_eq!(5e-324, soft::exp(-744.44));
+        let subnormal = f64::MIN_POSITIVE / 4.0;
+        assert!(close(subnormal.ln(), soft::ln(subnormal)));
+    }
+
+    #[test]
+    fn deterministic_random_sequence() {
+        let mut state = DETERMINISTIC_SEED;
+        let first: Vec<f64> = (0..3).map(|_| next_random(&mut state)).collect();
+        let mut state = DETERMINISTIC_SEED;
+        let second: Vec<f64> = (0..3).map(|_| next_random(&mut state)).collect();
+
+        assert_eq!(first, second);
+        assert!(first.iter().all(|n| (0.0..1.0).contains(n)));
+        assert_ne!(first[0], first[1]);
+    }
+}
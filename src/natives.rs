@@ -0,0 +1,1159 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+
+use crate::chunk::{
+    track_heap, Class, Instance, List, Map, MapKey, Native, NativeFn, Value, FIELD_SIZE,
+    LIST_ITEM_SIZE, MAP_ENTRY_SIZE,
+};
+use crate::error::{Exit, NativeError, ParseError, RuntimeError};
+
+/// Access to the world outside the VM, which the host must grant before natives needing it can
+/// be called, so untrusted scripts stay deterministic and can't stall the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    /// Reading the wall clock and sleeping.
+    Time,
+    /// Starting threads and waiting on them or on channels.
+    Threads,
+    /// Running other programs.
+    Process,
+}
+
+impl FromStr for Capability {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "time" => Ok(Capability::Time),
+            "threads" => Ok(Capability::Threads),
+            "process" => Ok(Capability::Process),
+            _ => Err(ParseError::UnknownCapability(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Time => write!(f, "time"),
+            Self::Threads => write!(f, "threads"),
+            Self::Process => write!(f, "process"),
+        }
+    }
+}
+
+/// Every native a VM defines as a global, including those from optional features.
+pub fn all() -> impl Iterator<Item = &'static Native> {
+    let natives = NATIVES
+        .iter()
+        .chain(crate::math::NATIVES)
+        .chain(crate::worker::NATIVES);
+    #[cfg(feature = "regex")]
+    let natives = natives.chain(REGEX_NATIVES);
+    natives
+}
+
+/// Functions implemented in Rust that every VM defines as globals.
+const NATIVES: &[Native] = &[
+    Native {
+        name: "clock",
+        arity: 0,
+        function: NativeFn::Args(clock),
+        capability: None,
+    },
+    Native {
+        name: "exit",
+        arity: 1,
+        function: NativeFn::Args(exit),
+        capability: None,
+    },
+    Native {
+        name: "toFixed",
+        arity: 2,
+        function: NativeFn::Args(to_fixed),
+        capability: None,
+    },
+    Native {
+        name: "toPrecision",
+        arity: 2,
+        function: NativeFn::Args(to_precision),
+        capability: None,
+    },
+    Native {
+        name: "isNil",
+        arity: 1,
+        function: NativeFn::Args(is_nil),
+        capability: None,
+    },
+    Native {
+        name: "isBool",
+        arity: 1,
+        function: NativeFn::Args(is_bool),
+        capability: None,
+    },
+    Native {
+        name: "isNumber",
+        arity: 1,
+        function: NativeFn::Args(is_number),
+        capability: None,
+    },
+    Native {
+        name: "isString",
+        arity: 1,
+        function: NativeFn::Args(is_string),
+        capability: None,
+    },
+    Native {
+        name: "isBytes",
+        arity: 1,
+        function: NativeFn::Args(is_bytes),
+        capability: None,
+    },
+    Native {
+        name: "isList",
+        arity: 1,
+        function: NativeFn::Args(is_list),
+        capability: None,
+    },
+    Native {
+        name: "isMap",
+        arity: 1,
+        function: NativeFn::Args(is_map),
+        capability: None,
+    },
+    Native {
+        name: "isFunction",
+        arity: 1,
+        function: NativeFn::Args(is_function),
+        capability: None,
+    },
+    Native {
+        name: "isClass",
+        arity: 1,
+        function: NativeFn::Args(is_class),
+        capability: None,
+    },
+    Native {
+        name: "isInstance",
+        arity: 1,
+        function: NativeFn::Args(is_instance),
+        capability: None,
+    },
+    Native {
+        name: "len",
+        arity: 1,
+        function: NativeFn::Args(len),
+        capability: None,
+    },
+    Native {
+        name: "push",
+        arity: 2,
+        function: NativeFn::Args(push),
+        capability: None,
+    },
+    Native {
+        name: "pop",
+        arity: 1,
+        function: NativeFn::Args(pop),
+        capability: None,
+    },
+    Native {
+        name: "keys",
+        arity: 1,
+        function: NativeFn::Args(keys),
+        capability: None,
+    },
+    Native {
+        name: "values",
+        arity: 1,
+        function: NativeFn::Args(values),
+        capability: None,
+    },
+    Native {
+        name: "has",
+        arity: 2,
+        function: NativeFn::Args(has),
+        capability: None,
+    },
+    Native {
+        name: "remove",
+        arity: 2,
+        function: NativeFn::Args(remove),
+        capability: None,
+    },
+    Native {
+        name: "fields",
+        arity: 1,
+        function: NativeFn::Args(fields),
+        capability: None,
+    },
+    Native {
+        name: "getField",
+        arity: 2,
+        function: NativeFn::Args(get_field),
+        capability: None,
+    },
+    Native {
+        name: "setField",
+        arity: 3,
+        function: NativeFn::Args(set_field),
+        capability: None,
+    },
+    Native {
+        name: "freeze",
+        arity: 1,
+        function: NativeFn::Args(freeze),
+        capability: None,
+    },
+    Native {
+        name: "methods",
+        arity: 1,
+        function: NativeFn::Args(methods),
+        capability: None,
+    },
+    Native {
+        name: "superclass",
+        arity: 1,
+        function: NativeFn::Args(superclass),
+        capability: None,
+    },
+    Native {
+        name: "name",
+        arity: 1,
+        function: NativeFn::Args(name),
+        capability: None,
+    },
+    Native {
+        name: "deepEquals",
+        arity: 2,
+        function: NativeFn::Args(deep_equals),
+        capability: None,
+    },
+    Native {
+        name: "num",
+        arity: 1,
+        function: NativeFn::Args(num),
+        capability: None,
+    },
+    Native {
+        name: "substring",
+        arity: 3,
+        function: NativeFn::Args(substring),
+        capability: None,
+    },
+    Native {
+        name: "indexOf",
+        arity: 2,
+        function: NativeFn::Args(index_of),
+        capability: None,
+    },
+    Native {
+        name: "split",
+        arity: 2,
+        function: NativeFn::Args(split),
+        capability: None,
+    },
+    Native {
+        name: "toUpperCase",
+        arity: 1,
+        function: NativeFn::Args(to_upper_case),
+        capability: None,
+    },
+    Native {
+        name: "toLowerCase",
+        arity: 1,
+        function: NativeFn::Args(to_lower_case),
+        capability: None,
+    },
+    Native {
+        name: "utf8Encode",
+        arity: 1,
+        function: NativeFn::Args(utf8_encode),
+        capability: None,
+    },
+    Native {
+        name: "utf8Decode",
+        arity: 1,
+        function: NativeFn::Args(utf8_decode),
+        capability: None,
+    },
+    Native {
+        name: "hexEncode",
+        arity: 1,
+        function: NativeFn::Args(hex_encode),
+        capability: None,
+    },
+    Native {
+        name: "hexDecode",
+        arity: 1,
+        function: NativeFn::Args(hex_decode),
+        capability: None,
+    },
+    Native {
+        name: "now",
+        arity: 0,
+        function: NativeFn::Args(now),
+        capability: Some(Capability::Time),
+    },
+    Native {
+        name: "sleep",
+        arity: 1,
+        function: NativeFn::Args(sleep),
+        capability: Some(Capability::Time),
+    },
+    Native {
+        name: "formatTime",
+        arity: 2,
+        function: NativeFn::Args(format_time),
+        capability: Some(Capability::Time),
+    },
+    Native {
+        name: "exec",
+        arity: 2,
+        function: NativeFn::Args(exec),
+        capability: Some(Capability::Process),
+    },
+];
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Seconds since the first call, for timing scripts.
+fn clock(_args: &[Value]) -> Result<Value> {
+    let start = START.get_or_init(Instant::now);
+    Ok(Value::Number(start.elapsed().as_secs_f64()))
+}
+
+/// Formats `n` with exactly `digits` digits after the decimal point.
+fn to_fixed(args: &[Value]) -> Result<Value> {
+    let n = number("toFixed", &args[0])?;
+    let digits = digits("toFixed", &args[1], 0..=100)?;
+
+    Ok(Value::from_string(format!("{:.*}", digits, n)))
+}
+
+/// Stops the script, with `code` as the exit status of the process running it.
+fn exit(args: &[Value]) -> Result<Value> {
+    let code = number("exit", &args[0])?;
+    if code.fract() != 0.0 || code < i32::MIN as f64 || code > i32::MAX as f64 {
+        let message = format!("exit code must be a whole number, got {}", code);
+        return Err(NativeError::InvalidArgument("exit", message).into());
+    }
+    Err(Exit(code as i32).into())
+}
+
+/// Formats `n` with `digits` significant digits, switching to exponential notation when the
+/// number is too large or too small to write out that way, as JavaScript's `toPrecision` does.
+fn to_precision(args: &[Value]) -> Result<Value> {
+    let n = number("toPrecision", &args[0])?;
+    let digits = digits("toPrecision", &args[1], 1..=100)?;
+    if !n.is_finite() {
+        return Ok(Value::from_string(n.to_string()));
+    }
+
+    // Round first, since rounding can carry into the next power of ten
+    let exponential = format!("{:.*e}", digits - 1, n);
+    let (mantissa, exponent) = exponential.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse()?;
+
+    let formatted = if exponent < -6 || exponent >= digits as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{}", mantissa, sign, exponent.abs())
+    } else {
+        format!("{:.*}", (digits as i32 - 1 - exponent) as usize, n)
+    };
+    Ok(Value::from_string(formatted))
+}
+
+fn is_nil(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(args[0], Value::Nil)))
+}
+
+fn is_bool(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(args[0], Value::Bool(_))))
+}
+
+fn is_number(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(matches!(args[0], Value::Number(_))))
+}
+
+fn is_string(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(args[0].as_string().is_some()))
+}
+
+fn is_bytes(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(args[0].as_bytes().is_some()))
+}
+
+fn is_list(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(args[0].as_list().is_some()))
+}
+
+fn is_map(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(args[0].as_map().is_some()))
+}
+
+/// Whether the value is a function, method or native. Classes and generators can be called
+/// too, but have predicates of their own or none.
+fn is_function(args: &[Value]) -> Result<Value> {
+    let value = &args[0];
+    Ok(Value::Bool(
+        value.as_function().is_some()
+            || value.as_bound_method().is_some()
+            || value.as_native().is_some()
+            || value.as_host_function().is_some(),
+    ))
+}
+
+fn is_class(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(args[0].as_class().is_some()))
+}
+
+fn is_instance(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(args[0].as_instance().is_some()))
+}
+
+/// The number of characters in a string, bytes in a byte array, elements in a list or entries in
+/// a map.
+fn len(args: &[Value]) -> Result<Value> {
+    let value = &args[0];
+    let len = if let Some(s) = value.as_string() {
+        s.chars().count()
+    } else if let Some(bytes) = value.as_bytes() {
+        bytes.len()
+    } else if let Some(list) = value.as_list() {
+        list.borrow().len()
+    } else if let Some(map) = value.as_map() {
+        map.borrow().len()
+    } else {
+        let message = format!("expected a string, bytes, a list or a map, got '{}'", value);
+        return Err(NativeError::InvalidArgument("len", message).into());
+    };
+    Ok(Value::Number(len as f64))
+}
+
+/// Parses a string as a number, written as `print` would write it, or returns `nil` if it isn't
+/// one.
+fn num(args: &[Value]) -> Result<Value> {
+    let s = string("num", &args[0])?;
+    Ok(crate::number::parse(s).map_or(Value::Nil, Value::Number))
+}
+
+/// The characters of a string from `start` up to `end`, with the same rules as slices, so `nil`
+/// means the start or end.
+fn substring(args: &[Value]) -> Result<Value> {
+    let s = string("substring", &args[0])?;
+    let len = s.chars().count();
+    let range = crate::vm::range(&args[1], &args[2], len)
+        .map_err(|e| NativeError::InvalidArgument("substring", e.to_string()))?;
+
+    let substring = s.chars().skip(range.start).take(range.len()).collect();
+    Ok(Value::from_string(substring))
+}
+
+/// The position of the first occurrence of `needle` in characters, or -1 if there is none.
+fn index_of(args: &[Value]) -> Result<Value> {
+    let s = string("indexOf", &args[0])?;
+    let needle = string("indexOf", &args[1])?;
+
+    let index = match s.find(needle) {
+        Some(byte) => s[..byte].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+/// Splits a string into a list of the pieces between each `separator`. An empty separator
+/// splits it into characters.
+fn split(args: &[Value]) -> Result<Value> {
+    let s = string("split", &args[0])?;
+    let separator = string("split", &args[1])?;
+
+    let pieces = if separator.is_empty() {
+        s.chars()
+            .map(|c| Value::from_string(c.to_string()))
+            .collect()
+    } else {
+        s.split(separator)
+            .map(|piece| Value::from_string(piece.to_string()))
+            .collect()
+    };
+    Ok(Value::from_list(pieces))
+}
+
+fn to_upper_case(args: &[Value]) -> Result<Value> {
+    let s = string("toUpperCase", &args[0])?;
+    Ok(Value::from_string(s.to_uppercase()))
+}
+
+fn to_lower_case(args: &[Value]) -> Result<Value> {
+    let s = string("toLowerCase", &args[0])?;
+    Ok(Value::from_string(s.to_lowercase()))
+}
+
+/// Appends a value to the end of a list.
+fn push(args: &[Value]) -> Result<Value> {
+    let list = list("push", &args[0])?;
+    let mut list = list.borrow_mut();
+    if list.is_frozen() {
+        return Err(RuntimeError::Frozen("list").into());
+    }
+    list.push(args[1].clone());
+    track_heap(LIST_ITEM_SIZE as isize);
+    Ok(Value::Nil)
+}
+
+/// Removes the last element of a list and returns it, or `nil` if the list is empty.
+fn pop(args: &[Value]) -> Result<Value> {
+    let list = list("pop", &args[0])?;
+    let mut list = list.borrow_mut();
+    if list.is_frozen() {
+        return Err(RuntimeError::Frozen("list").into());
+    }
+    let popped = list.pop();
+    if popped.is_some() {
+        track_heap(-(LIST_ITEM_SIZE as isize));
+    }
+    Ok(popped.unwrap_or_default())
+}
+
+/// A list of a map's keys, in the order they were first inserted.
+fn keys(args: &[Value]) -> Result<Value> {
+    let keys = map("keys", &args[0])?.borrow().keys().collect();
+    Ok(Value::from_list(keys))
+}
+
+/// A list of a map's values, in the same order as its keys.
+fn values(args: &[Value]) -> Result<Value> {
+    let values = map("values", &args[0])?
+        .borrow()
+        .values()
+        .cloned()
+        .collect();
+    Ok(Value::from_list(values))
+}
+
+fn has(args: &[Value]) -> Result<Value> {
+    let key = map_key("has", &args[1])?;
+    Ok(Value::Bool(
+        map("has", &args[0])?.borrow().get(&key).is_some(),
+    ))
+}
+
+/// Removes a key from a map, returning its value or `nil` if it wasn't there.
+fn remove(args: &[Value]) -> Result<Value> {
+    let key = map_key("remove", &args[1])?;
+    let map = map("remove", &args[0])?;
+    let mut map = map.borrow_mut();
+    if map.is_frozen() {
+        return Err(RuntimeError::Frozen("map").into());
+    }
+    let removed = map.remove(&key);
+    if removed.is_some() {
+        track_heap(-(MAP_ENTRY_SIZE as isize));
+    }
+    Ok(removed.unwrap_or_default())
+}
+
+/// A list of the names of an instance's fields, in alphabetical order.
+fn fields(args: &[Value]) -> Result<Value> {
+    let instance = instance("fields", &args[0])?;
+    let mut names: Vec<_> = instance.borrow().fields.keys().cloned().collect();
+    names.sort();
+    Ok(Value::from_list(
+        names.into_iter().map(Value::from_string).collect(),
+    ))
+}
+
+/// The value of an instance's field `name`, like `obj.name` but with a name that's only known
+/// as the script runs. Methods aren't fields.
+fn get_field(args: &[Value]) -> Result<Value> {
+    let instance = instance("getField", &args[0])?;
+    let name = string("getField", &args[1])?;
+
+    let value = instance.borrow().fields.get(name).cloned();
+    value.ok_or_else(|| RuntimeError::UndefinedProperty(name.to_string()).into())
+}
+
+/// Sets an instance's field `name`, like `obj.name = value`, and returns the value.
+fn set_field(args: &[Value]) -> Result<Value> {
+    let instance = instance("setField", &args[0])?;
+    let name = string("setField", &args[1])?;
+
+    let mut instance = instance.borrow_mut();
+    if instance.frozen {
+        return Err(RuntimeError::Frozen("instance").into());
+    }
+    let value = args[2].clone();
+    if instance
+        .fields
+        .insert(name.to_string(), value.clone())
+        .is_none()
+    {
+        track_heap(FIELD_SIZE as isize);
+    }
+    Ok(value)
+}
+
+/// Makes an instance, list or map read-only and returns it. Assigning to its fields or
+/// elements, or adding and removing them, is then a runtime error. The values it holds aren't
+/// frozen along with it.
+fn freeze(args: &[Value]) -> Result<Value> {
+    let value = &args[0];
+    if let Some(instance) = value.as_instance() {
+        instance.borrow_mut().frozen = true;
+    } else if let Some(list) = value.as_list() {
+        list.borrow_mut().freeze();
+    } else if let Some(map) = value.as_map() {
+        map.borrow_mut().freeze();
+    } else {
+        let message = format!("expected an instance, list or map, got '{}'", value);
+        return Err(NativeError::InvalidArgument("freeze", message).into());
+    }
+    Ok(value.clone())
+}
+
+/// A list of the names of a class's methods, in alphabetical order.
+fn methods(args: &[Value]) -> Result<Value> {
+    let class = class("methods", &args[0])?;
+    let mut names: Vec<_> = class.borrow().methods.keys().cloned().collect();
+    names.sort();
+    Ok(Value::from_list(
+        names.into_iter().map(Value::from_string).collect(),
+    ))
+}
+
+/// A class's superclass. Classes can't inherit yet, so this is always nil, but scripts that
+/// walk the hierarchy can already stop on it.
+fn superclass(args: &[Value]) -> Result<Value> {
+    class("superclass", &args[0])?;
+    Ok(Value::Nil)
+}
+
+/// The name a class was declared with.
+fn name(args: &[Value]) -> Result<Value> {
+    let class = class("name", &args[0])?;
+    let name = class.borrow().name.clone();
+    Ok(Value::from_string(name))
+}
+
+/// Whether two values are equal, comparing lists, maps and instances by what they contain
+/// rather than by identity, as `==` does.
+fn deep_equals(args: &[Value]) -> Result<Value> {
+    Ok(Value::Bool(deep_equal(
+        &args[0],
+        &args[1],
+        &mut HashSet::new(),
+    )))
+}
+
+/// Compares `a` and `b` structurally. `comparing` holds the pairs of objects already being
+/// compared further up, which are taken to be equal when they come round again, so values
+/// that contain themselves can't recurse forever.
+fn deep_equal(a: &Value, b: &Value, comparing: &mut HashSet<(usize, usize)>) -> bool {
+    fn address<T>(rc: &Rc<RefCell<T>>) -> usize {
+        Rc::as_ptr(rc) as *const () as usize
+    }
+
+    if let (Some(a), Some(b)) = (a.as_list(), b.as_list()) {
+        if Rc::ptr_eq(&a, &b) || !comparing.insert((address(&a), address(&b))) {
+            return true;
+        }
+        let (a, b) = (a.borrow(), b.borrow());
+        a.len() == b.len()
+            && a.iter()
+                .zip(b.iter())
+                .all(|(a, b)| deep_equal(a, b, comparing))
+    } else if let (Some(a), Some(b)) = (a.as_map(), b.as_map()) {
+        if Rc::ptr_eq(&a, &b) || !comparing.insert((address(&a), address(&b))) {
+            return true;
+        }
+        let (a, b) = (a.borrow(), b.borrow());
+        a.len() == b.len()
+            && a.entries().all(|(key, a)| match b.get(key) {
+                Some(b) => deep_equal(a, b, comparing),
+                None => false,
+            })
+    } else if let (Some(a), Some(b)) = (a.as_instance(), b.as_instance()) {
+        if Rc::ptr_eq(&a, &b) || !comparing.insert((address(&a), address(&b))) {
+            return true;
+        }
+        let (a, b) = (a.borrow(), b.borrow());
+        Rc::ptr_eq(&a.class, &b.class)
+            && a.fields.len() == b.fields.len()
+            && a.fields.iter().all(|(name, a)| match b.fields.get(name) {
+                Some(b) => deep_equal(a, b, comparing),
+                None => false,
+            })
+    } else {
+        a == b
+    }
+}
+
+fn utf8_encode(args: &[Value]) -> Result<Value> {
+    let s = string("utf8Encode", &args[0])?;
+    Ok(Value::from_bytes(s.as_bytes().to_vec()))
+}
+
+fn utf8_decode(args: &[Value]) -> Result<Value> {
+    let bytes = bytes("utf8Decode", &args[0])?;
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Ok(Value::from_string(s.to_string())),
+        Err(e) => Err(NativeError::InvalidArgument("utf8Decode", e.to_string()).into()),
+    }
+}
+
+/// Writes bytes as lowercase hex, two digits per byte.
+fn hex_encode(args: &[Value]) -> Result<Value> {
+    let bytes = bytes("hexEncode", &args[0])?;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(Value::from_string(hex))
+}
+
+fn hex_decode(args: &[Value]) -> Result<Value> {
+    let hex = string("hexDecode", &args[0])?;
+    match decode_hex(hex) {
+        Some(bytes) => Ok(Value::from_bytes(bytes)),
+        None => {
+            let message = format!("'{}' isn't an even number of hex digits", hex);
+            Err(NativeError::InvalidArgument("hexDecode", message).into())
+        }
+    }
+}
+
+/// Decodes pairs of hex digits in either case, or `None` if `hex` has anything else in it.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Seconds since the Unix epoch, with a fractional part.
+fn now(_args: &[Value]) -> Result<Value> {
+    let elapsed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    Ok(Value::Number(elapsed.as_secs_f64()))
+}
+
+fn sleep(args: &[Value]) -> Result<Value> {
+    let seconds = number("sleep", &args[0])?;
+    let duration = Duration::try_from_secs_f64(seconds).map_err(|_| {
+        NativeError::InvalidArgument("sleep", format!("can't sleep for {} seconds", seconds))
+    })?;
+
+    std::thread::sleep(duration);
+    Ok(Value::Nil)
+}
+
+/// Formats a time given in epoch seconds as UTC, following `strftime` for the directives `%Y`,
+/// `%m`, `%d`, `%H`, `%M`, `%S` and `%%`.
+fn format_time(args: &[Value]) -> Result<Value> {
+    let epoch = number("formatTime", &args[0])?;
+    let format = string("formatTime", &args[1])?;
+    if !epoch.is_finite() {
+        let message = format!("can't format {} as a time", epoch);
+        return Err(NativeError::InvalidArgument("formatTime", message).into());
+    }
+
+    let seconds = epoch.floor() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+
+    let mut formatted = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => formatted.push_str(&format!("{:04}", year)),
+            Some('m') => formatted.push_str(&format!("{:02}", month)),
+            Some('d') => formatted.push_str(&format!("{:02}", day)),
+            Some('H') => formatted.push_str(&format!("{:02}", time / 3600)),
+            Some('M') => formatted.push_str(&format!("{:02}", time / 60 % 60)),
+            Some('S') => formatted.push_str(&format!("{:02}", time % 60)),
+            Some('%') => formatted.push('%'),
+            directive => {
+                let directive = directive.map(String::from).unwrap_or_default();
+                let message = format!("unsupported directive '%{}'", directive);
+                return Err(NativeError::InvalidArgument("formatTime", message).into());
+            }
+        }
+    }
+    Ok(Value::from_string(formatted))
+}
+
+/// Runs the program `command` with a list of string arguments and waits for it to finish,
+/// returning a map of its exit `status`, or `nil` if a signal ended it, and what it wrote to
+/// `stdout` and `stderr`. Output that isn't UTF-8 has its invalid bytes replaced.
+fn exec(args: &[Value]) -> Result<Value> {
+    let command = string("exec", &args[0])?;
+    let arguments = list("exec", &args[1])?;
+    let arguments = arguments
+        .borrow()
+        .iter()
+        .map(|argument| string("exec", argument).map(String::from))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output = std::process::Command::new(command)
+        .args(arguments)
+        .output()
+        .map_err(|e| NativeError::Failed("exec", format!("can't run '{}': {}", command, e)))?;
+
+    let mut result = Map::default();
+    let mut insert = |key: &str, value| result.insert(MapKey::String(key.to_string()), value);
+    let status = output.status.code();
+    insert(
+        "status",
+        status.map_or(Value::Nil, |code| Value::Number(code.into())),
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    insert("stdout", Value::from_string(stdout));
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    insert("stderr", Value::from_string(stderr));
+    Ok(Value::from_map(result))
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date in the proleptic Gregorian
+/// calendar, using Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+pub(crate) fn number(native: &'static str, value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(n) => Ok(*n),
+        _ => Err(NativeError::InvalidArgument(
+            native,
+            format!("expected a number, got '{}'", value),
+        )
+        .into()),
+    }
+}
+
+fn string<'a>(native: &'static str, value: &'a Value) -> Result<&'a str> {
+    match value.as_string() {
+        Some(s) => Ok(s),
+        None => {
+            let message = format!("expected a string, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+fn bytes<'a>(native: &'static str, value: &'a Value) -> Result<&'a [u8]> {
+    match value.as_bytes() {
+        Some(bytes) => Ok(bytes),
+        None => {
+            let message = format!("expected bytes, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+fn instance(native: &'static str, value: &Value) -> Result<Rc<RefCell<Instance>>> {
+    match value.as_instance() {
+        Some(instance) => Ok(instance),
+        None => {
+            let message = format!("expected an instance, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+fn class(native: &'static str, value: &Value) -> Result<Rc<RefCell<Class>>> {
+    match value.as_class() {
+        Some(class) => Ok(class),
+        None => {
+            let message = format!("expected a class, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+fn list(native: &'static str, value: &Value) -> Result<Rc<RefCell<List>>> {
+    match value.as_list() {
+        Some(list) => Ok(list),
+        None => {
+            let message = format!("expected a list, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+fn map(native: &'static str, value: &Value) -> Result<Rc<RefCell<Map>>> {
+    match value.as_map() {
+        Some(map) => Ok(map),
+        None => {
+            let message = format!("expected a map, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+fn map_key(native: &'static str, value: &Value) -> Result<MapKey> {
+    match MapKey::new(value) {
+        Some(key) => Ok(key),
+        None => {
+            let message = format!(
+                "map keys must be numbers, strings or booleans, got '{}'",
+                value
+            );
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+const REGEX_NATIVES: &[Native] = &[
+    Native {
+        name: "reMatch",
+        arity: 2,
+        function: NativeFn::Args(re_match),
+        capability: None,
+    },
+    Native {
+        name: "reReplace",
+        arity: 3,
+        function: NativeFn::Args(re_replace),
+        capability: None,
+    },
+    Native {
+        name: "reSplit",
+        arity: 2,
+        function: NativeFn::Args(re_split),
+        capability: None,
+    },
+];
+
+#[cfg(feature = "regex")]
+fn regex(native: &'static str, pattern: &Value) -> Result<regex::Regex> {
+    regex::Regex::new(string(native, pattern)?)
+        .map_err(|e| NativeError::InvalidArgument(native, e.to_string()).into())
+}
+
+/// Whether `pattern` matches anywhere in `text`.
+#[cfg(feature = "regex")]
+fn re_match(args: &[Value]) -> Result<Value> {
+    let regex = regex("reMatch", &args[0])?;
+    let text = string("reMatch", &args[1])?;
+
+    Ok(Value::Bool(regex.is_match(text)))
+}
+
+/// Replaces every match of `pattern` in `text`. The replacement can refer to capture groups as
+/// `$1` or `${name}`.
+#[cfg(feature = "regex")]
+fn re_replace(args: &[Value]) -> Result<Value> {
+    let regex = regex("reReplace", &args[0])?;
+    let text = string("reReplace", &args[1])?;
+    let replacement = string("reReplace", &args[2])?;
+
+    let replaced = regex.replace_all(text, replacement).into_owned();
+    Ok(Value::from_string(replaced))
+}
+
+/// Splits `text` into a list of the pieces between matches of `pattern`.
+#[cfg(feature = "regex")]
+fn re_split(args: &[Value]) -> Result<Value> {
+    let regex = regex("reSplit", &args[0])?;
+    let text = string("reSplit", &args[1])?;
+
+    let pieces = regex
+        .split(text)
+        .map(|piece| Value::from_string(piece.to_string()))
+        .collect();
+    Ok(Value::from_list(pieces))
+}
+
+/// Reads a whole number of digits within `range`.
+fn digits(
+    native: &'static str,
+    value: &Value,
+    range: std::ops::RangeInclusive<usize>,
+) -> Result<usize> {
+    let n = number(native, value)?;
+    if n.fract() != 0.0 || n < *range.start() as f64 || n > *range.end() as f64 {
+        let message = format!(
+            "digits must be a whole number from {} to {}, got {}",
+            range.start(),
+            range.end(),
+            n
+        );
+        return Err(NativeError::InvalidArgument(native, message).into());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(function: fn(&[Value]) -> Result<Value>, n: f64, digits: f64) -> String {
+        function(&[Value::Number(n), Value::Number(digits)])
+            .unwrap()
+            .to_string()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec() {
+        let string = |s: &str| Value::from_string(s.to_string());
+        let run = |script: &str| {
+            let args = Value::from_list(vec![string("-c"), string(script)]);
+            super::exec(&[string("sh"), args]).unwrap().to_string()
+        };
+
+        assert_eq!(
+            "{status: 3, stdout: out\n, stderr: err\n}",
+            run("echo out; echo err >&2; exit 3")
+        );
+        assert_eq!("{status: nil, stdout: , stderr: }", run("kill -9 $$"));
+        assert!(super::exec(&[string("/nonexistent"), Value::from_list(vec![])]).is_err());
+        let numbers = Value::from_list(vec![Value::Number(1.0)]);
+        assert!(super::exec(&[string("true"), numbers]).is_err());
+    }
+
+    #[test]
+    fn fixed() {
+        assert_eq!("3", call(to_fixed, 2.5627, 0.0));
+        assert_eq!("2.563", call(to_fixed, 2.5627, 3.0));
+        assert_eq!("-0.50", call(to_fixed, -0.5, 2.0));
+        assert_eq!("1000000.00", call(to_fixed, 1e6, 2.0));
+        assert!(to_fixed(&[Value::Number(1.0), Value::Number(1.5)]).is_err());
+        assert!(to_fixed(&[Value::Number(1.0), Value::Number(101.0)]).is_err());
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!("123.5", call(to_precision, 123.456, 4.0));
+        assert_eq!("1.2e+2", call(to_precision, 123.456, 2.0));
+        assert_eq!("100", call(to_precision, 99.99, 3.0));
+        assert_eq!("0.000123", call(to_precision, 0.000123, 3.0));
+        assert_eq!("1.23e-7", call(to_precision, 0.000000123, 3.0));
+        assert_eq!("0.00", call(to_precision, 0.0, 3.0));
+        assert_eq!("inf", call(to_precision, f64::INFINITY, 3.0));
+        assert!(to_precision(&[Value::Number(1.0), Value::Number(0.0)]).is_err());
+    }
+
+    #[test]
+    fn time() {
+        let format = |epoch: f64, format: &str| {
+            format_time(&[Value::Number(epoch), Value::from_string(format.to_string())])
+        };
+
+        assert_eq!(
+            "1970-01-01 00:00:00",
+            format(0.0, "%Y-%m-%d %H:%M:%S").unwrap().to_string()
+        );
+        assert_eq!(
+            "2000-02-29T23:59:59 100%",
+            format(951868799.5, "%Y-%m-%dT%H:%M:%S 100%%")
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!("1969-12-31", format(-1.0, "%Y-%m-%d").unwrap().to_string());
+        assert!(format(0.0, "%Q").is_err());
+        assert!(format(0.0, "%").is_err());
+        assert!(sleep(&[Value::Number(-1.0)]).is_err());
+        assert!(now(&[]).unwrap() > Value::Number(1e9));
+    }
+
+    #[test]
+    fn strings() {
+        let string = |s: &str| Value::from_string(s.to_string());
+        let text = string("héllo, wörld");
+
+        let substring = |start: Value, end: Value| substring(&[text.clone(), start, end]);
+        assert_eq!(
+            "éllo",
+            substring(Value::Number(1.0), Value::Number(5.0))
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!(
+            "wörld",
+            substring(Value::Number(7.0), Value::Nil)
+                .unwrap()
+                .to_string()
+        );
+        assert!(substring(Value::Number(3.0), Value::Number(2.0)).is_err());
+        assert!(substring(Value::Number(0.5), Value::Nil).is_err());
+
+        assert_eq!(
+            Value::Number(9.0),
+            index_of(&[text.clone(), string("r")]).unwrap()
+        );
+        assert_eq!(
+            Value::Number(-1.0),
+            index_of(&[text.clone(), string("x")]).unwrap()
+        );
+        assert_eq!(
+            "[héllo, wörld]",
+            split(&[text.clone(), string(", ")]).unwrap().to_string()
+        );
+        assert_eq!(
+            "[a, b]",
+            split(&[string("ab"), string("")]).unwrap().to_string()
+        );
+        assert_eq!(
+            "HÉLLO, WÖRLD",
+            to_upper_case(std::slice::from_ref(&text))
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!("abc", to_lower_case(&[string("ABC")]).unwrap().to_string());
+        assert!(to_upper_case(&[Value::Number(1.0)]).is_err());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex() {
+        let string = |s: &str| Value::from_string(s.to_string());
+
+        assert_eq!(
+            Value::Bool(true),
+            re_match(&[string("^\\d+$"), string("2024")]).unwrap()
+        );
+        assert_eq!(
+            Value::Bool(false),
+            re_match(&[string("^\\d+$"), string("20x4")]).unwrap()
+        );
+        assert_eq!(
+            "2024-01-31",
+            re_replace(&[
+                string("(\\d+)/(\\d+)/(\\d+)"),
+                string("31/01/2024"),
+                string("$3-$2-$1"),
+            ])
+            .unwrap()
+            .to_string()
+        );
+        assert_eq!(
+            "[a, b, c]",
+            re_split(&[string(",\\s*"), string("a, b,c")])
+                .unwrap()
+                .to_string()
+        );
+        assert!(re_match(&[string("("), string("")]).is_err());
+        assert!(re_match(&[Value::Number(1.0), string("")]).is_err());
+    }
+}
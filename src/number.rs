@@ -0,0 +1,180 @@
+//! Converting numbers to and from text. Everything that reads or writes a Lox number goes
+//! through here, so scripts behave the same whatever the system locale: the decimal separator
+//! is always `.`, there are no grouping separators, and printing a number then parsing it gives
+//! back exactly the same number.
+
+/// Parses a number written as in Lox source, `123` or `1.5`, optionally with a leading `-` and
+/// an exponent such as `e-3`, or as `inf`, `-inf` or `NaN`, as `format` writes those. Anything
+/// else, including surrounding whitespace, isn't a number.
+pub fn parse(s: &str) -> Option<f64> {
+    let unsigned = s.strip_prefix('-').unwrap_or(s);
+    if !(unsigned == "inf" || s == "NaN" || is_decimal(unsigned)) {
+        return None;
+    }
+    // Rust's parsing never consults the locale, and accepts everything `is_decimal` does
+    s.parse().ok()
+}
+
+/// Whether `s` is digits, optionally followed by `.` and more digits, then by an exponent.
+fn is_decimal(s: &str) -> bool {
+    let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (s, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+
+    let mantissa = match mantissa.split_once('.') {
+        Some((whole, fraction)) => digits(whole) && digits(fraction),
+        None => digits(mantissa),
+    };
+    let exponent = exponent
+        .is_none_or(|exponent| digits(exponent.strip_prefix(['-', '+']).unwrap_or(exponent)));
+    mantissa && exponent
+}
+
+/// Whether the number written as `literal`, as `parse` accepts, can't be held exactly and will
+/// be rounded. Whole numbers must be exact. Fractions such as `0.1`, which no binary number
+/// holds exactly, only count when they have more significant digits than survive the rounding.
+pub fn loses_precision(literal: &str) -> bool {
+    let Some(n) = parse(literal) else {
+        return false;
+    };
+    let unsigned = literal.strip_prefix('-').unwrap_or(literal);
+    if unsigned == "inf" || n.is_nan() {
+        return false;
+    }
+    if n.is_infinite() {
+        return true;
+    }
+
+    if is_decimal(unsigned) && !unsigned.contains(['.', 'e', 'E']) {
+        // Formatting with no fractional digits writes out the number's exact value
+        let whole = unsigned.trim_start_matches('0');
+        format!("{:.0}", n.abs()) != if whole.is_empty() { "0" } else { whole }
+    } else {
+        // `{:e}` writes the fewest digits that round to `n`
+        significant_digits(unsigned) != significant_digits(&format!("{:e}", n.abs()))
+    }
+}
+
+/// The digits of a number's mantissa, without leading or trailing zeros.
+fn significant_digits(s: &str) -> String {
+    let mantissa = s.split(['e', 'E']).next().unwrap_or(s);
+    let digits: String = mantissa.chars().filter(char::is_ascii_digit).collect();
+    digits.trim_matches('0').to_string()
+}
+
+/// Writes `n` the way `print` shows it: the fewest digits that parse back to exactly `n`, with
+/// no exponent and no trailing `.0` on whole numbers.
+pub fn format(n: f64) -> String {
+    // Rust's formatting is locale-independent and already gives the shortest round-tripping form
+    n.to_string()
+}
+
+/// Writes out every digit of the value `n` holds, where `format` stops once the digits identify
+/// it. Any fraction a binary number holds ends within 1074 decimal places.
+pub fn exact(n: f64) -> String {
+    if !n.is_finite() {
+        return format(n);
+    }
+    let digits = format!("{:.1074}", n);
+    digits
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formatting() {
+        assert_eq!("1", format(1.0));
+        assert_eq!("-0", format(-0.0));
+        assert_eq!("0.1", format(0.1));
+        assert_eq!("1234567.5", format(1234567.5));
+        assert_eq!("0.30000000000000004", format(0.1 + 0.2));
+        assert_eq!("100000000000000000000000", format(1e23));
+        assert_eq!("inf", format(f64::INFINITY));
+        assert_eq!("NaN", format(f64::NAN));
+    }
+
+    #[test]
+    fn exact_values() {
+        assert_eq!("1", exact(1.0));
+        assert_eq!("0.5", exact(0.5));
+        assert_eq!("12345678901234567168", exact(12345678901234567890.0));
+        assert_eq!(
+            "0.1000000000000000055511151231257827021181583404541015625",
+            exact(0.1)
+        );
+        // The smallest number there is needs every place
+        let smallest = exact(5e-324);
+        assert_eq!(1076, smallest.len());
+        assert!(smallest.starts_with("0.000") && smallest.ends_with("625"));
+        assert_eq!("inf", exact(f64::INFINITY));
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(Some(1.5), parse("1.5"));
+        assert_eq!(Some(-12.0), parse("-12"));
+        assert_eq!(Some(2500.0), parse("2.5e3"));
+        assert_eq!(Some(f64::NEG_INFINITY), parse("-inf"));
+        assert!(parse("NaN").unwrap().is_nan());
+        for invalid in [
+            "", "1,5", "1.", ".5", " 1", "+1", "1e", "0x10", "infinity", "--1",
+        ] {
+            assert_eq!(None, parse(invalid), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn precision() {
+        for exact in [
+            "0",
+            "007",
+            "9007199254740992",
+            "10000000000000000000000",
+            "0.1",
+            "0.30000000000000004",
+            "2.50",
+            "1.5e3",
+            "-12",
+            "inf",
+        ] {
+            assert!(!loses_precision(exact), "{}", exact);
+        }
+        for inexact in [
+            "9007199254740993",
+            "1234567890123456789",
+            "0.12345678901234567891",
+            "3.141592653589793238",
+            &"9".repeat(400),
+        ] {
+            assert!(loses_precision(inexact), "{}", inexact);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = [
+            0.0,
+            -0.0,
+            1.0 / 3.0,
+            -2.5e-8,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            5e-324,
+            f64::EPSILON,
+            123456789.123,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for n in values {
+            let parsed = parse(&format(n)).unwrap();
+            assert_eq!(n.to_bits(), parsed.to_bits(), "{}", n);
+        }
+    }
+}
@@ -4,6 +4,7 @@ use crate::token::{Token, TokenType};
 pub enum Precedence {
     None,
     Assignment,
+    Pipe,
     Or,
     And,
     Equality,
@@ -20,7 +21,8 @@ impl Precedence {
     pub fn next(&self) -> Precedence {
         match self {
             Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Pipe,
+            Precedence::Pipe => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
@@ -67,6 +69,17 @@ pub enum ParseFn {
     Number,
     Literal,
     String,
+    And,
+    Or,
+    Call,
+    Dot,
+    SafeDot,
+    This,
+    Bytes,
+    Index,
+    List,
+    Map,
+    Pipe,
     None,
 }
 
@@ -83,8 +96,8 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
     match tt {
         TokenType::LeftParen => ParseRule {
             prefix: ParseFn::Grouping,
-            infix: ParseFn::None,
-            precedence: Precedence::None,
+            infix: ParseFn::Call,
+            precedence: Precedence::Call,
         },
         TokenType::RightParen => ParseRule {
             prefix: ParseFn::None,
@@ -92,7 +105,7 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
             precedence: Precedence::None,
         },
         TokenType::LeftBrace => ParseRule {
-            prefix: ParseFn::None,
+            prefix: ParseFn::Map,
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
@@ -101,16 +114,41 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
+        TokenType::LeftBracket => ParseRule {
+            prefix: ParseFn::List,
+            infix: ParseFn::Index,
+            precedence: Precedence::Call,
+        },
+        TokenType::RightBracket => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Pipe => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::Pipe,
+            precedence: Precedence::Pipe,
+        },
         TokenType::Comma => ParseRule {
             prefix: ParseFn::None,
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
-        TokenType::Dot => ParseRule {
+        TokenType::Colon => ParseRule {
             prefix: ParseFn::None,
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
+        TokenType::Dot => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::Dot,
+            precedence: Precedence::Call,
+        },
+        TokenType::QuestionDot => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::SafeDot,
+            precedence: Precedence::Call,
+        },
         TokenType::Minus => ParseRule {
             prefix: ParseFn::Unary,
             infix: ParseFn::Binary,
@@ -191,11 +229,16 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
-        TokenType::And => ParseRule {
-            prefix: ParseFn::None,
+        TokenType::Bytes => ParseRule {
+            prefix: ParseFn::Bytes,
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
+        TokenType::And => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::And,
+            precedence: Precedence::And,
+        },
         TokenType::Class => ParseRule {
             prefix: ParseFn::None,
             infix: ParseFn::None,
@@ -233,8 +276,8 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
         },
         TokenType::Or => ParseRule {
             prefix: ParseFn::None,
-            infix: ParseFn::None,
-            precedence: Precedence::None,
+            infix: ParseFn::Or,
+            precedence: Precedence::Or,
         },
         TokenType::Print => ParseRule {
             prefix: ParseFn::None,
@@ -252,7 +295,7 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
             precedence: Precedence::None,
         },
         TokenType::This => ParseRule {
-            prefix: ParseFn::None,
+            prefix: ParseFn::This,
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
@@ -271,6 +314,66 @@ pub fn parse_rule(tt: &TokenType) -> ParseRule {
             infix: ParseFn::None,
             precedence: Precedence::None,
         },
+        TokenType::Defer => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Break => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Continue => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Yield => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Switch => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Case => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Default => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Try => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Catch => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Throw => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::StaticAssert => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
+        TokenType::Echo => ParseRule {
+            prefix: ParseFn::None,
+            infix: ParseFn::None,
+            precedence: Precedence::None,
+        },
         TokenType::Eof => ParseRule {
             prefix: ParseFn::None,
             infix: ParseFn::None,
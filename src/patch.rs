@@ -0,0 +1,491 @@
+//! Rewrites compiled bytecode, for tools that instrument programs, e.g. injecting probes. A
+//! [`DecodedChunk`] lists a chunk's instructions with their operands decoded, and jumps aimed at
+//! instructions rather than byte offsets, so instructions can be changed or inserted without
+//! breaking the jumps around them. Encoding it again works out the offsets and verifies the
+//! result, so a patch can't produce bytecode the VM would misread.
+
+pub use crate::chunk::{Chunk, Constant, Function, OpCode, ValueType};
+use crate::error::ChunkError;
+
+/// An instruction's operand, decoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    None,
+    /// A local slot, or a count of arguments or elements.
+    Byte(u8),
+    /// An index into the chunk's constants.
+    Constant(usize),
+    /// The index of the instruction a jump goes to. One past the last instruction is the end of
+    /// the code.
+    Jump(usize),
+    /// The local slot and type checked by `OpCode::CheckType`.
+    CheckType(u8, ValueType),
+    /// The arguments to `OpCode::CallKeywords`, then how many of them are keyword arguments.
+    Arguments(u8, u8),
+    /// The id `OpCode::Probe` hands the VM's probe handler.
+    Probe(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub op: OpCode,
+    pub operand: Operand,
+    /// The source line the instruction was compiled from.
+    pub line: usize,
+}
+
+/// What kind of operand an opcode takes.
+#[derive(PartialEq)]
+enum Kind {
+    None,
+    Byte,
+    Constant,
+    /// A 24 bit constant index.
+    LongConstant,
+    Jump,
+    Loop,
+    CheckType,
+    Arguments,
+    Probe,
+}
+
+impl Kind {
+    fn of(op: OpCode) -> Kind {
+        match op {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::Pipe
+            | OpCode::BuildList
+            | OpCode::BuildMap => Kind::Byte,
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method => Kind::Constant,
+            OpCode::ConstantLong => Kind::LongConstant,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::PushHandler => Kind::Jump,
+            OpCode::Loop => Kind::Loop,
+            OpCode::CheckType => Kind::CheckType,
+            OpCode::CallKeywords => Kind::Arguments,
+            OpCode::Probe => Kind::Probe,
+            _ => Kind::None,
+        }
+    }
+
+    /// How many bytes the operand takes.
+    fn width(&self) -> usize {
+        match self {
+            Kind::None => 0,
+            Kind::Byte | Kind::Constant => 1,
+            Kind::Jump | Kind::Loop | Kind::CheckType | Kind::Arguments | Kind::Probe => 2,
+            Kind::LongConstant => 3,
+        }
+    }
+
+    fn matches(&self, operand: &Operand) -> bool {
+        matches!(
+            (self, operand),
+            (Kind::None, Operand::None)
+                | (Kind::Byte, Operand::Byte(_))
+                | (Kind::Constant | Kind::LongConstant, Operand::Constant(_))
+                | (Kind::Jump | Kind::Loop, Operand::Jump(_))
+                | (Kind::CheckType, Operand::CheckType(..))
+                | (Kind::Arguments, Operand::Arguments(..))
+                | (Kind::Probe, Operand::Probe(_))
+        )
+    }
+}
+
+/// A chunk's instructions and constants, ready to be patched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedChunk {
+    instructions: Vec<Instruction>,
+    constants: Vec<Constant>,
+}
+
+impl DecodedChunk {
+    /// Decodes every instruction in `chunk`, failing unless each is complete, refers to a
+    /// constant the chunk has and jumps to the start of an instruction.
+    pub fn decode(chunk: &Chunk) -> Result<DecodedChunk, ChunkError> {
+        let code = &chunk.code;
+        let byte = |offset: usize| {
+            code.get(offset)
+                .copied()
+                .ok_or(ChunkError::Malformed("truncated instruction"))
+        };
+
+        // Offsets are resolved to instructions once every instruction's offset is known
+        let mut offsets = Vec::new();
+        let mut decoded = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let op = OpCode::try_from(code[offset])
+                .map_err(|_| ChunkError::UnknownOpCode(code[offset]))?;
+            let kind = Kind::of(op);
+            let after = offset + 1 + kind.width();
+            byte(after - 1)?;
+            let operand = match kind {
+                Kind::None => Operand::None,
+                Kind::Byte => Operand::Byte(code[offset + 1]),
+                Kind::Constant => Operand::Constant(code[offset + 1] as usize),
+                Kind::LongConstant => Operand::Constant(chunk.read_long(offset + 1)),
+                Kind::Jump => Operand::Jump(after + chunk.read_short(offset + 1)),
+                Kind::Loop => Operand::Jump(
+                    after
+                        .checked_sub(chunk.read_short(offset + 1))
+                        .ok_or(ChunkError::Malformed("jump target is not an instruction"))?,
+                ),
+                Kind::CheckType => {
+                    Operand::CheckType(code[offset + 1], ValueType::try_from(code[offset + 2])?)
+                }
+                Kind::Arguments => Operand::Arguments(code[offset + 1], code[offset + 2]),
+                Kind::Probe => Operand::Probe(chunk.read_short(offset + 1) as u16),
+            };
+            if let Operand::Constant(index) = operand {
+                if index >= chunk.constants().len() {
+                    return Err(ChunkError::Malformed("constant index out of range"));
+                }
+            }
+            offsets.push(offset);
+            decoded.push(Instruction {
+                op,
+                operand,
+                line: chunk.line_for_offset(offset),
+            });
+            offset = after;
+        }
+        offsets.push(code.len());
+
+        for instruction in &mut decoded {
+            if let Operand::Jump(target) = &mut instruction.operand {
+                *target = offsets
+                    .binary_search(target)
+                    .map_err(|_| ChunkError::Malformed("jump target is not an instruction"))?;
+            }
+        }
+
+        Ok(DecodedChunk {
+            instructions: decoded,
+            constants: chunk.constants().to_vec(),
+        })
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    /// Adds a constant for patched instructions to use, returning its index.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        self.constants.push(constant);
+        self.constants.len() - 1
+    }
+
+    /// Points the instruction at `index`, which must take a constant, at another constant.
+    pub fn set_constant(&mut self, index: usize, constant: usize) -> Result<(), ChunkError> {
+        if constant >= self.constants.len() {
+            return Err(ChunkError::Patch(index, "no such constant"));
+        }
+        match self.instructions.get_mut(index) {
+            Some(Instruction {
+                operand: Operand::Constant(operand),
+                ..
+            }) => *operand = constant,
+            Some(_) => return Err(ChunkError::Patch(index, "not a constant instruction")),
+            None => return Err(ChunkError::Patch(index, "no such instruction")),
+        }
+        Ok(())
+    }
+
+    /// Sends the jump at `index` to the instruction at `target` instead.
+    pub fn retarget(&mut self, index: usize, target: usize) -> Result<(), ChunkError> {
+        if target > self.instructions.len() {
+            return Err(ChunkError::Patch(index, "jump target out of range"));
+        }
+        match self.instructions.get_mut(index) {
+            Some(Instruction {
+                operand: Operand::Jump(operand),
+                ..
+            }) => *operand = target,
+            Some(_) => return Err(ChunkError::Patch(index, "not a jump")),
+            None => return Err(ChunkError::Patch(index, "no such instruction")),
+        }
+        Ok(())
+    }
+
+    /// Inserts `instructions` before the one at `index`. Jumps to that instruction run the
+    /// inserted ones first, so a probe sees every way into it. Jumps among the inserted
+    /// instructions are taken as indices into the patched chunk.
+    pub fn insert(
+        &mut self,
+        index: usize,
+        instructions: Vec<Instruction>,
+    ) -> Result<(), ChunkError> {
+        if index > self.instructions.len() {
+            return Err(ChunkError::Patch(index, "no such instruction"));
+        }
+        let count = instructions.len();
+        for instruction in &mut self.instructions {
+            if let Operand::Jump(target) = &mut instruction.operand {
+                if *target > index {
+                    *target += count;
+                }
+            }
+        }
+        self.instructions.splice(index..index, instructions);
+        Ok(())
+    }
+
+    /// Encodes the instructions back into a chunk, verifying the VM can run the result as the
+    /// body of an ordinary function or script. A `Constant` whose index has outgrown a byte
+    /// becomes a `ConstantLong`.
+    pub fn encode(&self) -> Result<Chunk, ChunkError> {
+        self.encode_as(false)
+    }
+
+    /// Like `encode`, but for the body of a generator function, which may yield.
+    pub fn encode_generator(&self) -> Result<Chunk, ChunkError> {
+        self.encode_as(true)
+    }
+
+    fn encode_as(&self, generator: bool) -> Result<Chunk, ChunkError> {
+        let ops: Vec<_> = self
+            .instructions
+            .iter()
+            .map(|instruction| match (instruction.op, instruction.operand) {
+                (OpCode::Constant, Operand::Constant(index)) if index > u8::MAX as usize => {
+                    OpCode::ConstantLong
+                }
+                (op, _) => op,
+            })
+            .collect();
+
+        let mut offsets = vec![0];
+        for op in &ops {
+            offsets.push(offsets.last().unwrap() + 1 + Kind::of(*op).width());
+        }
+
+        let mut chunk = Chunk::new();
+        for constant in &self.constants {
+            chunk
+                .add_constant(constant.clone())
+                .map_err(|_| ChunkError::Malformed("too many constants"))?;
+        }
+        for (index, (instruction, op)) in self.instructions.iter().zip(ops).enumerate() {
+            let kind = Kind::of(op);
+            if !kind.matches(&instruction.operand) {
+                return Err(ChunkError::Patch(index, "operand doesn't suit the opcode"));
+            }
+            let after = offsets[index + 1];
+            let operand = match instruction.operand {
+                Operand::None => vec![],
+                Operand::Byte(byte) => vec![byte],
+                Operand::Constant(constant) if kind == Kind::LongConstant => {
+                    constant.to_be_bytes()[std::mem::size_of::<usize>() - 3..].to_vec()
+                }
+                Operand::Constant(constant) => vec![u8::try_from(constant)
+                    .map_err(|_| ChunkError::Patch(index, "constant index doesn't fit"))?],
+                Operand::Jump(target) => {
+                    let target = *offsets
+                        .get(target)
+                        .ok_or(ChunkError::Patch(index, "jump target out of range"))?;
+                    let distance = match kind {
+                        Kind::Loop => after.checked_sub(target),
+                        _ => target.checked_sub(after),
+                    };
+                    let distance = distance
+                        .and_then(|distance| u16::try_from(distance).ok())
+                        .ok_or(ChunkError::Patch(index, "jump target out of reach"))?;
+                    distance.to_be_bytes().to_vec()
+                }
+                Operand::CheckType(slot, value_type) => vec![slot, value_type.into()],
+                Operand::Arguments(count, keywords) => vec![count, keywords],
+                Operand::Probe(id) => id.to_be_bytes().to_vec(),
+            };
+            chunk.write(op, instruction.line);
+            for byte in operand {
+                chunk.write(byte, instruction.line);
+            }
+        }
+
+        chunk.verify(generator)?;
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile, CompileOptions};
+    use crate::intern::intern;
+    use crate::program::Program;
+    use crate::vm::test::Buffer;
+    use crate::vm::VM;
+
+    fn run(chunk: Chunk) -> String {
+        let out = Buffer::default();
+        VM::with_output(Box::new(out.clone()))
+            .run(&Program::from(Function::script(chunk)))
+            .unwrap();
+        out.contents()
+    }
+
+    #[test]
+    fn round_trip() {
+        let source =
+            "for (var i = 0; i < 3; i = i + 1) { if (i == 1) print \"one\"; else print i; }";
+        let program = compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let chunk = &program.script().chunk;
+
+        let decoded = DecodedChunk::decode(chunk).unwrap();
+        let encoded = decoded.encode().unwrap();
+        assert_eq!(chunk.code, encoded.code);
+        assert_eq!(chunk.constants(), encoded.constants());
+        assert_eq!("0\none\n2\n", run(encoded));
+    }
+
+    #[test]
+    fn probes() {
+        let source = "var i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}\nprint i;";
+        let program = compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let mut decoded = DecodedChunk::decode(&program.script().chunk).unwrap();
+
+        // Count each time round the loop, by probing the start of the condition it jumps back to
+        let start = match decoded.instructions().iter().find(|i| i.op == OpCode::Loop) {
+            Some(Instruction {
+                operand: Operand::Jump(start),
+                ..
+            }) => *start,
+            _ => panic!("no loop"),
+        };
+        let probe = decoded.add_constant(Constant::String(intern("probe")));
+        let line = decoded.instructions()[start].line;
+        decoded
+            .insert(
+                start,
+                vec![
+                    Instruction {
+                        op: OpCode::Constant,
+                        operand: Operand::Constant(probe),
+                        line,
+                    },
+                    Instruction {
+                        op: OpCode::Print,
+                        operand: Operand::None,
+                        line,
+                    },
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            "probe\nprobe\nprobe\nprobe\n3\n",
+            run(decoded.encode().unwrap())
+        );
+
+        // Printing something else instead
+        let other = decoded.add_constant(Constant::Number(7.0));
+        decoded.set_constant(start, other).unwrap();
+        assert_eq!("7\n7\n7\n7\n3\n", run(decoded.encode().unwrap()));
+
+        assert!(matches!(
+            decoded.set_constant(start + 1, other),
+            Err(ChunkError::Patch(_, "not a constant instruction"))
+        ));
+        assert!(matches!(
+            decoded.retarget(start, 0),
+            Err(ChunkError::Patch(_, "not a jump"))
+        ));
+    }
+
+    #[test]
+    fn retargeting() {
+        let source = "var c = true;\nif (c) print 1; else print 2;\nprint 3;";
+        let program = compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let mut decoded = DecodedChunk::decode(&program.script().chunk).unwrap();
+
+        // Skip the rest of the script when the `then` branch is done
+        let exit = decoded
+            .instructions()
+            .iter()
+            .position(|i| i.op == OpCode::Jump)
+            .unwrap();
+        let end = decoded.instructions().len() - 2;
+        decoded.retarget(exit, end).unwrap();
+        assert_eq!("1\n", run(decoded.encode().unwrap()));
+
+        // A jump forward can't go backward
+        decoded.retarget(exit, 0).unwrap();
+        assert!(matches!(
+            decoded.encode(),
+            Err(ChunkError::Patch(_, "jump target out of reach"))
+        ));
+    }
+
+    #[test]
+    fn verification() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Constant, 1usize);
+        assert!(matches!(
+            DecodedChunk::decode(&chunk),
+            Err(ChunkError::Malformed("truncated instruction"))
+        ));
+        chunk.write(0u8, 1usize);
+        assert!(matches!(
+            DecodedChunk::decode(&chunk),
+            Err(ChunkError::Malformed("constant index out of range"))
+        ));
+
+        // Into the middle of the jump itself
+        let mut chunk = Chunk::new();
+        for byte in [OpCode::Jump as u8, 0xff, 0xfe] {
+            chunk.write(byte, 1usize);
+        }
+        assert!(matches!(
+            DecodedChunk::decode(&chunk),
+            Err(ChunkError::Malformed("jump target is not an instruction"))
+        ));
+    }
+
+    #[test]
+    fn encoding_verifies() {
+        // The VM would read past the end of code that doesn't return
+        let mut chunk = Chunk::new();
+        let constant = chunk.add_constant(Constant::Number(1.0)).unwrap();
+        chunk.write(OpCode::Constant, 1usize);
+        chunk.write(constant as u8, 1usize);
+        let decoded = DecodedChunk::decode(&chunk).unwrap();
+        assert!(matches!(
+            decoded.encode(),
+            Err(ChunkError::Malformed("code does not end with a return"))
+        ));
+
+        let source = "fun count() { yield 1; }";
+        let program = compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let generator = program
+            .script()
+            .chunk
+            .constants()
+            .iter()
+            .find_map(|constant| match constant {
+                Constant::Function(function) => Some(function.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let decoded = DecodedChunk::decode(&generator.chunk).unwrap();
+        assert_eq!(
+            generator.chunk.code,
+            decoded.encode_generator().unwrap().code
+        );
+        assert!(matches!(
+            decoded.encode(),
+            Err(ChunkError::Malformed("yield outside a generator"))
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::chunk::{Constant, Function, Value};
+use crate::intern::Symbol;
+
+/// The constants of every function a VM has loaded, as values, shared between their chunks so a
+/// string or number used throughout a program is only stored once.
+///
+/// Chunks keep their own constant indices; loading a function produces a table mapping each of
+/// them to the constant's index in the pool.
+#[derive(Default)]
+pub struct ConstantPool {
+    values: Vec<Value>,
+    strings: HashMap<Symbol, usize>,
+    /// Keyed by bit pattern, so `0` and `-0` stay distinct.
+    numbers: HashMap<u64, usize>,
+    /// Each function loaded, by address, with the index of the value holding it and its
+    /// remapping table. That value keeps the function alive, so the address can't be reused.
+    functions: HashMap<*const Function, (usize, Rc<[usize]>)>,
+}
+
+impl ConstantPool {
+    /// Adds `function`'s constants, and those of any functions nested in them, returning where
+    /// each of its chunk's constants lives in the pool.
+    pub fn load(&mut self, function: &Arc<Function>) -> Rc<[usize]> {
+        self.load_function(function).1
+    }
+
+    fn load_function(&mut self, function: &Arc<Function>) -> (usize, Rc<[usize]>) {
+        if let Some((index, remap)) = self.functions.get(&Arc::as_ptr(function)) {
+            return (*index, Rc::clone(remap));
+        }
+
+        let index = self.push(Value::from_function(Arc::clone(function)));
+        let remap: Rc<[usize]> = function
+            .chunk
+            .constants()
+            .iter()
+            .map(|constant| self.intern(constant))
+            .collect();
+        self.functions
+            .insert(Arc::as_ptr(function), (index, Rc::clone(&remap)));
+        (index, remap)
+    }
+
+    pub fn get(&self, index: usize) -> &Value {
+        &self.values[index]
+    }
+
+    #[allow(dead_code)] // Embedding API
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn intern(&mut self, constant: &Constant) -> usize {
+        match constant {
+            Constant::Number(n) => {
+                if let Some(&index) = self.numbers.get(&n.to_bits()) {
+                    return index;
+                }
+                let index = self.push(Value::Number(*n));
+                self.numbers.insert(n.to_bits(), index);
+                index
+            }
+            Constant::String(s) => self.string(s),
+            Constant::Function(function) => self.load_function(function).0,
+            constant => self.push(constant.into()),
+        }
+    }
+
+    /// The index of the string `s`, adding it if it's new. Each distinct string has one index, so
+    /// it also identifies a name.
+    pub fn string(&mut self, s: &Symbol) -> usize {
+        if let Some(&index) = self.strings.get(s) {
+            return index;
+        }
+        let index = self.push(Value::from_symbol(s.clone()));
+        self.strings.insert(s.clone(), index);
+        index
+    }
+
+    fn push(&mut self, value: Value) -> usize {
+        self.values.push(value);
+        self.values.len() - 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::{compile_with_status, CompileOptions};
+
+    #[test]
+    fn shared_constants() {
+        let source = String::from(
+            "fun a() { print \"hello\"; return 1; }\n\
+             fun b() { print \"hello\"; return 1; }\n\
+             print \"hello\";",
+        );
+        let (program, had_error) = compile_with_status(source, &CompileOptions::default()).unwrap();
+        assert!(!had_error);
+
+        let mut pool = ConstantPool::default();
+        let script = program.script();
+        let remap = pool.load(script);
+        let strings = (0..pool.len())
+            .filter(|index| pool.get(*index).as_string() == Some("hello"))
+            .count();
+        let numbers = (0..pool.len())
+            .filter(|index| *pool.get(*index) == Value::Number(1.0))
+            .count();
+
+        assert_eq!(1, strings);
+        assert_eq!(1, numbers);
+        // Loading the same function again reuses its table
+        assert!(Rc::ptr_eq(&remap, &pool.load(script)));
+    }
+}
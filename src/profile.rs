@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{LoxResult, ParseError};
+
+/// Functions called at least this often in a profile are hot.
+const HOT_CALLS: u64 = 1000;
+
+/// How often each function was called while a program ran, recorded by a VM with profiling on
+/// and handed to the compiler to optimize later builds for the same workload.
+///
+/// Saved as text, one `calls <function> <count>` line per function. Functions are known by name
+/// alone, so methods and functions sharing a name are counted together.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    calls: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    pub fn load<P: AsRef<Path>>(path: P) -> LoxResult<Profile> {
+        Ok(std::fs::read_to_string(path)?.parse()?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> LoxResult<()> {
+        Ok(std::fs::write(path, self.to_string())?)
+    }
+
+    pub fn record_call(&mut self, function: &str) {
+        *self.calls.entry(function.to_string()).or_default() += 1;
+    }
+
+    pub fn calls(&self, function: &str) -> u64 {
+        self.calls.get(function).copied().unwrap_or_default()
+    }
+
+    pub fn is_hot(&self, function: &str) -> bool {
+        self.calls(function) >= HOT_CALLS
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (function, count) in &self.calls {
+            writeln!(f, "calls {} {}", function, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Profile {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut profile = Profile::default();
+        for (index, line) in s.lines().enumerate() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => {}
+                ["calls", function, count] => {
+                    let count = count
+                        .parse()
+                        .map_err(|_| ParseError::InvalidProfile(index + 1))?;
+                    profile.calls.insert(function.to_string(), count);
+                }
+                _ => return Err(ParseError::InvalidProfile(index + 1)),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut profile = Profile::default();
+        for _ in 0..HOT_CALLS {
+            profile.record_call("fib");
+        }
+        profile.record_call("main");
+
+        let text = profile.to_string();
+        assert_eq!("calls fib 1000\ncalls main 1\n", text);
+        let loaded: Profile = text.parse().unwrap();
+        assert_eq!(profile, loaded);
+        assert!(loaded.is_hot("fib"));
+        assert!(!loaded.is_hot("main"));
+        assert_eq!(0, loaded.calls("other"));
+
+        assert_eq!(
+            Err(ParseError::InvalidProfile(2)),
+            "calls f 1\ncalls g many\n".parse::<Profile>()
+        );
+    }
+}
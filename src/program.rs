@@ -0,0 +1,199 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::chunk::{Chunk, Constant, Function};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error::LoxResult;
+
+/// A compiled script: its top-level function along with everything needed to run or inspect it.
+/// A program isn't changed by running it, so one can be run any number of times, by any number
+/// of VMs, and shared with VMs on other threads in an `Arc`.
+#[derive(Debug)]
+pub struct Program {
+    script: Arc<Function>,
+    /// Every function declared in the script, in the order their declarations appear.
+    functions: Vec<Arc<Function>>,
+    /// The source files compiled into the program, including those read for `#include`s, for
+    /// tools reporting on it.
+    files: Vec<String>,
+    /// Warnings found while compiling, which didn't stop the program compiling.
+    warnings: Vec<Diagnostic>,
+    /// The statement each probe marks, indexed by id.
+    probes: Vec<Span>,
+}
+
+impl Program {
+    pub fn new(script: Function, files: Vec<String>) -> Program {
+        let script = Arc::new(script);
+        let mut functions = Vec::new();
+        collect_functions(&script, &mut functions);
+
+        Program {
+            script,
+            functions,
+            files,
+            warnings: Vec::new(),
+            probes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_warnings(self, warnings: Vec<Diagnostic>) -> Program {
+        Program { warnings, ..self }
+    }
+
+    pub(crate) fn with_probes(self, probes: Vec<Span>) -> Program {
+        Program { probes, ..self }
+    }
+
+    /// The function running the top level of the script.
+    pub fn script(&self) -> &Arc<Function> {
+        &self.script
+    }
+
+    pub fn functions(&self) -> &[Arc<Function>] {
+        &self.functions
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Warnings to report to whoever wrote the source.
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// Where the statement each probe marks starts, by the id the VM's probe handler is given.
+    /// Empty unless the program was compiled with `CompileOptions::probes`.
+    pub fn probes(&self) -> &[Span] {
+        &self.probes
+    }
+
+    /// Loads a program saved by `save`. Fails if the file isn't bytecode, or is bytecode in a
+    /// format this version of the library doesn't run.
+    pub fn load<P: AsRef<Path>>(path: P) -> LoxResult<Program> {
+        let bytes = std::fs::read(path)?;
+        Ok(Function::script(Chunk::from_bytes(&bytes)?).into())
+    }
+
+    /// Saves the program's bytecode, with every function in it, to run later without compiling it
+    /// again. Only the code is saved: not the warnings, the probe sites or the files compiled.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> LoxResult<()> {
+        Ok(std::fs::write(path, self.script.chunk.to_bytes())?)
+    }
+}
+
+impl From<Function> for Program {
+    fn from(script: Function) -> Program {
+        Program::new(script, Vec::new())
+    }
+}
+
+fn collect_functions(function: &Function, functions: &mut Vec<Arc<Function>>) {
+    for constant in function.chunk.constants() {
+        if let Constant::Function(nested) = constant {
+            functions.push(Arc::clone(nested));
+            collect_functions(nested, functions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::Program;
+    use crate::compiler::{compile, CompileOptions};
+    use crate::error::{ChunkError, LoxError};
+    use crate::vm::VM;
+
+    #[test]
+    fn functions() {
+        let source = String::from(
+            "fun outer() { fun inner() {} }\n\
+             class A { method() {} }",
+        );
+        let program = compile(source, &CompileOptions::default()).unwrap();
+        let names: Vec<_> = program
+            .functions()
+            .iter()
+            .map(|function| function.name.clone().unwrap_or_default())
+            .collect();
+
+        assert_eq!(vec!["outer", "inner", "method"], names);
+    }
+
+    #[test]
+    fn included_files() {
+        let dir = std::env::temp_dir().join(format!("lox-program-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.lox"), "var x = 1;").unwrap();
+        let main = dir.join("main.lox").display().to_string();
+        let lib = dir.join("lib.lox").display().to_string();
+
+        let options = CompileOptions {
+            lang: crate::lang::Lang::Extended,
+            ..Default::default()
+        };
+        let sources = vec![(Some(main.clone()), String::from("#include \"lib.lox\""))];
+        let program = crate::compiler::compile_files(sources, &options).unwrap();
+        assert_eq!(&[main, lib], program.files());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let source = String::from(
+            "fun greet(name) { return \"hello \" + name; }\n\
+             class A { init(n) { this.n = n; } }\n\
+             return greet(\"loxc\") + \" \" + toFixed(A(n: 2).n, 0);",
+        );
+        let program = compile(source, &CompileOptions::default()).unwrap();
+        let path = std::env::temp_dir().join(format!("lox-program-{}.loxc", std::process::id()));
+        program.save(&path).unwrap();
+
+        let loaded = Program::load(&path).unwrap();
+        let mut vm = VM::with_output(Box::new(std::io::sink()));
+        assert_eq!("hello loxc 2", vm.run(&loaded).unwrap().to_string());
+        assert_eq!(program.functions().len(), loaded.functions().len());
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[4] += 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            Program::load(&path),
+            Err(LoxError::Chunk(ChunkError::Version(_, _)))
+        ));
+        std::fs::write(&path, "print 1;").unwrap();
+        assert!(matches!(
+            Program::load(&path),
+            Err(LoxError::Chunk(ChunkError::NotBytecode))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let source = String::from(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\n\
+             var greeting = \"fib\";\n\
+             return greeting + \" \" + toFixed(fib(15), 0);",
+        );
+        let program = Arc::new(compile(source, &CompileOptions::default()).unwrap());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let program = Arc::clone(&program);
+                std::thread::spawn(move || {
+                    let mut vm = VM::with_output(Box::new(std::io::sink()));
+                    vm.run(&program).unwrap().to_string()
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!("fib 610", thread.join().unwrap());
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::compiler::{self, CompileOptions};
+use crate::error::{LoxResult, ProjectError};
+use crate::program::Program;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &str = "lox.pkg";
+
+/// A `lox.pkg` manifest: a list of `key = value` lines naming the project's source files.
+///
+/// ```text
+/// # Shapes demo
+/// file = shapes.lox
+/// file = util.lox
+/// entry = main.lox
+/// ```
+///
+/// Paths are relative to the manifest. Library files are compiled in the order listed, followed
+/// by the entry point, so definitions in earlier files are available to later ones.
+#[derive(Debug, PartialEq)]
+pub struct Manifest {
+    pub entry: PathBuf,
+    pub files: Vec<PathBuf>,
+}
+
+impl Manifest {
+    pub fn load<P: AsRef<Path>>(path: P) -> LoxResult<Manifest> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        Ok(Manifest::parse(&text, base)?)
+    }
+
+    pub fn parse(text: &str, base: &Path) -> Result<Manifest, ProjectError> {
+        let mut entry = None;
+        let mut files = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or(ProjectError::InvalidLine(number + 1))?;
+            if value.is_empty() {
+                return Err(ProjectError::InvalidLine(number + 1));
+            }
+
+            match key {
+                "entry" if entry.is_some() => return Err(ProjectError::DuplicateEntry),
+                "entry" => entry = Some(base.join(value)),
+                "file" => files.push(base.join(value)),
+                _ => return Err(ProjectError::UnknownKey(key.to_string())),
+            }
+        }
+
+        Ok(Manifest {
+            entry: entry.ok_or(ProjectError::MissingEntry)?,
+            files,
+        })
+    }
+
+    /// Reads every source file and compiles them together into one script.
+    pub fn compile(&self, options: &CompileOptions) -> LoxResult<Program> {
+        let mut sources = Vec::new();
+        for path in self.files.iter().chain(std::iter::once(&self.entry)) {
+            let source = crate::source::read(path)
+                .map_err(|e| ProjectError::Read(path.display().to_string(), e.to_string()))?;
+            sources.push((Some(path.display().to_string()), source));
+        }
+
+        Ok(compiler::compile_files(sources, options)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_manifest() {
+        let text = "# comment\nfile = a.lox\n\nfile = lib/b.lox\nentry = main.lox\n";
+        let manifest = Manifest::parse(text, Path::new("proj")).unwrap();
+
+        assert_eq!(PathBuf::from("proj/main.lox"), manifest.entry);
+        assert_eq!(
+            vec![PathBuf::from("proj/a.lox"), PathBuf::from("proj/lib/b.lox")],
+            manifest.files
+        );
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        let base = Path::new("");
+
+        assert_eq!(
+            Err(ProjectError::MissingEntry),
+            Manifest::parse("file = a.lox", base)
+        );
+        assert_eq!(
+            Err(ProjectError::DuplicateEntry),
+            Manifest::parse("entry = a.lox\nentry = b.lox", base)
+        );
+        assert_eq!(
+            Err(ProjectError::InvalidLine(2)),
+            Manifest::parse("entry = a.lox\nfile", base)
+        );
+        assert_eq!(
+            Err(ProjectError::UnknownKey("main".to_string())),
+            Manifest::parse("main = a.lox", base)
+        );
+    }
+
+    #[test]
+    fn cross_file_globals() {
+        let dir = std::env::temp_dir().join(format!("lox-project-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.lox"), "var greeting = \"hello\";").unwrap();
+        fs::write(dir.join("main.lox"), "print greeting;").unwrap();
+        fs::write(
+            dir.join(MANIFEST_NAME),
+            "file = lib.lox\nentry = main.lox\n",
+        )
+        .unwrap();
+
+        let script = Manifest::load(dir.join(MANIFEST_NAME))
+            .unwrap()
+            .compile(&CompileOptions::default())
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // DefineGlobal in lib.lox, GetGlobal in main.lox, sharing the name's constant
+        assert_eq!(
+            vec![1, 1, 16, 0, 17, 0, 14, 2, 0],
+            script.script().chunk.code
+        );
+    }
+}
@@ -1,39 +1,151 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 
 use crate::error::*;
+use crate::lang::{Extension, Lang};
 use crate::token::{Token, TokenType};
 
 use anyhow::Result;
 
+/// How deeply `#include`s may nest, so a chain of includes too long to be deliberate stops with
+/// an error rather than exhausting memory.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
 #[derive(Debug)]
 pub(crate) struct Scanner {
     source: String,
+    /// Byte offsets of the start of the token being scanned and of the next character.
     pub start: usize,
     pub current: usize,
     pub line: usize,
+    /// The columns of `start` and `current`, counted in characters from 1.
+    start_column: usize,
+    column: usize,
+    pub file: Option<Rc<str>>,
+    pub lang: Lang,
+    pub template: bool,
+    region: Region,
+    pending: VecDeque<Token>,
+    includes: Vec<Include>,
+    /// Every file read for an `#include`, in the order they were read.
+    included: Vec<String>,
+}
+
+/// Which part of a template the scanner is in. Outside of template mode this stays `Text`.
+#[derive(Debug, PartialEq)]
+enum Region {
+    Text,
+    Expression,
+    Statements,
+}
+
+/// Scanner state of a file suspended by an `#include`, resumed once the included file is
+/// exhausted.
+#[derive(Debug)]
+struct Include {
+    source: String,
+    current: usize,
+    line: usize,
+    column: usize,
+    file: Option<Rc<str>>,
 }
 
 impl Scanner {
     pub fn new(source: String) -> Scanner {
         Scanner {
-            source,
+            source: crate::source::strip_bom(source),
             start: 0,
             current: 0,
             line: 1,
+            start_column: 1,
+            column: 1,
+            file: None,
+            lang: Lang::default(),
+            template: false,
+            region: Region::Text,
+            pending: VecDeque::new(),
+            includes: Vec::new(),
+            included: Vec::new(),
+        }
+    }
+
+    pub fn with_file(source: String, file: Option<String>) -> Scanner {
+        Scanner {
+            file: file.map(Rc::from),
+            ..Scanner::new(source)
         }
     }
 
+    /// The text of `line` in `file`, if that's the source being scanned or one suspended by an
+    /// `#include`.
+    pub fn source_line(&self, file: Option<&str>, line: usize) -> Option<String> {
+        let source = if self.file.as_deref() == file {
+            &self.source
+        } else {
+            let include = self.includes.iter().find(|i| i.file.as_deref() == file)?;
+            &include.source
+        };
+        source.lines().nth(line.checked_sub(1)?).map(String::from)
+    }
+
+    /// The files read for `#include`s so far.
+    pub fn included(&self) -> &[String] {
+        &self.included
+    }
+
+    /// Returns the token after the one most recently scanned, without consuming it.
+    pub fn peek_token(&mut self) -> Result<&Token> {
+        if self.pending.is_empty() {
+            // Template text may queue tokens of its own behind the one returned
+            let token = self.scan_token()?;
+            self.pending.push_front(token);
+        }
+        Ok(&self.pending[0])
+    }
+
     pub fn scan_token(&mut self) -> Result<Token> {
-        self.skip_whitespace();
-        self.start = self.current;
+        if let Some(token) = self.pending.pop_front() {
+            return Ok(token);
+        }
+        if self.template && self.region == Region::Text {
+            return self.template_text();
+        }
+
+        self.skip_whitespace()?;
+        self.mark_start();
         if let Some(c) = self.next() {
             let token = match c {
+                '}' if self.region == Region::Expression && self.next_is('}') => {
+                    self.region = Region::Text;
+                    self.make_token(TokenType::Semicolon)
+                }
+                '%' if self.region == Region::Statements && self.next_is('}') => {
+                    self.region = Region::Text;
+                    return self.scan_token();
+                }
                 '(' => self.make_token(TokenType::LeftParen),
                 ')' => self.make_token(TokenType::RightParen),
                 '{' => self.make_token(TokenType::LeftBrace),
                 '}' => self.make_token(TokenType::RightBrace),
+                '|' if self.next_is('>') => {
+                    if !self.lang.allows(Extension::Pipeline) {
+                        return Err(ParseError::ExtensionDisabled(Extension::Pipeline).into());
+                    }
+                    self.make_token(TokenType::Pipe)
+                }
+                '[' => self.make_token(TokenType::LeftBracket),
+                ']' => self.make_token(TokenType::RightBracket),
                 ',' => self.make_token(TokenType::Comma),
+                ':' => self.make_token(TokenType::Colon),
                 '.' => self.make_token(TokenType::Dot),
+                '?' if self.next_is('.') => {
+                    if !self.lang.allows(Extension::SafeNavigation) {
+                        return Err(ParseError::ExtensionDisabled(Extension::SafeNavigation).into());
+                    }
+                    self.make_token(TokenType::QuestionDot)
+                }
                 '-' => self.make_token(TokenType::Minus),
                 '+' => self.make_token(TokenType::Plus),
                 ';' => self.make_token(TokenType::Semicolon),
@@ -68,18 +180,180 @@ impl Scanner {
                     }
                 }
                 '"' => self.string()?,
+                'b' | 'x' if self.peek() == Some('"') && self.lang.allows(Extension::Bytes) => {
+                    self.bytes()?
+                }
                 n if n.is_ascii_digit() => self.number()?,
                 i if (i.is_ascii_alphabetic() || i == '_') => self.identifier()?,
-                t => todo!("{}", t),
+                '#' => {
+                    self.directive()?;
+                    return self.scan_token();
+                }
+                c => {
+                    return Err(ParseError::UnexpectedCharacter {
+                        ch: c,
+                        line: self.line,
+                        column: self.start_column,
+                    }
+                    .into());
+                }
             };
 
             Ok(token)
+        } else if let Some(include) = self.includes.pop() {
+            self.source = include.source;
+            self.current = include.current;
+            self.line = include.line;
+            self.column = include.column;
+            self.file = include.file;
+            self.scan_token()
         } else {
             Ok(self.make_token(TokenType::Eof))
         }
     }
 
-    fn skip_whitespace(&mut self) {
+    /// Scans literal template text up to the next `{{` or `{%`. The text is handed to the compiler
+    /// as `Echo "text" ;` and an expression region as `Echo expr ;`, so the template compiles
+    /// like an ordinary program and statements are free to span several regions.
+    fn template_text(&mut self) -> Result<Token> {
+        if !self.lang.allows(Extension::Template) {
+            // The rest of the input isn't Lox, so don't try to scan it as such
+            self.template = false;
+            self.source.clear();
+            self.current = 0;
+            self.column = 1;
+            return Err(ParseError::ExtensionDisabled(Extension::Template).into());
+        }
+
+        self.mark_start();
+        let line = self.line;
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '{' && matches!(self.peek_next(), Some('{') | Some('%')) {
+                break;
+            }
+            if c == '\n' {
+                self.line += 1;
+            }
+            text.push(c);
+            let _ = self.next();
+        }
+
+        if !text.is_empty() {
+            let file = self.file.clone();
+            self.pending.extend([
+                Token::new(TokenType::Echo, String::new(), line, file.clone()),
+                Token::new(
+                    TokenType::String,
+                    format!("\"{}\"", text),
+                    line,
+                    file.clone(),
+                ),
+                Token::new(TokenType::Semicolon, String::new(), self.line, file),
+            ]);
+        }
+
+        self.mark_start();
+        match (self.next(), self.next()) {
+            (Some('{'), Some('{')) => {
+                self.region = Region::Expression;
+                let token = self.make_token(TokenType::Echo);
+                self.pending.push_back(token);
+            }
+            (Some('{'), Some('%')) => self.region = Region::Statements,
+            _ => {
+                self.current = self.start;
+                self.column = self.start_column;
+                let token = self.make_token(TokenType::Eof);
+                self.pending.push_back(token);
+            }
+        }
+
+        self.scan_token()
+    }
+
+    /// Handles a `#include "path"` line by suspending the current source and continuing with the
+    /// included file. Paths are relative to the including file.
+    fn directive(&mut self) -> Result<()> {
+        let loc = ErrorLoc {
+            line: self.line,
+            at: self.start,
+        };
+
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+            name.push(c);
+            let _ = self.next();
+        }
+        if name != "include" {
+            return Err(ParseError::UnknownDirective(name, loc).into());
+        }
+        if !self.lang.allows(Extension::Include) {
+            return Err(ParseError::ExtensionDisabled(Extension::Include).into());
+        }
+
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            let _ = self.next();
+        }
+        if !self.next_is('"') {
+            return Err(ParseError::MalformedInclude(loc).into());
+        }
+        let mut path = String::new();
+        while let Some(c) = self.peek().filter(|c| *c != '"' && *c != '\n') {
+            path.push(c);
+            let _ = self.next();
+        }
+        if !self.next_is('"') {
+            return Err(ParseError::MalformedInclude(loc).into());
+        }
+        while matches!(self.peek(), Some(' ') | Some('\t') | Some('\r')) {
+            let _ = self.next();
+        }
+        if self.peek().is_some_and(|c| c != '\n') {
+            return Err(ParseError::MalformedInclude(loc).into());
+        }
+
+        let path = match self
+            .file
+            .as_deref()
+            .and_then(|file| Path::new(file).parent())
+        {
+            Some(dir) => dir.join(path),
+            None => path.into(),
+        };
+        let path = path.display().to_string();
+
+        // The same file can be reached by different paths, e.g. `lib/../main.lox`
+        let canonical =
+            |file: &str| std::fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file));
+        let target = canonical(&path);
+        let active = std::iter::once(&self.file).chain(self.includes.iter().map(|i| &i.file));
+        if active.flatten().any(|file| canonical(file) == target) {
+            return Err(ParseError::RecursiveInclude(path).into());
+        }
+        if self.includes.len() >= MAX_INCLUDE_DEPTH {
+            return Err(ParseError::IncludeTooDeep(path, MAX_INCLUDE_DEPTH).into());
+        }
+
+        let source = crate::source::read(&path)
+            .map_err(|e| ParseError::IncludeFailed(path.clone(), e.to_string()))?;
+        self.included.push(path.clone());
+
+        self.includes.push(Include {
+            source: std::mem::replace(&mut self.source, source),
+            current: self.current,
+            line: self.line,
+            column: self.column,
+            file: self.file.replace(Rc::from(path)),
+        });
+        self.current = 0;
+        self.line = 1;
+        self.column = 1;
+
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) -> Result<()> {
         loop {
             if let Some(c) = self.peek() {
                 match c {
@@ -90,31 +364,62 @@ impl Scanner {
                         self.line += 1;
                         self.next();
                     }
-                    '/' => {
-                        if self.peek_next() == Some('/') {
-                            while self.peek().is_some() && self.peek().unwrap() != '\n' {
-                                self.next();
-                            }
-                        } else {
-                            return;
+                    '/' if self.peek_next() == Some('/') => {
+                        while self.peek().is_some() && self.peek().unwrap() != '\n' {
+                            self.next();
                         }
                     }
-                    _ => return,
+                    '/' if self.peek_next() == Some('*')
+                        && self.lang.allows(Extension::BlockComments) =>
+                    {
+                        self.block_comment()?
+                    }
+                    _ => return Ok(()),
                 }
             } else {
-                return;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Skips a `/* ... */` comment, including any block comments nested inside it.
+    fn block_comment(&mut self) -> Result<()> {
+        let line = self.line;
+        let mut depth = 0;
+        loop {
+            match (self.peek(), self.peek_next()) {
+                (Some('/'), Some('*')) => {
+                    self.next();
+                    self.next();
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.next();
+                    self.next();
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                (Some(c), _) => {
+                    if c == '\n' {
+                        self.line += 1;
+                    }
+                    self.next();
+                }
+                (None, _) => return Err(ParseError::UnterminatedComment { line }.into()),
             }
         }
     }
 
     fn make_token(&mut self, t: TokenType) -> Token {
-        let lexeme = self
-            .source
-            .chars()
-            .skip(self.start)
-            .take(self.current - self.start)
-            .collect::<String>();
-        Token::new(t, lexeme, self.line)
+        let lexeme = self.source[self.start..self.current].to_string();
+        Token {
+            column: self.start_column,
+            start: self.start,
+            len: lexeme.len(),
+            ..Token::new(t, lexeme, self.line, self.file.clone())
+        }
     }
 
     fn string(&mut self) -> Result<Token> {
@@ -137,6 +442,32 @@ impl Scanner {
         Ok(self.make_token(TokenType::String))
     }
 
+    /// Scans a byte array literal after its `b` or `x` prefix. Unlike strings these have
+    /// escapes, so a `\"` doesn't end the literal. Escapes are decoded by the compiler.
+    fn bytes(&mut self) -> Result<Token> {
+        let _ = self.next();
+        while let Some(c) = self.peek().filter(|c| *c != '"') {
+            if c == '\n' {
+                self.line += 1;
+            }
+            let _ = self.next();
+            if c == '\\' && self.peek().is_some() {
+                let _ = self.next();
+            }
+        }
+
+        if self.peek().is_none() {
+            return Err(ParseError::UnterminatedString(ErrorLoc {
+                line: self.line,
+                at: self.start,
+            })
+            .into());
+        }
+        let _ = self.next();
+
+        Ok(self.make_token(TokenType::Bytes))
+    }
+
     fn number(&mut self) -> Result<Token> {
         while self.peek().filter(char::is_ascii_digit).is_some() {
             let _ = self.next();
@@ -161,36 +492,60 @@ impl Scanner {
             let _ = self.next();
         }
 
-        let text = self
-            .source
-            .chars()
-            .skip(self.start)
-            .take(self.current - self.start)
-            .collect::<String>();
-
-        if let Ok(token_type) = TokenType::from_str(&text) {
-            Ok(self.make_token(token_type))
-        } else {
-            Ok(self.make_token(TokenType::Identifier))
+        match TokenType::from_str(&self.source[self.start..self.current]) {
+            Ok(TokenType::Defer) if !self.lang.allows(Extension::Defer) => {
+                Ok(self.make_token(TokenType::Identifier))
+            }
+            Ok(TokenType::Break | TokenType::Continue)
+                if !self.lang.allows(Extension::LoopControl) =>
+            {
+                Ok(self.make_token(TokenType::Identifier))
+            }
+            Ok(TokenType::Yield) if !self.lang.allows(Extension::Generators) => {
+                Ok(self.make_token(TokenType::Identifier))
+            }
+            Ok(TokenType::Switch | TokenType::Case | TokenType::Default)
+                if !self.lang.allows(Extension::Switch) =>
+            {
+                Ok(self.make_token(TokenType::Identifier))
+            }
+            Ok(TokenType::Try | TokenType::Catch | TokenType::Throw)
+                if !self.lang.allows(Extension::Exceptions) =>
+            {
+                Ok(self.make_token(TokenType::Identifier))
+            }
+            Ok(TokenType::StaticAssert) if !self.lang.allows(Extension::StaticAssert) => {
+                Ok(self.make_token(TokenType::Identifier))
+            }
+            Ok(token_type) => Ok(self.make_token(token_type)),
+            Err(_) => Ok(self.make_token(TokenType::Identifier)),
         }
     }
 
+    /// Starts the next token at the current character.
+    fn mark_start(&mut self) {
+        self.start = self.current;
+        self.start_column = self.column;
+    }
+
     fn next(&mut self) -> Option<char> {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1)
+        let c = self.peek()?;
+        self.current += c.len_utf8();
+        self.column = if c == '\n' { 1 } else { self.column + 1 };
+        Some(c)
     }
 
     fn peek(&self) -> Option<char> {
-        self.source.chars().nth(self.current)
+        self.source[self.current..].chars().next()
     }
 
     fn peek_next(&self) -> Option<char> {
-        self.source.chars().nth(self.current + 1)
+        self.source[self.current..].chars().nth(1)
     }
 
     fn next_is(&mut self, c: char) -> bool {
         if self.peek() == Some(c) {
-            self.current += 1;
+            self.next();
             true
         } else {
             false
@@ -257,6 +612,150 @@ mod test {
         assert_eq!(TokenType::Eof, scanner.scan_token().unwrap().token_type);
     }
 
+    #[test]
+    fn block_comments() {
+        let input = String::from("1 /* one\n /* two */\n */ 2 /**/ 3 /* open");
+        let mut scanner = Scanner::new(input);
+
+        assert_eq!(1, scanner.scan_token().unwrap().line);
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(("2", 3), (token.lexeme.as_str(), token.line));
+        assert_eq!("3", scanner.scan_token().unwrap().lexeme);
+        let e = scanner.scan_token().unwrap_err();
+        assert_eq!(
+            Some(&ParseError::UnterminatedComment { line: 3 }),
+            e.downcast_ref()
+        );
+        assert_eq!("Unterminated block comment.", e.to_string());
+
+        let mut scanner = Scanner::new(String::from("/* x */"));
+        scanner.lang = Lang::Strict;
+        assert_eq!(TokenType::Slash, scanner.scan_token().unwrap().token_type);
+    }
+
+    #[test]
+    fn unexpected_characters() {
+        let mut scanner = Scanner::new(String::from("a\n @ b"));
+
+        assert_eq!("a", scanner.scan_token().unwrap().lexeme);
+        let e = scanner.scan_token().unwrap_err();
+        assert_eq!("unexpected character '@'", e.to_string());
+        assert_eq!(
+            Some(&ParseError::UnexpectedCharacter {
+                ch: '@',
+                line: 2,
+                column: 2
+            }),
+            e.downcast_ref()
+        );
+        // Scanning carries on after the character
+        assert_eq!("b", scanner.scan_token().unwrap().lexeme);
+    }
+
+    #[test]
+    fn spans() {
+        let source = String::from("var s = \"é\";\n  print s;");
+        let mut scanner = Scanner::new(source.clone());
+
+        let spans: Vec<_> = std::iter::from_fn(|| {
+            let token = scanner.scan_token().unwrap();
+            (token.token_type != TokenType::Eof).then_some((
+                token.line,
+                token.column,
+                token.start,
+                token.len,
+            ))
+        })
+        .collect();
+        assert_eq!(
+            vec![
+                (1, 1, 0, 3),
+                (1, 5, 4, 1),
+                (1, 7, 6, 1),
+                (1, 9, 8, 4),
+                (1, 12, 12, 1),
+                (2, 3, 16, 5),
+                (2, 9, 22, 1),
+                (2, 10, 23, 1),
+            ],
+            spans
+        );
+        // Each span maps back to its lexeme
+        assert_eq!("\"é\"", &source[8..12]);
+        assert_eq!("print", &source[16..21]);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("lox-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.lox"), "\n\nfoo").unwrap();
+        std::fs::write(dir.join("self.lox"), "#include \"self.lox\"").unwrap();
+        let main = dir.join("main.lox").display().to_string();
+        let lib = dir.join("lib.lox").display().to_string();
+
+        let input = String::from("one\n#include \"lib.lox\"\ntwo");
+        let mut scanner = Scanner::with_file(input, Some(main.clone()));
+
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(
+            ("one", 1, Some(main.as_str())),
+            (token.lexeme.as_str(), token.line, token.file.as_deref())
+        );
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(
+            ("foo", 3, Some(lib.as_str())),
+            (token.lexeme.as_str(), token.line, token.file.as_deref())
+        );
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(
+            ("two", 3, Some(main.as_str())),
+            (token.lexeme.as_str(), token.line, token.file.as_deref())
+        );
+        assert_eq!(TokenType::Eof, scanner.scan_token().unwrap().token_type);
+        assert_eq!([lib], scanner.included());
+
+        let input = String::from("#include \"self.lox\"");
+        let mut scanner = Scanner::with_file(input, Some(main));
+        assert!(scanner.scan_token().is_err());
+
+        // A cycle through a path spelled differently is caught too
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/a.lox"), "#include \"../sub/a.lox\"").unwrap();
+        let input = String::from("#include \"sub/a.lox\"");
+        let mut scanner =
+            Scanner::with_file(input, Some(dir.join("main.lox").display().to_string()));
+        let e = scanner.scan_token().unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(ParseError::RecursiveInclude(_))
+        ));
+
+        // As is a chain of includes too long to finish
+        for i in 0..=MAX_INCLUDE_DEPTH {
+            let include = format!("#include \"chain{}.lox\"", i + 1);
+            std::fs::write(dir.join(format!("chain{}.lox", i)), include).unwrap();
+        }
+        let input = String::from("#include \"chain0.lox\"");
+        let mut scanner =
+            Scanner::with_file(input, Some(dir.join("main.lox").display().to_string()));
+        let e = scanner.scan_token().unwrap_err();
+        assert_eq!(
+            Some(&ParseError::IncludeTooDeep(
+                dir.join("chain64.lox").display().to_string(),
+                MAX_INCLUDE_DEPTH
+            )),
+            e.downcast_ref()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut scanner = Scanner::new(String::from("#include lib.lox"));
+        assert!(scanner.scan_token().is_err());
+        let mut scanner = Scanner::new(String::from("#define"));
+        assert!(scanner.scan_token().is_err());
+    }
+
     // #[test]
     // fn test_comments() {
     //     let input = String::from("// This should be ignored");
@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+
+use crate::chunk::Value;
+use crate::error::LoxResult;
+use crate::program::Program;
+use crate::vm::{Progress, VM};
+
+/// Identifies a script added to a `Scheduler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TaskId(usize);
+
+/// A script and the VM running it, paused between slices.
+struct Task {
+    id: TaskId,
+    vm: VM,
+    /// How many instructions the script runs each time it gets a turn.
+    fuel: usize,
+}
+
+/// Runs many scripts cooperatively on one thread, taking turns in the order they were added. Each
+/// turn runs a script for its slice of fuel, then pauses it until its next turn, so a host can
+/// give every script a little time each frame without any of them hogging it.
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: VecDeque<Task>,
+    next_id: usize,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    /// Adds `program` to run on `vm`, `fuel` instructions per turn. It doesn't start running
+    /// until the next `tick`. The id is returned with its result once it finishes.
+    pub fn add(&mut self, mut vm: VM, program: &Program, fuel: usize) -> LoxResult<TaskId> {
+        let id = TaskId(self.next_id);
+        self.next_id += 1;
+
+        vm.start(program)?;
+        self.tasks.push_back(Task {
+            id,
+            vm,
+            fuel: fuel.max(1),
+        });
+        Ok(id)
+    }
+
+    /// Gives every script one turn. Scripts that finish or fail during their turn are removed,
+    /// and returned with their results in the order they ran.
+    pub fn tick(&mut self) -> Vec<(TaskId, LoxResult<Value>)> {
+        let mut finished = Vec::new();
+        for _ in 0..self.tasks.len() {
+            let mut task = self
+                .tasks
+                .pop_front()
+                .expect("task count changed during tick");
+            match task.vm.run_for(task.fuel) {
+                Ok(Progress::Paused) => self.tasks.push_back(task),
+                Ok(Progress::Finished(result)) => finished.push((task.id, Ok(result))),
+                Err(e) => finished.push((task.id, Err(e))),
+            }
+        }
+        finished
+    }
+
+    /// Ticks until every script has finished, returning all their results.
+    pub fn run(&mut self) -> Vec<(TaskId, LoxResult<Value>)> {
+        let mut finished = Vec::new();
+        while !self.is_empty() {
+            finished.extend(self.tick());
+        }
+        finished
+    }
+
+    /// Drops a script without letting it finish. Returns whether it was still running.
+    pub fn cancel(&mut self, id: TaskId) -> bool {
+        let len = self.tasks.len();
+        self.tasks.retain(|task| task.id != id);
+        self.tasks.len() != len
+    }
+
+    /// How many scripts are still running.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::CompileOptions;
+    use crate::vm::test::Buffer;
+
+    fn program(source: &str) -> Program {
+        crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn round_robin() {
+        let out = Buffer::default();
+        let counter = program("for (var i = 0; i < 3; i = i + 1) print i; return \"counted\";");
+        let failing = program("print \"fail\"; nil();");
+
+        let mut scheduler = Scheduler::new();
+        let a = scheduler.add(VM::with_output(Box::new(out.clone())), &counter, 10);
+        let b = scheduler.add(VM::with_output(Box::new(out.clone())), &failing, 100);
+        let c = scheduler.add(VM::with_output(Box::new(out.clone())), &counter, 10);
+        let (a, b, c) = (a.unwrap(), b.unwrap(), c.unwrap());
+        assert_eq!(3, scheduler.len());
+
+        let finished = scheduler.tick();
+        assert_eq!(1, finished.len());
+        assert_eq!(b, finished[0].0);
+        assert!(finished[0].1.is_err());
+        assert_eq!(2, scheduler.len());
+
+        assert!(scheduler.cancel(c));
+        assert!(!scheduler.cancel(c));
+
+        let finished = scheduler.run();
+        assert_eq!(1, finished.len());
+        assert_eq!(a, finished[0].0);
+        assert_eq!(
+            Value::from_string("counted".to_string()),
+            *finished[0].1.as_ref().unwrap()
+        );
+        assert!(scheduler.is_empty());
+        // The counters' prints interleave, with the failing script running in between
+        assert!(out.contents().starts_with("0\nfail\n0\n"));
+        assert!(out.contents().ends_with("1\n2\n"));
+    }
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use crate::error::{EncodingError, LoxResult};
+
+const UTF8_BOM: &str = "\u{feff}";
+
+/// Reads a source file, which must be UTF-8. A leading byte order mark is dropped, since some
+/// editors add one, and anything else that isn't UTF-8 is reported with where it goes wrong
+/// rather than being scanned as garbage.
+pub fn read<P: AsRef<Path>>(path: P) -> LoxResult<String> {
+    let bytes = std::fs::read(path)?;
+    Ok(decode(bytes)?)
+}
+
+/// Converts the bytes of a source file to text, as `read` does.
+pub fn decode(bytes: Vec<u8>) -> Result<String, EncodingError> {
+    if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) {
+        return Err(EncodingError::Utf16);
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(source) => Ok(strip_bom(source)),
+        Err(e) => {
+            let offset = e.utf8_error().valid_up_to();
+            let line = e.as_bytes()[..offset]
+                .iter()
+                .filter(|b| **b == b'\n')
+                .count()
+                + 1;
+            Err(EncodingError::InvalidUtf8 { offset, line })
+        }
+    }
+}
+
+/// Drops a byte order mark from the start of `source`, if it has one.
+pub fn strip_bom(source: String) -> String {
+    match source.strip_prefix(UTF8_BOM) {
+        Some(rest) => rest.to_string(),
+        None => source,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decoding() {
+        assert_eq!(
+            "print 1;",
+            decode(b"\xef\xbb\xbfprint 1;".to_vec()).unwrap()
+        );
+        assert_eq!("print \"é\";", decode("print \"é\";".into()).unwrap());
+        assert_eq!(
+            Err(EncodingError::InvalidUtf8 {
+                offset: 15,
+                line: 2
+            }),
+            decode(b"print 1;\nprint \xe9;".to_vec())
+        );
+        assert_eq!(Err(EncodingError::Utf16), decode(b"\xff\xfep\x00".to_vec()));
+    }
+}
@@ -0,0 +1,228 @@
+use std::str::FromStr;
+
+use crate::error::ParseError;
+use crate::token::{TokenCategory, TokenType};
+
+/// Editor highlighting formats that can be generated from the scanner's token tables.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntaxFormat {
+    /// A `lox.tmLanguage.json` grammar, understood by VS Code, Sublime Text and friends.
+    TextMate,
+    /// A `highlights.scm` query for a tree-sitter grammar whose anonymous nodes are the
+    /// keyword and operator lexemes.
+    TreeSitter,
+}
+
+impl FromStr for SyntaxFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "textmate" => Ok(SyntaxFormat::TextMate),
+            "tree-sitter" => Ok(SyntaxFormat::TreeSitter),
+            _ => Err(ParseError::UnknownSyntaxFormat(s.to_string())),
+        }
+    }
+}
+
+/// Highlighting classes, named after the TextMate scope each one maps to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Class {
+    Control,
+    Logical,
+    Storage,
+    Constant,
+    Variable,
+    Builtin,
+}
+
+impl Class {
+    const ALL: &'static [Class] = &[
+        Class::Control,
+        Class::Logical,
+        Class::Storage,
+        Class::Constant,
+        Class::Variable,
+        Class::Builtin,
+    ];
+
+    fn textmate_scope(&self) -> &'static str {
+        match self {
+            Class::Control => "keyword.control.lox",
+            Class::Logical => "keyword.operator.logical.lox",
+            Class::Storage => "storage.type.lox",
+            Class::Constant => "constant.language.lox",
+            Class::Variable => "variable.language.lox",
+            Class::Builtin => "support.function.builtin.lox",
+        }
+    }
+
+    fn tree_sitter_capture(&self) -> &'static str {
+        match self {
+            Class::Control => "@keyword",
+            Class::Logical => "@keyword.operator",
+            Class::Storage => "@keyword",
+            Class::Constant => "@constant.builtin",
+            Class::Variable => "@variable.builtin",
+            Class::Builtin => "@function.builtin",
+        }
+    }
+}
+
+fn classify(keyword: &TokenType) -> Class {
+    match keyword {
+        TokenType::And | TokenType::Or => Class::Logical,
+        TokenType::Class | TokenType::Fun | TokenType::Var => Class::Storage,
+        TokenType::True | TokenType::False | TokenType::Nil => Class::Constant,
+        TokenType::This | TokenType::Super => Class::Variable,
+        TokenType::Print | TokenType::StaticAssert => Class::Builtin,
+        _ => Class::Control,
+    }
+}
+
+/// The tokens with a fixed lexeme in `category`.
+fn tokens(category: TokenCategory) -> impl Iterator<Item = &'static TokenType> {
+    [
+        TokenType::KEYWORDS,
+        TokenType::OPERATORS,
+        TokenType::PUNCTUATION,
+    ]
+    .into_iter()
+    .flatten()
+    .filter(move |token| token.category() == category)
+}
+
+fn keywords(class: Class) -> Vec<String> {
+    tokens(TokenCategory::Keyword)
+        .filter(|keyword| classify(keyword) == class)
+        .map(|keyword| keyword.to_string())
+        .collect()
+}
+
+/// Operators longest first, so `==` is matched before `=`.
+fn operators() -> Vec<String> {
+    let mut operators: Vec<String> = tokens(TokenCategory::Operator)
+        .map(|o| o.to_string())
+        .collect();
+    operators.sort_by_key(|o| std::cmp::Reverse(o.len()));
+    operators
+}
+
+fn punctuation() -> Vec<String> {
+    tokens(TokenCategory::Punctuation)
+        .map(|p| p.to_string())
+        .collect()
+}
+
+pub fn generate(format: SyntaxFormat) -> String {
+    match format {
+        SyntaxFormat::TextMate => textmate(),
+        SyntaxFormat::TreeSitter => tree_sitter(),
+    }
+}
+
+fn textmate() -> String {
+    let mut patterns = vec![
+        String::from(r#"{"name":"comment.line.double-slash.lox","match":"//.*$"}"#),
+        String::from(r#"{"name":"comment.block.lox","begin":"/\\*","end":"\\*/"}"#),
+        String::from(r#"{"name":"meta.preprocessor.include.lox","match":"^\\s*#include\\b"}"#),
+        String::from(
+            r#"{"name":"string.quoted.double.lox","begin":"\"","end":"\"","patterns":[]}"#,
+        ),
+        String::from(r#"{"name":"constant.numeric.lox","match":"\\b[0-9]+(\\.[0-9]+)?\\b"}"#),
+    ];
+    for class in Class::ALL {
+        patterns.push(format!(
+            r#"{{"name":"{}","match":"\\b({})\\b"}}"#,
+            class.textmate_scope(),
+            keywords(*class).join("|")
+        ));
+    }
+    let operators = operators()
+        .iter()
+        .map(|o| o.chars().map(|c| format!("\\\\{}", c)).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("|");
+    patterns.push(format!(
+        r#"{{"name":"keyword.operator.lox","match":"{}"}}"#,
+        operators
+    ));
+    let punctuation = punctuation()
+        .iter()
+        .map(|p| format!("\\\\{}", p))
+        .collect::<String>();
+    patterns.push(format!(
+        r#"{{"name":"punctuation.lox","match":"[{}]"}}"#,
+        punctuation
+    ));
+
+    format!(
+        r#"{{"$schema":"https://raw.githubusercontent.com/martinring/tmlanguage/master/tmlanguage.json","name":"Lox","scopeName":"source.lox","fileTypes":["lox"],"patterns":[{}]}}"#,
+        patterns.join(",")
+    )
+}
+
+fn tree_sitter() -> String {
+    let mut out = String::from("; Generated by `lox --syntax=tree-sitter`\n\n");
+    out.push_str("(comment) @comment\n(string) @string\n(number) @number\n\n");
+    for class in Class::ALL {
+        let keywords = keywords(*class)
+            .iter()
+            .map(|k| format!("\"{}\"", k))
+            .collect::<Vec<_>>()
+            .join(" ");
+        out.push_str(&format!("[{}] {}\n", keywords, class.tree_sitter_capture()));
+    }
+    let operators = operators()
+        .iter()
+        .map(|o| format!("\"{}\"", o))
+        .collect::<Vec<_>>()
+        .join(" ");
+    out.push_str(&format!("\n[{}] @operator\n", operators));
+    let punctuation = punctuation()
+        .iter()
+        .map(|p| format!("\"{}\"", p))
+        .collect::<Vec<_>>()
+        .join(" ");
+    out.push_str(&format!("[{}] @punctuation\n", punctuation));
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn every_keyword_classified_once() {
+        let classified: usize = Class::ALL.iter().map(|c| keywords(*c).len()).sum();
+        assert_eq!(TokenType::KEYWORDS.len(), classified);
+
+        for keyword in TokenType::KEYWORDS {
+            assert_eq!(Ok(keyword.clone()), keyword.to_string().parse());
+        }
+    }
+
+    #[test]
+    fn textmate_grammar() {
+        let grammar = generate(SyntaxFormat::TextMate);
+
+        assert!(grammar.contains(
+            r#"{"name":"keyword.control.lox","match":"\\b(else|for|if|return|while|defer|break|continue|yield|switch|case|default|try|catch|throw)\\b"}"#
+        ));
+        assert!(grammar
+            .contains(r#"{"name":"constant.language.lox","match":"\\b(false|nil|true)\\b"}"#));
+        assert!(grammar.contains(r#""match":"\\!\\=|\\=\\=|"#));
+        assert!(grammar.contains(r#""match":"[\\(\\)\\{\\}\\[\\]\\,\\:\\.\\;]""#));
+    }
+
+    #[test]
+    fn tree_sitter_queries() {
+        let queries = generate(SyntaxFormat::TreeSitter);
+
+        assert!(queries.contains("[\"and\" \"or\"] @keyword.operator\n"));
+        assert!(queries.contains("[\"!=\" \"==\" \">=\" \"<=\" "));
+        assert!(queries.contains(
+            "[\"(\" \")\" \"{\" \"}\" \"[\" \"]\" \",\" \":\" \".\" \";\"] @punctuation\n"
+        ));
+    }
+}
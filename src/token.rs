@@ -1,3 +1,4 @@
+use std::rc::Rc;
 use std::str::FromStr;
 
 use crate::error::ParseError;
@@ -8,14 +9,31 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    pub file: Option<Rc<str>>,
+    /// Column of the token's first character, counted in characters from 1. Tokens made up by
+    /// the compiler or the template scanner, with no text of their own, are at column 0.
+    pub column: usize,
+    /// Byte offset of the token's text in its source.
+    pub start: usize,
+    /// Length of the token's text in bytes.
+    pub len: usize,
 }
 
 impl Token {
-    pub(crate) fn new(token_type: TokenType, lexeme: String, line: usize) -> Token {
+    pub(crate) fn new(
+        token_type: TokenType,
+        lexeme: String,
+        line: usize,
+        file: Option<Rc<str>>,
+    ) -> Token {
         Token {
             token_type,
             lexeme,
             line,
+            file,
+            column: 0,
+            start: 0,
+            len: 0,
         }
     }
 }
@@ -33,7 +51,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -50,11 +71,17 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    /// `|>`, passing the value on its left as the first argument to the call on its right.
+    Pipe,
+    /// `?.`, accessing a property unless the receiver is nil.
+    QuestionDot,
 
     // Literals
     Identifier,
     String,
     Number,
+    /// A byte array, written `b"..."` with escapes or `x"..."` in hex.
+    Bytes,
 
     // Keywords
     And,
@@ -73,18 +100,177 @@ pub enum TokenType {
     True,
     Var,
     While,
+    Defer,
+    Break,
+    Continue,
+    Yield,
+    Switch,
+    Case,
+    Default,
+    Try,
+    Catch,
+    Throw,
+    StaticAssert,
+
+    // Template output: emitted by the scanner in front of each `{{ expr }}` region and each run
+    // of literal text, which the compiler turns into a write to the output sink.
+    Echo,
 
     Eof,
 }
 
+impl TokenType {
+    /// Every reserved word, spelled as its `Display` form.
+    pub const KEYWORDS: &'static [TokenType] = &[
+        Self::And,
+        Self::Class,
+        Self::Else,
+        Self::False,
+        Self::Fun,
+        Self::For,
+        Self::If,
+        Self::Nil,
+        Self::Or,
+        Self::Print,
+        Self::Return,
+        Self::Super,
+        Self::This,
+        Self::True,
+        Self::Var,
+        Self::While,
+        Self::Defer,
+        Self::Break,
+        Self::Continue,
+        Self::Yield,
+        Self::Switch,
+        Self::Case,
+        Self::Default,
+        Self::Try,
+        Self::Catch,
+        Self::Throw,
+        Self::StaticAssert,
+    ];
+
+    /// Arithmetic, comparison and assignment operators.
+    pub const OPERATORS: &'static [TokenType] = &[
+        Self::Minus,
+        Self::Plus,
+        Self::Slash,
+        Self::Star,
+        Self::Bang,
+        Self::BangEqual,
+        Self::Equal,
+        Self::EqualEqual,
+        Self::Greater,
+        Self::GreaterEqual,
+        Self::Less,
+        Self::LessEqual,
+        Self::Pipe,
+        Self::QuestionDot,
+    ];
+
+    /// Brackets and separators.
+    pub const PUNCTUATION: &'static [TokenType] = &[
+        Self::LeftParen,
+        Self::RightParen,
+        Self::LeftBrace,
+        Self::RightBrace,
+        Self::LeftBracket,
+        Self::RightBracket,
+        Self::Comma,
+        Self::Colon,
+        Self::Dot,
+        Self::Semicolon,
+    ];
+
+    pub fn category(&self) -> TokenCategory {
+        match self {
+            Self::LeftParen
+            | Self::RightParen
+            | Self::LeftBrace
+            | Self::RightBrace
+            | Self::LeftBracket
+            | Self::RightBracket
+            | Self::Comma
+            | Self::Colon
+            | Self::Dot
+            | Self::Semicolon => TokenCategory::Punctuation,
+            Self::Minus
+            | Self::Plus
+            | Self::Slash
+            | Self::Star
+            | Self::Bang
+            | Self::BangEqual
+            | Self::Equal
+            | Self::EqualEqual
+            | Self::Greater
+            | Self::GreaterEqual
+            | Self::Less
+            | Self::LessEqual
+            | Self::Pipe
+            | Self::QuestionDot => TokenCategory::Operator,
+            Self::Identifier => TokenCategory::Identifier,
+            Self::String | Self::Number | Self::Bytes => TokenCategory::Literal,
+            Self::And
+            | Self::Class
+            | Self::Else
+            | Self::False
+            | Self::Fun
+            | Self::For
+            | Self::If
+            | Self::Nil
+            | Self::Or
+            | Self::Print
+            | Self::Return
+            | Self::Super
+            | Self::This
+            | Self::True
+            | Self::Var
+            | Self::While
+            | Self::Defer
+            | Self::Break
+            | Self::Continue
+            | Self::Yield
+            | Self::Switch
+            | Self::Case
+            | Self::Default
+            | Self::Try
+            | Self::Catch
+            | Self::Throw
+            | Self::StaticAssert => TokenCategory::Keyword,
+            Self::Echo | Self::Eof => TokenCategory::Other,
+        }
+    }
+}
+
+/// The broad kinds of token, as highlighters tell them apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenCategory {
+    /// Arithmetic, comparison and assignment operators, listed in `TokenType::OPERATORS`.
+    Operator,
+    /// Reserved words, listed in `TokenType::KEYWORDS`, including the literals `true`, `false`
+    /// and `nil`.
+    Keyword,
+    /// Strings, numbers and byte arrays.
+    Literal,
+    Identifier,
+    /// Brackets and separators, listed in `TokenType::PUNCTUATION`.
+    Punctuation,
+    /// Tokens the scanner makes up rather than reads, which have no lexeme.
+    Other,
+}
+
 impl std::fmt::Display for TokenType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::LeftParen => write!(f, "("),
             Self::RightParen => write!(f, ")"),
-            Self::LeftBrace => write!(f, "["),
-            Self::RightBrace => write!(f, "]"),
+            Self::LeftBrace => write!(f, "{{"),
+            Self::RightBrace => write!(f, "}}"),
+            Self::LeftBracket => write!(f, "["),
+            Self::RightBracket => write!(f, "]"),
             Self::Comma => write!(f, ","),
+            Self::Colon => write!(f, ":"),
             Self::Dot => write!(f, "."),
             Self::Minus => write!(f, "-"),
             Self::Plus => write!(f, "+"),
@@ -99,9 +285,12 @@ impl std::fmt::Display for TokenType {
             Self::GreaterEqual => write!(f, ">="),
             Self::Less => write!(f, "<"),
             Self::LessEqual => write!(f, "<="),
+            Self::Pipe => write!(f, "|>"),
+            Self::QuestionDot => write!(f, "?."),
             Self::Identifier => write!(f, "IDENTIFIER"),
             Self::String => write!(f, "STRING"),
             Self::Number => write!(f, "NUMBER"),
+            Self::Bytes => write!(f, "BYTES"),
             Self::And => write!(f, "and"),
             Self::Class => write!(f, "class"),
             Self::Else => write!(f, "else"),
@@ -118,6 +307,18 @@ impl std::fmt::Display for TokenType {
             Self::True => write!(f, "true"),
             Self::Var => write!(f, "var"),
             Self::While => write!(f, "while"),
+            Self::Defer => write!(f, "defer"),
+            Self::Break => write!(f, "break"),
+            Self::Continue => write!(f, "continue"),
+            Self::Yield => write!(f, "yield"),
+            Self::Switch => write!(f, "switch"),
+            Self::Case => write!(f, "case"),
+            Self::Default => write!(f, "default"),
+            Self::Try => write!(f, "try"),
+            Self::Catch => write!(f, "catch"),
+            Self::Throw => write!(f, "throw"),
+            Self::StaticAssert => write!(f, "static_assert"),
+            Self::Echo => write!(f, "{{{{"),
             Self::Eof => write!(f, "EOF"),
         }
     }
@@ -130,9 +331,12 @@ impl FromStr for TokenType {
         match s {
             "(" => Ok(Self::LeftParen),
             ")" => Ok(Self::RightParen),
-            "[" => Ok(Self::LeftBrace),
-            "]" => Ok(Self::RightBrace),
+            "{" => Ok(Self::LeftBrace),
+            "}" => Ok(Self::RightBrace),
+            "[" => Ok(Self::LeftBracket),
+            "]" => Ok(Self::RightBracket),
             "," => Ok(Self::Comma),
+            ":" => Ok(Self::Colon),
             "." => Ok(Self::Dot),
             "-" => Ok(Self::Minus),
             "+" => Ok(Self::Plus),
@@ -147,9 +351,8 @@ impl FromStr for TokenType {
             ">=" => Ok(Self::GreaterEqual),
             "<" => Ok(Self::Less),
             "<=" => Ok(Self::LessEqual),
-            // Identifier(i)=> write!(f, "Identifier({})", i)
-            // String(s)=> write!(f, "String({})", s)
-            // Number(n)=> write!(f, "Number({})", n)
+            "|>" => Ok(Self::Pipe),
+            "?." => Ok(Self::QuestionDot),
             "and" => Ok(Self::And),
             "class" => Ok(Self::Class),
             "else" => Ok(Self::Else),
@@ -166,7 +369,44 @@ impl FromStr for TokenType {
             "true" => Ok(Self::True),
             "var" => Ok(Self::Var),
             "while" => Ok(Self::While),
+            "defer" => Ok(Self::Defer),
+            "break" => Ok(Self::Break),
+            "continue" => Ok(Self::Continue),
+            "yield" => Ok(Self::Yield),
+            "switch" => Ok(Self::Switch),
+            "case" => Ok(Self::Case),
+            "default" => Ok(Self::Default),
+            "try" => Ok(Self::Try),
+            "catch" => Ok(Self::Catch),
+            "throw" => Ok(Self::Throw),
+            "static_assert" => Ok(Self::StaticAssert),
             _ => Err(ParseError::UnknownTokenType),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lexemes_round_trip() {
+        let lists = [
+            (TokenType::KEYWORDS, TokenCategory::Keyword),
+            (TokenType::OPERATORS, TokenCategory::Operator),
+            (TokenType::PUNCTUATION, TokenCategory::Punctuation),
+        ];
+        for (tokens, category) in lists {
+            for token in tokens {
+                assert_eq!(category, token.category(), "{:?}", token);
+                assert_eq!(Ok(token.clone()), token.to_string().parse(), "{:?}", token);
+            }
+        }
+
+        assert_eq!("{", TokenType::LeftBrace.to_string());
+        assert_eq!("]", TokenType::RightBracket.to_string());
+        for token in [TokenType::Identifier, TokenType::Number, TokenType::Eof] {
+            assert!(token.to_string().parse::<TokenType>().is_err());
+        }
+    }
+}
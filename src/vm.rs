@@ -1,98 +1,1098 @@
-use crate::chunk::{Chunk, OpCode, Value};
-use crate::error::{InterpretError, RuntimeError};
-use crate::LOX_TRACE_EXECUTION;
+use crate::chunk::{
+    heap_bytes, track_heap, BoundMethod, Class, Function, Generator, GeneratorState, HostFn,
+    HostFunction, Instance, Map, MapKey, NativeFn, OpCode, Value, ValueType, FIELD_SIZE,
+    MAP_ENTRY_SIZE,
+};
+use crate::compiler::CompileOptions;
+use crate::error::{
+    ChunkError, ConversionError, EvaluationError, Exit, LoxResult, NativeError, RuntimeError,
+    Unhandled,
+};
+use crate::intern::{intern, Symbol};
+use crate::natives::Capability;
+use crate::pool::ConstantPool;
+use crate::profile::Profile;
+use crate::program::Program;
 
 use anyhow::Result;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const STACK_MAX: u32 = 256;
+const FRAMES_MAX: usize = 64;
+/// How many values the stack holds by default, enough for every frame to have all its locals.
+pub const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
 
-pub struct VM<'a> {
-    chunk: &'a Chunk,
+/// A function invocation in progress.
+struct CallFrame {
+    function: Arc<Function>,
     ip: usize,
+    /// Stack index of the frame's slot zero, which holds the function itself.
+    slots: usize,
+    /// Where each of the chunk's constants lives in the VM's constant pool.
+    constants: Rc<[usize]>,
+    /// The generator this frame runs, which is suspended again when it yields.
+    generator: Option<Rc<RefCell<Generator>>>,
+}
+
+impl CallFrame {
+    /// The line of the instruction the frame is running, or is about to run if it's stopped
+    /// before its first one, e.g. by a limit checked after jumping back to the start.
+    fn line(&self) -> usize {
+        self.function
+            .chunk
+            .line_for_offset(self.ip.saturating_sub(1))
+    }
+}
+
+/// A `try` block in progress, which thrown values and runtime errors unwind to.
+struct Handler {
+    /// How many frames were active when the handler was registered; it belongs to the last.
+    frames: usize,
+    /// The stack height to unwind to before pushing the exception for the `catch` block.
+    stack: usize,
+    /// Where the `catch` block starts.
+    ip: usize,
+}
+
+/// How far a VM running on a limited amount of fuel got.
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    /// The program returned this value.
+    Finished(Value),
+    /// The fuel ran out; `run_for` carries on from where it stopped.
+    Paused,
+}
+
+/// Limits on what each script a VM runs may use, so untrusted scripts can't run forever or
+/// exhaust memory. Each is unlimited when `None`. Going over a limit stops the script with
+/// `RuntimeError::LimitExceeded`, which `catch` blocks don't handle. Threads the script spawns
+/// count against the same instruction, heap and time limits as the script itself.
+#[derive(Clone, Debug, Default)]
+pub struct VmOptions {
+    /// How many instructions a script may run.
+    pub max_instructions: Option<u64>,
+    /// How many values the stack may hold, `STACK_MAX` when `None`. Going over this one is a
+    /// stack overflow, as with `VM::set_stack_max`.
+    pub max_stack: Option<usize>,
+    /// How far the heap may grow while a script runs, in bytes, as roughly estimated from the
+    /// strings, byte arrays, lists, maps and instances alive on the VM's thread and those of the
+    /// threads its script spawned. It's checked between instructions, so a single native call can
+    /// go over it once.
+    pub max_heap_bytes: Option<usize>,
+    /// How long a script may take from starting, including time spent paused between
+    /// `VM::run_for` calls.
+    pub wall_clock_timeout: Option<Duration>,
+}
+
+/// What a script run with limits from `VmOptions` used, to help choose them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub instructions: u64,
+    /// The most the estimated size of the heap grew past what it was when the script started.
+    pub peak_heap_bytes: usize,
+    /// The most values the stack held between instructions.
+    pub peak_stack: usize,
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} instructions, peak heap {} bytes, peak stack {} values",
+            self.instructions, self.peak_heap_bytes, self.peak_stack
+        )
+    }
+}
+
+/// How much of its limits the running script has used.
+struct Usage {
+    used: ResourceUsage,
+    /// The estimated size of the heap when the script started.
+    heap_base: isize,
+    /// How much of the heap counted in `budget` this VM accounts for.
+    heap_shared: isize,
+    budget: Budget,
+}
+
+/// Whatever a VM allocated is freed along with it, or copied to the VM that joins its thread.
+impl Drop for Usage {
+    fn drop(&mut self) {
+        self.budget
+            .heap
+            .fetch_sub(self.heap_shared, Ordering::Relaxed);
+    }
+}
+
+/// What a script and the threads it spawned have used of their limits together, so spawning
+/// threads can't multiply them.
+#[derive(Clone, Debug)]
+pub(crate) struct Budget {
+    instructions: Arc<AtomicU64>,
+    /// How far the heaps of every thread grew, in bytes.
+    heap: Arc<AtomicIsize>,
+    /// When the script started, which the time its threads take counts from too.
+    started: Instant,
+}
+
+impl Budget {
+    fn new() -> Budget {
+        Budget {
+            instructions: Arc::default(),
+            heap: Arc::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// What a VM counts as it runs, once `VM::start_stats` is called.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunStats {
+    pub instructions: u64,
+    /// The most values the stack held between instructions, across every call in progress.
+    pub peak_stack: usize,
+}
+
+/// How values are treated when used as a condition by `if`, `while`, `for`, `and` and `or`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Truthiness {
+    /// `nil` and `false` are falsey and everything else is truthy, as in the book.
+    #[default]
+    Lox,
+    /// Only `true` and `false` are accepted; anything else is a runtime error.
+    Strict,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals: HashMap<String, Value>,
+    /// The most values `stack` may hold before the script stops with a stack overflow.
+    stack_max: usize,
+    /// Indexed by where the global's name is in the constant pool, which gives each distinct string
+    /// one index, so a global is found by two array lookups rather than by hashing its name.
+    /// `None` for names that aren't defined globals.
+    globals: Vec<Option<Value>>,
+    constants: ConstantPool,
+    out: Box<dyn Write>,
+    /// Where disassembly and execution traces go, stderr unless the host says otherwise.
+    debug_out: Box<dyn Write>,
+    /// Whether to list a program's code before running it.
+    disassemble: bool,
+    /// Whether to write out the stack and each instruction as it runs.
+    trace_execution: bool,
+    truthiness: Truthiness,
+    /// Whether arithmetic producing an infinity or NaN is a runtime error.
+    checked_arithmetic: bool,
+    /// What natives that reach outside the VM are allowed to do.
+    capabilities: Vec<Capability>,
+    /// Innermost last.
+    handlers: Vec<Handler>,
+    /// The runtime error last thrown to a handler, with the value it was thrown as, so that
+    /// throwing that value on past the last handler raises the error itself again.
+    unwinding: Option<(Value, anyhow::Error)>,
+    /// While reloading, the existing globals redefined so far.
+    reloading: Option<ReloadReport>,
+    /// Call counts, while profiling.
+    profile: Option<Profile>,
+    /// Called with the id of each probe run.
+    probe_handler: Option<Box<dyn FnMut(usize)>>,
+    /// Instruction counts, once asked for.
+    stats: Option<RunStats>,
+    /// Set by the host, often from a signal handler, to stop the running script.
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Whether stopping for `interrupt` clears it. Threads a script spawns leave it set for the
+    /// VM the host gave it to.
+    clears_interrupt: bool,
+    options: VmOptions,
+    /// Kept while a script runs with limits to check.
+    usage: Option<Usage>,
+    /// For the VM of a spawned thread, what it shares with the script that spawned it.
+    budget: Option<Budget>,
+    /// The state of the generator behind `random`.
+    random: u64,
 }
 
-impl<'a> VM<'a> {
-    pub fn interpret(source: String) -> Result<()> {
-        let chunk = crate::compiler::compile(source).map_err(|_| InterpretError::Compile)?;
+/// What natives called through `NativeFn::Context` can use of the VM calling them.
+pub struct NativeContext<'a> {
+    /// The state of the VM's generator behind `random`.
+    pub(crate) random: &'a mut u64,
+    capabilities: &'a [Capability],
+    options: &'a VmOptions,
+    stack_max: usize,
+    interrupt: Option<&'a Arc<AtomicBool>>,
+    usage: Option<&'a Usage>,
+}
+
+impl NativeContext<'_> {
+    /// What the VM of a thread started now takes over from the calling VM.
+    pub(crate) fn spawner(&self) -> Spawner {
+        Spawner {
+            capabilities: self.capabilities.to_vec(),
+            options: VmOptions {
+                max_stack: Some(self.stack_max),
+                ..self.options.clone()
+            },
+            interrupt: self.interrupt.cloned(),
+            budget: self.usage.map(|usage| usage.budget.clone()),
+        }
+    }
+}
+
+/// The capabilities, limits and interrupt flag of a VM whose script is starting a thread, for
+/// the thread's own VM, so a script can't escape them by running code on another thread.
+pub(crate) struct Spawner {
+    capabilities: Vec<Capability>,
+    options: VmOptions,
+    interrupt: Option<Arc<AtomicBool>>,
+    budget: Option<Budget>,
+}
+
+impl Spawner {
+    /// A VM for the new thread, with only the natives as globals.
+    pub(crate) fn vm(self) -> VM {
+        let mut vm = VM::new();
+        vm.capabilities = self.capabilities;
+        vm.set_options(self.options);
+        vm.interrupt = self.interrupt;
+        vm.budget = self.budget;
+        vm
+    }
+}
+
+/// What changed when a script was reloaded into a running VM.
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    /// Globals defined for the first time by the new source.
+    pub added: Vec<String>,
+    /// Globals the new source redefines, whose existing values were kept.
+    pub preserved: Vec<String>,
+    /// Functions and classes the new source redefines, which were replaced by their new
+    /// definitions.
+    pub replaced: Vec<String>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> VM {
+        VM::with_output(Box::new(std::io::stdout()))
+    }
 
+    /// Creates a VM whose `print` and template output goes to `out` rather than stdout.
+    pub fn with_output(out: Box<dyn Write>) -> VM {
         let mut vm = VM {
-            chunk: &chunk,
-            ip: 0,
-            stack: Vec::with_capacity(STACK_MAX as usize), // TODO: This is a "soft max"
-            globals: HashMap::new(),
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(STACK_MAX),
+            stack_max: STACK_MAX,
+            globals: Vec::new(),
+            constants: ConstantPool::default(),
+            out,
+            debug_out: Box::new(std::io::stderr()),
+            disassemble: false,
+            trace_execution: crate::trace_execution(),
+            truthiness: Truthiness::default(),
+            checked_arithmetic: false,
+            capabilities: Vec::new(),
+            handlers: Vec::new(),
+            unwinding: None,
+            reloading: None,
+            profile: None,
+            probe_handler: None,
+            stats: None,
+            interrupt: None,
+            clears_interrupt: false,
+            options: VmOptions::default(),
+            usage: None,
+            budget: None,
+            random: crate::math::random_seed(),
         };
+        for native in crate::natives::all() {
+            vm.define_global(native.name, Value::from_native(*native));
+        }
+        vm
+    }
 
-        vm.run()
+    /// Sends disassembly and execution traces to `out` rather than stderr.
+    pub fn set_debug_output(&mut self, out: Box<dyn Write>) {
+        self.debug_out = out;
     }
 
-    fn runtime_error(&mut self) -> Result<()> {
-        Err(InterpretError::Runtime.into())
+    /// Lists each program's code to the debug output before running it.
+    pub fn set_disassemble(&mut self, disassemble: bool) {
+        self.disassemble = disassemble;
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        self.chunk.disassemble("RUN");
-        loop {
-            if LOX_TRACE_EXECUTION.get() == Some(&true) {
-                print!("          ");
-                for item in &self.stack {
-                    print!("[ {} ]", item);
+    /// Writes the stack and each instruction to the debug output as it runs. Setting
+    /// `LOX_TRACE_EXECUTION` turns this on for every VM.
+    pub fn set_trace_execution(&mut self, trace: bool) {
+        self.trace_execution = trace;
+    }
+
+    pub fn set_truthiness(&mut self, truthiness: Truthiness) {
+        self.truthiness = truthiness;
+    }
+
+    /// Makes arithmetic that overflows to an infinity or produces NaN, such as dividing by zero,
+    /// raise a runtime error naming the line, rather than carrying on with the result.
+    pub fn set_checked_arithmetic(&mut self, checked: bool) {
+        self.checked_arithmetic = checked;
+    }
+
+    /// Makes `pow`, `sin`, `cos` and `random` give the same results on every platform and every
+    /// run, for replays and lockstep simulations. The maths is done in software rather than by
+    /// the platform's libm, and `random` restarts a fixed sequence of its own.
+    pub fn set_deterministic(&mut self) {
+        for native in crate::math::DETERMINISTIC {
+            self.define_global(native.name, Value::from_native(*native));
+        }
+        self.random = crate::math::DETERMINISTIC_SEED;
+    }
+
+    /// Defines a global function `name` that calls `function` with exactly `arity` arguments,
+    /// letting the host give scripts access to its own functionality. Errors `function` returns
+    /// are raised in the script as runtime errors, which it can catch. `FromLoxArgs` converts the
+    /// arguments to Rust types, and results convert with `Value::from`.
+    pub fn register_fn<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value> + 'static,
+    {
+        let function = HostFunction {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        };
+        self.define_global(name, Value::from_host_function(function));
+    }
+
+    fn define_global(&mut self, name: &str, value: Value) {
+        let index = self.constants.string(&intern(name));
+        self.set_global(index, value);
+    }
+
+    /// Sets the global whose name is at `index` in the constant pool, defining it if need be.
+    fn set_global(&mut self, index: usize, value: Value) {
+        if index >= self.globals.len() {
+            self.globals.resize_with(index + 1, || None);
+        }
+        self.globals[index] = Some(value);
+    }
+
+    fn global(&self, index: usize) -> Option<&Value> {
+        self.globals.get(index).and_then(Option::as_ref)
+    }
+
+    /// The names of the globals defined so far.
+    fn global_names(&self) -> Vec<Symbol> {
+        let defined = self.globals.iter().enumerate().filter(|(_, v)| v.is_some());
+        defined
+            .filter_map(|(index, _)| self.constants.get(index).as_symbol().cloned())
+            .collect()
+    }
+
+    /// Starts counting the calls to each function, for a `Profile` to compile with later.
+    pub fn start_profile(&mut self) {
+        self.profile.get_or_insert_with(Profile::default);
+    }
+
+    /// The calls counted since `start_profile`, if it was called.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Starts counting the instructions run and how deep the stack gets, which costs a little
+    /// for every instruction.
+    pub fn start_stats(&mut self) {
+        self.stats.get_or_insert_with(RunStats::default);
+    }
+
+    /// What's been counted since `start_stats`, if it was called.
+    pub fn stats(&self) -> Option<&RunStats> {
+        self.stats.as_ref()
+    }
+
+    /// Calls `handler` with the id of each probe as it runs, in programs compiled with
+    /// `CompileOptions::probes`. `Program::probes` says where each id is in the source.
+    pub fn set_probe_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(usize) + 'static,
+    {
+        self.probe_handler = Some(Box::new(handler));
+    }
+
+    /// Limits how many values the stack may hold, across every call in progress, to `values`
+    /// rather than `STACK_MAX`. Scripts that need more stop with a stack overflow error.
+    pub fn set_stack_max(&mut self, values: usize) {
+        self.stack_max = values;
+    }
+
+    /// Sets the limits on what scripts may use, for the scripts this VM runs from now on.
+    pub fn set_options(&mut self, options: VmOptions) {
+        self.stack_max = options.max_stack.unwrap_or(STACK_MAX);
+        self.options = options;
+    }
+
+    /// What the last script run with limits used, once it has finished or stopped. `None` if no
+    /// limits were set, apart from `max_stack`.
+    pub fn usage(&self) -> Option<&ResourceUsage> {
+        self.usage.as_ref().map(|usage| &usage.used)
+    }
+
+    /// Stops the running script with an `Interrupted` runtime error, which `catch` blocks don't
+    /// handle, once `flag` is set. The flag is checked before each instruction and cleared when
+    /// the script stops, so a host can set it from a Ctrl-C handler and run more code afterwards.
+    pub fn set_interrupt(&mut self, flag: Arc<AtomicBool>) {
+        self.interrupt = Some(flag);
+        self.clears_interrupt = true;
+    }
+
+    /// Allows scripts run by this VM to call natives needing `capability`.
+    pub fn grant(&mut self, capability: Capability) {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+    }
+
+    /// Compiles and runs a script against this VM's globals, returning what it returns. Globals
+    /// it defines stay defined, so later calls can use them.
+    pub fn interpret(&mut self, source: &str) -> LoxResult<Value> {
+        let script = crate::compiler::compile(source.to_string(), &CompileOptions::default())?;
+        self.run(&script)
+    }
+
+    pub fn execute(program: &Program, capabilities: &[Capability]) -> LoxResult<()> {
+        let mut vm = VM::new();
+        for capability in capabilities {
+            vm.grant(*capability);
+        }
+        vm.run(program).map(|_| ())
+    }
+
+    /// Evaluates a single expression, without a trailing `;`, against this VM's globals and
+    /// returns its value. Useful for hosts treating Lox as a formula or config language.
+    pub fn eval_expression(&mut self, source: &str) -> LoxResult<Value> {
+        let script =
+            crate::compiler::compile_expression(source.to_string(), &CompileOptions::default())?;
+        self.run(&script)
+    }
+
+    /// Recompiles and reruns a script in a VM that has already run a previous version of it.
+    /// Functions and classes take their new definitions, while other globals that already exist
+    /// keep their current values, so state built up by the running script survives the new code
+    /// being picked up.
+    pub fn reload(&mut self, source: &str) -> LoxResult<ReloadReport> {
+        let script = crate::compiler::compile(source.to_string(), &CompileOptions::default())?;
+
+        let before = self.global_names();
+        self.reloading = Some(ReloadReport::default());
+        let result = self.run(&script);
+        let mut report = self.reloading.take().unwrap_or_default();
+        result?;
+
+        report.added = self
+            .global_names()
+            .iter()
+            .filter(|name| !before.contains(name))
+            .map(|name| name.to_string())
+            .collect();
+        report.added.sort();
+
+        Ok(report)
+    }
+
+    /// Throws `error`'s message to the innermost `catch` block, if there is one. Otherwise
+    /// abandons execution, failing with `error` along with a trace of the calls in progress,
+    /// innermost first. The stack is left as it was before the failing instruction, so the
+    /// operands that caused the error are still there.
+    fn runtime_error<E: Into<anyhow::Error>>(&mut self, error: E) -> Result<()> {
+        let error = error.into();
+        if !self.handlers.is_empty() {
+            let exception = Value::from_string(error.to_string());
+            self.unwinding = Some((exception.clone(), error));
+            self.unwind(exception);
+            return Ok(());
+        }
+
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| (frame.line(), frame.function.name.clone()))
+            .collect();
+        Err(Unhandled { error, trace }.into())
+    }
+
+    /// Stops the script with `error`, without giving `catch` blocks the chance to handle it.
+    fn abort(&mut self, error: RuntimeError) -> Result<()> {
+        self.handlers.clear();
+        self.runtime_error(error)
+    }
+
+    /// Records the stack and heap as they are before the instruction about to run, and counts it
+    /// unless it would go over the instruction limit. Returns the error for the first limit the
+    /// script has gone over, if any.
+    fn check_limits(&mut self) -> Option<RuntimeError> {
+        let usage = self.usage.as_mut()?;
+        let used = &mut usage.used;
+        used.peak_stack = used.peak_stack.max(self.stack.len());
+        let heap = (heap_bytes() - usage.heap_base).max(0);
+        used.peak_heap_bytes = used.peak_heap_bytes.max(heap as usize);
+
+        let options = &self.options;
+        let budget = &usage.budget;
+        if let Some(max) = options.max_instructions {
+            if budget.instructions.fetch_add(1, Ordering::Relaxed) >= max {
+                return Some(RuntimeError::LimitExceeded("instruction", used.clone()));
+            }
+        }
+        used.instructions += 1;
+        let exceeded = |limit| Some(RuntimeError::LimitExceeded(limit, used.clone()));
+        if let Some(max) = options.max_heap_bytes {
+            let grown = heap - usage.heap_shared;
+            usage.heap_shared = heap;
+            if budget.heap.fetch_add(grown, Ordering::Relaxed) + grown > max as isize {
+                return exceeded("heap");
+            }
+        }
+        // Reading the clock costs more than an instruction, so it's only done now and then
+        if let Some(timeout) = options.wall_clock_timeout {
+            if used.instructions % 1024 == 0 && budget.started.elapsed() > timeout {
+                return exceeded("time");
+            }
+        }
+        None
+    }
+
+    /// Abandons everything the innermost handler's `try` block started, and continues in its
+    /// `catch` block with `exception` as the caught value.
+    fn unwind(&mut self, exception: Value) {
+        let handler = self.handlers.pop().expect("no handler to unwind to");
+        while self.frames.len() > handler.frames {
+            let frame = self.frames.pop().unwrap();
+            if let Some(generator) = frame.generator {
+                generator.borrow_mut().state = GeneratorState::Done;
+            }
+        }
+        self.stack.truncate(handler.stack);
+        self.stack.push(exception);
+        self.frames.last_mut().unwrap().ip = handler.ip;
+    }
+
+    /// Pops the top of the stack. The compiler never pops more than it pushed, so an empty stack
+    /// means the bytecode is malformed, which stops the script rather than panicking.
+    fn pop(&mut self) -> Result<Value> {
+        self.stack.pop().ok_or_else(|| self.underflow())
+    }
+
+    /// The value `distance` down from the top of the stack.
+    fn peek(&self, distance: usize) -> Result<&Value> {
+        let len = self.require(distance + 1)?;
+        Ok(&self.stack[len - 1 - distance])
+    }
+
+    fn peek_mut(&mut self) -> Result<&mut Value> {
+        self.require(1)?;
+        Ok(self.stack.last_mut().unwrap())
+    }
+
+    fn slot(&self, slot: usize) -> Result<&Value> {
+        self.stack.get(slot).ok_or_else(|| self.underflow())
+    }
+
+    /// The length of the stack, once it's known to hold at least `count` values.
+    fn require(&self, count: usize) -> Result<usize> {
+        match self.stack.len() {
+            len if len < count => Err(self.underflow()),
+            len => Ok(len),
+        }
+    }
+
+    fn underflow(&self) -> anyhow::Error {
+        let frame = self.frame();
+        let name = frame.function.name.as_deref().unwrap_or("script");
+        ChunkError::StackUnderflow(name.to_string(), frame.line()).into()
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no call frame")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().expect("no call frame");
+        let byte = frame.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_short(&mut self) -> usize {
+        let frame = self.frames.last_mut().expect("no call frame");
+        let short = frame.function.chunk.read_short(frame.ip);
+        frame.ip += 2;
+        short
+    }
+
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_byte() as usize;
+        self.constant(index)
+    }
+
+    fn read_constant_long(&mut self) -> Value {
+        let frame = self.frames.last_mut().expect("no call frame");
+        let index = frame.function.chunk.read_long(frame.ip);
+        frame.ip += 3;
+        self.constant(index)
+    }
+
+    /// Reads a global's name constant, returning where the name is in the constant pool.
+    fn read_global(&mut self) -> usize {
+        let index = self.read_byte() as usize;
+        self.frame().constants[index]
+    }
+
+    fn undefined_variable(&self, index: usize) -> RuntimeError {
+        let name = self.constants.get(index).as_symbol();
+        RuntimeError::UndefinedVariable(name.expect("global names are strings").to_string())
+    }
+
+    /// The value of the constant at `index` in the running function's chunk.
+    fn constant(&self, index: usize) -> Value {
+        let index = self.frame().constants[index];
+        self.constants.get(index).clone()
+    }
+
+    /// Replaces the top two values with the result of an arithmetic operator, using `number` when
+    /// both are numbers and falling back to `op` otherwise. Operands are only popped once the
+    /// operation has succeeded.
+    fn binary_op(
+        &mut self,
+        number: fn(f64, f64) -> f64,
+        op: fn(Value, Value) -> Result<Value>,
+    ) -> Result<()> {
+        let len = self.require(2)?;
+        let result = match (&self.stack[len - 2], &self.stack[len - 1]) {
+            (Value::Number(a), Value::Number(b)) => {
+                let result = number(*a, *b);
+                if self.checked_arithmetic && !result.is_finite() {
+                    let line = self.frame().line();
+                    return self.runtime_error(RuntimeError::NonFinite(result, line));
                 }
-                println!("");
-                let _ = self.chunk.disassemble_instruction(self.ip);
+                Value::Number(result)
             }
+            (a, b) => match op(a.clone(), b.clone()) {
+                Ok(result) => result,
+                Err(e) => return self.runtime_error(e),
+            },
+        };
 
-            let instruction = self.chunk.code[self.ip];
-            self.ip += 1;
+        self.stack.pop();
+        *self.peek_mut()? = result;
+        Ok(())
+    }
 
-            match instruction.try_into()? {
-                OpCode::Return => return Ok(()),
-                OpCode::Negate => {
-                    if let Some(value) = self.stack.pop() {
-                        match -value {
-                            Ok(value) => self.stack.push(value),
-                            Err(_) => self.runtime_error()?,
-                        }
-                    }
+    /// Replaces the top two values with the result of comparing them. Only numbers can be
+    /// compared; anything else is an error, raised with both operands left on the stack.
+    fn compare(&mut self, operator: &str, compare: fn(f64, f64) -> bool) -> Result<()> {
+        let (&Value::Number(a), &Value::Number(b)) = (self.peek(1)?, self.peek(0)?) else {
+            return self.runtime_error(EvaluationError::Comparision(operator.to_string()));
+        };
+
+        self.stack.pop();
+        *self.peek_mut()? = Value::Bool(compare(a, b));
+        Ok(())
+    }
+
+    /// Moves the keyword arguments at the top of the last `arg_count` values on the stack, one
+    /// for each of `names`, to the positions of the parameters they name. Calls with the wrong
+    /// number of arguments are left for `call_value` to report.
+    fn bind_keywords(
+        &mut self,
+        callee: &Value,
+        arg_count: usize,
+        names: &[Value],
+    ) -> Result<(), RuntimeError> {
+        let function = if let Some(function) = callee.as_function() {
+            function
+        } else if let Some(bound) = callee.as_bound_method() {
+            Arc::clone(&bound.method)
+        } else if let Some(class) = callee.as_class() {
+            let initializer = class.borrow().methods.get("init").cloned();
+            initializer.ok_or(RuntimeError::NoKeywordArguments)?
+        } else {
+            return Err(RuntimeError::NoKeywordArguments);
+        };
+        if arg_count != function.params.len() {
+            return Ok(());
+        }
+
+        // Which argument goes to each parameter, worked out before the stack is touched
+        let positional = arg_count - names.len();
+        let mut order: Vec<_> = (0..arg_count)
+            .map(|i| (i < positional).then_some(i))
+            .collect();
+        for (i, name) in names.iter().enumerate() {
+            let name = name.to_string();
+            let Some(index) = function.params.iter().position(|param| *param == name) else {
+                return Err(RuntimeError::UnknownParameter(function.name.clone(), name));
+            };
+            if order[index].is_some() {
+                return Err(RuntimeError::DuplicateArgument(function.name.clone(), name));
+            }
+            order[index] = Some(positional + i);
+        }
+
+        // Each argument went to a different parameter, so every parameter has one
+        let start = self.stack.len() - arg_count;
+        let args: Vec<_> = order
+            .into_iter()
+            .flatten()
+            .map(|i| self.stack[start + i].clone())
+            .collect();
+        self.stack.truncate(start);
+        self.stack.extend(args);
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        if let Some(function) = callee.as_function() {
+            return self.call(function, arg_count);
+        }
+        if let Some(bound) = callee.as_bound_method() {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.stack[callee_slot] = bound.receiver.clone();
+            return self.call(Arc::clone(&bound.method), arg_count);
+        }
+        if let Some(generator) = callee.as_generator() {
+            return self.resume(generator, arg_count);
+        }
+        if let Some(cursor) = callee.as_cursor() {
+            if arg_count != 0 {
+                return self.runtime_error(RuntimeError::Arity(None, 0, arg_count));
+            }
+            *self.peek_mut()? = cursor.borrow_mut().advance().unwrap_or_default();
+            return Ok(());
+        }
+        if let Some(native) = callee.as_native() {
+            if arg_count != native.arity as usize {
+                let name = Some(native.name.to_string());
+                return self.runtime_error(RuntimeError::Arity(name, native.arity, arg_count));
+            }
+            if let Some(capability) = native.capability {
+                if !self.capabilities.contains(&capability) {
+                    return self
+                        .runtime_error(NativeError::CapabilityDenied(native.name, capability));
                 }
-                OpCode::Add => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    match a + b {
-                        Ok(sum) => self.stack.push(sum),
-                        Err(_) => self.runtime_error()?,
-                    }
+            }
+            return match native.function {
+                NativeFn::Args(function) => self.call_native(&function, arg_count),
+                NativeFn::Context(function) => {
+                    let args = self.stack.len() - arg_count;
+                    let mut context = NativeContext {
+                        random: &mut self.random,
+                        capabilities: &self.capabilities,
+                        options: &self.options,
+                        stack_max: self.stack_max,
+                        interrupt: self.interrupt.as_ref(),
+                        usage: self.usage.as_ref(),
+                    };
+                    let result = function(&mut context, &self.stack[args..]);
+                    self.native_returned(result, args)
                 }
-                OpCode::Subtract => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    match a - b {
-                        Ok(diff) => self.stack.push(diff),
-                        Err(_) => self.runtime_error()?,
-                    }
+            };
+        }
+        if let Some(host) = callee.as_host_function() {
+            if arg_count != host.arity as usize {
+                let name = Some(host.name.clone());
+                return self.runtime_error(RuntimeError::Arity(name, host.arity, arg_count));
+            }
+            return self.call_native(&*host.function, arg_count);
+        }
+        if let Some(class) = callee.as_class() {
+            let initializer = class.borrow().methods.get("init").cloned();
+            let name = class.borrow().name.clone();
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.stack[callee_slot] = Value::from_instance(Instance::new(class));
+            // The initializer returns the instance, which is already where its result goes
+            return match initializer {
+                Some(initializer) => self.call(initializer, arg_count),
+                None if arg_count != 0 => {
+                    self.runtime_error(RuntimeError::Arity(Some(name), 0, arg_count))
+                }
+                None => Ok(()),
+            };
+        }
+        self.runtime_error(RuntimeError::NotCallable)
+    }
+
+    /// Calls a function implemented in Rust with the arguments on top of the stack, replacing them
+    /// and the callee with its result. Its errors are raised as runtime errors, except for `exit()`,
+    /// which unwinds the whole script.
+    fn call_native(&mut self, function: &HostFn, arg_count: usize) -> Result<()> {
+        let args = self.stack.len() - arg_count;
+        let result = function(&self.stack[args..]);
+        self.native_returned(result, args)
+    }
+
+    /// Finishes a call to a native whose arguments start at `args` on the stack.
+    fn native_returned(&mut self, result: Result<Value>, args: usize) -> Result<()> {
+        match result {
+            Ok(result) => {
+                self.stack.truncate(args - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Err(e) if e.is::<Exit>() => {
+                self.frames.clear();
+                self.handlers.clear();
+                self.stack.clear();
+                self.out.flush()?;
+                Err(e)
+            }
+            Err(e) => self.runtime_error(e),
+        }
+    }
+
+    /// Continues a generator from where it last yielded. Once finished it only returns `nil`.
+    fn resume(&mut self, generator: Rc<RefCell<Generator>>, arg_count: usize) -> Result<()> {
+        if arg_count != 0 {
+            let name = generator.borrow().function.name.clone();
+            return self.runtime_error(RuntimeError::Arity(name, 0, arg_count));
+        }
+        let state = generator.borrow().state;
+        match state {
+            GeneratorState::Running => return self.runtime_error(RuntimeError::GeneratorRunning),
+            GeneratorState::Done => {
+                *self.peek_mut()? = Value::Nil;
+                return Ok(());
+            }
+            GeneratorState::Suspended => {}
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return self.runtime_error(RuntimeError::StackOverflow);
+        }
+
+        let mut suspended = generator.borrow_mut();
+        suspended.state = GeneratorState::Running;
+        // The generator's own slot zero replaces the generator being called
+        let slots = self.stack.len() - 1;
+        self.stack.pop();
+        self.stack.append(&mut suspended.stack);
+        let function = Arc::clone(&suspended.function);
+        let ip = suspended.ip;
+        drop(suspended);
+
+        let constants = self.constants.load(&function);
+        self.frames.push(CallFrame {
+            function,
+            ip,
+            slots,
+            constants,
+            generator: Some(generator),
+        });
+        Ok(())
+    }
+
+    fn call(&mut self, function: Arc<Function>, arg_count: usize) -> Result<()> {
+        if arg_count != function.arity as usize {
+            let name = function.name.clone();
+            return self.runtime_error(RuntimeError::Arity(name, function.arity, arg_count));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return self.runtime_error(RuntimeError::StackOverflow);
+        }
+        if let (Some(profile), Some(name)) = (&mut self.profile, &function.name) {
+            profile.record_call(name);
+        }
+
+        let slots = self.stack.len() - arg_count - 1;
+        if function.generator {
+            let generator = Generator {
+                function,
+                stack: self.stack.split_off(slots),
+                ip: 0,
+                state: GeneratorState::Suspended,
+            };
+            self.stack.push(Value::from_generator(generator));
+            return Ok(());
+        }
+
+        let constants = self.constants.load(&function);
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots,
+            constants,
+            generator: None,
+        });
+        Ok(())
+    }
+
+    /// Writes the stack and the instruction about to run to the debug output.
+    fn trace_instruction(&mut self) -> std::io::Result<()> {
+        let out = self.debug_out.as_mut();
+        write!(out, "          ")?;
+        for item in &self.stack {
+            write!(out, "[ {} ]", item)?;
+        }
+        writeln!(out)?;
+        let frame = self.frames.last().expect("no frame to trace");
+        frame
+            .function
+            .chunk
+            .disassemble_instruction(frame.ip, out)?;
+        Ok(())
+    }
+
+    /// Runs `program` from the start, returning whatever value it returns (`nil` for scripts that
+    /// run off the end, the result for expressions).
+    pub fn run(&mut self, program: &Program) -> LoxResult<Value> {
+        if self.disassemble {
+            program
+                .script()
+                .chunk
+                .disassemble("RUN", self.debug_out.as_mut())?;
+        }
+        self.call_function(Arc::clone(program.script()), Vec::new())
+    }
+
+    /// Calls `function` with `args` on a fresh stack, returning its result. Globals are left as
+    /// they are.
+    pub fn call_function(&mut self, function: Arc<Function>, args: Vec<Value>) -> LoxResult<Value> {
+        match self.begin(function, args)? {
+            Progress::Finished(result) => Ok(result),
+            Progress::Paused => match self.execute_for(None)? {
+                Progress::Finished(result) => Ok(result),
+                Progress::Paused => unreachable!("ran out of unlimited fuel"),
+            },
+        }
+    }
+
+    /// Sets `program` up to run from the start without running any of it, so that it can be run a
+    /// slice at a time with `run_for`.
+    pub fn start(&mut self, program: &Program) -> LoxResult<Progress> {
+        Ok(self.begin(Arc::clone(program.script()), Vec::new())?)
+    }
+
+    /// Continues a paused program for at most `fuel` instructions. A VM with nothing left to run
+    /// finishes straight away with `nil`.
+    pub fn run_for(&mut self, fuel: usize) -> LoxResult<Progress> {
+        if self.frames.is_empty() {
+            return Ok(Progress::Finished(Value::Nil));
+        }
+        Ok(self.execute_for(Some(fuel))?)
+    }
+
+    /// Pushes a call to `function` onto a fresh stack, leaving it paused before its first
+    /// instruction.
+    fn begin(&mut self, function: Arc<Function>, args: Vec<Value>) -> Result<Progress> {
+        self.frames.clear();
+        self.handlers.clear();
+        self.unwinding = None;
+        self.stack.clear();
+        let options = &self.options;
+        let limited = options.max_instructions.is_some()
+            || options.max_heap_bytes.is_some()
+            || options.wall_clock_timeout.is_some();
+        self.usage = limited.then(|| Usage {
+            used: ResourceUsage::default(),
+            heap_base: heap_bytes(),
+            heap_shared: 0,
+            budget: self.budget.clone().unwrap_or_else(Budget::new),
+        });
+
+        let arg_count = args.len();
+        self.stack.push(Value::from_function(Arc::clone(&function)));
+        self.stack.extend(args);
+        self.call(function, arg_count)?;
+        // Calling a generator function just creates the generator
+        if self.frames.is_empty() {
+            return Ok(Progress::Finished(self.stack.pop().unwrap_or_default()));
+        }
+        Ok(Progress::Paused)
+    }
+
+    /// Runs instructions until the outermost call returns, or until `fuel` instructions have run.
+    fn execute_for(&mut self, mut fuel: Option<usize>) -> Result<Progress> {
+        loop {
+            if let Some(fuel) = &mut fuel {
+                if *fuel == 0 {
+                    self.out.flush()?;
+                    return Ok(Progress::Paused);
                 }
-                OpCode::Multiply => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    match a * b {
-                        Ok(prod) => self.stack.push(prod),
-                        Err(_) => self.runtime_error()?,
+                *fuel -= 1;
+            }
+
+            if let Some(flag) = &self.interrupt {
+                if flag.load(Ordering::Relaxed) {
+                    if self.clears_interrupt {
+                        flag.store(false, Ordering::Relaxed);
                     }
+                    self.abort(RuntimeError::Interrupted)?;
                 }
-                OpCode::Divide => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    match a / b {
-                        Ok(quot) => self.stack.push(quot),
-                        Err(_) => self.runtime_error()?,
+            }
+            if let Some(e) = self.check_limits() {
+                self.abort(e)?;
+            }
+            // No instruction pushes more than a few values, so checking between them is enough
+            if self.stack.len() > self.stack_max {
+                self.runtime_error(RuntimeError::StackOverflow)?;
+            }
+            if let Some(stats) = &mut self.stats {
+                stats.instructions += 1;
+                stats.peak_stack = stats.peak_stack.max(self.stack.len());
+            }
+            if self.trace_execution {
+                self.trace_instruction()?;
+            }
+
+            let instruction = self.read_byte();
+
+            match instruction.try_into()? {
+                OpCode::Return => {
+                    let result = self.stack.pop().unwrap_or_default();
+                    let frame = self.frames.pop().expect("no call frame");
+                    self.stack.truncate(frame.slots);
+                    if let Some(generator) = frame.generator {
+                        generator.borrow_mut().state = GeneratorState::Done;
+                    }
+                    // Returning from inside a `try` block leaves it
+                    while self
+                        .handlers
+                        .last()
+                        .is_some_and(|handler| handler.frames > self.frames.len())
+                    {
+                        self.handlers.pop();
                     }
+                    if self.frames.is_empty() {
+                        self.out.flush()?;
+                        return Ok(Progress::Finished(result));
+                    }
+                    self.stack.push(result);
                 }
+                OpCode::Negate => match self.peek_mut()? {
+                    Value::Number(n) => *n = -*n,
+                    value => {
+                        let e = (-value.clone()).unwrap_err();
+                        self.runtime_error(e)?
+                    }
+                },
+                OpCode::Add => self.binary_op(|a, b| a + b, |a, b| a + b)?,
+                OpCode::Subtract => self.binary_op(|a, b| a - b, |a, b| a - b)?,
+                OpCode::Multiply => self.binary_op(|a, b| a * b, |a, b| a * b)?,
+                OpCode::Divide => self.binary_op(|a, b| a / b, |a, b| a / b)?,
                 OpCode::Constant => {
-                    let constant = self.chunk.read_constant(self.chunk.code[self.ip] as usize);
-                    self.ip += 1;
+                    let constant = self.read_constant();
+                    self.stack.push(constant);
+                }
+                OpCode::ConstantLong => {
+                    let constant = self.read_constant_long();
                     self.stack.push(constant);
                 }
                 OpCode::Nil => {
@@ -105,63 +1105,2117 @@ impl<'a> VM<'a> {
                     self.stack.push(Value::Bool(false));
                 }
                 OpCode::Not => {
-                    let value = self.stack.pop().unwrap();
+                    let value = self.pop()?;
                     self.stack.push(Value::Bool(value.is_falsey()))
                 }
                 OpCode::Equal => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
+                    let b = self.pop()?;
+                    let a = self.pop()?;
                     self.stack.push(Value::Bool(a == b));
                 }
-                OpCode::Greater => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    self.stack.push(Value::Bool(a > b));
+                OpCode::Greater => self.compare(">", |a, b| a > b)?,
+                OpCode::Less => self.compare("<", |a, b| a < b)?,
+                OpCode::Swap => {
+                    let len = self.require(2)?;
+                    self.stack.swap(len - 1, len - 2);
                 }
-                OpCode::Less => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-
-                    self.stack.push(Value::Bool(a < b));
+                OpCode::Over => {
+                    let value = self.peek(1)?.clone();
+                    self.stack.push(value);
                 }
                 OpCode::Print => {
-                    let a = self.stack.pop().unwrap();
-                    println!("{}", a);
+                    let a = self.pop()?;
+                    writeln!(self.out, "{}", a)?;
+                }
+                OpCode::Echo => {
+                    let a = self.pop()?;
+                    write!(self.out, "{}", a)?;
                 }
                 OpCode::Pop => {
                     let _ = self.stack.pop();
                 }
                 OpCode::DefineGlobal => {
-                    let name = self.chunk.read_constant(self.chunk.code[self.ip] as usize);
-                    self.ip += 1;
-                    self.globals
-                        .insert(name.to_string(), self.stack.last().unwrap().to_owned());
-
-                    let _ = self.stack.pop();
+                    let index = self.read_global();
+                    let defined = self.globals.get(index).is_some_and(Option::is_some);
+                    if let (Some(report), true) = (&mut self.reloading, defined) {
+                        let name = self.constants.get(index).to_string();
+                        let value = self.stack.last();
+                        if value.is_some_and(|value| {
+                            value.as_function().is_some()
+                                || value.as_class().is_some()
+                                || value.as_native().is_some()
+                        }) {
+                            report.replaced.push(name);
+                        } else {
+                            report.preserved.push(name);
+                            let _ = self.stack.pop();
+                            continue;
+                        }
+                    }
+                    let value = self.pop()?;
+                    self.set_global(index, value);
                 }
                 OpCode::GetGlobal => {
-                    let name = self.chunk.read_constant(self.chunk.code[self.ip] as usize);
-                    self.ip += 1;
-                    match self.globals.get(&name.to_string()) {
+                    let index = self.read_global();
+                    match self.global(index) {
                         Some(value) => self.stack.push(value.to_owned()),
-                        None => self.runtime_error()?,
+                        None => self.runtime_error(self.undefined_variable(index))?,
                     }
                 }
                 OpCode::SetGlobal => {
-                    let name = self.chunk.read_constant(self.chunk.code[self.ip] as usize);
-                    self.ip += 1;
+                    let index = self.read_global();
 
-                    if !self.globals.contains_key(&name.to_string()) {
-                        self.runtime_error()?
+                    if self.global(index).is_none() {
+                        // A handler may catch the error, but the variable still isn't defined
+                        self.runtime_error(self.undefined_variable(index))?
+                    } else {
+                        // Assignment is an expression, so the value stays on the stack
+                        let value = self.peek(0)?.to_owned();
+                        self.set_global(index, value);
                     }
-
-                    self.globals
-                        .insert(name.to_string(), self.stack.last().unwrap().to_owned());
-
-                    let _ = self.stack.pop();
                 }
+                OpCode::GetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.require(slot + 1)?;
+                    self.stack.push(self.stack[slot].to_owned());
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.require(slot + 1)?;
+                    self.stack[slot] = self.peek(0)?.to_owned();
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_short();
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        ip: self.frame().ip + offset,
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Probe => {
+                    let id = self.read_short();
+                    if let Some(handler) = &mut self.probe_handler {
+                        handler(id);
+                    }
+                }
+                OpCode::CheckType => {
+                    let slot = self.read_byte() as usize;
+                    let value_type = ValueType::try_from(self.read_byte())?;
+                    let value = self.slot(self.frame().slots + slot)?;
+                    if !value_type.matches(value) {
+                        let e =
+                            ConversionError::WrongType(value_type.description(), value.to_string());
+                        self.runtime_error(ConversionError::Argument(slot, Box::new(e)))?;
+                    }
+                }
+                OpCode::Throw => {
+                    let exception = self.peek(0)?.clone();
+                    if self.handlers.is_empty() {
+                        // Deferred code rethrows runtime errors once it has run
+                        match self.unwinding.take() {
+                            Some((Value::Obj(thrown), error)) if matches!(&exception, Value::Obj(e) if Rc::ptr_eq(e, &thrown)) => {
+                                self.runtime_error(error)?
+                            }
+                            _ => {
+                                self.runtime_error(RuntimeError::Uncaught(exception.to_string()))?
+                            }
+                        }
+                    } else {
+                        self.unwind(exception);
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frames.last_mut().unwrap().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    let condition = self.peek(0)?;
+                    if self.truthiness == Truthiness::Strict && !matches!(condition, Value::Bool(_))
+                    {
+                        let e = RuntimeError::NonBooleanCondition(condition.to_string());
+                        self.runtime_error(e)?
+                    }
+                    if self.peek(0)?.is_falsey() {
+                        self.frames.last_mut().unwrap().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frames.last_mut().unwrap().ip -= offset;
+                }
+                OpCode::Class => {
+                    let name = self.read_constant();
+                    self.stack
+                        .push(Value::from_class(Class::new(name.to_string())));
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_constant().to_string();
+                    let Some(instance) = self.peek(0)?.as_instance() else {
+                        self.runtime_error(RuntimeError::NotAnInstance)?;
+                        continue;
+                    };
+
+                    // Fields shadow methods
+                    let receiver = self.peek(0)?;
+                    let instance = instance.borrow();
+                    let value = instance.fields.get(&name).cloned().or_else(|| {
+                        let class = instance.class.borrow();
+                        let method = Arc::clone(class.methods.get(&name)?);
+                        Some(Value::from_bound_method(BoundMethod {
+                            receiver: receiver.clone(),
+                            method,
+                        }))
+                    });
+                    drop(instance);
+
+                    let Some(value) = value else {
+                        self.runtime_error(RuntimeError::UndefinedProperty(name))?;
+                        continue;
+                    };
+                    *self.peek_mut()? = value;
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_constant().to_string();
+                    let receiver = self.peek(1)?;
+                    let Some(instance) = receiver.as_instance() else {
+                        self.runtime_error(RuntimeError::NotAnInstance)?;
+                        continue;
+                    };
+
+                    if instance.borrow().frozen {
+                        self.runtime_error(RuntimeError::Frozen("instance"))?;
+                        continue;
+                    }
+
+                    let value = self.pop()?;
+                    if instance
+                        .borrow_mut()
+                        .fields
+                        .insert(name, value.clone())
+                        .is_none()
+                    {
+                        track_heap(FIELD_SIZE as isize);
+                    }
+                    // Assignment is an expression, so its value replaces the instance
+                    *self.peek_mut()? = value;
+                }
+                OpCode::Method => {
+                    let name = self.read_constant().to_string();
+                    let (Some(method), Some(class)) =
+                        (self.peek(0)?.as_function(), self.peek(1)?.as_class())
+                    else {
+                        return Err(ChunkError::Malformed("method defined outside a class").into());
+                    };
+                    self.stack.pop();
+                    class.borrow_mut().methods.insert(name, method);
+                }
+                OpCode::Yield => {
+                    let value = self.pop()?;
+                    let frame = self.frames.pop().expect("no call frame");
+                    let generator = frame.generator.expect("yield outside a generator");
+                    let mut suspended = generator.borrow_mut();
+                    suspended.stack = self.stack.split_off(frame.slots);
+                    suspended.ip = frame.ip;
+                    suspended.state = GeneratorState::Suspended;
+                    self.stack.push(value);
+                }
+                OpCode::Done => {
+                    let value = self.peek(0)?;
+                    let done = if let Some(generator) = value.as_generator() {
+                        generator.borrow().state == GeneratorState::Done
+                    } else if let Some(cursor) = value.as_cursor() {
+                        cursor.borrow().is_done()
+                    } else {
+                        self.runtime_error(RuntimeError::NotAGenerator)?;
+                        continue;
+                    };
+                    *self.peek_mut()? = Value::Bool(done);
+                }
+                OpCode::Iter => {
+                    let value = self.peek(0)?;
+                    let items = if value.as_generator().is_some() {
+                        continue;
+                    } else if let Some(list) = value.as_list() {
+                        list.borrow().clone()
+                    } else if let Some(map) = value.as_map() {
+                        map.borrow().keys().collect()
+                    } else {
+                        self.runtime_error(RuntimeError::NotIterable)?;
+                        continue;
+                    };
+                    *self.peek_mut()? = Value::from_cursor(items);
+                }
+                OpCode::IndexGet => {
+                    let len = self.require(2)?;
+                    let (target, index) = (&self.stack[len - 2], &self.stack[len - 1]);
+                    let result = if let Some(bytes) = target.as_bytes() {
+                        position(index, bytes.len(), false).map(|i| Value::Number(bytes[i] as f64))
+                    } else if let Some(list) = target.as_list() {
+                        let list = list.borrow();
+                        position(index, list.len(), false).map(|i| list[i].clone())
+                    } else if let Some(map) = target.as_map() {
+                        // Missing keys read as nil
+                        MapKey::new(index)
+                            .map(|key| map.borrow().get(&key).cloned().unwrap_or_default())
+                            .ok_or_else(|| RuntimeError::InvalidKey(index.to_string()))
+                    } else {
+                        Err(RuntimeError::NotIndexable)
+                    };
+                    match result {
+                        Ok(value) => {
+                            self.stack.truncate(len - 2);
+                            self.stack.push(value);
+                        }
+                        Err(e) => self.runtime_error(e)?,
+                    }
+                }
+                OpCode::Slice => {
+                    let len = self.require(3)?;
+                    let (target, start, end) = (
+                        &self.stack[len - 3],
+                        &self.stack[len - 2],
+                        &self.stack[len - 1],
+                    );
+                    let result = if let Some(bytes) = target.as_bytes() {
+                        range(start, end, bytes.len()).map(|r| Value::from_bytes(bytes[r].to_vec()))
+                    } else if let Some(list) = target.as_list() {
+                        let list = list.borrow();
+                        range(start, end, list.len()).map(|r| Value::from_list(list[r].to_vec()))
+                    } else {
+                        Err(RuntimeError::NotIndexable)
+                    };
+                    match result {
+                        Ok(value) => {
+                            self.stack.truncate(len - 3);
+                            self.stack.push(value);
+                        }
+                        Err(e) => self.runtime_error(e)?,
+                    }
+                }
+                OpCode::IndexSet => {
+                    let len = self.require(3)?;
+                    let (target, index, value) = (
+                        &self.stack[len - 3],
+                        &self.stack[len - 2],
+                        &self.stack[len - 1],
+                    );
+                    let result = if let Some(list) = target.as_list() {
+                        let mut list = list.borrow_mut();
+                        let size = list.len();
+                        if list.is_frozen() {
+                            Err(RuntimeError::Frozen("list"))
+                        } else {
+                            position(index, size, false).map(|i| list[i] = value.clone())
+                        }
+                    } else if let Some(map) = target.as_map() {
+                        if map.borrow().is_frozen() {
+                            Err(RuntimeError::Frozen("map"))
+                        } else {
+                            MapKey::new(index)
+                                .map(|key| {
+                                    let mut map = map.borrow_mut();
+                                    if map.get(&key).is_none() {
+                                        track_heap(MAP_ENTRY_SIZE as isize);
+                                    }
+                                    map.insert(key, value.clone())
+                                })
+                                .ok_or_else(|| RuntimeError::InvalidKey(index.to_string()))
+                        }
+                    } else {
+                        Err(RuntimeError::NotAssignable)
+                    };
+                    match result {
+                        Ok(()) => {
+                            let value = self.pop()?;
+                            self.stack.truncate(len - 3);
+                            self.stack.push(value);
+                        }
+                        Err(e) => self.runtime_error(e)?,
+                    }
+                }
+                OpCode::BuildList => {
+                    let count = self.read_byte() as usize;
+                    let items = self.stack.split_off(self.require(count)? - count);
+                    self.stack.push(Value::from_list(items));
+                }
+                OpCode::BuildMap => {
+                    let count = 2 * self.read_byte() as usize;
+                    let start = self.require(count)? - count;
+                    let mut map = Map::default();
+                    let mut result = Ok(());
+                    for pair in self.stack[start..].chunks(2) {
+                        match MapKey::new(&pair[0]) {
+                            Some(key) => map.insert(key, pair[1].clone()),
+                            None => {
+                                result = Err(RuntimeError::InvalidKey(pair[0].to_string()));
+                                break;
+                            }
+                        }
+                    }
+                    match result {
+                        Ok(()) => {
+                            self.stack.truncate(start);
+                            self.stack.push(Value::from_map(map));
+                        }
+                        Err(e) => self.runtime_error(e)?,
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count)?.clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::CallKeywords => {
+                    let arg_count = self.read_byte() as usize;
+                    let keyword_count = self.read_byte() as usize;
+                    let names_start = self.require(arg_count + keyword_count + 1)? - keyword_count;
+                    let names = self.stack.split_off(names_start);
+                    let callee = self.peek(arg_count)?.clone();
+                    match self.bind_keywords(&callee, arg_count, &names) {
+                        Ok(()) => self.call_value(callee, arg_count)?,
+                        Err(e) => self.runtime_error(e)?,
+                    }
+                }
+                OpCode::Pipe => {
+                    let arg_count = self.read_byte() as usize;
+                    // Move the callee beneath the piped value, making that its first argument
+                    let callee_slot = self.require(arg_count + 2)? - 1 - arg_count;
+                    let callee = self.stack.remove(callee_slot);
+                    self.stack.insert(callee_slot - 1, callee.clone());
+                    self.call_value(callee, arg_count + 1)?;
+                }
+            }
+        }
+    }
+}
+
+/// Converts an index into a sequence of length `len`. Slice bounds may also be `len` itself.
+fn position(index: &Value, len: usize, bound: bool) -> Result<usize, RuntimeError> {
+    let Value::Number(n) = *index else {
+        return Err(RuntimeError::InvalidIndex(index.to_string()));
+    };
+    if n.fract() != 0.0 || n < 0.0 {
+        return Err(RuntimeError::InvalidIndex(index.to_string()));
+    }
+    if n > len as f64 || (n == len as f64 && !bound) {
+        return Err(RuntimeError::IndexOutOfRange(n, len));
+    }
+    Ok(n as usize)
+}
+
+/// The range a `[start:end]` slice covers of a sequence `len` long, where a `nil` start or end
+/// means the start or end of the sequence.
+pub(crate) fn range(start: &Value, end: &Value, len: usize) -> Result<Range<usize>, RuntimeError> {
+    let start = match start {
+        Value::Nil => 0,
+        start => position(start, len, true)?,
+    };
+    let end = match end {
+        Value::Nil => len,
+        end => position(end, len, true)?,
+    };
+    if start > end {
+        return Err(RuntimeError::InvalidSlice(start, end));
+    }
+    Ok(start..end)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::chunk::MAX_REPEAT_LEN;
+    use crate::compiler::OptLevel;
+    use crate::error::{EvaluationError, LoxError, ScriptError};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Output sink that can still be read after being handed to the VM.
+    #[derive(Clone, Default)]
+    pub(crate) struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        pub(crate) fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn render(template: &str) -> String {
+        let options = CompileOptions {
+            template: true,
+            ..Default::default()
+        };
+        let script = crate::compiler::compile(template.to_string(), &options).unwrap();
+        let out = Buffer::default();
+        VM::with_output(Box::new(out.clone())).run(&script).unwrap();
+        out.contents()
+    }
+
+    #[test]
+    fn globals() {
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.interpret("var count = 1; fun bump() { count = count + 1; }")
+            .unwrap();
+        vm.interpret("bump(); bump(); print count;").unwrap();
+        vm.register_fn("clock", 0, |_| Ok(Value::Number(42.0)));
+        vm.interpret("print clock();").unwrap();
+        assert_eq!("3\n42\n", out.contents());
+
+        assert!(vm.interpret("undefined = 1;").is_err());
+        assert!(vm.interpret("print undefined;").is_err());
+    }
+
+    #[test]
+    fn stack_limits() {
+        let script = crate::compiler::compile(
+            "try { print [1, 2, 3, 4, 5, 6, 7, 8]; } catch (e) { print e; }".to_string(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.set_stack_max(8);
+        vm.run(&script).unwrap();
+        assert_eq!("stack overflow\n", out.contents());
+
+        // Malformed bytecode popping an empty stack stops the script rather than panicking
+        let mut chunk = crate::chunk::Chunk::default();
+        chunk.write(OpCode::Add, 3usize);
+        chunk.write(OpCode::Return, 3usize);
+        let program = Program::from(Function::script(chunk));
+        assert!(matches!(
+            VM::with_output(Box::new(Buffer::default())).run(&program),
+            Err(LoxError::Chunk(ChunkError::StackUnderflow(name, 3))) if name == "script"
+        ));
+    }
+
+    #[test]
+    fn limits() {
+        let run_limited = |source: &str, options: VmOptions| {
+            let script =
+                crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+            let mut vm = VM::with_output(Box::new(Buffer::default()));
+            vm.set_options(options);
+            match vm.run(&script) {
+                Err(LoxError::Runtime(
+                    ScriptError::Runtime(RuntimeError::LimitExceeded(limit, used)),
+                    _,
+                )) => {
+                    assert_eq!(Some(&used), vm.usage());
+                    Some(limit)
+                }
+                result => {
+                    result.unwrap();
+                    None
+                }
+            }
+        };
+
+        let options = VmOptions {
+            max_instructions: Some(10_000),
+            ..Default::default()
+        };
+        assert_eq!(None, run_limited("print 1 + 2;", options.clone()));
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        vm.set_options(options.clone());
+        vm.interpret("var list = [1, 2, 3]; print list;").unwrap();
+        let used = vm.usage().unwrap();
+        assert_eq!(9, used.instructions);
+        assert_eq!(4, used.peak_stack);
+        assert!(used.peak_heap_bytes > 0);
+        assert_eq!(
+            Some("instruction"),
+            run_limited("try { while (true) {} } catch (e) {}", options)
+        );
+
+        let options = VmOptions {
+            max_heap_bytes: Some(1 << 20),
+            ..Default::default()
+        };
+        let source = "var list = []; while (true) push(list, \"item\");";
+        assert_eq!(Some("heap"), run_limited(source, options));
+
+        let options = VmOptions {
+            wall_clock_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        assert_eq!(Some("time"), run_limited("while (true) {}", options));
+    }
+
+    #[test]
+    fn limit_at_start_of_chunk() {
+        // The loop jumps back to offset 0, where the limit is checked before anything is read
+        for max in [0, 1000] {
+            let options = VmOptions {
+                max_instructions: Some(max),
+                ..Default::default()
+            };
+            let mut vm = VM::with_output(Box::new(Buffer::default()));
+            vm.set_options(options);
+            let e = vm.interpret("while (true) {}").unwrap_err();
+            assert!(
+                matches!(
+                    e,
+                    LoxError::Runtime(
+                        ScriptError::Runtime(RuntimeError::LimitExceeded("instruction", _)),
+                        _
+                    )
+                ),
+                "{:?}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn limit_reports_usage() {
+        let options = VmOptions {
+            max_instructions: Some(100),
+            ..Default::default()
+        };
+        // Numbers aren't on the heap, so the loop takes no more of it than the script itself
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        vm.set_options(options.clone());
+        vm.interpret("var n = 0;").unwrap();
+        let heap = vm.usage().unwrap().peak_heap_bytes;
+
+        let script = crate::compiler::compile(
+            "var n = 0; while (true) n = n + 1;".to_string(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        vm.set_options(options);
+        let e = vm.run(&script).unwrap_err();
+        let LoxError::Runtime(
+            ScriptError::Runtime(error @ RuntimeError::LimitExceeded(limit, used)),
+            _,
+        ) = &e
+        else {
+            panic!("expected a limit error, got {:?}", e);
+        };
+        assert_eq!("instruction", *limit);
+        // The script's slot, then `n` and `1` for the addition
+        let expected = ResourceUsage {
+            instructions: 100,
+            peak_heap_bytes: heap,
+            peak_stack: 3,
+        };
+        assert_eq!(&expected, used);
+        assert_eq!(Some(&expected), vm.usage());
+        assert_eq!(
+            format!(
+                "instruction limit exceeded after 100 instructions, peak heap {} bytes, \
+                 peak stack 3 values",
+                heap
+            ),
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn interrupt() {
+        let script = crate::compiler::compile(
+            "try { while (true) {} } catch (e) { print \"caught\"; }".to_string(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let flag = Arc::new(AtomicBool::new(false));
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.set_interrupt(Arc::clone(&flag));
+
+        let setter = Arc::clone(&flag);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            setter.store(true, Ordering::Relaxed);
+        });
+        assert!(matches!(
+            vm.run(&script),
+            Err(LoxError::Runtime(
+                ScriptError::Runtime(RuntimeError::Interrupted),
+                _
+            ))
+        ));
+        assert_eq!("", out.contents());
+        assert!(!flag.load(Ordering::Relaxed));
+        assert_eq!(Value::Number(2.0), vm.eval_expression("1 + 1").unwrap());
+    }
+
+    #[test]
+    fn reload() {
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.reload("var score = 1; score = score + 1;").unwrap();
+
+        let report = vm
+            .reload("var score = 0; var lives = 3; print score + lives;")
+            .unwrap();
+
+        assert_eq!(vec!["lives".to_string()], report.added);
+        assert_eq!(vec!["score".to_string()], report.preserved);
+        assert!(report.replaced.is_empty());
+        assert_eq!("5\n", out.contents());
+        assert!(vm.reload("var = ;").is_err());
+
+        // Functions and classes are code, so they're replaced rather than kept
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.reload(
+            "var hits = 0;
+            fun hit() { hits = hits + 1; return \"old\"; }
+            class A { f() { return 1; } }",
+        )
+        .unwrap();
+        let report = vm
+            .reload(
+                "var hits = 0;
+                fun hit() { hits = hits + 10; return \"new\"; }
+                class A { f() { return 2; } }
+                print hit(); print hits; print A().f();",
+            )
+            .unwrap();
+        assert_eq!(vec!["hits".to_string()], report.preserved);
+        assert_eq!(vec!["hit".to_string(), "A".to_string()], report.replaced);
+        assert_eq!("new\n10\n2\n", out.contents());
+    }
+
+    #[test]
+    fn template() {
+        assert_eq!("", render(""));
+        assert_eq!("just \"text\"\n", render("just \"text\"\n"));
+        assert_eq!("1 + 2 = 3!", render("1 + 2 = {{ 1 + 2 }}!"));
+        assert_eq!(
+            "Hello, world\n",
+            render("{% var name = \"world\"; %}Hello, {{name}}\n")
+        );
+        assert_eq!("a\n", render("{% print \"a\"; %}"));
+    }
+
+    #[test]
+    fn locals() {
+        let source = "var a = \"global\";
+            {
+                var a = \"outer\";
+                { var a = \"inner\"; print a; }
+                print a;
+                var b = a = \"assigned\";
+                print b;
+            }
+            print a;";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        VM::with_output(Box::new(out.clone())).run(&script).unwrap();
+
+        assert_eq!("inner\nouter\nassigned\nglobal\n", out.contents());
+    }
+
+    #[test]
+    fn if_else() {
+        let source = "if (true) print 1; else print 2;
+            if (nil) print 3; else print 4;
+            if (false) print 5;
+            var a = 0;
+            if (a == 0) { a = 6; } print a;";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        VM::with_output(Box::new(out.clone())).run(&script).unwrap();
+
+        assert_eq!("1\n4\n6\n", out.contents());
+    }
+
+    #[test]
+    fn defer() {
+        let source = "{
+                var a = 1;
+                defer print \"first\";
+                defer print a;
+                a = 2;
+                print \"body\";
+            }
+            defer print \"end\";
+            print \"after\";";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        VM::with_output(Box::new(out.clone())).run(&script).unwrap();
+
+        assert_eq!("body\n2\nfirst\nafter\nend\n", out.contents());
+    }
+
+    #[test]
+    fn defer_on_throw() {
+        let (_, out) = run("fun f() { defer print \"cleanup\"; throw \"x\"; }
+            try { f(); } catch (e) { print e; }
+            try {
+                defer print 1;
+                { var a = 2; defer print a; throw \"y\"; }
+            } catch (e) { print e; }
+            fun g() { defer print \"g\"; return nil + 1; }
+            try { g(); } catch (e) { print \"caught\"; }
+            try { defer throw \"from defer\"; print \"body\"; } catch (e) { print e; }");
+        assert_eq!("cleanup\nx\n2\n1\ny\ng\ncaught\nbody\nfrom defer\n", out);
+
+        let (result, out) = run("defer print \"last\"; throw \"boom\";");
+        assert!(result.is_err());
+        assert_eq!("last\n", out);
+
+        // Runtime errors carry on once the deferred code has run, rather than becoming exceptions
+        let (result, out) = run("{ defer print 1; print missing; }");
+        assert!(
+            matches!(
+                &result,
+                Err(LoxError::Runtime(ScriptError::Runtime(
+                    RuntimeError::UndefinedVariable(name)
+                ), _)) if name == "missing"
+            ),
+            "{:?}",
+            result
+        );
+        assert_eq!("1\n", out);
+        let (result, _) = run("fun f() { defer print 1; return nil - 1; } f();");
+        assert!(
+            matches!(
+                result,
+                Err(LoxError::Runtime(
+                    ScriptError::Evaluation(EvaluationError::Arithmatic(_)),
+                    _
+                ))
+            ),
+            "{:?}",
+            result
+        );
+        let (result, _) = run("{ defer print 1; throw \"missing\"; }");
+        assert!(
+            matches!(
+                result,
+                Err(LoxError::Runtime(
+                    ScriptError::Runtime(RuntimeError::Uncaught(_)),
+                    _
+                ))
+            ),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn loops() {
+        let source = "var i = 0;
+            outer: while (3 > i) {
+                i = i + 1;
+                var j = 0;
+                while (true) {
+                    j = j + 1;
+                    if (j > 2) break;
+                    if (i == 2) continue outer;
+                    print i * 10 + j;
+                }
+            }
+            while (true) {
+                var k = \"k\";
+                defer print \"cleanup \" + k;
+                break;
+            }";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        VM::with_output(Box::new(out.clone())).run(&script).unwrap();
+
+        assert_eq!("11\n12\n31\n32\ncleanup k\n", out.contents());
+    }
+
+    #[test]
+    fn for_loops() {
+        let source = "for (var i = 0; 4 > i; i = i + 1) {
+                if (i == 1) continue;
+                print i;
+            }
+            var n = 0;
+            for (; 2 > n;) n = n + 1;
+            for (n = 10;; n = n + 1) if (n == 12) break;
+            print n;";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.run(&script).unwrap();
+
+        assert_eq!("0\n2\n3\n12\n", out.contents());
+        // The loop variable is scoped to the loop rather than leaking out as a global
+        assert!(vm.eval_expression("i").is_err());
+    }
+
+    fn run(source: &str) -> (LoxResult<Value>, String) {
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        let result = VM::with_output(Box::new(out.clone())).run(&script);
+        (result, out.contents())
+    }
+
+    #[test]
+    fn functions() {
+        let (result, out) = run("fun fib(n) {
+                if (2 > n) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            print fib(10);
+            print fib;
+            fun noisy(a, b) {
+                defer print \"done\";
+                { var c = a + b; return c; }
+            }
+            print noisy(1, 2);
+            {
+                fun local() { return \"local\"; }
+                print local();
+            }
+            fun none() {}
+            print none();
+            return 7;
+            print \"unreachable\";");
+
+        assert_eq!(Value::Number(7.0), result.unwrap());
+        assert_eq!("55\n<fn fib>\ndone\n3\nlocal\nnil\n", out);
+    }
+
+    #[test]
+    fn exit() {
+        let (result, out) = run("fun quit() {
+                try { exit(3); } catch (e) { print \"caught\"; }
+            }
+            print \"before\";
+            quit();
+            print \"after\";");
+        assert!(matches!(result, Err(LoxError::Exit(3))));
+        assert_eq!("before\n", out);
+
+        let (result, _) = run("exit(1.5);");
+        assert!(matches!(result, Err(LoxError::Runtime(..))));
+    }
+
+    #[test]
+    fn classes() {
+        let (result, out) = run("class Pair {
+                describe() { return \"a pair\"; }
+            }
+            var p = Pair();
+            p.first = 1;
+            print p.second = 2;
+            print p.first + p.second;
+            print p.describe();
+            p.describe = \"shadowed\";
+            print p.describe;
+            print Pair;
+            print p;
+            var q = Pair();
+            q.first = 10;
+            print p.first;");
+
+        assert!(result.is_ok());
+        assert_eq!("2\n3\na pair\nshadowed\nPair\nPair instance\n1\n", out);
+
+        assert!(run("var x = 1; x.y;").0.is_err());
+        assert!(run("var x = 1; x.y = 2;").0.is_err());
+        assert!(run("class A {} A().missing;").0.is_err());
+        assert!(run("class A {} A(1);").0.is_err());
+    }
+
+    #[test]
+    fn field_reflection() {
+        let (result, out) = run("class Point {
+                sum() { return this.x + this.y; }
+            }
+            var p = Point();
+            p.y = 2;
+            p.x = 1;
+            print fields(p);
+            print getField(p, \"y\");
+            print setField(p, \"x\", 10);
+            print p.x;
+            setField(p, \"z\", 3);
+            print fields(p);
+            print p.sum();");
+
+        assert!(result.is_ok());
+        assert_eq!("[x, y]\n2\n10\n10\n[x, y, z]\n12\n", out);
+
+        assert!(run("class A {} getField(A(), \"missing\");").0.is_err());
+        assert!(run("class A { m() {} } getField(A(), \"m\");").0.is_err());
+        assert!(run("fields([1]);").0.is_err());
+        assert!(run("class A {} setField(A(), 1, 2);").0.is_err());
+    }
+
+    #[test]
+    fn class_reflection() {
+        let (result, out) = run("class Shape {
+                area() { return 0; }
+                describe() { return \"a shape\"; }
+            }
+            print methods(Shape);
+            print superclass(Shape);
+            print name(Shape);
+            class Empty {}
+            print methods(Empty);
+            var s = Shape();
+            print s.area();");
+
+        assert!(result.is_ok());
+        assert_eq!("[area, describe]\nnil\nShape\n[]\n0\n", out);
+
+        assert!(run("class A {} methods(A());").0.is_err());
+        assert!(run("superclass(1);").0.is_err());
+        assert!(run("name(\"A\");").0.is_err());
+    }
+
+    #[test]
+    fn bound_methods() {
+        let (result, out) = run("class Counter {
+                increment() { this.count = this.count + 1; return this; }
+                show() { print this.count; }
+            }
+            var c = Counter();
+            c.count = 0;
+            c.increment().increment();
+            var show = c.show;
+            c.count = 5;
+            show();
+            var other = Counter();
+            other.count = 100;
+            other.show = c.increment;
+            other.show();
+            print c.count;
+            print show;");
+
+        assert!(result.is_ok());
+        assert_eq!("5\n6\n<fn show>\n", out);
+    }
+
+    #[test]
+    fn initializers() {
+        let (result, out) = run("class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                    if (x > 100) return;
+                    this.small = true;
+                }
+            }
+            var p = Point(1, 2);
+            print p.x + p.y;
+            print p.small;
+            print Point(101, 0).x;
+            print p.init(3, 4) == p;
+            print p.x;");
+
+        assert!(result.is_ok());
+        assert_eq!("3\ntrue\n101\ntrue\n3\n", out);
+        assert!(run("class A { init(a) {} } A();").0.is_err());
+        assert!(run("class A {} A(1);").0.is_err());
+    }
+
+    #[test]
+    fn bytes() {
+        let (result, out) = run("var b = b\"hi\\x00\\n\";
+            print b;
+            print b[1];
+            print b[1:3] == b\"i\\0\";
+            print b[:1] + x\"ff 00\";
+            print b[2:];
+            print b[:];
+            print utf8Decode(b[:2]);
+            print hexEncode(utf8Encode(\"é\"));
+            print hexDecode(\"CAFE\") == x\"cafe\";");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            "b\"hi\\x00\\n\"\n105\ntrue\nb\"h\\xff\\x00\"\nb\"\\x00\\n\"\nb\"hi\\x00\\n\"\nhi\nc3a9\ntrue\n",
+            out
+        );
+        assert!(run("b\"ab\"[2];").0.is_err());
+        assert!(run("b\"ab\"[0.5];").0.is_err());
+        assert!(run("b\"ab\"[2:1];").0.is_err());
+        assert!(run("b\"ab\"[0:3];").0.is_err());
+        assert!(run("\"ab\"[0];").0.is_err());
+        assert!(run("utf8Decode(x\"ff\");").0.is_err());
+        assert!(run("hexDecode(\"abc\");").0.is_err());
+    }
+
+    #[test]
+    fn generators() {
+        let (result, out) = run("fun count(from, to) {
+                var i = from;
+                while (to > i) {
+                    yield i;
+                    i = i + 1;
+                }
+                return \"done\";
+            }
+            var g = count(1, 3);
+            print g;
+            print g();
+            print g();
+            print g();
+            print g();
+            for (var n in count(0, 10)) {
+                if (n == 1) continue;
+                if (n == 4) break;
+                print n;
+            }
+            class Letters {
+                init(word) { this.word = word; }
+                each() { yield this.word; yield this.word + \"!\"; }
+            }
+            for (var w in Letters(\"hi\").each()) print w;
+            fun nested() {
+                for (var a in count(0, 2)) for (var b in count(0, 2)) yield a * 10 + b;
+            }
+            for (var x in nested()) print x;");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            "<generator count>\n1\n2\ndone\nnil\n0\n2\n3\nhi\nhi!\n0\n1\n10\n11\n",
+            out
+        );
+        assert!(run("fun f() { yield 1; } f()(1);").0.is_err());
+        assert!(run("for (var x in 1) print x;").0.is_err());
+        assert!(run("var g; fun f() { g(); yield 1; } g = f(); g();")
+            .0
+            .is_err());
+    }
+
+    #[test]
+    fn pipeline() {
+        let (result, out) = run("fun double(n) { return n * 2; }
+            fun add(a, b) { return a + b; }
+            class Format { prefix(s, p) { return p + s; } }
+            var format = Format();
+            print 1 + 2 |> double |> add(10);
+            print 3 |> add(1) |> toFixed(2) |> format.prefix(\"$\");
+            fun adder(n) { fun add(x) { return x + 1; } return add; }
+            print 1 |> adder(0)();");
+
+        assert!(result.is_ok());
+        assert_eq!("16\n$4.00\n2\n", out);
+        assert!(run("1 |> double;").0.is_err());
+        assert!(run("fun f() {} 1 |> f;").0.is_err());
+    }
+
+    #[test]
+    fn safe_navigation() {
+        let (result, out) = run("class Node {
+                describe(prefix) { return prefix + this.name; }
+            }
+            var node = Node();
+            node.name = \"root\";
+            node.next = nil;
+            print node?.name;
+            print node?.describe(\"node \");
+            print node.next?.name;
+            var calls = 0;
+            fun count() { calls = calls + 1; return \"\"; }
+            print node.next?.describe(count());
+            print calls;
+            print node?.next?.name;");
+
+        result.unwrap();
+        assert_eq!("root\nnode root\nnil\nnil\n0\nnil\n", out);
+
+        assert!(run("var x = 1; x?.y;").0.is_err());
+        assert!(run("class A {} A()?.missing;").0.is_err());
+        assert!(run("var x; x?.y.z;").0.is_err());
+    }
+
+    #[test]
+    fn keyword_arguments() {
+        let (result, out) = run("fun point(x, y, z) { print [x, y, z]; }
+            point(1, z: 3, y: 2);
+            var order = [];
+            fun arg(n) { push(order, n); return n; }
+            point(z: arg(\"c\"), x: arg(\"a\"), y: arg(\"b\"));
+            print order;
+            class Range {
+                init(start, end) { this.start = start; this.end = end; }
+                clamp(n, low) { return n - low; }
+            }
+            var r = Range(end: 10, start: 1);
+            print r.end - r.start;
+            print r.clamp(low: 2, n: 5);
+            var f = point;
+            try { f(1, 2, w: 3); } catch (e) { print e; }
+            try { f(1, 2, x: 3); } catch (e) { print e; }
+            try { len(list: []); } catch (e) { print e; }");
+
+        result.unwrap();
+        assert_eq!(
+            "[1, 2, 3]\n[a, b, c]\n[c, a, b]\n9\n3\n\
+             point() has no parameter 'w'\npoint() was given argument 'x' twice\n\
+             only functions, methods and classes with an initializer take keyword arguments\n",
+            out
+        );
+
+        assert!(run("fun f(a, b) {} var g = f; g(a: 1);").0.is_err());
+        assert!(run("class A {} A(a: 1);").0.is_err());
+    }
+
+    #[test]
+    fn repetition() {
+        let (result, out) = run("print \"ab\" * 3;
+            print 2 * \"-\" + \"|\";
+            print \"x\" * 0 == \"\";
+            print x\"0102\" * 2;
+            print [1, nil] * 2;
+            print len(0 * [1]);
+            var grid = [[0]] * 2;
+            grid[0][0] = 1;
+            print grid;");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            "ababab\n--|\ntrue\nb\"\\x01\\x02\\x01\\x02\"\n[1, nil, 1, nil]\n0\n[[1], [1]]\n",
+            out
+        );
+        assert!(run("\"ab\" * 1.5;").0.is_err());
+        assert!(run("\"ab\" * -1;").0.is_err());
+        assert!(run("\"ab\" * \"ab\";").0.is_err());
+        assert!(run("\"ab\" * 100000000;").0.is_err());
+        assert!(run("\"ab\" * 1000000000000000000000000;").0.is_err());
+        assert!(run("[1] * -1;").0.is_err());
+        assert!(run("[1, 2] * 100000000;").0.is_err());
+    }
+
+    #[test]
+    fn arity_errors() {
+        let (result, out) = run("fun f(a) {} try { f(1, 2); } catch (e) { print e; }
+            fun g(a, b) {} try { g(); } catch (e) { print e; }
+            class A {} try { A(1); } catch (e) { print e; }
+            try { clock(1); } catch (e) { print e; }");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            "f() expected 1 argument but got 2\n\
+             g() expected 2 arguments but got 0\n\
+             A() expected 0 arguments but got 1\n\
+             clock() expected 0 arguments but got 1\n",
+            out
+        );
+    }
+
+    #[test]
+    fn concatenation_limit() {
+        // Doubling a value in a loop stops at the same limit as repeating it
+        let (result, out) = run("var s = \"x\";
+            try { while (true) s = s + s; } catch (e) { print e; }
+            print len(s);
+            var b = x\"00\";
+            try { while (true) b = b + b; } catch (e) { print len(b); }");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            format!(
+                "concatenated value would be longer than {0} bytes\n{0}\n{0}\n",
+                MAX_REPEAT_LEN
+            ),
+            out
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        let (result, out) = run("print 1 < 2;
+            print 2 < 1;
+            fun middle() { print \"middle\"; return 5; }
+            print 0 < middle() < 10;
+            print 0 < middle() <= 5 > 1;
+            print 10 < middle() < 20;
+            print 0 < 11 < 10;
+            print 0 < 1 < 2 < 3 < 2;
+            print (0 < 1) == true;");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            "true\nfalse\nmiddle\ntrue\nmiddle\ntrue\nmiddle\nfalse\nfalse\nfalse\ntrue\n",
+            out
+        );
+    }
+
+    #[test]
+    fn lists() {
+        let (result, out) = run("var a = [1, \"two\", nil,];
+            print a;
+            print a[1];
+            var b = a;
+            b[0] = a[0] + 10;
+            print a[0];
+            push(a, [3]);
+            print len(a);
+            print pop(a)[0];
+            print a[1:] + [4] * 2;
+            print [] == [];
+            print b == a;
+            print pop([]);
+            print len(\"héllo\");
+            a[3] = 1;");
+
+        assert!(result.is_err());
+        assert_eq!(
+            "[1, two, nil]\ntwo\n11\n4\n3\n[two, nil, 4, 4]\nfalse\ntrue\nnil\n5\n",
+            out
+        );
+    }
+
+    #[test]
+    fn self_referencing_list() {
+        let (result, out) = run("var l = [1];
+            push(l, l);
+            print l == l;
+            print l == [1, l];
+            print l;
+            print [l, l];");
+
+        result.unwrap();
+        assert_eq!("true\nfalse\n[1, [...]]\n[[1, [...]], [1, [...]]]\n", out);
+    }
+
+    #[test]
+    fn deep_equality() {
+        let (result, out) = run("class Point {}
+            var a = Point();
+            a.x = [1, {\"y\": 2}];
+            var b = Point();
+            b.x = [1, {\"y\": 2}];
+            print a == b;
+            print deepEquals(a, b);
+            b.x[1][\"y\"] = 3;
+            print deepEquals(a, b);
+            class Other {}
+            print deepEquals(Point(), Other());
+            print deepEquals({1: 2, 3: 4}, {3: 4, 1: 2});
+            print deepEquals([1, 2], [1, 2, 3]);
+            print deepEquals(\"a\", \"a\");
+            print deepEquals(1, \"1\");
+            var l = [1];
+            push(l, l);
+            var m = [1];
+            push(m, m);
+            print deepEquals(l, m);
+            push(m, 2);
+            print deepEquals(l, m);");
+
+        result.unwrap();
+        assert_eq!(
+            "false\ntrue\nfalse\nfalse\ntrue\nfalse\ntrue\nfalse\ntrue\nfalse\n",
+            out
+        );
+    }
+
+    #[test]
+    fn frozen_objects() {
+        let (result, out) = run("class Config {}
+            var config = Config();
+            config.name = \"prod\";
+            config.ports = [80];
+            print freeze(config) == config;
+            push(config.ports, 443);
+            print config.ports;
+            try { config.name = \"dev\"; } catch (e) { print e; }
+            try { setField(config, \"debug\", true); } catch (e) { print e; }
+            print config.name;
+            var l = freeze([1, 2]);
+            try { l[0] = 3; } catch (e) { print e; }
+            try { push(l, 3); } catch (e) { print e; }
+            try { pop(l); } catch (e) { print e; }
+            print l;
+            var m = freeze({\"a\": 1});
+            try { m[\"b\"] = 2; } catch (e) { print e; }
+            try { remove(m, \"a\"); } catch (e) { print e; }
+            print m;
+            print [1] + l;");
+
+        result.unwrap();
+        assert_eq!(
+            "true\n[80, 443]\n\
+             can't modify a frozen instance\ncan't modify a frozen instance\nprod\n\
+             can't modify a frozen list\ncan't modify a frozen list\ncan't modify a frozen list\n\
+             [1, 2]\ncan't modify a frozen map\ncan't modify a frozen map\n{a: 1}\n[1, 1, 2]\n",
+            out
+        );
+
+        assert!(run("freeze(1);").0.is_err());
+        assert!(run("var l = freeze([1]); l[0] = 2;").0.is_err());
+    }
+
+    #[test]
+    fn maps() {
+        let (result, out) = run("var m = {\"one\": 1, 2: \"two\", true: nil,};
+            print m;
+            print {0: \"zero\"}[-0];
+            m[\"one\"] = 11;
+            m[3] = [3];
+            print m[\"one\"];
+            print m[\"missing\"];
+            print len(m);
+            print has(m, true);
+            print remove(m, 2);
+            print keys(m);
+            print values(m);
+            for (var k in m) print k;
+            for (var x in [1, 2]) print x * 10;
+            print {1: 2, 3: 4} == {3: 4, 1: 2};
+            var same = m;
+            print same == m;
+            print {};
+            m[nil] = 1;");
+
+        assert!(result.is_err());
+        assert_eq!(
+            "{one: 1, 2: two, true: nil}\nzero\n11\nnil\n4\ntrue\ntwo\n[one, true, 3]\n\
+             [11, nil, [3]]\none\ntrue\n3\n10\n20\nfalse\ntrue\n{}\n",
+            out
+        );
+    }
+
+    #[test]
+    fn self_referencing_map() {
+        let (result, out) = run("var m = {};
+            m[\"self\"] = m;
+            m[\"list\"] = [m];
+            print m == m;
+            print m;");
+
+        result.unwrap();
+        assert_eq!("true\n{self: {...}, list: [{...}]}\n", out);
+    }
+
+    #[test]
+    fn exceptions() {
+        let (result, out) = run("fun fail(n) {
+                if (n > 0) fail(n - 1);
+                throw \"failed at \" + toFixed(n, 0);
+            }
+            try {
+                var a = 1;
+                fail(3);
+                print \"unreachable\";
+            } catch (e) {
+                print e;
+            }
+            try {
+                print 1 + nil;
+            } catch (e) {
+                print e;
+            }
+            fun early() {
+                try { return \"returned\"; } catch (e) {}
+            }
+            print early();
+            for (var i = 0; i < 3; i = i + 1) {
+                try {
+                    if (i == 1) break;
+                } catch (e) {}
+            }
+            try {
+                try { throw 1; } catch (e) { throw e + 1; }
+            } catch (e) {
+                print e;
+            }
+            var x = \"after\";
+            print x;
+            throw \"oops\";");
+
+        assert!(result.is_err());
+        assert_eq!(
+            "failed at 0\ncannot perform add on non-numeric values\nreturned\n2\nafter\n",
+            out
+        );
+
+        let options = CompileOptions::default();
+        let source = "fun g() { try { yield 1; } catch (e) {} }";
+        assert!(crate::compiler::compile(source.to_string(), &options).is_err());
+    }
+
+    fn run_at(source: &str, opt_level: OptLevel) -> (LoxResult<Value>, String) {
+        let options = CompileOptions {
+            opt_level,
+            ..Default::default()
+        };
+        let script = crate::compiler::compile(source.to_string(), &options).unwrap();
+        let out = Buffer::default();
+        let result = VM::with_output(Box::new(out.clone())).run(&script);
+        (result, out.contents())
+    }
+
+    #[test]
+    fn optimized() {
+        let source = "{
+                var n = 3;
+                var label = \"count\";
+                var i = 0;
+                while (i < n) { print label + \": \" + toFixed(i * 2 + 1, 0); i = i + 1; }
+                var nan = 0 / 0;
+                print nan >= nan;
+                print !(n > 2) == false;
+            }
+            fun f(a) { var b = 2; return a * b + b * 10; }
+            print f(4);";
+        let outputs: Vec<_> = [OptLevel::O0, OptLevel::O1, OptLevel::O2]
+            .into_iter()
+            .map(|opt_level| run_at(source, opt_level).1)
+            .collect();
+
+        assert_eq!("count: 1\ncount: 3\ncount: 5\ntrue\ntrue\n28\n", outputs[0]);
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+    }
+
+    #[test]
+    fn inlined_calls() {
+        let source = "fun square(x) { return x * x; }
+            fun greet(name) { return \"hi \" + name; }
+            fun scale(a, b) { return a * b + offset; }
+            fun fact(n) { if (n < 2) return 1; return n * fact(n - 1); }
+            fun first(a, b) { return a; }
+            fun twice(x) { return x + x; }
+            var offset = 1;
+            print square(3) + square(square(2));
+            print greet(\"bob\");
+            print scale(2, 3);
+            print fact(5);
+            print first(1, 2);
+            print twice(2);
+            twice = square;
+            print twice(3);
+            print square(1, 2);";
+        let (result, out) = run_at(source, OptLevel::O0);
+        assert_eq!("25\nhi bob\n7\n120\n1\n4\n9\n", out);
+        assert!(result.is_err());
+
+        // The last call has too many arguments, which still fails
+        let (inlined, inlined_out) = run_at(source, OptLevel::O2);
+        assert_eq!(out, inlined_out);
+        assert!(inlined.is_err());
+    }
+
+    #[test]
+    fn debug_output() {
+        let script =
+            crate::compiler::compile("print 1;".to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        let debug = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.set_debug_output(Box::new(debug.clone()));
+        vm.set_trace_execution(false);
+        vm.run(&script).unwrap();
+        assert_eq!("", debug.contents());
+
+        vm.set_disassemble(true);
+        vm.set_trace_execution(true);
+        vm.run(&script).unwrap();
+        assert_eq!("1\n1\n", out.contents());
+        let debug = debug.contents();
+        assert!(
+            debug.starts_with("== RUN ==\n0000    1 OP_CONSTANT"),
+            "{}",
+            debug
+        );
+        assert!(
+            debug.contains("[ <script> ][ 1 ]\n0002    | OP_PRINT"),
+            "{}",
+            debug
+        );
+    }
+
+    #[test]
+    fn profiling() {
+        let script = crate::compiler::compile(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } fib(10);"
+                .to_string(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        vm.run(&script).unwrap();
+        assert!(vm.profile().is_none());
+
+        vm.start_profile();
+        vm.run(&script).unwrap();
+        assert_eq!(177, vm.profile().unwrap().calls("fib"));
+    }
+
+    #[test]
+    fn probes() {
+        let source = "var n = 0;\nwhile (n < 2)\n  n = n + 1;\nif (n > 5) {\n  print n;\n}\n";
+        let options = CompileOptions {
+            probes: true,
+            ..Default::default()
+        };
+        let script = crate::compiler::compile(source.to_string(), &options).unwrap();
+        let lines: Vec<_> = script.probes().iter().map(|span| span.line).collect();
+        assert_eq!(vec![1, 2, 3, 4, 4, 5], lines);
+
+        // Each statement is traced as it starts
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        let traced = Rc::clone(&hits);
+        vm.set_probe_handler(move |id| traced.borrow_mut().push(lines[id]));
+        vm.run(&script).unwrap();
+        assert_eq!(vec![1, 2, 3, 3, 4], *hits.borrow());
+
+        // Without a handler, probes do nothing
+        assert!(VM::with_output(Box::new(Buffer::default()))
+            .run(&script)
+            .is_ok());
+        let plain = crate::compiler::compile(source.to_string(), &CompileOptions::default());
+        assert!(plain.unwrap().probes().is_empty());
+    }
+
+    #[test]
+    fn stats() {
+        let script = crate::compiler::compile(
+            "fun f(a, b) { return a + b; } print f(1, 2);".to_string(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        vm.run(&script).unwrap();
+        assert!(vm.stats().is_none());
+
+        vm.start_stats();
+        vm.run(&script).unwrap();
+        // The script and `f`, its arguments, and the copies of them `f` adds
+        let expected = RunStats {
+            instructions: 13,
+            peak_stack: 6,
+        };
+        assert_eq!(Some(&expected), vm.stats());
+    }
+
+    #[test]
+    fn values_share_objects() {
+        let value = Value::from_bytes(vec![1, 2, 3]);
+        let copy = value.clone();
+        match (&value, &copy) {
+            (Value::Obj(a), Value::Obj(b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("expected objects"),
+        }
+    }
+
+    #[test]
+    fn long_constants() {
+        let source: String = (0..300).map(|i| format!("print \"s{}\";", i)).collect();
+        let (result, out) = run(&source);
+
+        assert!(result.is_ok());
+        let expected: String = (0..300).map(|i| format!("s{}\n", i)).collect();
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn undefined_variables() {
+        for (source, name) in [("print a;", "a"), ("var b; c = 1;", "c")] {
+            let (result, _) = run(source);
+            assert!(matches!(
+                result,
+                Err(LoxError::Runtime(ScriptError::Runtime(RuntimeError::UndefinedVariable(n)), _)) if n == name
+            ));
+        }
+        // Assigning doesn't define it either
+        let (result, _) = run("fun f() { d = 1; } try { f(); } catch (e) { print e; } print d;");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn type_checks() {
+        let source = "fun f(a: number, b, c: string) { print a; }
+            class C { m(x: instance) { return x; } }
+            f(1, nil, \"s\");
+            print C().m(C());
+            try { f(1, 2, 3); } catch (e) { print e; }
+            C().m(C);";
+        let options = CompileOptions {
+            check_types: true,
+            ..Default::default()
+        };
+        let script = crate::compiler::compile(source.to_string(), &options).unwrap();
+        let out = Buffer::default();
+        let result = VM::with_output(Box::new(out.clone())).run(&script);
+
+        assert!(matches!(
+            result,
+            Err(LoxError::Runtime(
+                ScriptError::Conversion(ConversionError::Argument(1, _)),
+                _
+            ))
+        ));
+        assert_eq!(
+            "1\nC instance\nargument 3: expected a string, got '3'\n",
+            out.contents()
+        );
+
+        // Without the option, annotations don't change what runs
+        let (result, out) = run(source);
+        assert!(result.is_ok());
+        assert_eq!("1\nC instance\n1\n", out);
+    }
+
+    #[test]
+    fn switch() {
+        let (result, out) = run("fun describe(n) {
+                switch (n) {
+                    case 1:
+                        var word = \"one\";
+                        return word;
+                    case 1 + 1: return \"two\";
+                    default: return \"many\";
+                }
+            }
+            print describe(1);
+            print describe(2);
+            print describe(3);
+            for (var i = 0; i < 3; i = i + 1) {
+                switch (i) {
+                    case 0: print \"zero\";
+                    case 1: break;
+                }
+                print i;
+            }
+            switch (\"x\") { case \"y\": print \"no\"; }
+            print \"done\";");
+
+        assert!(result.is_ok());
+        assert_eq!("one\ntwo\nmany\nzero\n0\ndone\n", out);
+    }
+
+    #[test]
+    fn type_predicates() {
+        let (result, out) = run("class A { m() {} }
+            fun f() {}
+            fun check(v) {
+                print (isNil(v) and \"nil\") or (isBool(v) and \"bool\")
+                    or (isNumber(v) and \"number\") or (isString(v) and \"string\")
+                    or (isBytes(v) and \"bytes\") or (isFunction(v) and \"function\")
+                    or (isClass(v) and \"class\") or (isInstance(v) and \"instance\");
             }
+            check(nil); check(true); check(1); check(\"s\"); check(b\"b\");
+            check(f); check(A().m); check(clock); check(A); check(A());");
+
+        assert!(result.is_ok());
+        assert_eq!(
+            "nil\nbool\nnumber\nstring\nbytes\nfunction\nfunction\nfunction\nclass\ninstance\n",
+            out
+        );
+    }
+
+    #[test]
+    fn natives() {
+        let (result, out) = run("print toFixed(2.5627, 2);
+            print toPrecision(123.456, 4) + \"!\";
+            print clock;
+            print clock() >= 0;
+            print num(\"2.5\") + num(\"0.1\");
+            print num(\"2,5\");");
+
+        assert!(result.is_ok());
+        assert_eq!("2.56\n123.5!\n<native fn>\ntrue\n2.6\nnil\n", out);
+        assert!(run("toFixed(1);").0.is_err());
+        assert!(run("toFixed(\"1\", 2);").0.is_err());
+    }
+
+    #[test]
+    fn capabilities() {
+        let source = "print formatTime(86400, \"%Y-%m-%d\");";
+        let (result, out) = run(source);
+        assert!(result.is_err());
+        assert_eq!("", out);
+
+        let script = crate::compiler::compile(source.to_string(), &CompileOptions::default());
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.grant(Capability::Time);
+        vm.run(&script.unwrap()).unwrap();
+        assert_eq!("1970-01-02\n", out.contents());
+
+        // Running other programs isn't allowed unless granted either
+        let (result, _) = run("exec(\"true\", []);");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn deterministic() {
+        let source = "print random(); print pow(2, 0.5); print sin(1) + cos(1); print sqrt(16);";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let runs: Vec<String> = (0..2)
+            .map(|_| {
+                let out = Buffer::default();
+                let mut vm = VM::with_output(Box::new(out.clone()));
+                vm.set_deterministic();
+                vm.run(&script).unwrap();
+                out.contents()
+            })
+            .collect();
+
+        // The same on every platform, so the exact digits can be checked
+        assert_eq!(runs[0], runs[1]);
+        assert_eq!(
+            "0.753439610411553\n1.414213562373095\n1.3817732906760363\n4\n",
+            runs[0]
+        );
+    }
+
+    #[test]
+    fn random_is_per_vm() {
+        let deterministic = || {
+            let mut vm = VM::with_output(Box::new(Buffer::default()));
+            vm.set_deterministic();
+            vm
+        };
+        let mut alone = deterministic();
+        let expected: Vec<_> = (0..4)
+            .map(|_| alone.eval_expression("random()").unwrap())
+            .collect();
+
+        // Drawing from one VM doesn't move the other on, nor does starting a third
+        let (mut a, mut b) = (deterministic(), deterministic());
+        let mut drawn = (Vec::new(), Vec::new());
+        for _ in 0..4 {
+            drawn.0.push(a.eval_expression("random()").unwrap());
+            deterministic();
+            drawn.1.push(b.eval_expression("random()").unwrap());
         }
+        assert_eq!(expected, drawn.0);
+        assert_eq!(expected, drawn.1);
+    }
+
+    #[test]
+    fn threads() {
+        let source = "fun work(job) {
+                var sum = 0;
+                for (var x in job[\"items\"]) sum = sum + x;
+                send(job[\"results\"], sum);
+                return [job[\"items\"][0], sum];
+            }
+            var results = channel();
+            var items = [1, 2, 3];
+            var worker = spawn(work, {\"items\": items, \"results\": results});
+            print recv(results);
+            print join(worker);
+            items[0] = 10;
+            print items;
+            spawn(work, clock);";
+        assert!(run(source).0.is_err());
+
+        let script = crate::compiler::compile(source.to_string(), &CompileOptions::default());
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.grant(Capability::Threads);
+        assert!(vm.run(&script.unwrap()).is_err());
+        assert_eq!("6\n[1, 6]\n[10, 2, 3]\n", out.contents());
+
+        // A thread's error is raised by `join`, rather than reported by the thread
+        let source = "fun fail(x) { return x.missing; }
+            try { join(spawn(fail, 1)); } catch (e) { print e; }";
+        let script = crate::compiler::compile(source.to_string(), &CompileOptions::default());
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.grant(Capability::Threads);
+        vm.run(&script.unwrap()).unwrap();
+        assert_eq!(
+            "join: thread failed: only instances have properties\n",
+            out.contents()
+        );
+
+        // Threads have the capabilities of the VM that spawned them
+        let source = "fun date(t) { return formatTime(t, \"%Y\"); }
+            print join(spawn(date, 0));";
+        let script = crate::compiler::compile(source.to_string(), &CompileOptions::default());
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.grant(Capability::Threads);
+        vm.grant(Capability::Time);
+        vm.run(&script.unwrap()).unwrap();
+        assert_eq!("1970\n", out.contents());
+    }
+
+    #[test]
+    fn thread_limits() {
+        let run_limited = |source: &str| {
+            let script = crate::compiler::compile(source.to_string(), &CompileOptions::default());
+            let out = Buffer::default();
+            let mut vm = VM::with_output(Box::new(out.clone()));
+            vm.grant(Capability::Threads);
+            vm.set_options(VmOptions {
+                max_instructions: Some(5000),
+                ..Default::default()
+            });
+            (vm.run(&script.unwrap()), out.contents())
+        };
+        let instruction_limit = |result: &LoxResult<Value>| {
+            matches!(
+                result,
+                Err(LoxError::Runtime(
+                    ScriptError::Runtime(RuntimeError::LimitExceeded("instruction", _)),
+                    _
+                ))
+            )
+        };
+
+        // A thread's instructions count against the limit of the script that spawned it
+        let (result, out) = run_limited(
+            "fun spin(x) { while (true) {} }
+            try { join(spawn(spin, nil)); } catch (e) { print e; }",
+        );
+        assert!(instruction_limit(&result), "{:?}", result);
+        assert_eq!("", out);
+
+        // So spawning more threads doesn't get a script more instructions
+        let count = "fun count(n) { var i = 0; while (i < n) i = i + 1; return i; }";
+        let (result, out) = run_limited(&format!("{} print join(spawn(count, 300));", count));
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!("300\n", out);
+        let (result, _) = run_limited(&format!(
+            "{} var threads = [];
+            for (var i = 0; i < 4; i = i + 1) push(threads, spawn(count, 300));
+            for (var thread in threads) join(thread);",
+            count
+        ));
+        assert!(instruction_limit(&result), "{:?}", result);
+    }
+
+    #[test]
+    fn interrupting_threads() {
+        // The thread stops, and so does the script waiting for it rather than catching its error
+        let script = crate::compiler::compile(
+            "fun spin(x) { while (true) {} }
+            try { join(spawn(spin, nil)); } catch (e) { print \"caught\"; }"
+                .to_string(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let flag = Arc::new(AtomicBool::new(false));
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.grant(Capability::Threads);
+        vm.set_interrupt(Arc::clone(&flag));
+
+        let setter = Arc::clone(&flag);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            setter.store(true, Ordering::Relaxed);
+        });
+        assert!(matches!(
+            vm.run(&script),
+            Err(LoxError::Runtime(
+                ScriptError::Runtime(RuntimeError::Interrupted),
+                _
+            ))
+        ));
+        assert_eq!("", out.contents());
+        assert!(!flag.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn call_errors() {
+        assert!(run("fun f(a) {} f();").0.is_err());
+        assert!(run("var f = 1; f();").0.is_err());
+        assert!(run("fun f() { f(); } f();").0.is_err());
+
+        let options = CompileOptions {
+            lang: crate::lang::Lang::Strict,
+            ..Default::default()
+        };
+        assert!(crate::compiler::compile("return;".to_string(), &options).is_err());
+    }
+
+    #[test]
+    fn runtime_errors_keep_operands() {
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+
+        assert!(vm.eval_expression("1 + (2 * nil)").is_err());
+        assert_eq!(
+            vec![Value::Number(1.0), Value::Number(2.0), Value::Nil],
+            vm.stack[1..]
+        );
+
+        assert!(vm.eval_expression("-nil").is_err());
+        assert_eq!(vec![Value::Nil], vm.stack[1..]);
+    }
+
+    #[test]
+    fn comparisons_need_numbers() {
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+
+        for source in [
+            "nil < 1",
+            "\"b\" > 1",
+            "0 < \"a\"",
+            "[1] < [2]",
+            "\"a\" <= \"b\"",
+        ] {
+            let e = vm.eval_expression(source).unwrap_err();
+            assert!(
+                matches!(
+                    e,
+                    LoxError::Runtime(ScriptError::Evaluation(EvaluationError::Comparision(_)), _)
+                ),
+                "{} gave {:?}",
+                source,
+                e
+            );
+        }
+        assert!(vm.eval_expression("nil > 1").is_err());
+        assert_eq!(
+            vec![Value::Nil, Value::Number(1.0)],
+            vm.stack[vm.stack.len() - 2..]
+        );
+
+        assert_eq!(Value::Bool(true), vm.eval_expression("1 < 2").unwrap());
+        assert_eq!(Value::Bool(false), vm.eval_expression("1 >= 2").unwrap());
+        assert_eq!(
+            "operands of '>' must be numbers\n",
+            run("try { print nil > 1; } catch (e) { print e; }").1
+        );
+    }
+
+    #[test]
+    fn strict_truthiness() {
+        let mut vm = VM::with_output(Box::new(Buffer::default()));
+        vm.set_truthiness(Truthiness::Strict);
+
+        assert_eq!(Value::Bool(true), vm.eval_expression("!nil").unwrap());
+        assert_eq!(
+            Value::Number(1.0),
+            vm.eval_expression("true and 1").unwrap()
+        );
+        assert!(vm.eval_expression("nil or true").is_err());
+        assert!(vm.eval_expression("1 and true").is_err());
+
+        let script =
+            crate::compiler::compile("if (0) {}".to_string(), &CompileOptions::default()).unwrap();
+        assert!(vm.run(&script).is_err());
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let source = "try {
+                var n = 2; while (true) n = n * n;
+            } catch (e) {
+                print e;
+            }
+            print 1 / 0;";
+        let script =
+            crate::compiler::compile(source.to_string(), &CompileOptions::default()).unwrap();
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        vm.set_checked_arithmetic(true);
+
+        assert!(vm.run(&script).is_err());
+        assert_eq!("arithmetic produced inf on line 2\n", out.contents());
+        assert!(vm.eval_expression("0 / 0").is_err());
+        assert_eq!(Value::Number(0.5), vm.eval_expression("1 / 2").unwrap());
+        // Without the mode, the book's IEEE behaviour is kept
+        assert_eq!("inf\n", run("print 1 / 0;").1);
+    }
+
+    #[test]
+    fn interpret() {
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+
+        vm.interpret("var count = 0; fun bump() { count = count + 1; }")
+            .unwrap();
+        vm.interpret("bump(); bump(); print count;").unwrap();
+        assert_eq!("2\n", out.contents());
+
+        let LoxError::Compile(e) = vm.interpret("print ;").unwrap_err() else {
+            panic!("expected a compile error");
+        };
+        assert_eq!(" at ';'", e.diagnostics[0].location);
+        assert_eq!("expected expression", e.diagnostics[0].message);
+
+        let e = vm.interpret("bump(1);").unwrap_err();
+        assert_eq!("bump() expected 0 arguments but got 1", e.to_string());
+        assert!(matches!(
+            e,
+            LoxError::Runtime(ScriptError::Runtime(RuntimeError::Arity(_, 0, 1)), _)
+        ));
+        let e = vm.interpret("print -\"a\";").unwrap_err();
+        assert!(matches!(
+            e,
+            LoxError::Runtime(ScriptError::Evaluation(EvaluationError::Negation), _)
+        ));
+        vm.register_fn("fail", 0, |_| Err(anyhow::anyhow!("host failed")));
+        let e = vm.interpret("fail();").unwrap_err();
+        let LoxError::Runtime(ScriptError::Host(e), _) = e else {
+            panic!("expected a host error, got {:?}", e);
+        };
+        assert_eq!("host failed", e.to_string());
+    }
+
+    #[test]
+    fn host_functions() {
+        let out = Buffer::default();
+        let mut vm = VM::with_output(Box::new(out.clone()));
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&calls);
+        vm.register_fn("log", 1, move |args| {
+            log.borrow_mut().push(args[0].to_string());
+            Ok(Value::Number(log.borrow().len() as f64))
+        });
+        vm.register_fn("fail", 0, |_| Err(anyhow::anyhow!("host failed")));
+
+        vm.interpret(
+            "print log(\"a\") + log(\"b\");
+            print log;
+            print isFunction(log);
+            try { fail(); } catch (e) { print e; }",
+        )
+        .unwrap();
+        assert_eq!("3\n<native fn>\ntrue\nhost failed\n", out.contents());
+        assert_eq!(vec!["a", "b"], *calls.borrow());
+        assert!(vm.interpret("log();").is_err());
+        assert_eq!(2, calls.borrow().len());
+    }
+
+    #[test]
+    fn eval_expression() {
+        let mut vm = VM::new();
+        let script =
+            crate::compiler::compile("var x = 4;".to_string(), &CompileOptions::default()).unwrap();
+        vm.run(&script).unwrap();
+
+        assert_eq!(Value::Number(9.0), vm.eval_expression("1 + 2 * x").unwrap());
+        assert_eq!(Value::Bool(true), vm.eval_expression("!nil").unwrap());
+        assert_eq!(Value::Nil, vm.eval_expression("nil and y").unwrap());
+        assert_eq!(
+            Value::Number(4.0),
+            vm.eval_expression("false or x").unwrap()
+        );
+        assert_eq!(Value::Number(4.0), vm.eval_expression("x or y").unwrap());
+        assert_eq!(
+            Value::Bool(false),
+            vm.eval_expression("1 and false or false").unwrap()
+        );
+        assert!(vm.eval_expression("1 +").is_err());
+        assert!(vm.eval_expression("1; 2").is_err());
+        assert!(vm.eval_expression("y").is_err());
     }
 }
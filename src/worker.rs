@@ -0,0 +1,249 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use anyhow::Result;
+
+use crate::chunk::{Function, Map, MapKey, Native, NativeFn, Value};
+use crate::error::NativeError;
+use crate::natives::Capability;
+use crate::vm::NativeContext;
+
+/// How deeply lists and maps can nest in a value sent between threads, which also stops a list
+/// containing itself from being copied forever.
+const MAX_MESSAGE_DEPTH: usize = 64;
+
+/// Natives for running functions on other threads, each with a VM of its own, and passing
+/// values between them.
+pub const NATIVES: &[Native] = &[
+    Native {
+        name: "spawn",
+        arity: 2,
+        function: NativeFn::Context(spawn),
+        capability: Some(Capability::Threads),
+    },
+    Native {
+        name: "join",
+        arity: 1,
+        function: NativeFn::Args(join),
+        capability: Some(Capability::Threads),
+    },
+    Native {
+        name: "channel",
+        arity: 0,
+        function: NativeFn::Args(channel),
+        capability: None,
+    },
+    Native {
+        name: "send",
+        arity: 2,
+        function: NativeFn::Args(send),
+        capability: None,
+    },
+    Native {
+        name: "recv",
+        arity: 1,
+        function: NativeFn::Args(recv),
+        capability: Some(Capability::Threads),
+    },
+];
+
+/// A value copied out of one VM to be rebuilt in another, possibly on another thread. Lists and
+/// maps are copied deeply so VMs never share mutable state; only channels are shared.
+#[derive(Debug)]
+enum Message {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    List(Vec<Message>),
+    Map(Vec<(MapKey, Message)>),
+    Function(Arc<Function>),
+    Channel(Arc<Channel>),
+}
+
+impl Message {
+    fn new(native: &'static str, value: &Value, depth: usize) -> Result<Message> {
+        if depth > MAX_MESSAGE_DEPTH {
+            let message = format!("values nested more than {} deep", MAX_MESSAGE_DEPTH);
+            return Err(NativeError::InvalidArgument(native, message).into());
+        }
+
+        let message = match value {
+            Value::Nil => Message::Nil,
+            Value::Bool(b) => Message::Bool(*b),
+            Value::Number(n) => Message::Number(*n),
+            value => {
+                if let Some(s) = value.as_string() {
+                    Message::String(s.to_string())
+                } else if let Some(bytes) = value.as_bytes() {
+                    Message::Bytes(bytes.to_vec())
+                } else if let Some(list) = value.as_list() {
+                    let items = list.borrow();
+                    let items = items
+                        .iter()
+                        .map(|item| Message::new(native, item, depth + 1));
+                    Message::List(items.collect::<Result<_>>()?)
+                } else if let Some(map) = value.as_map() {
+                    let map = map.borrow();
+                    let entries = map.entries().map(|(key, value)| {
+                        Ok((key.clone(), Message::new(native, value, depth + 1)?))
+                    });
+                    Message::Map(entries.collect::<Result<_>>()?)
+                } else if let Some(function) = value.as_function() {
+                    Message::Function(function)
+                } else if let Some(channel) = value.as_channel() {
+                    Message::Channel(channel)
+                } else {
+                    let message = format!("'{}' can't be sent to another thread", value);
+                    return Err(NativeError::InvalidArgument(native, message).into());
+                }
+            }
+        };
+        Ok(message)
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Message::Nil => Value::Nil,
+            Message::Bool(b) => Value::Bool(b),
+            Message::Number(n) => Value::Number(n),
+            Message::String(s) => Value::from_string(s),
+            Message::Bytes(bytes) => Value::from_bytes(bytes),
+            Message::List(items) => {
+                Value::from_list(items.into_iter().map(Message::into_value).collect())
+            }
+            Message::Map(entries) => {
+                let mut map = Map::default();
+                for (key, value) in entries {
+                    map.insert(key, value.into_value());
+                }
+                Value::from_map(map)
+            }
+            Message::Function(function) => Value::from_function(function),
+            Message::Channel(channel) => Value::from_channel(channel),
+        }
+    }
+}
+
+/// A queue of values any thread holding the channel can send to or receive from.
+#[derive(Debug)]
+pub struct Channel {
+    sender: Sender<Message>,
+    receiver: Mutex<Receiver<Message>>,
+}
+
+/// Channels are only equal to themselves.
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Channel {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (self == other).then_some(std::cmp::Ordering::Equal)
+    }
+}
+
+/// A function running on another thread. It finishes with its result, or the message of the
+/// error it failed with.
+#[derive(Debug)]
+pub struct Worker {
+    handle: Option<JoinHandle<Result<Message, String>>>,
+}
+
+impl PartialEq for Worker {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Worker {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (self == other).then_some(std::cmp::Ordering::Equal)
+    }
+}
+
+/// Calls a one-argument function on a new thread with its own VM, passing it a copy of `arg`.
+/// The new VM only has the natives as globals, and may itself start threads. It has the
+/// capabilities of the VM calling `spawn`, counts against the same limits, and stops when that VM
+/// is interrupted.
+fn spawn(context: &mut NativeContext, args: &[Value]) -> Result<Value> {
+    let function = match args[0].as_function() {
+        Some(function) if function.arity == 1 => function,
+        _ => {
+            let message = format!("expected a function of one argument, got '{}'", args[0]);
+            return Err(NativeError::InvalidArgument("spawn", message).into());
+        }
+    };
+    let arg = Message::new("spawn", &args[1], 0)?;
+
+    let spawner = context.spawner();
+
+    let handle = std::thread::spawn(move || {
+        let mut vm = spawner.vm();
+        let result = vm
+            .call_function(function, vec![arg.into_value()])
+            .map_err(|e| e.to_string())?;
+        Message::new("spawn", &result, 0).map_err(|e| e.to_string())
+    });
+    Ok(Value::from_worker(Worker {
+        handle: Some(handle),
+    }))
+}
+
+/// Waits for a thread to finish, returning a copy of its result.
+fn join(args: &[Value]) -> Result<Value> {
+    let Some(worker) = args[0].as_worker() else {
+        let message = format!("expected a thread, got '{}'", args[0]);
+        return Err(NativeError::InvalidArgument("join", message).into());
+    };
+    let Some(handle) = worker.borrow_mut().handle.take() else {
+        return Err(NativeError::Failed("join", "thread was already joined".to_string()).into());
+    };
+
+    match handle.join() {
+        Ok(Ok(result)) => Ok(result.into_value()),
+        Ok(Err(e)) => Err(NativeError::Failed("join", format!("thread failed: {}", e)).into()),
+        Err(_) => Err(NativeError::Failed("join", "thread failed".to_string()).into()),
+    }
+}
+
+fn channel(_args: &[Value]) -> Result<Value> {
+    let (sender, receiver) = mpsc::channel();
+    Ok(Value::from_channel(Arc::new(Channel {
+        sender,
+        receiver: Mutex::new(receiver),
+    })))
+}
+
+/// Sends a copy of a value, without waiting for it to be received.
+fn send(args: &[Value]) -> Result<Value> {
+    let channel = channel_arg("send", &args[0])?;
+    let message = Message::new("send", &args[1], 0)?;
+    // The channel holds its own receiver, so sending can't fail
+    let _ = channel.sender.send(message);
+    Ok(Value::Nil)
+}
+
+/// Waits for a value to be sent, returning it.
+fn recv(args: &[Value]) -> Result<Value> {
+    let channel = channel_arg("recv", &args[0])?;
+    let receiver = channel.receiver.lock().unwrap_or_else(|e| e.into_inner());
+    match receiver.recv() {
+        Ok(message) => Ok(message.into_value()),
+        Err(e) => Err(NativeError::Failed("recv", e.to_string()).into()),
+    }
+}
+
+fn channel_arg(native: &'static str, value: &Value) -> Result<Arc<Channel>> {
+    match value.as_channel() {
+        Some(channel) => Ok(channel),
+        None => {
+            let message = format!("expected a channel, got '{}'", value);
+            Err(NativeError::InvalidArgument(native, message).into())
+        }
+    }
+}